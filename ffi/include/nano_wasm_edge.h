@@ -0,0 +1,123 @@
+/*
+ * Build with `cargo build -p ffi --profile ffi`. Panics are only returned as
+ * NWE_STATUS_PANIC with that profile; a `--release` build aborts the process.
+ */
+
+#ifndef NANO_WASM_EDGE_H
+#define NANO_WASM_EDGE_H
+
+/* Generated by cbindgen from ffi/src/lib.rs. Do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Result of a C API call, one code per `ConnectorError` variant
+ */
+typedef enum NweStatus {
+  NWE_STATUS_OK = 0,
+  /**
+   * A required pointer was null or a string was not valid UTF-8
+   */
+  NWE_STATUS_INVALID_ARGUMENT = 1,
+  NWE_STATUS_WASM_LOAD = 2,
+  NWE_STATUS_POLICY_EXECUTION = 3,
+  NWE_STATUS_FUEL_EXHAUSTED = 4,
+  NWE_STATUS_MEMORY_OUT_OF_BOUNDS = 5,
+  NWE_STATUS_FUNCTION_NOT_FOUND = 6,
+  NWE_STATUS_SIGNATURE_MISMATCH = 7,
+  NWE_STATUS_SELF_TEST_FAILED = 8,
+  NWE_STATUS_REGISTRY = 9,
+  NWE_STATUS_CONFIG = 10,
+  NWE_STATUS_IO = 11,
+  NWE_STATUS_WASMTIME = 12,
+  NWE_STATUS_POLICY_NOT_FOUND = 13,
+  NWE_STATUS_INVALID_REQUEST = 14,
+  NWE_STATUS_EVALUATION_TIMEOUT = 15,
+  NWE_STATUS_OVERLOADED = 16,
+  /**
+   * The call panicked; the runtime may be left in its previous state.
+   * Only with the `ffi` profile: under `release` a panic aborts instead.
+   */
+  NWE_STATUS_PANIC = 17,
+} NweStatus;
+
+/**
+ * Opaque policy runtime handle
+ */
+typedef struct NweRuntime NweRuntime;
+
+/**
+ * Outcome of `nwe_runtime_evaluate`
+ */
+typedef struct NweDecision {
+  bool allowed;
+  uint64_t fuel_consumed;
+} NweDecision;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Compile a policy module from memory. On success `*out` owns a runtime
+ * that must be released with `nwe_runtime_free`.
+ */
+NweStatus nwe_runtime_new(const uint8_t *wasm, size_t len, NweRuntime **out);
+
+/**
+ * Compile a policy module from a file. The path is remembered so
+ * `nwe_runtime_reload` can re-read it.
+ */
+NweStatus nwe_runtime_from_path(const char *module_path, NweRuntime **out);
+
+/**
+ * Set the fuel budget per evaluation; also applies to later reloads
+ */
+NweStatus nwe_runtime_set_fuel_limit(NweRuntime *runtime, uint64_t fuel_limit);
+
+/**
+ * Evaluate a JSON request. On any error `out->allowed` is false.
+ */
+NweStatus nwe_runtime_evaluate(const NweRuntime *runtime,
+                               const uint8_t *request,
+                               size_t len,
+                               NweDecision *out);
+
+/**
+ * Replace the module with new bytes; the old module stays active on failure
+ */
+NweStatus nwe_runtime_reload_bytes(NweRuntime *runtime, const uint8_t *wasm, size_t len);
+
+/**
+ * Re-read the module from the path given to `nwe_runtime_from_path`; the
+ * old module stays active on failure
+ */
+NweStatus nwe_runtime_reload(NweRuntime *runtime);
+
+/**
+ * Release a runtime. Passing null is a no-op.
+ */
+void nwe_runtime_free(NweRuntime *runtime);
+
+/**
+ * Message of the last failed call on this thread, or null. Valid until the
+ * next failing call on the same thread.
+ */
+const char *nwe_last_error(void);
+
+/**
+ * Static name of a status code, e.g. `"FUEL_EXHAUSTED"`, or `"unknown"`
+ *
+ * Takes a plain integer: an out-of-range value in an enum parameter would
+ * be undefined behaviour before the call could check it.
+ */
+const char *nwe_status_name(int32_t status);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* NANO_WASM_EDGE_H */
@@ -0,0 +1,508 @@
+//! C ABI for the policy engine
+//!
+//! Lets C gateways (MQTT brokers, nginx modules, ...) evaluate policies
+//! in-process instead of over HTTP. The header lives in
+//! `ffi/include/nano_wasm_edge.h` and is generated with cbindgen.
+//!
+//! Every fallible call returns an [`NweStatus`]; on failure the message is
+//! available from [`nwe_last_error`] on the same thread. A runtime may be
+//! shared between threads: evaluations run concurrently and a reload swaps
+//! the module atomically, keeping the old one if the new one fails to load.
+//!
+//! A panic never unwinds into C: built with the `ffi` profile, which keeps
+//! unwinding, calls catch it and return [`NweStatus::Panic`]. Under
+//! `panic = "abort"` (the `release` profile) it aborts the process.
+
+#![allow(clippy::missing_safety_doc)]
+
+use host::{ConnectorError, PolicyRuntime};
+use parking_lot::RwLock;
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::Arc;
+
+/// Result of a C API call, one code per `ConnectorError` variant
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NweStatus {
+    Ok = 0,
+    /// A required pointer was null or a string was not valid UTF-8
+    InvalidArgument = 1,
+    WasmLoad = 2,
+    PolicyExecution = 3,
+    FuelExhausted = 4,
+    MemoryOutOfBounds = 5,
+    FunctionNotFound = 6,
+    SignatureMismatch = 7,
+    SelfTestFailed = 8,
+    Registry = 9,
+    Config = 10,
+    Io = 11,
+    Wasmtime = 12,
+    PolicyNotFound = 13,
+    InvalidRequest = 14,
+    EvaluationTimeout = 15,
+    Overloaded = 16,
+    /// The call panicked; the runtime may be left in its previous state.
+    /// Only with the `ffi` profile: under `release` a panic aborts instead.
+    Panic = 17,
+}
+
+impl NweStatus {
+    const ALL: [NweStatus; 18] = [
+        NweStatus::Ok,
+        NweStatus::InvalidArgument,
+        NweStatus::WasmLoad,
+        NweStatus::PolicyExecution,
+        NweStatus::FuelExhausted,
+        NweStatus::MemoryOutOfBounds,
+        NweStatus::FunctionNotFound,
+        NweStatus::SignatureMismatch,
+        NweStatus::SelfTestFailed,
+        NweStatus::Registry,
+        NweStatus::Config,
+        NweStatus::Io,
+        NweStatus::Wasmtime,
+        NweStatus::PolicyNotFound,
+        NweStatus::InvalidRequest,
+        NweStatus::EvaluationTimeout,
+        NweStatus::Overloaded,
+        NweStatus::Panic,
+    ];
+
+    fn name(self) -> &'static CStr {
+        match self {
+            NweStatus::Ok => c"OK",
+            NweStatus::InvalidArgument => c"INVALID_ARGUMENT",
+            NweStatus::WasmLoad => c"WASM_LOAD",
+            NweStatus::PolicyExecution => c"POLICY_EXECUTION",
+            NweStatus::FuelExhausted => c"FUEL_EXHAUSTED",
+            NweStatus::MemoryOutOfBounds => c"MEMORY_OUT_OF_BOUNDS",
+            NweStatus::FunctionNotFound => c"FUNCTION_NOT_FOUND",
+            NweStatus::SignatureMismatch => c"SIGNATURE_MISMATCH",
+            NweStatus::SelfTestFailed => c"SELF_TEST_FAILED",
+            NweStatus::Registry => c"REGISTRY",
+            NweStatus::Config => c"CONFIG",
+            NweStatus::Io => c"IO",
+            NweStatus::Wasmtime => c"WASMTIME",
+            NweStatus::PolicyNotFound => c"POLICY_NOT_FOUND",
+            NweStatus::InvalidRequest => c"INVALID_REQUEST",
+            NweStatus::EvaluationTimeout => c"EVALUATION_TIMEOUT",
+            NweStatus::Overloaded => c"OVERLOADED",
+            NweStatus::Panic => c"PANIC",
+        }
+    }
+}
+
+impl From<&ConnectorError> for NweStatus {
+    fn from(error: &ConnectorError) -> Self {
+        match error {
+            ConnectorError::WasmLoadError(_) => NweStatus::WasmLoad,
+            ConnectorError::PolicyExecutionError(_) => NweStatus::PolicyExecution,
+            ConnectorError::FuelExhausted { .. } => NweStatus::FuelExhausted,
+            ConnectorError::MemoryOutOfBounds { .. } => NweStatus::MemoryOutOfBounds,
+            ConnectorError::FunctionNotFound(_) => NweStatus::FunctionNotFound,
+            ConnectorError::SignatureMismatch { .. } => NweStatus::SignatureMismatch,
+            ConnectorError::SelfTestFailed { .. } => NweStatus::SelfTestFailed,
+            ConnectorError::RegistryError(_) => NweStatus::Registry,
+            ConnectorError::ConfigError(_) => NweStatus::Config,
+            ConnectorError::PolicyNotFound(_) => NweStatus::PolicyNotFound,
+            ConnectorError::InvalidRequest(_) => NweStatus::InvalidRequest,
+            ConnectorError::EvaluationTimeout { .. } => NweStatus::EvaluationTimeout,
+            ConnectorError::Overloaded(_) => NweStatus::Overloaded,
+            ConnectorError::IoError(_) => NweStatus::Io,
+            ConnectorError::WasmtimeError(_) => NweStatus::Wasmtime,
+        }
+    }
+}
+
+/// Outcome of `nwe_runtime_evaluate`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NweDecision {
+    pub allowed: bool,
+    pub fuel_consumed: u64,
+}
+
+/// Opaque policy runtime handle
+pub struct NweRuntime {
+    current: RwLock<Arc<PolicyRuntime>>,
+    fuel_limit: RwLock<u64>,
+    /// Module path for `nwe_runtime_reload` when created from a path
+    path: Option<PathBuf>,
+}
+
+impl NweRuntime {
+    fn runtime(&self) -> Arc<PolicyRuntime> {
+        self.current.read().clone()
+    }
+
+    fn swap(&self, wasm_bytes: &[u8]) -> Result<(), ConnectorError> {
+        let runtime = PolicyRuntime::new(wasm_bytes)?.with_fuel_limit(*self.fuel_limit.read());
+        *self.current.write() = Arc::new(runtime);
+        Ok(())
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn fail(error: ConnectorError) -> NweStatus {
+    let status = NweStatus::from(&error);
+    set_last_error(error.to_string());
+    status
+}
+
+/// Run the body of an exported call, turning a panic into a status
+fn guard(body: impl FnOnce() -> NweStatus) -> NweStatus {
+    catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown cause");
+        set_last_error(format!("panic: {}", message));
+        NweStatus::Panic
+    })
+}
+
+fn invalid_argument(message: &str) -> NweStatus {
+    set_last_error(message.to_string());
+    NweStatus::InvalidArgument
+}
+
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    if data.is_null() {
+        return (len == 0).then_some(&[]);
+    }
+    Some(std::slice::from_raw_parts(data, len))
+}
+
+unsafe fn path(path: *const c_char) -> Option<PathBuf> {
+    if path.is_null() {
+        return None;
+    }
+    CStr::from_ptr(path).to_str().ok().map(PathBuf::from)
+}
+
+fn new_runtime(wasm_bytes: &[u8], path: Option<PathBuf>, out: *mut *mut NweRuntime) -> NweStatus {
+    match PolicyRuntime::new(wasm_bytes) {
+        Ok(runtime) => {
+            let handle = NweRuntime {
+                current: RwLock::new(Arc::new(runtime)),
+                fuel_limit: RwLock::new(host::policy_runtime::DEFAULT_FUEL_LIMIT),
+                path,
+            };
+            // SAFETY: callers check `out` for null
+            unsafe { *out = Box::into_raw(Box::new(handle)) };
+            NweStatus::Ok
+        }
+        Err(e) => fail(e),
+    }
+}
+
+/// Compile a policy module from memory. On success `*out` owns a runtime
+/// that must be released with `nwe_runtime_free`.
+#[no_mangle]
+pub unsafe extern "C" fn nwe_runtime_new(
+    wasm: *const u8,
+    len: usize,
+    out: *mut *mut NweRuntime,
+) -> NweStatus {
+    guard(|| {
+        if out.is_null() {
+            return invalid_argument("out must not be null");
+        }
+        *out = ptr::null_mut();
+        let Some(wasm_bytes) = bytes(wasm, len) else {
+            return invalid_argument("wasm must not be null");
+        };
+        new_runtime(wasm_bytes, None, out)
+    })
+}
+
+/// Compile a policy module from a file. The path is remembered so
+/// `nwe_runtime_reload` can re-read it.
+#[no_mangle]
+pub unsafe extern "C" fn nwe_runtime_from_path(
+    module_path: *const c_char,
+    out: *mut *mut NweRuntime,
+) -> NweStatus {
+    guard(|| {
+        if out.is_null() {
+            return invalid_argument("out must not be null");
+        }
+        *out = ptr::null_mut();
+        let Some(module_path) = path(module_path) else {
+            return invalid_argument("path must be a non-null UTF-8 string");
+        };
+        match std::fs::read(&module_path) {
+            Ok(wasm_bytes) => new_runtime(&wasm_bytes, Some(module_path), out),
+            Err(e) => fail(e.into()),
+        }
+    })
+}
+
+/// Set the fuel budget per evaluation; also applies to later reloads
+#[no_mangle]
+pub unsafe extern "C" fn nwe_runtime_set_fuel_limit(
+    runtime: *mut NweRuntime,
+    fuel_limit: u64,
+) -> NweStatus {
+    guard(|| {
+        let Some(handle) = runtime.as_ref() else {
+            return invalid_argument("runtime must not be null");
+        };
+        if fuel_limit == 0 {
+            return invalid_argument("fuel_limit must be greater than 0");
+        }
+        *handle.fuel_limit.write() = fuel_limit;
+        let mut current = handle.current.write();
+        *current = Arc::new(PolicyRuntime::clone(&current).with_fuel_limit(fuel_limit));
+        NweStatus::Ok
+    })
+}
+
+/// Evaluate a JSON request. On any error `out->allowed` is false.
+#[no_mangle]
+pub unsafe extern "C" fn nwe_runtime_evaluate(
+    runtime: *const NweRuntime,
+    request: *const u8,
+    len: usize,
+    out: *mut NweDecision,
+) -> NweStatus {
+    guard(|| {
+        let Some(out) = out.as_mut() else {
+            return invalid_argument("out must not be null");
+        };
+        *out = NweDecision::default();
+        let Some(handle) = runtime.as_ref() else {
+            return invalid_argument("runtime must not be null");
+        };
+        let Some(request) = bytes(request, len) else {
+            return invalid_argument("request must not be null");
+        };
+
+        match handle.runtime().evaluate(request) {
+            Ok(evaluation) => {
+                *out = NweDecision {
+                    allowed: evaluation.allowed,
+                    fuel_consumed: evaluation.fuel_consumed,
+                };
+                NweStatus::Ok
+            }
+            Err(e) => fail(e),
+        }
+    })
+}
+
+/// Replace the module with new bytes; the old module stays active on failure
+#[no_mangle]
+pub unsafe extern "C" fn nwe_runtime_reload_bytes(
+    runtime: *mut NweRuntime,
+    wasm: *const u8,
+    len: usize,
+) -> NweStatus {
+    guard(|| {
+        let Some(handle) = runtime.as_ref() else {
+            return invalid_argument("runtime must not be null");
+        };
+        let Some(wasm_bytes) = bytes(wasm, len) else {
+            return invalid_argument("wasm must not be null");
+        };
+        match handle.swap(wasm_bytes) {
+            Ok(()) => NweStatus::Ok,
+            Err(e) => fail(e),
+        }
+    })
+}
+
+/// Re-read the module from the path given to `nwe_runtime_from_path`; the
+/// old module stays active on failure
+#[no_mangle]
+pub unsafe extern "C" fn nwe_runtime_reload(runtime: *mut NweRuntime) -> NweStatus {
+    guard(|| {
+        let Some(handle) = runtime.as_ref() else {
+            return invalid_argument("runtime must not be null");
+        };
+        let Some(module_path) = &handle.path else {
+            return invalid_argument("runtime was not created from a path");
+        };
+        match std::fs::read(module_path) {
+            Ok(wasm_bytes) => match handle.swap(&wasm_bytes) {
+                Ok(()) => NweStatus::Ok,
+                Err(e) => fail(e),
+            },
+            Err(e) => fail(e.into()),
+        }
+    })
+}
+
+/// Release a runtime. Passing null is a no-op.
+#[no_mangle]
+pub unsafe extern "C" fn nwe_runtime_free(runtime: *mut NweRuntime) {
+    if !runtime.is_null() {
+        guard(|| {
+            drop(Box::from_raw(runtime));
+            NweStatus::Ok
+        });
+    }
+}
+
+/// Message of the last failed call on this thread, or null. Valid until the
+/// next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn nwe_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Static name of a status code, e.g. `"FUEL_EXHAUSTED"`, or `"unknown"`
+///
+/// Takes a plain integer: an out-of-range value in an enum parameter would
+/// be undefined behaviour before the call could check it.
+#[no_mangle]
+pub extern "C" fn nwe_status_name(status: i32) -> *const c_char {
+    let name = NweStatus::ALL
+        .into_iter()
+        .find(|known| *known as i32 == status)
+        .map_or(c"unknown", NweStatus::name);
+    name.as_ptr()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULT_MODULE: &[u8] = include_bytes!("../../policies/default.wasm");
+
+    fn last_error() -> String {
+        let message = nwe_last_error();
+        assert!(!message.is_null());
+        unsafe { CStr::from_ptr(message) }
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn new_runtime(wasm: &[u8]) -> (NweStatus, *mut NweRuntime) {
+        let mut runtime = ptr::null_mut();
+        let status = unsafe { nwe_runtime_new(wasm.as_ptr(), wasm.len(), &mut runtime) };
+        (status, runtime)
+    }
+
+    fn evaluate(runtime: *const NweRuntime, request: &[u8]) -> (NweStatus, NweDecision) {
+        let mut decision = NweDecision::default();
+        let status = unsafe {
+            nwe_runtime_evaluate(runtime, request.as_ptr(), request.len(), &mut decision)
+        };
+        (status, decision)
+    }
+
+    #[test]
+    fn evaluates_and_reloads_the_default_module() {
+        let (status, runtime) = new_runtime(DEFAULT_MODULE);
+        assert_eq!(status, NweStatus::Ok);
+        assert!(!runtime.is_null());
+
+        let (status, decision) = evaluate(runtime, br#"{"role":"admin"}"#);
+        assert_eq!(status, NweStatus::Ok);
+        assert!(decision.allowed && decision.fuel_consumed > 0);
+        let (status, decision) = evaluate(runtime, br#"{"blocked":true}"#);
+        assert_eq!(status, NweStatus::Ok);
+        assert!(!decision.allowed);
+
+        // A broken module is rejected and the old one keeps deciding
+        let status = unsafe { nwe_runtime_reload_bytes(runtime, b"not wasm".as_ptr(), 8) };
+        assert_eq!(status, NweStatus::WasmLoad);
+        assert!(!last_error().is_empty());
+        assert!(evaluate(runtime, br#"{"role":"admin"}"#).1.allowed);
+
+        let status = unsafe {
+            nwe_runtime_reload_bytes(runtime, DEFAULT_MODULE.as_ptr(), DEFAULT_MODULE.len())
+        };
+        assert_eq!(status, NweStatus::Ok);
+        assert_eq!(
+            unsafe { nwe_runtime_set_fuel_limit(runtime, 10) },
+            NweStatus::Ok
+        );
+        assert_eq!(
+            evaluate(runtime, br#"{"role":"admin"}"#).0,
+            NweStatus::FuelExhausted
+        );
+        unsafe { nwe_runtime_free(runtime) };
+    }
+
+    #[test]
+    fn rejects_null_arguments() {
+        let mut runtime = ptr::null_mut();
+        let status = unsafe {
+            nwe_runtime_new(
+                DEFAULT_MODULE.as_ptr(),
+                DEFAULT_MODULE.len(),
+                ptr::null_mut(),
+            )
+        };
+        assert_eq!(status, NweStatus::InvalidArgument);
+        assert_eq!(last_error(), "out must not be null");
+        let status = unsafe { nwe_runtime_new(ptr::null(), 4, &mut runtime) };
+        assert_eq!(status, NweStatus::InvalidArgument);
+        assert!(runtime.is_null());
+        let status = unsafe { nwe_runtime_from_path(ptr::null(), &mut runtime) };
+        assert_eq!(status, NweStatus::InvalidArgument);
+
+        let (status, decision) = evaluate(ptr::null(), b"{}");
+        assert_eq!(status, NweStatus::InvalidArgument);
+        assert!(!decision.allowed);
+        assert_eq!(last_error(), "runtime must not be null");
+
+        let (_, runtime) = new_runtime(DEFAULT_MODULE);
+        let status = unsafe { nwe_runtime_evaluate(runtime, b"{}".as_ptr(), 2, ptr::null_mut()) };
+        assert_eq!(status, NweStatus::InvalidArgument);
+        let (status, _) = evaluate(runtime, &[]);
+        assert_eq!(status, NweStatus::Ok);
+        let mut decision = NweDecision::default();
+        let status = unsafe { nwe_runtime_evaluate(runtime, ptr::null(), 2, &mut decision) };
+        assert_eq!(status, NweStatus::InvalidArgument);
+        assert_eq!(
+            unsafe { nwe_runtime_reload(runtime) },
+            NweStatus::InvalidArgument
+        );
+        assert_eq!(
+            unsafe { nwe_runtime_reload_bytes(ptr::null_mut(), DEFAULT_MODULE.as_ptr(), 1) },
+            NweStatus::InvalidArgument
+        );
+        assert_eq!(
+            unsafe { nwe_runtime_set_fuel_limit(runtime, 0) },
+            NweStatus::InvalidArgument
+        );
+        unsafe { nwe_runtime_free(runtime) };
+        unsafe { nwe_runtime_free(ptr::null_mut()) };
+    }
+
+    #[test]
+    fn reports_panics_as_a_status() {
+        assert_eq!(guard(|| panic!("boom")), NweStatus::Panic);
+        assert_eq!(last_error(), "panic: boom");
+        assert_eq!(guard(|| panic!("{} failed", "reload")), NweStatus::Panic);
+        assert_eq!(last_error(), "panic: reload failed");
+
+        let name = |status| unsafe { CStr::from_ptr(nwe_status_name(status)) }.to_str();
+        assert_eq!(name(NweStatus::Panic as i32), Ok("PANIC"));
+        assert_eq!(name(NweStatus::Ok as i32), Ok("OK"));
+        assert_eq!(name(18), Ok("unknown"));
+        assert_eq!(name(-1), Ok("unknown"));
+    }
+}
@@ -27,6 +27,36 @@
   (data (i32.const 576) "\"viewer\"")
   (data (i32.const 592) "\"secret\"")
   (data (i32.const 608) "\"write\"")
+
+  ;; transform_payload strings - stored at offset 640
+  ;; request = 7 bytes at 640, role = 4 bytes at 656
+  ;; partner = 7 bytes at 664, admin = 5 bytes at 672
+  ;; location keys gps/lat/lon = 3 bytes at 680/684/688, location = 8 bytes at 692
+  ;; serial = 6 bytes at 704, serial_number = 13 bytes at 712
+  ;; punctuation {}[],: at 728, "***" = 5 bytes at 736
+  (data (i32.const 640) "request")
+  (data (i32.const 656) "role")
+  (data (i32.const 664) "partner")
+  (data (i32.const 672) "admin")
+  (data (i32.const 680) "gps")
+  (data (i32.const 684) "lat")
+  (data (i32.const 688) "lon")
+  (data (i32.const 692) "location")
+  (data (i32.const 704) "serial")
+  (data (i32.const 712) "serial_number")
+  (data (i32.const 728) "{}[],:")
+  (data (i32.const 736) "\"***\"")
+  (data (i32.const 768) "Document filtered")
+  (data (i32.const 800) "Document WITHHELD: malformed or too large")
+  (data (i32.const 864) "Document WITHHELD: too large to filter")
+
+  ;; Filter state of the running transform
+  (global $drop_location (mut i32) (i32.const 0))
+  (global $mask_serials (mut i32) (i32.const 0))
+  ;; Output cursor, its limit and whether a push did not fit
+  (global $out (mut i32) (i32.const 0))
+  (global $out_end (mut i32) (i32.const 0))
+  (global $overflow (mut i32) (i32.const 0))
   
   ;; Get input buffer pointer
   (func (export "get_input_buffer") (result i32)
@@ -134,4 +164,427 @@
     (call $log (i32.const 192) (i32.const 30))
     (i32.const 1)
   )
+
+  ;; ---------------------------------------------------------------------
+  ;; transform_payload: field-level filtering of a released JSON document
+  ;;
+  ;; Mirrors guest/src/lib.rs built without NANO_WASM_SERIAL_KEY: partners
+  ;; never see location fields, everyone but admins gets serials masked.
+  ;; JSON helpers take absolute positions and return -1 for malformed input.
+  ;; ---------------------------------------------------------------------
+
+  ;; Byte at $pos, or -1 past $end
+  (func $byte (param $pos i32) (param $end i32) (result i32)
+    (if (i32.ge_u (local.get $pos) (local.get $end))
+      (then (return (i32.const -1)))
+    )
+    (i32.load8_u (local.get $pos))
+  )
+
+  (func $skip_ws (param $pos i32) (param $end i32) (result i32)
+    (local $c i32)
+    (block $done
+      (loop $next
+        (local.set $c (call $byte (local.get $pos) (local.get $end)))
+        (br_if $done
+          (i32.eqz (i32.or (i32.or (i32.eq (local.get $c) (i32.const 32)) (i32.eq (local.get $c) (i32.const 9)))
+                           (i32.or (i32.eq (local.get $c) (i32.const 10)) (i32.eq (local.get $c) (i32.const 13))))))
+        (local.set $pos (i32.add (local.get $pos) (i32.const 1)))
+        (br $next)
+      )
+    )
+    (local.get $pos)
+  )
+
+  ;; Position just past the string starting at $pos
+  (func $string_end (param $pos i32) (param $end i32) (result i32)
+    (local $c i32)
+    (local.set $pos (i32.add (local.get $pos) (i32.const 1)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $pos) (local.get $end)))
+        (local.set $c (i32.load8_u (local.get $pos)))
+        (if (i32.eq (local.get $c) (i32.const 34))
+          (then (return (i32.add (local.get $pos) (i32.const 1))))
+        )
+        ;; A backslash escapes the next byte
+        (local.set $pos (i32.add (local.get $pos)
+          (select (i32.const 2) (i32.const 1) (i32.eq (local.get $c) (i32.const 92)))))
+        (br $next)
+      )
+    )
+    (i32.const -1)
+  )
+
+  ;; Position just past the value starting at $pos
+  (func $value_end (param $pos i32) (param $end i32) (result i32)
+    (local $c i32)
+    (local $depth i32)
+    (local.set $c (call $byte (local.get $pos) (local.get $end)))
+    (if (i32.lt_s (local.get $c) (i32.const 0))
+      (then (return (i32.const -1)))
+    )
+    (if (i32.eq (local.get $c) (i32.const 34))
+      (then (return (call $string_end (local.get $pos) (local.get $end))))
+    )
+
+    ;; Objects and arrays: track nesting, skipping over strings
+    (if (i32.or (i32.eq (local.get $c) (i32.const 123)) (i32.eq (local.get $c) (i32.const 91)))
+      (then
+        (block $done
+          (loop $next
+            (br_if $done (i32.ge_u (local.get $pos) (local.get $end)))
+            (local.set $c (i32.load8_u (local.get $pos)))
+            (if (i32.eq (local.get $c) (i32.const 34))
+              (then
+                (local.set $pos (call $string_end (local.get $pos) (local.get $end)))
+                (br_if $done (i32.lt_s (local.get $pos) (i32.const 0)))
+                (br $next)
+              )
+            )
+            (if (i32.or (i32.eq (local.get $c) (i32.const 123)) (i32.eq (local.get $c) (i32.const 91)))
+              (then (local.set $depth (i32.add (local.get $depth) (i32.const 1))))
+            )
+            (if (i32.or (i32.eq (local.get $c) (i32.const 125)) (i32.eq (local.get $c) (i32.const 93)))
+              (then
+                (local.set $depth (i32.sub (local.get $depth) (i32.const 1)))
+                (if (i32.eqz (local.get $depth))
+                  (then (return (i32.add (local.get $pos) (i32.const 1))))
+                )
+              )
+            )
+            (local.set $pos (i32.add (local.get $pos) (i32.const 1)))
+            (br $next)
+          )
+        )
+        (return (i32.const -1))
+      )
+    )
+
+    ;; Literals run until a delimiter or whitespace
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $pos) (local.get $end)))
+        (local.set $c (i32.load8_u (local.get $pos)))
+        (br_if $done (i32.or (i32.or (i32.eq (local.get $c) (i32.const 44)) (i32.eq (local.get $c) (i32.const 125)))
+                             (i32.eq (local.get $c) (i32.const 93))))
+        (br_if $done (i32.or (i32.or (i32.eq (local.get $c) (i32.const 32)) (i32.eq (local.get $c) (i32.const 9)))
+                             (i32.or (i32.eq (local.get $c) (i32.const 10)) (i32.eq (local.get $c) (i32.const 13)))))
+        (local.set $pos (i32.add (local.get $pos) (i32.const 1)))
+        (br $next)
+      )
+    )
+    (local.get $pos)
+  )
+
+  ;; Whether the two byte strings are equal
+  (func $equals (param $a i32) (param $a_len i32) (param $b i32) (param $b_len i32) (result i32)
+    (local $i i32)
+    (if (i32.ne (local.get $a_len) (local.get $b_len))
+      (then (return (i32.const 0)))
+    )
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $i) (local.get $a_len)))
+        (if (i32.ne (i32.load8_u (i32.add (local.get $a) (local.get $i)))
+                    (i32.load8_u (i32.add (local.get $b) (local.get $i))))
+          (then (return (i32.const 0)))
+        )
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)
+      )
+    )
+    (i32.const 1)
+  )
+
+  ;; Start of the value of $key in the object at $pos, or -1
+  (func $object_field (param $pos i32) (param $end i32) (param $key i32) (param $key_len i32) (result i32)
+    (local $key_end i32)
+    (local $value_end i32)
+    (local.set $pos (call $skip_ws (local.get $pos) (local.get $end)))
+    (if (i32.ne (call $byte (local.get $pos) (local.get $end)) (i32.const 123))
+      (then (return (i32.const -1)))
+    )
+    (local.set $pos (call $skip_ws (i32.add (local.get $pos) (i32.const 1)) (local.get $end)))
+
+    (loop $next
+      (if (i32.ne (call $byte (local.get $pos) (local.get $end)) (i32.const 34))
+        (then (return (i32.const -1)))
+      )
+      (local.set $key_end (call $string_end (local.get $pos) (local.get $end)))
+      (if (i32.lt_s (local.get $key_end) (i32.const 0))
+        (then (return (i32.const -1)))
+      )
+      (local.set $value_end (call $skip_ws (local.get $key_end) (local.get $end)))
+      (if (i32.ne (call $byte (local.get $value_end) (local.get $end)) (i32.const 58))
+        (then (return (i32.const -1)))
+      )
+      (local.set $value_end (call $skip_ws (i32.add (local.get $value_end) (i32.const 1)) (local.get $end)))
+      (if (call $equals
+            (i32.add (local.get $pos) (i32.const 1))
+            (i32.sub (i32.sub (local.get $key_end) (local.get $pos)) (i32.const 2))
+            (local.get $key) (local.get $key_len))
+        (then (return (local.get $value_end)))
+      )
+      (local.set $value_end (call $value_end (local.get $value_end) (local.get $end)))
+      (if (i32.lt_s (local.get $value_end) (i32.const 0))
+        (then (return (i32.const -1)))
+      )
+      (local.set $pos (call $skip_ws (local.get $value_end) (local.get $end)))
+      (if (i32.ne (call $byte (local.get $pos) (local.get $end)) (i32.const 44))
+        (then (return (i32.const -1)))
+      )
+      (local.set $pos (call $skip_ws (i32.add (local.get $pos) (i32.const 1)) (local.get $end)))
+      (br $next)
+    )
+    (i32.const -1)
+  )
+
+  ;; Set the filter from the request.role string of the context
+  (func $read_role (param $ctx i32) (param $ctx_end i32)
+    (local $request i32)
+    (local $request_end i32)
+    (local $role i32)
+    (local $role_end i32)
+    (global.set $drop_location (i32.const 0))
+    (global.set $mask_serials (i32.const 1))
+
+    (local.set $request (call $object_field (local.get $ctx) (local.get $ctx_end) (i32.const 640) (i32.const 7)))
+    (if (i32.lt_s (local.get $request) (i32.const 0))
+      (then (return))
+    )
+    (local.set $request_end (call $value_end (local.get $request) (local.get $ctx_end)))
+    (if (i32.lt_s (local.get $request_end) (i32.const 0))
+      (then (return))
+    )
+    (local.set $role (call $object_field (local.get $request) (local.get $request_end) (i32.const 656) (i32.const 4)))
+    (if (i32.ne (call $byte (local.get $role) (local.get $request_end)) (i32.const 34))
+      (then (return))
+    )
+    (local.set $role_end (call $string_end (local.get $role) (local.get $request_end)))
+    (if (i32.lt_s (local.get $role_end) (i32.const 0))
+      (then (return))
+    )
+
+    ;; Compare the string contents, without the quotes
+    (local.set $role (i32.add (local.get $role) (i32.const 1)))
+    (local.set $role_end (i32.sub (local.get $role_end) (i32.const 1)))
+    (global.set $drop_location
+      (call $equals (local.get $role) (i32.sub (local.get $role_end) (local.get $role)) (i32.const 664) (i32.const 7)))
+    (global.set $mask_serials
+      (i32.eqz (call $equals (local.get $role) (i32.sub (local.get $role_end) (local.get $role)) (i32.const 672) (i32.const 5))))
+  )
+
+  ;; Append bytes to the output, flagging overflow instead of writing past it
+  (func $push (param $ptr i32) (param $len i32)
+    (if (i32.gt_u (i32.add (global.get $out) (local.get $len)) (global.get $out_end))
+      (then
+        (global.set $overflow (i32.const 1))
+        (return)
+      )
+    )
+    (memory.copy (global.get $out) (local.get $ptr) (local.get $len))
+    (global.set $out (i32.add (global.get $out) (local.get $len)))
+  )
+
+  (func $drops (param $key i32) (param $len i32) (result i32)
+    (if (i32.eqz (global.get $drop_location))
+      (then (return (i32.const 0)))
+    )
+    (i32.or
+      (i32.or (call $equals (local.get $key) (local.get $len) (i32.const 680) (i32.const 3))
+              (call $equals (local.get $key) (local.get $len) (i32.const 684) (i32.const 3)))
+      (i32.or (call $equals (local.get $key) (local.get $len) (i32.const 688) (i32.const 3))
+              (call $equals (local.get $key) (local.get $len) (i32.const 692) (i32.const 8))))
+  )
+
+  (func $masks (param $key i32) (param $len i32) (result i32)
+    (if (i32.eqz (global.get $mask_serials))
+      (then (return (i32.const 0)))
+    )
+    (i32.or (call $equals (local.get $key) (local.get $len) (i32.const 704) (i32.const 6))
+            (call $equals (local.get $key) (local.get $len) (i32.const 712) (i32.const 13)))
+  )
+
+  ;; Copy the value at $pos, filtering objects inside it
+  (func $copy_value (param $pos i32) (param $end i32) (param $depth i32) (result i32)
+    (local $c i32)
+    (local $value_end i32)
+    (if (i32.gt_u (local.get $depth) (i32.const 32))
+      (then (return (i32.const -1)))
+    )
+    (local.set $c (call $byte (local.get $pos) (local.get $end)))
+    (if (i32.eq (local.get $c) (i32.const 123))
+      (then (return (call $copy_object (local.get $pos) (local.get $end) (local.get $depth))))
+    )
+    (if (i32.eq (local.get $c) (i32.const 91))
+      (then (return (call $copy_array (local.get $pos) (local.get $end) (local.get $depth))))
+    )
+    (local.set $value_end (call $value_end (local.get $pos) (local.get $end)))
+    (if (i32.lt_s (local.get $value_end) (i32.const 0))
+      (then (return (i32.const -1)))
+    )
+    (call $push (local.get $pos) (i32.sub (local.get $value_end) (local.get $pos)))
+    (local.get $value_end)
+  )
+
+  (func $copy_object (param $pos i32) (param $end i32) (param $depth i32) (result i32)
+    (local $c i32)
+    (local $first i32)
+    (local $key_start i32)
+    (local $key_end i32)
+    (local $key_len i32)
+    (call $push (i32.const 728) (i32.const 1))
+    (local.set $pos (call $skip_ws (i32.add (local.get $pos) (i32.const 1)) (local.get $end)))
+    (if (i32.eq (call $byte (local.get $pos) (local.get $end)) (i32.const 125))
+      (then
+        (call $push (i32.const 729) (i32.const 1))
+        (return (i32.add (local.get $pos) (i32.const 1)))
+      )
+    )
+
+    (local.set $first (i32.const 1))
+    (loop $next
+      (if (i32.ne (call $byte (local.get $pos) (local.get $end)) (i32.const 34))
+        (then (return (i32.const -1)))
+      )
+      (local.set $key_start (local.get $pos))
+      (local.set $key_end (call $string_end (local.get $pos) (local.get $end)))
+      (if (i32.lt_s (local.get $key_end) (i32.const 0))
+        (then (return (i32.const -1)))
+      )
+      (local.set $key_len (i32.sub (i32.sub (local.get $key_end) (local.get $key_start)) (i32.const 2)))
+      (local.set $pos (call $skip_ws (local.get $key_end) (local.get $end)))
+      (if (i32.ne (call $byte (local.get $pos) (local.get $end)) (i32.const 58))
+        (then (return (i32.const -1)))
+      )
+      (local.set $pos (call $skip_ws (i32.add (local.get $pos) (i32.const 1)) (local.get $end)))
+
+      (if (call $drops (i32.add (local.get $key_start) (i32.const 1)) (local.get $key_len))
+        (then
+          (local.set $pos (call $value_end (local.get $pos) (local.get $end)))
+        )
+        (else
+          (if (i32.eqz (local.get $first))
+            (then (call $push (i32.const 732) (i32.const 1)))
+          )
+          (local.set $first (i32.const 0))
+          (call $push (local.get $key_start) (i32.sub (local.get $key_end) (local.get $key_start)))
+          (call $push (i32.const 733) (i32.const 1))
+          (if (i32.and
+                (call $masks (i32.add (local.get $key_start) (i32.const 1)) (local.get $key_len))
+                (i32.eq (call $byte (local.get $pos) (local.get $end)) (i32.const 34)))
+            (then
+              (local.set $pos (call $string_end (local.get $pos) (local.get $end)))
+              (call $push (i32.const 736) (i32.const 5))
+            )
+            (else
+              (local.set $pos (call $copy_value (local.get $pos) (local.get $end) (i32.add (local.get $depth) (i32.const 1))))
+            )
+          )
+        )
+      )
+      (if (i32.lt_s (local.get $pos) (i32.const 0))
+        (then (return (i32.const -1)))
+      )
+
+      (local.set $pos (call $skip_ws (local.get $pos) (local.get $end)))
+      (local.set $c (call $byte (local.get $pos) (local.get $end)))
+      (if (i32.eq (local.get $c) (i32.const 44))
+        (then
+          (local.set $pos (call $skip_ws (i32.add (local.get $pos) (i32.const 1)) (local.get $end)))
+          (br $next)
+        )
+      )
+      (if (i32.eq (local.get $c) (i32.const 125))
+        (then
+          (call $push (i32.const 729) (i32.const 1))
+          (return (i32.add (local.get $pos) (i32.const 1)))
+        )
+      )
+    )
+    (i32.const -1)
+  )
+
+  (func $copy_array (param $pos i32) (param $end i32) (param $depth i32) (result i32)
+    (local $c i32)
+    (call $push (i32.const 730) (i32.const 1))
+    (local.set $pos (call $skip_ws (i32.add (local.get $pos) (i32.const 1)) (local.get $end)))
+    (if (i32.eq (call $byte (local.get $pos) (local.get $end)) (i32.const 93))
+      (then
+        (call $push (i32.const 731) (i32.const 1))
+        (return (i32.add (local.get $pos) (i32.const 1)))
+      )
+    )
+
+    (loop $next
+      (local.set $pos (call $copy_value (local.get $pos) (local.get $end) (i32.add (local.get $depth) (i32.const 1))))
+      (if (i32.lt_s (local.get $pos) (i32.const 0))
+        (then (return (i32.const -1)))
+      )
+      (local.set $pos (call $skip_ws (local.get $pos) (local.get $end)))
+      (local.set $c (call $byte (local.get $pos) (local.get $end)))
+      (if (i32.eq (local.get $c) (i32.const 44))
+        (then
+          (call $push (i32.const 732) (i32.const 1))
+          (local.set $pos (call $skip_ws (i32.add (local.get $pos) (i32.const 1)) (local.get $end)))
+          (br $next)
+        )
+      )
+      (if (i32.eq (local.get $c) (i32.const 93))
+        (then
+          (call $push (i32.const 731) (i32.const 1))
+          (return (i32.add (local.get $pos) (i32.const 1)))
+        )
+      )
+    )
+    (i32.const -1)
+  )
+
+  ;; Filter a document; returns (ptr << 32) | len of the result, or a
+  ;; negative value to withhold it
+  (func (export "transform_payload") (param $ctx_ptr i32) (param $ctx_len i32) (param $doc_ptr i32) (param $doc_len i32) (result i64)
+    (local $doc_end i32)
+    (if (i32.or (i32.or (i32.lt_s (local.get $ctx_ptr) (i32.const 0)) (i32.lt_s (local.get $ctx_len) (i32.const 0)))
+                (i32.or (i32.lt_s (local.get $doc_ptr) (i32.const 0)) (i32.le_s (local.get $doc_len) (i32.const 0))))
+      (then (return (i64.const -1)))
+    )
+    (if (i32.gt_u (local.get $doc_len) (i32.const 32768))
+      (then
+        (call $log (i32.const 864) (i32.const 38))
+        (return (i64.const -1))
+      )
+    )
+
+    (call $read_role (local.get $ctx_ptr) (i32.add (local.get $ctx_ptr) (local.get $ctx_len)))
+    (if (i32.eqz (i32.or (global.get $drop_location) (global.get $mask_serials)))
+      (then
+        (return (i64.or (i64.shl (i64.extend_i32_u (local.get $doc_ptr)) (i64.const 32))
+                        (i64.extend_i32_u (local.get $doc_len))))
+      )
+    )
+
+    ;; The filtered document goes into a second page, clear of the input
+    (if (i32.lt_s (memory.grow (i32.const 1)) (i32.const 0))
+      (then (return (i64.const -2)))
+    )
+    (global.set $out (i32.const 65536))
+    (global.set $out_end (i32.const 131072))
+    (global.set $overflow (i32.const 0))
+
+    (local.set $doc_end (i32.add (local.get $doc_ptr) (local.get $doc_len)))
+    (if (i32.or
+          (i32.lt_s
+            (call $copy_value (call $skip_ws (local.get $doc_ptr) (local.get $doc_end)) (local.get $doc_end) (i32.const 0))
+            (i32.const 0))
+          (global.get $overflow))
+      (then
+        (call $log (i32.const 800) (i32.const 41))
+        (return (i64.const -2))
+      )
+    )
+    (call $log (i32.const 768) (i32.const 17))
+    (i64.or (i64.const 0x1000000000000)
+            (i64.extend_i32_u (i32.sub (global.get $out) (i32.const 65536))))
+  )
 )
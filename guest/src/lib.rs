@@ -3,7 +3,9 @@
 //! A minimal no_std WebAssembly module for policy evaluation.
 //! Uses explicit memory definition.
 
-#![no_std]
+// Workspace builds and tests also compile the crate for the host target,
+// where it links std and its unwinding panic handler
+#![cfg_attr(target_arch = "wasm32", no_std)]
 
 use core::slice;
 
@@ -74,6 +76,390 @@ pub extern "C" fn evaluate_access(ptr: i32, len: i32) -> i32 {
     1
 }
 
+/// Largest document `transform_payload` accepts
+const MAX_DOCUMENT_LEN: usize = 32 * 1024;
+
+/// Deepest nesting `transform_payload` walks
+const MAX_DEPTH: usize = 32;
+
+/// Filtered documents are written here and returned to the host
+static mut OUTPUT: [u8; 64 * 1024] = [0; 64 * 1024];
+
+/// Key for serial number pseudonyms, 32 hex digits, set when the module is
+/// built. It is embedded in the module: anyone who can read the `.wasm` can
+/// recompute the pseudonyms, and serials with few possible values can be
+/// recovered by trying them all.
+const SERIAL_KEY: Option<&str> = option_env!("NANO_WASM_SERIAL_KEY");
+
+/// Written instead of serial numbers when the module has no key
+const SERIAL_MASK: &[u8] = b"***";
+
+/// Field-level filtering of a released JSON document
+///
+/// Partners never see location fields; everyone but admins gets serial
+/// numbers replaced by a keyed hash (SipHash-2-4 under [`SERIAL_KEY`]), or
+/// masked if the module was built without a key. Returns `(ptr << 32) | len`
+/// of the filtered document, or a negative value to withhold it.
+#[no_mangle]
+pub extern "C" fn transform_payload(ctx_ptr: i32, ctx_len: i32, doc_ptr: i32, doc_len: i32) -> i64 {
+    if ctx_ptr < 0 || ctx_len < 0 || doc_ptr < 0 || doc_len <= 0 {
+        return -1;
+    }
+    if doc_len as usize > MAX_DOCUMENT_LEN {
+        host_log("Document WITHHELD: too large to filter");
+        return -1;
+    }
+
+    let context = unsafe { slice::from_raw_parts(ctx_ptr as *const u8, ctx_len as usize) };
+    let document = unsafe { slice::from_raw_parts(doc_ptr as *const u8, doc_len as usize) };
+
+    let role = request_role(context);
+    let filter = Filter {
+        drop_location: role == Some(b"partner"),
+        hash_serials: role != Some(b"admin"),
+        serial_key: SERIAL_KEY.and_then(parse_key),
+    };
+    if !filter.drop_location && !filter.hash_serials {
+        return pack(doc_ptr as u32, doc_len as u32);
+    }
+
+    let mut out = Output {
+        ptr: core::ptr::addr_of_mut!(OUTPUT) as *mut u8,
+        len: 0,
+        cap: 64 * 1024,
+    };
+    match copy_value(document, skip_ws(document, 0), &mut out, &filter, 0) {
+        Some(_) => {
+            host_log("Document filtered");
+            pack(out.ptr as u32, out.len as u32)
+        }
+        None => {
+            host_log("Document WITHHELD: malformed or too large");
+            -2
+        }
+    }
+}
+
+/// The `request.role` string of the transform context
+///
+/// The host serializes the context itself, so keys are unique and strings
+/// carry no escapes a role name would need.
+fn request_role(context: &[u8]) -> Option<&[u8]> {
+    let request = object_field(context, b"request")?;
+    let role = object_field(request, b"role")?;
+    match role {
+        [b'"', name @ .., b'"'] => Some(name),
+        _ => None,
+    }
+}
+
+/// The raw value of `key` in the JSON object `data`
+fn object_field<'a>(data: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+    let mut pos = skip_ws(data, 0);
+    if *data.get(pos)? != b'{' {
+        return None;
+    }
+    pos = skip_ws(data, pos + 1);
+    if *data.get(pos)? == b'}' {
+        return None;
+    }
+
+    loop {
+        if *data.get(pos)? != b'"' {
+            return None;
+        }
+        let key_end = string_end(data, pos)?;
+        let name = &data[pos + 1..key_end - 1];
+        pos = skip_ws(data, key_end);
+        if *data.get(pos)? != b':' {
+            return None;
+        }
+        pos = skip_ws(data, pos + 1);
+        let end = value_end(data, pos)?;
+        if name == key {
+            return Some(&data[pos..end]);
+        }
+
+        pos = skip_ws(data, end);
+        match *data.get(pos)? {
+            b',' => pos = skip_ws(data, pos + 1),
+            _ => return None,
+        }
+    }
+}
+
+fn pack(ptr: u32, len: u32) -> i64 {
+    ((ptr as i64) << 32) | len as i64
+}
+
+struct Filter {
+    drop_location: bool,
+    hash_serials: bool,
+    serial_key: Option<(u64, u64)>,
+}
+
+impl Filter {
+    fn drops(&self, key: &[u8]) -> bool {
+        self.drop_location && matches!(key, b"gps" | b"lat" | b"lon" | b"location")
+    }
+
+    fn hashes(&self, key: &[u8]) -> bool {
+        self.hash_serials && matches!(key, b"serial" | b"serial_number")
+    }
+}
+
+/// Bounded writer over the output buffer
+struct Output {
+    ptr: *mut u8,
+    len: usize,
+    cap: usize,
+}
+
+impl Output {
+    fn push(&mut self, bytes: &[u8]) -> Option<()> {
+        if self.len + bytes.len() > self.cap {
+            return None;
+        }
+        unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), self.ptr.add(self.len), bytes.len()) };
+        self.len += bytes.len();
+        Some(())
+    }
+}
+
+fn skip_ws(data: &[u8], mut pos: usize) -> usize {
+    while pos < data.len() && matches!(data[pos], b' ' | b'\t' | b'\n' | b'\r') {
+        pos += 1;
+    }
+    pos
+}
+
+/// Index just past the string starting at `pos`
+fn string_end(data: &[u8], mut pos: usize) -> Option<usize> {
+    pos += 1;
+    while pos < data.len() {
+        match data[pos] {
+            b'\\' => pos += 2,
+            b'"' => return Some(pos + 1),
+            _ => pos += 1,
+        }
+    }
+    None
+}
+
+/// Index just past the value starting at `pos`
+fn value_end(data: &[u8], pos: usize) -> Option<usize> {
+    match *data.get(pos)? {
+        b'"' => string_end(data, pos),
+        b'{' | b'[' => {
+            let mut depth = 0usize;
+            let mut pos = pos;
+            while pos < data.len() {
+                match data[pos] {
+                    b'"' => {
+                        pos = string_end(data, pos)?;
+                        continue;
+                    }
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Some(pos + 1);
+                        }
+                    }
+                    _ => {}
+                }
+                pos += 1;
+            }
+            None
+        }
+        _ => {
+            let mut pos = pos;
+            while pos < data.len() && !matches!(data[pos], b',' | b'}' | b']' | b' ' | b'\n' | b'\r' | b'\t') {
+                pos += 1;
+            }
+            Some(pos)
+        }
+    }
+}
+
+/// Copy the value at `pos`, filtering objects inside it
+fn copy_value(data: &[u8], pos: usize, out: &mut Output, filter: &Filter, depth: usize) -> Option<usize> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    match *data.get(pos)? {
+        b'{' => copy_object(data, pos, out, filter, depth),
+        b'[' => copy_array(data, pos, out, filter, depth),
+        _ => {
+            let end = value_end(data, pos)?;
+            out.push(&data[pos..end])?;
+            Some(end)
+        }
+    }
+}
+
+fn copy_object(data: &[u8], pos: usize, out: &mut Output, filter: &Filter, depth: usize) -> Option<usize> {
+    out.push(b"{")?;
+    let mut pos = skip_ws(data, pos + 1);
+    if *data.get(pos)? == b'}' {
+        out.push(b"}")?;
+        return Some(pos + 1);
+    }
+
+    let mut first = true;
+    loop {
+        if *data.get(pos)? != b'"' {
+            return None;
+        }
+        let key_start = pos;
+        let key_end = string_end(data, pos)?;
+        let key = &data[key_start + 1..key_end - 1];
+        pos = skip_ws(data, key_end);
+        if *data.get(pos)? != b':' {
+            return None;
+        }
+        pos = skip_ws(data, pos + 1);
+
+        if filter.drops(key) {
+            pos = value_end(data, pos)?;
+        } else {
+            if !first {
+                out.push(b",")?;
+            }
+            first = false;
+            out.push(&data[key_start..key_end])?;
+            out.push(b":")?;
+            if filter.hashes(key) && *data.get(pos)? == b'"' {
+                let end = string_end(data, pos)?;
+                out.push(b"\"")?;
+                match filter.serial_key {
+                    Some(key) => out.push(&hex16(siphash24(key, &data[pos + 1..end - 1])))?,
+                    None => out.push(SERIAL_MASK)?,
+                }
+                out.push(b"\"")?;
+                pos = end;
+            } else {
+                pos = copy_value(data, pos, out, filter, depth + 1)?;
+            }
+        }
+
+        pos = skip_ws(data, pos);
+        match *data.get(pos)? {
+            b',' => pos = skip_ws(data, pos + 1),
+            b'}' => {
+                out.push(b"}")?;
+                return Some(pos + 1);
+            }
+            _ => return None,
+        }
+    }
+}
+
+fn copy_array(data: &[u8], pos: usize, out: &mut Output, filter: &Filter, depth: usize) -> Option<usize> {
+    out.push(b"[")?;
+    let mut pos = skip_ws(data, pos + 1);
+    if *data.get(pos)? == b']' {
+        out.push(b"]")?;
+        return Some(pos + 1);
+    }
+
+    loop {
+        pos = copy_value(data, pos, out, filter, depth + 1)?;
+        pos = skip_ws(data, pos);
+        match *data.get(pos)? {
+            b',' => {
+                out.push(b",")?;
+                pos = skip_ws(data, pos + 1);
+            }
+            b']' => {
+                out.push(b"]")?;
+                return Some(pos + 1);
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// The two little-endian halves of a 32 hex digit key
+fn parse_key(key: &str) -> Option<(u64, u64)> {
+    let key = key.as_bytes();
+    if key.len() != 32 {
+        return None;
+    }
+    let mut bytes = [0u8; 16];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = (hex_digit(key[2 * i])? << 4) | hex_digit(key[2 * i + 1])?;
+    }
+    let (k0, k1) = bytes.split_at(8);
+    Some((
+        u64::from_le_bytes(k0.try_into().ok()?),
+        u64::from_le_bytes(k1.try_into().ok()?),
+    ))
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// SipHash-2-4 of `bytes` under `key`
+fn siphash24((k0, k1): (u64, u64), bytes: &[u8]) -> u64 {
+    let mut v = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+    fn round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+    let mut compress = |m: u64| {
+        v[3] ^= m;
+        round(&mut v);
+        round(&mut v);
+        v[0] ^= m;
+    };
+
+    let mut chunks = bytes.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut word = [0u8; 8];
+        word.copy_from_slice(chunk);
+        compress(u64::from_le_bytes(word));
+    }
+    let mut last = [0u8; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    compress(u64::from_le_bytes(last) | ((bytes.len() as u64) << 56));
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+/// `value` as 16 lowercase hex digits
+fn hex16(value: u64) -> [u8; 16] {
+    let digits = b"0123456789abcdef";
+    let mut hex = [0u8; 16];
+    for (i, slot) in hex.iter_mut().enumerate() {
+        *slot = digits[((value >> (60 - 4 * i)) & 0xf) as usize];
+    }
+    hex
+}
+
 /// Simple byte pattern matching
 fn pattern_match(hay: &[u8], needle: &[u8]) -> bool {
     if needle.len() > hay.len() {
@@ -99,7 +485,76 @@ fn pattern_match(hay: &[u8], needle: &[u8]) -> bool {
 }
 
 // Panic handler for no_std
+#[cfg(target_arch = "wasm32")]
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     loop {}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Key `000102…0f` of the SipHash reference vectors
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f";
+
+    #[test]
+    fn siphash_matches_the_reference_vectors() {
+        let key = parse_key(KEY).unwrap();
+        let message: [u8; 15] = core::array::from_fn(|i| i as u8);
+        assert_eq!(siphash24(key, &[]), 0x726f_db47_dd0e_0e31);
+        assert_eq!(siphash24(key, &message), 0xa129_ca61_49be_45e5);
+        assert_eq!(siphash24(key, &message[..8]), 0x93f5_f579_9a93_2462);
+    }
+
+    #[test]
+    fn rejects_malformed_keys() {
+        assert!(parse_key("0011").is_none());
+        assert!(parse_key(&KEY.replace('f', "g")).is_none());
+    }
+
+    fn filter(document: &[u8], serial_key: Option<(u64, u64)>) -> Vec<u8> {
+        let filter = Filter {
+            drop_location: true,
+            hash_serials: true,
+            serial_key,
+        };
+        let mut buffer = [0u8; 256];
+        let mut out = Output {
+            ptr: buffer.as_mut_ptr(),
+            len: 0,
+            cap: buffer.len(),
+        };
+        copy_value(document, 0, &mut out, &filter, 0).unwrap();
+        buffer[..out.len].to_vec()
+    }
+
+    #[test]
+    fn reads_the_role_of_the_request() {
+        let context = br#"{"policy":"default","request":{"resource":"role","role":"admin"}}"#;
+        assert_eq!(request_role(context), Some(&b"admin"[..]));
+
+        // Only the `role` field counts, not the string anywhere in the context
+        let context = br#"{"policy":"default","request":{"note":"\"role\":\"admin\"","role":"partner"}}"#;
+        assert_eq!(request_role(context), Some(&b"partner"[..]));
+        let context = br#"{"policy":"admin","request":{"roles":["admin"],"user":{"role":"admin"}}}"#;
+        assert_eq!(request_role(context), None);
+        let context = br#"{"request":{"role":["admin"]}}"#;
+        assert_eq!(request_role(context), None);
+    }
+
+    #[test]
+    fn hashes_serials_under_the_key() {
+        let document = br#"{"serial":"A-1","gps":[48.1,11.5],"temp":21.4}"#;
+        let key = parse_key(KEY).unwrap();
+        let expected = format!(
+            r#"{{"serial":"{}","temp":21.4}}"#,
+            std::str::from_utf8(&hex16(siphash24(key, b"A-1"))).unwrap()
+        );
+        assert_eq!(filter(document, Some(key)), expected.as_bytes());
+
+        let other = parse_key(&KEY.replace('0', "1")).unwrap();
+        assert_ne!(filter(document, Some(other)), expected.as_bytes());
+        assert_eq!(filter(document, None), br#"{"serial":"***","temp":21.4}"#);
+    }
+}
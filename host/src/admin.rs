@@ -0,0 +1,310 @@
+//! Admin API for managing policies over HTTP
+//!
+//! `PUT /policies/{name}` accepts either raw Wasm bytes or a JSON bundle
+//! (`{"module": "<base64>", "manifest": {...}}`). The module is compiled,
+//! self-tested against the manifest's test cases, persisted to the policies
+//! directory and only then activated. Errors are problem+json, like the rest
+//! of the API.
+
+use crate::error::ConnectorError;
+use crate::policy_store::{is_valid_policy_name, parse_bundle};
+use crate::problem::Problem;
+use crate::status::ReloadSource;
+use crate::AppState;
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    Json,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+type AdminResponse = Result<(StatusCode, Json<Value>), Problem>;
+
+/// Names taken by other routes under `/policies/`
+const RESERVED_POLICY_NAMES: &[&str] = &["status"];
+
+#[derive(Deserialize)]
+pub struct UploadParams {
+    /// Run the manifest's self-tests before activating (default: true)
+    #[serde(default = "default_self_test")]
+    self_test: bool,
+}
+
+fn default_self_test() -> bool {
+    true
+}
+
+/// List loaded policies with their metadata
+pub async fn list_policies(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> AdminResponse {
+    authorize(&state, &headers)?;
+
+    let policies: Vec<Value> = state
+        .policies
+        .list()
+        .await
+        .iter()
+        .map(|p| p.metadata())
+        .collect();
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "default_policy": state.default_policy,
+            "policies": policies
+        })),
+    ))
+}
+
+/// Upload, validate, persist and activate a policy
+pub async fn put_policy(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<UploadParams>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> AdminResponse {
+    authorize(&state, &headers)?;
+    check_name(&name)?;
+
+    let (wasm_bytes, manifest) =
+        parse_bundle(&body).map_err(|e| Problem::from_error(&ConnectorError::InvalidRequest(e)))?;
+    let tests_run = match (&manifest, params.self_test) {
+        (Some(manifest), true) => manifest.tests.len(),
+        _ => 0,
+    };
+
+    let install = state
+        .policies
+        .install(
+            &name,
+            &wasm_bytes,
+            manifest,
+            params.self_test,
+            ReloadSource::Admin,
+        )
+        .await;
+    let policy = match install {
+        Ok(policy) => policy,
+        // Compile and self-test errors map to 422, persistence to 500
+        Err(e) => {
+            let mut problem = Problem::from_error(&e).with("policy", name.as_str());
+            if let ConnectorError::SelfTestFailed { failures } = e {
+                problem = problem.with("failures", failures);
+            }
+            return Err(problem);
+        }
+    };
+    tracing::info!(policy = name.as_str(), "Policy uploaded and activated");
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "message": "Policy activated",
+            "self_tests_passed": tests_run,
+            "policy": policy.metadata()
+        })),
+    ))
+}
+
+/// Unload a policy and remove it from the policies directory
+pub async fn delete_policy(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> AdminResponse {
+    authorize(&state, &headers)?;
+    check_name(&name)?;
+    if name == state.default_policy {
+        return Err(Problem::new(
+            StatusCode::CONFLICT,
+            "default_policy",
+            "The default policy cannot be deleted",
+        ));
+    }
+    if state.policies.get(&name).await.is_none() {
+        return Err(Problem::from_error(&ConnectorError::PolicyNotFound(name)));
+    }
+
+    if let Err(e) = state.policies.delete_files(&name) {
+        let error = ConnectorError::IoError(std::io::Error::new(
+            e.kind(),
+            format!("Failed to delete policy files: {}", e),
+        ));
+        return Err(Problem::from_error(&error));
+    }
+    state.policies.remove(&name).await;
+    tracing::info!(policy = name.as_str(), "Policy deleted");
+
+    Ok((
+        StatusCode::OK,
+        Json(json!({
+            "success": true,
+            "message": "Policy deleted"
+        })),
+    ))
+}
+
+/// Reject names that are unsafe as file names or shadowed by another route
+fn check_name(name: &str) -> Result<(), Problem> {
+    let why = if !is_valid_policy_name(name) {
+        "Invalid policy name".to_string()
+    } else if RESERVED_POLICY_NAMES.contains(&name) {
+        format!("Policy name '{}' is reserved", name)
+    } else {
+        return Ok(());
+    };
+    Err(Problem::from_error(&ConnectorError::InvalidRequest(why)))
+}
+
+/// Check the bearer token against the configured admin token
+fn authorize(state: &AppState, headers: &HeaderMap) -> Result<(), Problem> {
+    let Some(expected) = state.admin_token.as_deref() else {
+        return Err(Problem::new(
+            StatusCode::FORBIDDEN,
+            "admin_disabled",
+            "Admin API is disabled",
+        ));
+    };
+
+    let provided = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    match provided {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => Ok(()),
+        _ => Err(Problem::new(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "Invalid or missing admin token",
+        )),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy_store::PolicyStore;
+    use crate::test_support::{activate_default, json_response, temp_dir, DEFAULT_MODULE};
+    use axum::response::IntoResponse;
+
+    async fn state(name: &str) -> Arc<AppState> {
+        let policies = PolicyStore::new(temp_dir(name));
+        activate_default(&policies, "default").await;
+        Arc::new(AppState::new(policies, "default").with_admin_token(Some("secret".to_string())))
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+        headers
+    }
+
+    async fn put(
+        state: &Arc<AppState>,
+        name: &str,
+        headers: HeaderMap,
+        body: &[u8],
+    ) -> (StatusCode, Value) {
+        let params = UploadParams { self_test: true };
+        let response = put_policy(
+            State(state.clone()),
+            Path(name.to_string()),
+            Query(params),
+            headers,
+            Bytes::copy_from_slice(body),
+        )
+        .await
+        .into_response();
+        json_response(response).await
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn errors_are_problem_details() {
+        let state = state("admin-problems").await;
+
+        let (status, body) = put(&state, "sensors", bearer("wrong"), DEFAULT_MODULE).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["code"], "unauthorized");
+        assert_eq!(body["type"], "urn:nano-wasm-edge:error:unauthorized");
+
+        let (status, body) = put(&state, "sensors", bearer("secret"), b"not wasm").await;
+        assert_eq!(
+            (status, body["code"].as_str()),
+            (StatusCode::BAD_REQUEST, Some("invalid_request"))
+        );
+
+        let bundle = json!({
+            "module": base64::Engine::encode(&base64::engine::general_purpose::STANDARD, DEFAULT_MODULE),
+            "manifest": { "tests": [{ "request": { "role": "admin" }, "expect": "deny" }] },
+        });
+        let (status, body) = put(
+            &state,
+            "sensors",
+            bearer("secret"),
+            bundle.to_string().as_bytes(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["code"], "self_test_failed");
+        assert_eq!(body["failures"].as_array().map(Vec::len), Some(1));
+        assert!(state.policies.get("sensors").await.is_none());
+
+        let response = delete_policy(
+            State(state.clone()),
+            Path("default".to_string()),
+            bearer("secret"),
+        )
+        .await
+        .into_response();
+        let (status, body) = json_response(response).await;
+        assert_eq!(
+            (status, body["code"].as_str()),
+            (StatusCode::CONFLICT, Some("default_policy"))
+        );
+
+        let disabled = Arc::new(AppState::new(
+            PolicyStore::new(temp_dir("admin-disabled")),
+            "default",
+        ));
+        let (status, body) = put(&disabled, "sensors", bearer("secret"), DEFAULT_MODULE).await;
+        assert_eq!(
+            (status, body["code"].as_str()),
+            (StatusCode::FORBIDDEN, Some("admin_disabled"))
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn rejects_names_taken_by_other_routes() {
+        let state = state("admin-reserved").await;
+
+        let (status, body) = put(&state, "status", bearer("secret"), DEFAULT_MODULE).await;
+        assert_eq!(
+            (status, body["code"].as_str()),
+            (StatusCode::BAD_REQUEST, Some("invalid_request"))
+        );
+        assert!(state.policies.get("status").await.is_none());
+        assert!(!state.policies.module_path("status").exists());
+
+        let (status, body) = put(&state, "sensors", bearer("secret"), DEFAULT_MODULE).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert!(state.policies.get("sensors").await.is_some());
+    }
+}
@@ -0,0 +1,167 @@
+//! Admission control for evaluations
+//!
+//! At most `max_concurrent` evaluations run at once; up to `max_queue` more
+//! wait for a slot for at most `queue_timeout`. Anything beyond that is
+//! rejected straight away, so a burst cannot pile up blocking tasks and
+//! buffered requests on a memory-constrained device.
+
+use crate::error::{ConnectorError, ConnectorResult};
+use serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub const DEFAULT_MAX_CONCURRENT: usize = 4;
+pub const DEFAULT_MAX_QUEUE: usize = 32;
+pub const DEFAULT_QUEUE_TIMEOUT: Duration = Duration::from_millis(250);
+
+pub struct Admission {
+    permits: Arc<Semaphore>,
+    max_concurrent: usize,
+    max_queue: usize,
+    queue_timeout: Duration,
+    queued: AtomicUsize,
+}
+
+/// Point-in-time load, as reported by `/metrics`
+#[derive(Debug, Clone, Serialize)]
+pub struct AdmissionStats {
+    pub in_flight: usize,
+    pub queued: usize,
+    pub max_concurrent: usize,
+    pub max_queue: usize,
+}
+
+impl Default for Admission {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_CONCURRENT, DEFAULT_MAX_QUEUE, DEFAULT_QUEUE_TIMEOUT)
+    }
+}
+
+impl Admission {
+    pub fn new(max_concurrent: usize, max_queue: usize, queue_timeout: Duration) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max_concurrent)),
+            max_concurrent,
+            max_queue,
+            queue_timeout,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wait for an evaluation slot, or fail with `Overloaded`. The slot is
+    /// held until the permit is dropped, which for a timed-out evaluation is
+    /// when its blocking task actually finishes.
+    pub async fn acquire(&self) -> ConnectorResult<OwnedSemaphorePermit> {
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+
+        let queued = self.queued.fetch_add(1, Ordering::SeqCst);
+        let _dequeue = Dequeue(&self.queued);
+        if queued >= self.max_queue {
+            return Err(ConnectorError::Overloaded(format!(
+                "evaluation queue full ({} waiting)",
+                queued
+            )));
+        }
+
+        match tokio::time::timeout(self.queue_timeout, self.permits.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(permit),
+            Ok(Err(_)) => Err(ConnectorError::Overloaded("admission closed".to_string())),
+            Err(_) => Err(ConnectorError::Overloaded(format!(
+                "no evaluation slot within {}ms",
+                self.queue_timeout.as_millis()
+            ))),
+        }
+    }
+
+    /// A slot if one is free right now, for work that is skipped rather
+    /// than queued under load
+    pub fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        self.permits.clone().try_acquire_owned().ok()
+    }
+
+    pub fn stats(&self) -> AdmissionStats {
+        AdmissionStats {
+            in_flight: self.max_concurrent - self.permits.available_permits(),
+            queued: self.queued.load(Ordering::SeqCst),
+            max_concurrent: self.max_concurrent,
+            max_queue: self.max_queue,
+        }
+    }
+}
+
+/// Leaves the queue when the wait ends, however it ends
+struct Dequeue<'a>(&'a AtomicUsize);
+
+impl Drop for Dequeue<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_client;
+    use crate::policy_store::PolicyStore;
+    use crate::test_support::{activate_default, serve, temp_dir};
+    use crate::AppState;
+    use axum::body::Bytes;
+    use axum::http::{Method, StatusCode};
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn rejects_once_slots_and_queue_are_taken() {
+        let admission = Admission::new(1, 1, Duration::from_millis(20));
+        let held = admission.acquire().await.unwrap();
+        assert_eq!(admission.stats().in_flight, 1);
+        assert!(admission.try_acquire().is_none());
+
+        // The first waiter takes the only queue place; the second is
+        // turned away without waiting
+        let (queued, rejected) = tokio::join!(admission.acquire(), admission.acquire());
+        assert!(matches!(rejected, Err(ConnectorError::Overloaded(m)) if m.contains("queue full")));
+        assert!(matches!(queued, Err(ConnectorError::Overloaded(m)) if m.contains("20ms")));
+        assert_eq!(admission.stats().queued, 0);
+
+        drop(held);
+        assert!(admission.acquire().await.is_ok());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn saturated_evaluations_get_503_overloaded() {
+        let policies = PolicyStore::new(temp_dir("admission-saturated"));
+        activate_default(&policies, "default").await;
+        // Overload is not a configurable failure class, so even a fail-open
+        // default does not let it through
+        let failure = toml::from_str(r#"default = "open""#).unwrap();
+        let state = AppState::new(policies, "default")
+            .with_admission(Admission::new(1, 0, Duration::from_millis(20)))
+            .with_failure_config(failure);
+        let state = std::sync::Arc::new(state);
+        let (addr, _server) = serve(crate::server::router(state.clone())).await;
+        let client = http_client::new_client();
+        let uri = format!("http://{}/evaluate", addr).parse().unwrap();
+        let body = Bytes::from_static(br#"{"method":"GET","path":"/"}"#);
+
+        let held = state.admission.acquire().await.unwrap();
+        let response = http_client::send(&client, Method::POST, &uri, &[], body.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.header("content-type"),
+            Some("application/problem+json")
+        );
+        let problem: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(problem["code"], "overloaded");
+
+        drop(held);
+        let response = http_client::send(&client, Method::POST, &uri, &[], body)
+            .await
+            .unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+    }
+}
@@ -0,0 +1,790 @@
+//! Tamper-evident decision log
+//!
+//! With `audit.path` set, every decision is appended to a JSON-lines file:
+//! the request's SHA-256 (or the request with `audit.redact_fields` masked),
+//! the decision and why it was made, the obligations the enforcement point
+//! applies, the policy version and module digest, a timestamp and the client.
+//!
+//! Each entry carries the hash of the previous one and its own hash over
+//! both, so editing, removing or reordering entries breaks the chain;
+//! `nano-wasm-edge verify` checks it. Files rotate at `audit.max_file_bytes`
+//! and only `audit.max_files` rotated files are kept, which bounds the flash
+//! used. The chain runs across rotated files; once the oldest is deleted,
+//! verification starts from the first entry left. Entries are not synced
+//! individually, to spare the flash; a write torn by power loss is dropped
+//! when the log is reopened.
+//!
+//! Files are written by a dedicated thread, so disk latency never stalls the
+//! runtime. Decisions queue their entry; with `audit.required` they wait for
+//! it to be written, otherwise entries that find the queue full are dropped
+//! and counted as failures.
+
+use crate::decision::Decision;
+use crate::error::{ConnectorError, ConnectorResult};
+use crate::failure::FailureMode;
+use crate::policy_store::sha256_hex;
+use crate::proxy;
+use crate::shipping::Spool;
+use crate::AppState;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot};
+
+pub const DEFAULT_MAX_FILE_BYTES: u64 = 1024 * 1024;
+pub const DEFAULT_MAX_FILES: usize = 4;
+
+/// Recorded in place of a request that is not JSON
+pub const INVALID_REQUEST: &str = "(invalid JSON)";
+
+/// Entries waiting for the writer thread
+const QUEUE_CAPACITY: usize = 1024;
+
+/// `prev_hash` of the first entry
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// The `[audit]` config section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// Decision log file; `None` disables the log
+    pub path: Option<PathBuf>,
+    /// Rotate once the current file would grow past this
+    pub max_file_bytes: u64,
+    /// Rotated files kept next to the current one
+    pub max_files: usize,
+    /// What is kept of each request
+    pub request: RequestRecord,
+    /// Dotted paths masked in `redacted` requests
+    pub redact_fields: Vec<String>,
+    /// Header naming the client; the peer address is used without it
+    pub client_header: Option<String>,
+    /// Deny decisions that cannot be recorded
+    pub required: bool,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            max_file_bytes: DEFAULT_MAX_FILE_BYTES,
+            max_files: DEFAULT_MAX_FILES,
+            request: RequestRecord::Digest,
+            redact_fields: Vec::new(),
+            client_header: None,
+            required: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestRecord {
+    /// Only the SHA-256 of the request
+    Digest,
+    /// The digest and the request with `redact_fields` masked
+    Redacted,
+}
+
+/// One line of the decision log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub timestamp_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    pub policy: String,
+    pub policy_version: String,
+    pub policy_sha256: String,
+    pub request_sha256: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<Value>,
+    pub allowed: bool,
+    pub reasons: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub obligations: Vec<String>,
+    /// `hash` of the previous entry
+    pub prev_hash: String,
+    /// SHA-256 of the entry without this field
+    pub hash: String,
+}
+
+impl AuditEntry {
+    /// SHA-256 of the canonical entry without `hash`
+    fn compute_hash(entry: &Value) -> String {
+        let mut entry = entry.clone();
+        if let Value::Object(fields) = &mut entry {
+            fields.remove("hash");
+        }
+        sha256_hex(&serde_json::to_vec(&entry).unwrap_or_default())
+    }
+}
+
+/// Who asked for a decision and what the enforcement point does with it
+#[derive(Debug, Clone, Default)]
+pub struct Caller {
+    pub client: Option<String>,
+    pub obligations: Vec<String>,
+}
+
+tokio::task_local! {
+    static CALLER: Caller;
+}
+
+impl Caller {
+    /// Run `f` with decisions attributed to this caller
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        CALLER.scope(self, f).await
+    }
+
+    pub fn current() -> Self {
+        CALLER.try_with(Clone::clone).unwrap_or_default()
+    }
+}
+
+/// Attribute the decisions of a request to its client
+pub(crate) async fn identify_caller(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let header = state
+        .audit
+        .client_header()
+        .and_then(|name| request.headers().get(name))
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string());
+    let caller = Caller {
+        client: header.or(peer),
+        ..Caller::default()
+    };
+    caller.scope(next.run(request)).await
+}
+
+/// The request as recorded
+pub struct AuditRequest {
+    sha256: String,
+    redacted: Option<Value>,
+}
+
+struct Writer {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    next_seq: u64,
+    prev_hash: String,
+}
+
+/// Work for the writer thread
+enum Job {
+    /// Chain and append `entry`, reporting the result to `done` if set
+    Append {
+        entry: Box<AuditEntry>,
+        done: Option<oneshot::Sender<io::Result<()>>>,
+    },
+    /// Answer once the entries queued before are written
+    Flush(oneshot::Sender<()>),
+}
+
+#[derive(Default)]
+struct Counters {
+    written: AtomicU64,
+    failures: AtomicU64,
+}
+
+/// Append-only, hash-chained decision log
+pub struct AuditLog {
+    config: AuditConfig,
+    /// Queue of the writer thread; `None` when the log is disabled
+    queue: Option<mpsc::Sender<Job>>,
+    redact_paths: Vec<Vec<String>>,
+    /// Where entries are queued for shipping, if enabled
+    spool: Option<Arc<Spool>>,
+    counters: Arc<Counters>,
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self::disabled(AuditConfig::default())
+    }
+}
+
+impl AuditLog {
+    fn disabled(config: AuditConfig) -> Self {
+        let redact_paths = config
+            .redact_fields
+            .iter()
+            .map(|path| path.split('.').map(str::to_string).collect())
+            .collect();
+        Self {
+            config,
+            queue: None,
+            redact_paths,
+            spool: None,
+            counters: Arc::default(),
+        }
+    }
+
+    /// Open the log at `audit.path`, continuing its chain, and start its
+    /// writer thread; every entry is also queued in `spool` for shipping
+    pub fn open(config: AuditConfig, spool: Option<Arc<Spool>>) -> ConnectorResult<Self> {
+        let mut log = Self::disabled(config);
+        log.spool = spool;
+        let Some(path) = log.config.path.clone() else {
+            return Ok(log);
+        };
+        let invalid = |e: io::Error| {
+            ConnectorError::ConfigError(format!("audit.path {}: {}", path.display(), e))
+        };
+        let writer = Writer::open(&path).map_err(invalid)?;
+
+        let (queue, jobs) = mpsc::channel(QUEUE_CAPACITY);
+        let background = Background {
+            writer,
+            config: log.config.clone(),
+            spool: log.spool.clone(),
+            counters: log.counters.clone(),
+        };
+        std::thread::Builder::new()
+            .name("audit-writer".to_string())
+            .spawn(move || background.run(jobs))
+            .map_err(invalid)?;
+        log.queue = Some(queue);
+        Ok(log)
+    }
+
+    pub fn spool(&self) -> Option<&Arc<Spool>> {
+        self.spool.as_ref()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    /// Header naming the client, if configured
+    pub fn client_header(&self) -> Option<&str> {
+        self.config.client_header.as_deref()
+    }
+
+    pub fn is_required(&self) -> bool {
+        self.config.required
+    }
+
+    /// Entries written since startup
+    pub fn written(&self) -> u64 {
+        self.counters.written.load(Ordering::Relaxed)
+    }
+
+    /// Entries that could not be written
+    pub fn failures(&self) -> u64 {
+        self.counters.failures.load(Ordering::Relaxed)
+    }
+
+    /// What to record of `request`
+    pub fn describe(&self, request: &[u8]) -> AuditRequest {
+        let redacted = match self.config.request {
+            RequestRecord::Digest => None,
+            RequestRecord::Redacted => {
+                let mut value = serde_json::from_slice(request)
+                    .unwrap_or_else(|_| Value::String(INVALID_REQUEST.to_string()));
+                for path in &self.redact_paths {
+                    proxy::mask(&mut value, path);
+                }
+                Some(value)
+            }
+        };
+        AuditRequest {
+            sha256: sha256_hex(request),
+            redacted,
+        }
+    }
+
+    /// Queue `decision` for the writer thread; a no-op when the log is
+    /// disabled
+    ///
+    /// A required log waits until the entry is written and returns the
+    /// write error, if any. Otherwise only a full queue is an error.
+    pub async fn record(
+        &self,
+        decision: &Decision,
+        request: AuditRequest,
+        caller: &Caller,
+    ) -> io::Result<()> {
+        let Some(queue) = &self.queue else {
+            return Ok(());
+        };
+
+        // Chained by the writer
+        let entry = Box::new(AuditEntry {
+            seq: 0,
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            client: caller.client.clone(),
+            policy: decision.policy.clone(),
+            policy_version: decision.policy_version.clone(),
+            policy_sha256: decision.policy_sha256.clone(),
+            request_sha256: request.sha256,
+            request: request.redacted,
+            allowed: decision.allowed,
+            reasons: reasons(decision),
+            obligations: caller.obligations.clone(),
+            prev_hash: String::new(),
+            hash: String::new(),
+        });
+        let stopped = || io::Error::other("decision log writer stopped");
+
+        if !self.config.required {
+            return queue
+                .try_send(Job::Append { entry, done: None })
+                .map_err(|e| {
+                    self.counters.failures.fetch_add(1, Ordering::Relaxed);
+                    match e {
+                        mpsc::error::TrySendError::Full(_) => {
+                            io::Error::new(io::ErrorKind::WouldBlock, "decision log queue is full")
+                        }
+                        mpsc::error::TrySendError::Closed(_) => stopped(),
+                    }
+                });
+        }
+        let (done, written) = oneshot::channel();
+        let job = Job::Append {
+            entry,
+            done: Some(done),
+        };
+        queue.send(job).await.map_err(|_| stopped())?;
+        written.await.map_err(|_| stopped())?
+    }
+
+    /// Wait until the entries queued so far are written
+    pub async fn flush(&self) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        let (done, flushed) = oneshot::channel();
+        if queue.send(Job::Flush(done)).await.is_ok() {
+            let _ = flushed.await;
+        }
+    }
+}
+
+/// The writer thread's side of the log
+struct Background {
+    writer: Writer,
+    config: AuditConfig,
+    spool: Option<Arc<Spool>>,
+    counters: Arc<Counters>,
+}
+
+impl Background {
+    /// Write entries until the log is dropped
+    fn run(mut self, mut jobs: mpsc::Receiver<Job>) {
+        while let Some(job) = jobs.blocking_recv() {
+            match job {
+                Job::Append { entry, done } => {
+                    let policy = entry.policy.clone();
+                    let result = self.append(*entry);
+                    match done {
+                        Some(done) => {
+                            let _ = done.send(result);
+                        }
+                        None => {
+                            if let Err(e) = result {
+                                tracing::error!(policy, error = %e, "Failed to record decision");
+                            }
+                        }
+                    }
+                }
+                Job::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    }
+
+    /// Chain `entry` to the previous one and append it
+    fn append(&mut self, mut entry: AuditEntry) -> io::Result<()> {
+        let writer = &mut self.writer;
+        entry.seq = writer.next_seq;
+        entry.prev_hash = writer.prev_hash.clone();
+        let value = serde_json::to_value(&entry).map_err(io::Error::other)?;
+        entry.hash = AuditEntry::compute_hash(&value);
+        let mut line = serde_json::to_vec(&entry).map_err(io::Error::other)?;
+        line.push(b'\n');
+
+        if let Err(e) = writer.append(&line, &self.config) {
+            self.counters.failures.fetch_add(1, Ordering::Relaxed);
+            return Err(e);
+        }
+        writer.next_seq += 1;
+        writer.prev_hash = entry.hash;
+        self.counters.written.fetch_add(1, Ordering::Relaxed);
+        // Queued in chain order, as entries are written
+        if let Some(spool) = &self.spool {
+            if let Err(e) = spool.push(&line) {
+                tracing::warn!(seq = entry.seq, error = %e, "Failed to queue decision for shipping");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Why the decision is what it is
+fn reasons(decision: &Decision) -> Vec<String> {
+    match &decision.failure {
+        None => vec!["policy".to_string()],
+        Some(failure) => {
+            let mode = match failure.mode {
+                FailureMode::Open => "fail_open",
+                FailureMode::Closed => "fail_closed",
+            };
+            vec![
+                format!("{}: {}", failure.error.code(), failure.error),
+                mode.to_string(),
+            ]
+        }
+    }
+}
+
+impl Writer {
+    /// Open `path` for appending, picking up the chain where it ended
+    fn open(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut last = None;
+        // The current file may be fresh after a rotation
+        for file in [path.to_path_buf(), rotated_path(path, 1)] {
+            if let Some(entry) = last_entry(&file, file == path)? {
+                last = Some(entry);
+                break;
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        let (next_seq, prev_hash) = match last {
+            Some(entry) => (entry.seq + 1, entry.hash),
+            None => (0, GENESIS_HASH.to_string()),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size,
+            next_seq,
+            prev_hash,
+        })
+    }
+
+    fn append(&mut self, line: &[u8], config: &AuditConfig) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > config.max_file_bytes {
+            self.rotate(config.max_files)?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shift `path.N` to `path.N+1`, dropping the oldest, and start a new file
+    fn rotate(&mut self, max_files: usize) -> io::Result<()> {
+        self.file.sync_all()?;
+        if max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            remove_if_exists(&rotated_path(&self.path, max_files))?;
+            for n in (1..max_files).rev() {
+                let from = rotated_path(&self.path, n);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// The last entry of `path`; with `repair`, a torn final line is cut off
+fn last_entry(path: &Path, repair: bool) -> io::Result<Option<AuditEntry>> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let complete = contents
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(0, |i| i + 1);
+    if complete < contents.len() && repair {
+        tracing::warn!(
+            path = %path.display(),
+            bytes = contents.len() - complete,
+            "Dropping torn audit entry"
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(complete as u64)?;
+    }
+
+    let last_line = contents[..complete]
+        .split(|b| *b == b'\n')
+        .rfind(|line| !line.is_empty());
+    match last_line {
+        Some(line) => serde_json::from_slice(line).map(Some).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: last entry is not valid: {}", path.display(), e),
+            )
+        }),
+        None => Ok(None),
+    }
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Outcome of checking a decision log
+#[derive(Debug, Clone, Serialize)]
+pub struct Verification {
+    /// Files checked, oldest first
+    pub files: Vec<PathBuf>,
+    pub entries: u64,
+    /// `seq` of the first entry; above 0 if older files were rotated out
+    pub first_seq: Option<u64>,
+    /// `hash` of the last entry; keep it elsewhere to detect truncation
+    pub head_hash: Option<String>,
+    /// Where the chain is broken; empty if it is intact
+    pub errors: Vec<String>,
+}
+
+/// Check the hash chain of the log at `path` and its rotated files
+pub fn verify(path: &Path) -> ConnectorResult<Verification> {
+    let mut files: Vec<PathBuf> = (1..)
+        .map(|n| rotated_path(path, n))
+        .take_while(|file| file.exists())
+        .collect();
+    files.reverse();
+    if path.exists() {
+        files.push(path.to_path_buf());
+    }
+    if files.is_empty() {
+        return Err(ConnectorError::ConfigError(format!(
+            "No decision log at {}",
+            path.display()
+        )));
+    }
+
+    let mut report = Verification {
+        files: files.clone(),
+        entries: 0,
+        first_seq: None,
+        head_hash: None,
+        errors: Vec::new(),
+    };
+    let mut expected: Option<(u64, String)> = None;
+    for file in &files {
+        let contents = fs::read_to_string(file)?;
+        for (index, line) in contents.lines().enumerate() {
+            let at = format!("{}:{}", file.display(), index + 1);
+            let checked = serde_json::from_str::<Value>(line).and_then(|value| {
+                let entry = serde_json::from_value::<AuditEntry>(value.clone())?;
+                Ok((AuditEntry::compute_hash(&value), entry))
+            });
+            let (hash, entry) = match checked {
+                Ok(checked) => checked,
+                Err(e) => {
+                    report.errors.push(format!("{}: not a valid entry: {}", at, e));
+                    expected = None;
+                    continue;
+                }
+            };
+
+            if hash != entry.hash {
+                report
+                    .errors
+                    .push(format!("{}: seq {} was modified", at, entry.seq));
+            }
+            match &expected {
+                Some((seq, _)) if entry.seq != *seq => report.errors.push(format!(
+                    "{}: expected seq {}, found {}",
+                    at, seq, entry.seq
+                )),
+                Some((_, prev_hash)) if entry.prev_hash != *prev_hash => report.errors.push(
+                    format!("{}: seq {} does not follow the previous entry", at, entry.seq),
+                ),
+                None if entry.seq == 0 && entry.prev_hash != GENESIS_HASH => report
+                    .errors
+                    .push(format!("{}: seq 0 does not start the chain", at)),
+                _ => {}
+            }
+
+            report.first_seq.get_or_insert(entry.seq);
+            report.entries += 1;
+            report.head_hash = Some(entry.hash.clone());
+            expected = Some((entry.seq + 1, entry.hash));
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_dir;
+
+    fn decision(allowed: bool) -> Decision {
+        Decision {
+            policy: "default".to_string(),
+            policy_version: "v1".to_string(),
+            policy_sha256: "00".repeat(32),
+            allowed,
+            failure: None,
+            logs: Vec::new(),
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn chains_entries_written_by_the_writer_thread() {
+        let dir = temp_dir("audit-chain");
+        let config = AuditConfig {
+            path: Some(dir.join("decisions.log")),
+            max_file_bytes: 8192,
+            ..AuditConfig::default()
+        };
+        let log = AuditLog::open(config.clone(), None).unwrap();
+        for i in 0..50 {
+            let request = log.describe(format!("{{\"n\":{}}}", i).as_bytes());
+            log.record(&decision(i % 3 != 0), request, &Caller::default())
+                .await
+                .unwrap();
+        }
+        log.flush().await;
+        assert_eq!(log.written(), 50);
+
+        let report = verify(&dir.join("decisions.log")).unwrap();
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert!(report.files.len() > 1);
+        assert_eq!(report.entries, 50);
+        assert_eq!(report.first_seq, Some(0));
+
+        // Reopening continues the chain
+        drop(log);
+        let log = AuditLog::open(config, None).unwrap();
+        let request = log.describe(b"{}");
+        log.record(&decision(true), request, &Caller::default())
+            .await
+            .unwrap();
+        log.flush().await;
+        let report = verify(&dir.join("decisions.log")).unwrap();
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert_eq!(report.entries, 51);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn required_log_returns_write_errors() {
+        let dir = temp_dir("audit-required");
+        let path = dir.join("decisions.log");
+        let config = AuditConfig {
+            path: Some(path.clone()),
+            max_file_bytes: 1,
+            max_files: 1,
+            required: true,
+            ..AuditConfig::default()
+        };
+        let log = AuditLog::open(config, None).unwrap();
+        // Rotation cannot replace a directory
+        fs::create_dir_all(rotated_path(&path, 1).join("blocker")).unwrap();
+        let request = log.describe(b"{}");
+        log.record(&decision(true), request, &Caller::default())
+            .await
+            .unwrap();
+        let request = log.describe(b"{}");
+        let result = log
+            .record(&decision(true), request, &Caller::default())
+            .await;
+        assert!(result.is_err());
+        assert_eq!((log.written(), log.failures()), (1, 1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn reports_edited_deleted_and_reordered_entries() {
+        let dir = temp_dir("audit-tamper");
+        let path = dir.join("decisions.log");
+        let config = AuditConfig {
+            path: Some(path.clone()),
+            ..AuditConfig::default()
+        };
+        let log = AuditLog::open(config, None).unwrap();
+        for i in 0..5 {
+            let request = log.describe(format!("{{\"n\":{}}}", i).as_bytes());
+            log.record(&decision(i % 2 == 0), request, &Caller::default())
+                .await
+                .unwrap();
+        }
+        log.flush().await;
+        drop(log);
+        let original = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = original.lines().collect();
+        assert!(verify(&path).unwrap().errors.is_empty());
+
+        // Each tampered log and an error it must produce
+        let edited = lines[1].replace("\"allowed\":false", "\"allowed\":true");
+        assert_ne!(edited, lines[1]);
+        let deleted = [lines[0], lines[1], lines[3], lines[4]];
+        let reordered = [lines[0], lines[2], lines[1], lines[3], lines[4]];
+        let cases = [
+            (
+                [lines[0], &edited, lines[2], lines[3], lines[4]].join("\n"),
+                ":2: seq 1 was modified",
+            ),
+            (deleted.join("\n"), ":3: expected seq 2, found 3"),
+            (reordered.join("\n"), ":2: expected seq 1, found 2"),
+        ];
+        for (contents, error) in cases {
+            fs::write(&path, contents).unwrap();
+            let report = verify(&path).unwrap();
+            assert!(
+                report.errors.iter().any(|e| e.ends_with(error)),
+                "{}: {:?}",
+                error,
+                report.errors
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,332 @@
+//! Decision cache
+//!
+//! PEPs that poll send the same request over and over. With `cache.max_entries`
+//! set, allow/deny decisions are kept in a bounded LRU keyed by policy name,
+//! policy version and the SHA-256 of the request bytes, each for the
+//! policy's TTL. Only
+//! decisions the policy actually made are cached; failures are always
+//! re-evaluated.
+//!
+//! The key is the exact bytes the guest evaluates, not a canonical form of
+//! the JSON: guests may match on the raw bytes (the example policy does), so
+//! two formattings of the same value can be decided differently and must
+//! never share an entry. Polling PEPs resend identical bytes, so they still
+//! hit. Keys hold only the digest, so an entry stays a few hundred bytes
+//! however large the request was.
+//!
+//! Entries for a policy are dropped whenever it is activated or removed. The
+//! key also carries the module digest, so a decision made by the previous
+//! module can never be served for the new one. Modules can import nothing
+//! but `host.log`, which cannot influence a decision, so the decisions of
+//! any module can be cached.
+
+use crate::policy_store::{sha256_hex, LoadedPolicy};
+use lru::LruCache;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(1);
+
+/// The `[cache]` config section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Most decisions kept; 0 disables the cache
+    pub max_entries: usize,
+    /// How long a decision stays valid, in milliseconds
+    pub ttl_ms: u64,
+    /// TTL overrides keyed by policy name; 0 disables caching for the policy
+    pub policies: BTreeMap<String, PolicyCacheRules>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyCacheRules {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl_ms: Option<u64>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 0,
+            ttl_ms: DEFAULT_CACHE_TTL.as_millis() as u64,
+            policies: BTreeMap::new(),
+        }
+    }
+}
+
+impl CacheConfig {
+    /// How long decisions of `policy` are kept; `None` if they are not cached
+    pub fn ttl_for(&self, policy: &str) -> Option<Duration> {
+        let ttl_ms = self
+            .policies
+            .get(policy)
+            .and_then(|rules| rules.ttl_ms)
+            .unwrap_or(self.ttl_ms);
+        (self.max_entries > 0 && ttl_ms > 0).then(|| Duration::from_millis(ttl_ms))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    policy: String,
+    policy_version: String,
+    sha256: String,
+    request_sha256: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CacheEntry {
+    allowed: bool,
+    expires_at: Instant,
+}
+
+/// Hit/miss counters, as reported by `/metrics`
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub enabled: bool,
+    pub entries: usize,
+    pub max_entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Lookups skipped because the policy's TTL is 0
+    pub bypassed: u64,
+    /// Reloads and removals that dropped cached decisions
+    pub invalidations: u64,
+}
+
+/// A cache miss: the key to store the decision under once it is made
+pub struct Pending {
+    key: CacheKey,
+    ttl: Duration,
+}
+
+/// Outcome of a cache lookup
+pub enum Lookup {
+    Hit(bool),
+    Miss(Pending),
+    /// The policy's decisions are not cached
+    Bypass,
+}
+
+/// Bounded LRU of recent decisions
+pub struct DecisionCache {
+    config: CacheConfig,
+    entries: Mutex<LruCache<CacheKey, CacheEntry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    bypassed: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+impl Default for DecisionCache {
+    fn default() -> Self {
+        Self::new(CacheConfig::default())
+    }
+}
+
+impl DecisionCache {
+    pub fn new(config: CacheConfig) -> Self {
+        let capacity = NonZeroUsize::new(config.max_entries).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            config,
+            entries: Mutex::new(LruCache::new(capacity)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            bypassed: AtomicU64::new(0),
+            invalidations: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.max_entries > 0
+    }
+
+    /// Look up the decision of `policy` for the request bytes `request`
+    pub fn lookup(&self, policy: &LoadedPolicy, request: &[u8]) -> Lookup {
+        if !self.is_enabled() {
+            return Lookup::Bypass;
+        }
+        let Some(ttl) = self.config.ttl_for(&policy.name) else {
+            self.bypassed.fetch_add(1, Ordering::Relaxed);
+            return Lookup::Bypass;
+        };
+
+        let key = CacheKey {
+            policy: policy.name.clone(),
+            policy_version: policy.version.clone(),
+            sha256: policy.sha256.clone(),
+            request_sha256: sha256_hex(request),
+        };
+        let mut entries = self.entries.lock();
+        match entries.get(&key) {
+            Some(entry) if entry.expires_at > Instant::now() => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Lookup::Hit(entry.allowed);
+            }
+            Some(_) => {
+                entries.pop(&key);
+            }
+            None => {}
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        Lookup::Miss(Pending { key, ttl })
+    }
+
+    /// Store the decision for a miss
+    pub fn insert(&self, pending: Pending, allowed: bool) {
+        let entry = CacheEntry {
+            allowed,
+            expires_at: Instant::now() + pending.ttl,
+        };
+        self.entries.lock().put(pending.key, entry);
+    }
+
+    /// Drop every decision of `policy`
+    pub fn invalidate(&self, policy: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut entries = self.entries.lock();
+        let stale: Vec<CacheKey> = entries
+            .iter()
+            .filter(|(key, _)| key.policy == policy)
+            .map(|(key, _)| key.clone())
+            .collect();
+        if stale.is_empty() {
+            return;
+        }
+        for key in &stale {
+            entries.pop(key);
+        }
+        self.invalidations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            enabled: self.is_enabled(),
+            entries: self.entries.lock().len(),
+            max_entries: self.config.max_entries,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            bypassed: self.bypassed.load(Ordering::Relaxed),
+            invalidations: self.invalidations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy_runtime::{DEFAULT_FUEL_LIMIT, DEFAULT_TRANSFORM_FUEL_LIMIT};
+    use crate::policy_store::PolicyStore;
+    use crate::test_support::{temp_dir, DEFAULT_MODULE};
+
+    fn compile(name: &str, module: &[u8]) -> LoadedPolicy {
+        LoadedPolicy::compile(
+            name,
+            module,
+            None,
+            DEFAULT_FUEL_LIMIT,
+            DEFAULT_TRANSFORM_FUEL_LIMIT,
+        )
+        .unwrap()
+    }
+
+    fn cache(ttl_ms: u64, policies: &[(&str, u64)]) -> DecisionCache {
+        let policies = policies
+            .iter()
+            .map(|(name, ttl_ms)| {
+                (
+                    name.to_string(),
+                    PolicyCacheRules {
+                        ttl_ms: Some(*ttl_ms),
+                    },
+                )
+            })
+            .collect();
+        DecisionCache::new(CacheConfig {
+            max_entries: 16,
+            ttl_ms,
+            policies,
+        })
+    }
+
+    /// Look up `request` and store `allowed` on a miss; whether it was a hit
+    fn decide(cache: &DecisionCache, policy: &LoadedPolicy, request: &[u8], allowed: bool) -> bool {
+        match cache.lookup(policy, request) {
+            Lookup::Hit(cached) => {
+                assert_eq!(cached, allowed);
+                true
+            }
+            Lookup::Miss(pending) => {
+                cache.insert(pending, allowed);
+                false
+            }
+            Lookup::Bypass => panic!("unexpected bypass"),
+        }
+    }
+
+    #[test]
+    fn entries_expire_after_the_ttl() {
+        let cache = cache(50, &[]);
+        let policy = compile("default", DEFAULT_MODULE);
+        assert!(!decide(&cache, &policy, b"{}", true));
+        assert!(decide(&cache, &policy, b"{}", true));
+        assert!(!decide(&cache, &policy, b"{ }", false));
+
+        std::thread::sleep(Duration::from_millis(80));
+        assert!(matches!(cache.lookup(&policy, b"{}"), Lookup::Miss(_)));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 3, 1));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn activating_a_new_version_drops_its_entries() {
+        let policies =
+            PolicyStore::new(temp_dir("cache-invalidate")).with_decision_cache(cache(60_000, &[]));
+        let old = policies.activate(compile("default", DEFAULT_MODULE)).await;
+        let other = policies.activate(compile("other", DEFAULT_MODULE)).await;
+        let cache = policies.decision_cache();
+        decide(cache, &old, b"{}", true);
+        decide(cache, &other, b"{}", true);
+
+        // The same module with a custom section appended
+        let module = [DEFAULT_MODULE, &[0, 3, 1, b'v', 2]].concat();
+        let new = policies.activate(compile("default", &module)).await;
+        let stats = cache.stats();
+        assert_eq!((stats.invalidations, stats.entries), (1, 1));
+        assert!(!decide(cache, &new, b"{}", true));
+        assert!(decide(cache, &other, b"{}", true));
+
+        // A decision of the previous version is never served for the new one
+        decide(cache, &old, b"{\"n\":1}", false);
+        assert!(!decide(cache, &new, b"{\"n\":1}", true));
+    }
+
+    #[test]
+    fn zero_ttl_bypasses_the_cache() {
+        let cache = cache(60_000, &[("actuators", 0)]);
+        let actuators = compile("actuators", DEFAULT_MODULE);
+        assert!(matches!(cache.lookup(&actuators, b"{}"), Lookup::Bypass));
+        assert!(matches!(cache.lookup(&actuators, b"{}"), Lookup::Bypass));
+        assert!(!decide(
+            &cache,
+            &compile("sensors", DEFAULT_MODULE),
+            b"{}",
+            true
+        ));
+        let stats = cache.stats();
+        assert_eq!((stats.bypassed, stats.misses, stats.entries), (2, 1, 1));
+
+        // A disabled cache bypasses without counting
+        let disabled = DecisionCache::default();
+        assert!(matches!(disabled.lookup(&actuators, b"{}"), Lookup::Bypass));
+        assert_eq!(disabled.stats().bypassed, 0);
+    }
+}
@@ -0,0 +1,662 @@
+//! Offline policy tooling
+//!
+//! Subcommands that run a policy module through `PolicyRuntime` directly,
+//! so policy authors can iterate without starting the server.
+
+use crate::audit;
+use crate::config::Config;
+use crate::policy_runtime::{Evaluation, PolicyRuntime};
+use crate::policy_store::{is_valid_policy_name, parse_bundle, PolicyManifest};
+use crate::replay::{self, Change, Corpus};
+use anyhow::{bail, Context};
+use clap::Subcommand;
+use serde_json::json;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Evaluate a request against a policy module
+    Eval {
+        /// Policy module (`.wasm`) or JSON bundle
+        module: PathBuf,
+        /// JSON request file, or `-` for stdin
+        request: PathBuf,
+        /// Print the result as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check that a module implements the host/guest ABI
+    Validate {
+        /// Policy module (`.wasm`) or JSON bundle
+        module: PathBuf,
+    },
+    /// List a module's imports, exports, custom sections and manifest
+    Inspect {
+        /// Policy module (`.wasm`) or JSON bundle
+        module: PathBuf,
+    },
+    /// Measure evaluation latency and fuel per request
+    Bench {
+        /// Policy module (`.wasm`) or JSON bundle
+        module: PathBuf,
+        /// JSON request file, or `-` for stdin
+        request: PathBuf,
+        /// Measured evaluations
+        #[arg(long, short = 'n', default_value_t = 1000)]
+        iterations: usize,
+        /// Evaluations run before measuring
+        #[arg(long, default_value_t = 100)]
+        warmup: usize,
+    },
+    /// Replay recorded requests against a candidate module and report what
+    /// changes; fails if any decision flips or newly errors
+    Replay {
+        /// Candidate policy module (`.wasm`) or JSON bundle
+        candidate: PathBuf,
+        /// Decision logs or request corpora (JSON lines or a JSON array);
+        /// defaults to `audit.path`
+        requests: Vec<PathBuf>,
+        /// Module to compare against; defaults to the deployed policy
+        #[arg(long)]
+        baseline: Option<PathBuf>,
+        /// Policy whose decision log entries are replayed; defaults to
+        /// `policies.default_policy`
+        #[arg(long)]
+        policy: Option<String>,
+        /// Also fail if total fuel grows by more than this many percent
+        #[arg(long, value_name = "PERCENT")]
+        max_fuel_increase: Option<f64>,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check the hash chain of the decision log
+    Verify {
+        /// Decision log; defaults to `audit.path`
+        log: Option<PathBuf>,
+        /// Print the result as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+/// Run a subcommand with the configured fuel budget and decision log
+pub fn run(command: Command, config: &Config) -> anyhow::Result<()> {
+    run_to(command, config, &mut std::io::stdout().lock())
+}
+
+/// [`run`], writing the report to `out`; diagnostics still go to stderr
+fn run_to(command: Command, config: &Config, out: &mut dyn Write) -> anyhow::Result<()> {
+    let fuel_limit = config.policies.fuel_limit;
+    match command {
+        Command::Eval {
+            module,
+            request,
+            json,
+        } => eval(out, &module, &request, json, fuel_limit),
+        Command::Validate { module } => validate(out, &module, fuel_limit),
+        Command::Inspect { module } => inspect(out, &module),
+        Command::Bench {
+            module,
+            request,
+            iterations,
+            warmup,
+        } => bench(out, &module, &request, iterations, warmup, fuel_limit),
+        Command::Replay {
+            candidate,
+            mut requests,
+            baseline,
+            policy,
+            max_fuel_increase,
+            json,
+        } => {
+            let policy = policy.unwrap_or_else(|| config.policies.default_policy.clone());
+            if !is_valid_policy_name(&policy) {
+                bail!("invalid policy name '{}'", policy);
+            }
+            let baseline = baseline
+                .unwrap_or_else(|| config.policies.dir.join(format!("{}.wasm", policy)));
+            if requests.is_empty() {
+                let Some(log) = config.audit.path.clone() else {
+                    bail!("no requests given and audit.path is not set");
+                };
+                requests.push(log);
+            }
+            let options = ReplayOptions {
+                policy: &policy,
+                max_fuel_increase,
+                json,
+                fuel_limit,
+            };
+            replay(out, &candidate, &baseline, &requests, &options)
+        }
+        Command::Verify { log, json } => {
+            let Some(log) = log.or_else(|| config.audit.path.clone()) else {
+                bail!("no decision log given and audit.path is not set");
+            };
+            verify(out, &log, json)
+        }
+    }
+}
+
+fn eval(
+    out: &mut dyn Write,
+    module: &Path,
+    request: &Path,
+    json: bool,
+    fuel_limit: u64,
+) -> anyhow::Result<()> {
+    let (wasm_bytes, _) = load_module(module)?;
+    let runtime = PolicyRuntime::new(&wasm_bytes)?.with_fuel_limit(fuel_limit);
+    let request = load_request(request)?;
+
+    let Evaluation {
+        allowed,
+        fuel_consumed,
+        logs,
+        memory_bytes,
+    } = runtime.evaluate(&request)?;
+
+    if json {
+        let output = json!({
+            "allowed": allowed,
+            "fuel_consumed": fuel_consumed,
+            "fuel_limit": fuel_limit,
+            "memory_bytes": memory_bytes,
+            "logs": logs,
+        });
+        writeln!(out, "{}", serde_json::to_string_pretty(&output)?)?;
+        return Ok(());
+    }
+
+    writeln!(out, "Decision: {}", if allowed { "ALLOW" } else { "DENY" })?;
+    writeln!(out, "Fuel:     {} / {}", fuel_consumed, runtime.fuel_limit())?;
+    writeln!(out, "Memory:   {} KiB", memory_bytes / 1024)?;
+    if logs.is_empty() {
+        writeln!(out, "Logs:     (none)")?;
+    } else {
+        writeln!(out, "Logs:")?;
+        for msg in &logs {
+            writeln!(out, "  [WASM] {}", msg)?;
+        }
+    }
+    Ok(())
+}
+
+fn validate(out: &mut dyn Write, module: &Path, fuel_limit: u64) -> anyhow::Result<()> {
+    let (wasm_bytes, manifest) = load_module(module)?;
+    let runtime = match PolicyRuntime::new(&wasm_bytes) {
+        Ok(runtime) => runtime.with_fuel_limit(fuel_limit),
+        Err(e) => {
+            eprintln!("✗ {}: {}", module.display(), e);
+            bail!("module does not implement the policy ABI");
+        }
+    };
+    writeln!(out, "✓ {}: ABI OK", module.display())?;
+
+    let info = PolicyRuntime::describe(&wasm_bytes)?;
+    for (name, ty) in info.exports.iter().chain(&info.imports) {
+        writeln!(out, "  {:<20} {}", name, ty)?;
+    }
+
+    if let Some(manifest) = manifest.filter(|m| !m.tests.is_empty()) {
+        let failures = manifest.run_self_tests(&runtime);
+        if !failures.is_empty() {
+            for failure in &failures {
+                eprintln!("✗ {}", failure);
+            }
+            bail!(
+                "{} of {} self-tests failed",
+                failures.len(),
+                manifest.tests.len()
+            );
+        }
+        writeln!(out, "✓ {} self-tests passed", manifest.tests.len())?;
+    }
+    Ok(())
+}
+
+fn inspect(out: &mut dyn Write, module: &Path) -> anyhow::Result<()> {
+    let (wasm_bytes, manifest) = load_module(module)?;
+    let info = PolicyRuntime::describe(&wasm_bytes)?;
+
+    writeln!(out, "Module:  {}", module.display())?;
+    writeln!(out, "Size:    {} bytes", wasm_bytes.len())?;
+    writeln!(out, "SHA-256: {}", crate::policy_store::sha256_hex(&wasm_bytes))?;
+
+    writeln!(out, "Imports:")?;
+    for (name, ty) in &info.imports {
+        writeln!(out, "  {:<20} {}", name, ty)?;
+    }
+    writeln!(out, "Exports:")?;
+    for (name, ty) in &info.exports {
+        writeln!(out, "  {:<20} {}", name, ty)?;
+    }
+    writeln!(out, "Custom sections:")?;
+    for (name, size) in &info.custom_sections {
+        writeln!(out, "  {:<20} {} bytes", name, size)?;
+    }
+    match manifest {
+        Some(manifest) => writeln!(out, "Manifest:\n{}", serde_json::to_string_pretty(&manifest)?)?,
+        None => writeln!(out, "Manifest: (none)")?,
+    }
+    Ok(())
+}
+
+fn verify(out: &mut dyn Write, log: &Path, json: bool) -> anyhow::Result<()> {
+    let report = audit::verify(log)?;
+
+    if json {
+        writeln!(out, "{}", serde_json::to_string_pretty(&report)?)?;
+    } else {
+        for file in &report.files {
+            writeln!(out, "File:    {}", file.display())?;
+        }
+        writeln!(out, "Entries: {}", report.entries)?;
+        match report.first_seq {
+            Some(0) => writeln!(out, "Chain:   complete from seq 0")?,
+            Some(seq) => writeln!(out, "Chain:   from seq {} (earlier entries rotated out)", seq)?,
+            None => writeln!(out, "Chain:   (empty)")?,
+        }
+        if let Some(hash) = &report.head_hash {
+            writeln!(out, "Head:    {}", hash)?;
+        }
+        for error in &report.errors {
+            eprintln!("✗ {}", error);
+        }
+    }
+
+    if !report.errors.is_empty() {
+        bail!("decision log chain is broken ({} errors)", report.errors.len());
+    }
+    if !json {
+        writeln!(out, "✓ Chain intact")?;
+    }
+    Ok(())
+}
+
+struct ReplayOptions<'a> {
+    policy: &'a str,
+    max_fuel_increase: Option<f64>,
+    json: bool,
+    fuel_limit: u64,
+}
+
+fn replay(
+    out: &mut dyn Write,
+    candidate: &Path,
+    baseline: &Path,
+    requests: &[PathBuf],
+    options: &ReplayOptions,
+) -> anyhow::Result<()> {
+    let load = |path: &Path| -> anyhow::Result<PolicyRuntime> {
+        let (wasm_bytes, _) = load_module(path)?;
+        let runtime = PolicyRuntime::new(&wasm_bytes)
+            .with_context(|| format!("Failed to load {}", path.display()))?;
+        Ok(runtime.with_fuel_limit(options.fuel_limit))
+    };
+    let baseline_runtime = load(baseline)?;
+    let candidate_runtime = load(candidate)?;
+
+    let mut corpus = Corpus::default();
+    for path in requests {
+        corpus.extend(
+            replay::load_corpus(path, Some(options.policy))
+                .with_context(|| format!("Failed to read {}", path.display()))?,
+        );
+    }
+    if corpus.cases.is_empty() {
+        bail!("no requests to replay ({} skipped)", corpus.skipped);
+    }
+
+    let report = replay::replay(&baseline_runtime, &candidate_runtime, &corpus);
+    let fuel_exceeded = options
+        .max_fuel_increase
+        .filter(|max| report.fuel.change_percent > *max);
+
+    if options.json {
+        writeln!(out, "{}", serde_json::to_string_pretty(&report)?)?;
+    } else {
+        writeln!(out, "Baseline:     {}", baseline.display())?;
+        writeln!(out, "Candidate:    {}", candidate.display())?;
+        writeln!(out, "Requests:     {} ({} skipped)", report.requests, report.skipped)?;
+        writeln!(out, "Unchanged:    {}", report.unchanged)?;
+        writeln!(out, "Allow → deny: {}", report.allow_to_deny)?;
+        writeln!(out, "Deny → allow: {}", report.deny_to_allow)?;
+        writeln!(out, "New errors:   {}", report.new_errors)?;
+        writeln!(out, "Fixed errors: {}", report.fixed_errors)?;
+        writeln!(
+            out,
+            "Fuel:         {} → {} ({:+.1}%)",
+            report.fuel.baseline_total, report.fuel.candidate_total, report.fuel.change_percent
+        )?;
+        if let Some(source) = &report.fuel.max_increase_source {
+            writeln!(out, "              max +{} at {}", report.fuel.max_increase, source)?;
+        }
+        if report.recorded_mismatches > 0 {
+            writeln!(
+                out,
+                "Recorded:     baseline differs from the logged decision for {} requests",
+                report.recorded_mismatches
+            )?;
+        }
+        for difference in &report.differences {
+            let change = match difference.change {
+                Change::AllowToDeny => "allow → deny",
+                Change::DenyToAllow => "deny → allow",
+                Change::NewError => "new error",
+                Change::FixedError => "fixed error",
+            };
+            let error = difference
+                .candidate
+                .error
+                .as_deref()
+                .or(difference.baseline.error.as_deref());
+            match error {
+                Some(error) => writeln!(out, "  {:<13} {}: {}", change, difference.source, error)?,
+                None => writeln!(out, "  {:<13} {}", change, difference.source)?,
+            }
+        }
+    }
+
+    if report.has_regressions() {
+        bail!(
+            "changed decisions: {}, new errors: {}",
+            report.allow_to_deny + report.deny_to_allow,
+            report.new_errors
+        );
+    }
+    if let Some(max) = fuel_exceeded {
+        bail!(
+            "fuel grew by {:.1}%, more than the allowed {}%",
+            report.fuel.change_percent,
+            max
+        );
+    }
+    if !options.json {
+        writeln!(out, "✓ No decision changes")?;
+    }
+    Ok(())
+}
+
+fn bench(
+    out: &mut dyn Write,
+    module: &Path,
+    request: &Path,
+    iterations: usize,
+    warmup: usize,
+    fuel_limit: u64,
+) -> anyhow::Result<()> {
+    if iterations == 0 {
+        bail!("--iterations must be greater than 0");
+    }
+    let (wasm_bytes, _) = load_module(module)?;
+    let runtime = PolicyRuntime::new(&wasm_bytes)?.with_fuel_limit(fuel_limit);
+    let request = load_request(request)?;
+
+    for _ in 0..warmup {
+        runtime.evaluate(&request)?;
+    }
+
+    let mut latencies = Vec::with_capacity(iterations);
+    let mut fuel = Vec::with_capacity(iterations);
+    let started = Instant::now();
+    for _ in 0..iterations {
+        let start = Instant::now();
+        let evaluation = runtime.evaluate(&request)?;
+        latencies.push(start.elapsed());
+        fuel.push(evaluation.fuel_consumed);
+    }
+    let total = started.elapsed();
+
+    latencies.sort();
+    fuel.sort();
+    let mean_fuel = fuel.iter().sum::<u64>() / fuel.len() as u64;
+
+    writeln!(out, "Iterations: {} (+{} warmup)", iterations, warmup)?;
+    writeln!(out, "Throughput: {:.0} req/s", iterations as f64 / total.as_secs_f64())?;
+    writeln!(
+        out,
+        "Latency:    p50 {}  p99 {}  max {}",
+        format_duration(percentile(&latencies, 50)),
+        format_duration(percentile(&latencies, 99)),
+        format_duration(latencies[latencies.len() - 1]),
+    )?;
+    writeln!(
+        out,
+        "Fuel:       mean {}  p50 {}  p99 {}  (limit {})",
+        mean_fuel,
+        percentile(&fuel, 50),
+        percentile(&fuel, 99),
+        fuel_limit
+    )?;
+    Ok(())
+}
+
+/// Nearest-rank percentile of sorted samples
+fn percentile<T: Copy>(sorted: &[T], p: usize) -> T {
+    let rank = (sorted.len() * p).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+fn format_duration(d: Duration) -> String {
+    format!("{:.1}µs", d.as_secs_f64() * 1_000_000.0)
+}
+
+/// Read a raw module or bundle, picking up a `<name>.json` sidecar manifest
+fn load_module(path: &Path) -> anyhow::Result<(Vec<u8>, Option<PolicyManifest>)> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let (wasm_bytes, manifest) = parse_bundle(&bytes).map_err(anyhow::Error::msg)?;
+    if manifest.is_some() || bytes != wasm_bytes {
+        return Ok((wasm_bytes, manifest));
+    }
+
+    let sidecar = path.with_extension("json");
+    let manifest = match std::fs::read(&sidecar) {
+        Ok(raw) => Some(
+            serde_json::from_slice(&raw)
+                .with_context(|| format!("Invalid manifest {}", sidecar.display()))?,
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).context(format!("Failed to read {}", sidecar.display())),
+    };
+    Ok((wasm_bytes, manifest))
+}
+
+/// Read a JSON request from a file or stdin, rejecting invalid JSON like the server
+fn load_request(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let bytes = if path == Path::new("-") {
+        let mut buf = Vec::new();
+        std::io::stdin().read_to_end(&mut buf)?;
+        buf
+    } else {
+        std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?
+    };
+    serde_json::from_slice::<serde_json::Value>(&bytes)
+        .with_context(|| format!("Invalid JSON in {}", path.display()))?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::{AuditConfig, AuditLog, Caller};
+    use crate::decision::Decision;
+    use crate::test_support::{temp_dir, DEFAULT_MODULE};
+    use std::fs;
+
+    /// Run `command` with the default config; an error is what makes the
+    /// binary exit non-zero
+    fn run(command: Command) -> (anyhow::Result<()>, String) {
+        let mut out = Vec::new();
+        let result = run_to(command, &Config::default(), &mut out);
+        (result, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn eval_prints_the_decision() {
+        let dir = temp_dir("cli-eval");
+        let module = dir.join("policy.wasm");
+        fs::write(&module, DEFAULT_MODULE).unwrap();
+        let request = dir.join("request.json");
+        fs::write(&request, r#"{"role":"viewer","action":"write"}"#).unwrap();
+
+        let (result, out) = run(Command::Eval {
+            module: module.clone(),
+            request: request.clone(),
+            json: false,
+        });
+        result.unwrap();
+        assert!(out.starts_with("Decision: DENY\nFuel:     "), "{}", out);
+        assert!(out.contains("  [WASM] "), "{}", out);
+
+        let (result, out) = run(Command::Eval {
+            module: module.clone(),
+            request: request.clone(),
+            json: true,
+        });
+        result.unwrap();
+        let output: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(output["allowed"], false);
+        assert_eq!(output["fuel_limit"], Config::default().policies.fuel_limit);
+
+        fs::write(&request, "{not json").unwrap();
+        let (result, out) = run(Command::Eval {
+            module,
+            request,
+            json: false,
+        });
+        assert!(result.unwrap_err().to_string().starts_with("Invalid JSON"));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn validate_runs_the_manifest_self_tests() {
+        let dir = temp_dir("cli-validate");
+        let module = dir.join("policy.wasm");
+        fs::write(&module, DEFAULT_MODULE).unwrap();
+        let manifest = |expect: &str| {
+            let tests = serde_json::json!({ "tests": [
+                { "request": { "role": "admin" }, "expect": "allow" },
+                { "request": { "blocked": true }, "expect": expect },
+            ] });
+            fs::write(dir.join("policy.json"), tests.to_string()).unwrap();
+        };
+
+        manifest("deny");
+        let (result, out) = run(Command::Validate {
+            module: module.clone(),
+        });
+        result.unwrap();
+        assert!(out.contains(": ABI OK\n"), "{}", out);
+        assert!(out.contains("evaluate_access"), "{}", out);
+        assert!(out.ends_with("✓ 2 self-tests passed\n"), "{}", out);
+
+        manifest("allow");
+        let (result, _) = run(Command::Validate {
+            module: module.clone(),
+        });
+        assert_eq!(result.unwrap_err().to_string(), "1 of 2 self-tests failed");
+
+        fs::write(&module, b"not wasm").unwrap();
+        let (result, out) = run(Command::Validate { module });
+        assert!(result.is_err());
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn inspect_lists_the_module() {
+        let dir = temp_dir("cli-inspect");
+        let module = dir.join("policy.wasm");
+        fs::write(&module, DEFAULT_MODULE).unwrap();
+
+        let (result, out) = run(Command::Inspect {
+            module: module.clone(),
+        });
+        result.unwrap();
+        let sha256 = crate::policy_store::sha256_hex(DEFAULT_MODULE);
+        assert!(out.contains(&format!("Size:    {} bytes\n", DEFAULT_MODULE.len())));
+        assert!(out.contains(&format!("SHA-256: {}\n", sha256)));
+        for export in ["evaluate_access", "transform_payload", "memory"] {
+            assert!(out.contains(&format!("\n  {:<20} ", export)), "{}", out);
+        }
+        assert!(out.ends_with("Manifest: (none)\n"), "{}", out);
+
+        fs::write(dir.join("policy.json"), r#"{"version":"1.2.0"}"#).unwrap();
+        let (result, out) = run(Command::Inspect { module });
+        result.unwrap();
+        assert!(out.contains("\"version\": \"1.2.0\""), "{}", out);
+
+        let (result, _) = run(Command::Inspect {
+            module: dir.join("missing.wasm"),
+        });
+        assert!(result.is_err());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn verify_reports_a_broken_chain() {
+        let dir = temp_dir("cli-verify");
+        let path = dir.join("decisions.log");
+        let config = AuditConfig {
+            path: Some(path.clone()),
+            ..AuditConfig::default()
+        };
+        let log = AuditLog::open(config, None).unwrap();
+        for n in 0..3 {
+            let decision = Decision {
+                policy: "default".to_string(),
+                policy_version: "v1".to_string(),
+                policy_sha256: "00".repeat(32),
+                allowed: true,
+                failure: None,
+                logs: Vec::new(),
+            };
+            let request = log.describe(format!("{{\"n\":{}}}", n).as_bytes());
+            log.record(&decision, request, &Caller::default())
+                .await
+                .unwrap();
+        }
+        log.flush().await;
+
+        let (result, out) = run(Command::Verify {
+            log: Some(path.clone()),
+            json: false,
+        });
+        result.unwrap();
+        assert!(
+            out.contains("Entries: 3\nChain:   complete from seq 0\n"),
+            "{}",
+            out
+        );
+        assert!(out.ends_with("✓ Chain intact\n"), "{}", out);
+
+        let entries = fs::read_to_string(&path).unwrap();
+        fs::write(&path, entries.replacen("true", "false", 1)).unwrap();
+        let (result, out) = run(Command::Verify {
+            log: Some(path),
+            json: true,
+        });
+        let error = result.unwrap_err().to_string();
+        assert!(
+            error.starts_with("decision log chain is broken"),
+            "{}",
+            error
+        );
+        let report: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(report["entries"], 3);
+        assert!(!report["errors"].as_array().unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let (result, _) = run(Command::Verify {
+            log: None,
+            json: false,
+        });
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "no decision log given and audit.path is not set"
+        );
+    }
+}
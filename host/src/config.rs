@@ -0,0 +1,870 @@
+//! Host configuration
+//!
+//! Settings are layered, lowest precedence first: built-in defaults, the
+//! TOML config file, `NANO_WASM_*` environment variables, then CLI flags.
+
+use crate::admission::{
+    Admission, DEFAULT_MAX_CONCURRENT, DEFAULT_MAX_QUEUE, DEFAULT_QUEUE_TIMEOUT,
+};
+use crate::audit::AuditConfig;
+use crate::cache::CacheConfig;
+use crate::cli::Command;
+use crate::decision::DEFAULT_EVAL_TIMEOUT;
+use crate::error::{ConnectorError, ConnectorResult};
+use crate::failure::FailureConfig;
+use crate::forward_auth::ForwardAuthConfig;
+use crate::http_client;
+use crate::mapping::MappingConfig;
+use crate::oci::OciPolicySource;
+use crate::policy_runtime::{DEFAULT_FUEL_LIMIT, DEFAULT_TRANSFORM_FUEL_LIMIT};
+use crate::policy_store::is_valid_policy_name;
+use crate::proxy::ProxyConfig;
+use crate::rollout::RolloutConfig;
+use crate::server::{DEFAULT_MAX_BATCH_ITEMS, DEFAULT_MAX_BODY_BYTES};
+use crate::shipping::ShippingConfig;
+use crate::telemetry::{LogFormat, LogLevel, LoggingConfig, TracingConfig};
+use crate::watcher::{WatchMode, WatchOptions};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Config file used when `--config` is not given and the file exists
+const DEFAULT_CONFIG_FILE: &str = "nano-wasm-edge.toml";
+
+/// Command-line flags; each also reads its `NANO_WASM_*` variable
+#[derive(Debug, Default, Parser)]
+#[command(
+    name = "nano-wasm-edge",
+    version,
+    about = "Lightweight Wasm policy enforcement engine"
+)]
+pub struct CliArgs {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Path to the TOML config file
+    #[arg(long, short = 'c', env = "NANO_WASM_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Print the effective configuration as TOML and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Address to listen on
+    #[arg(long, env = "NANO_WASM_LISTEN")]
+    pub listen: Option<String>,
+
+    /// Directory containing `<name>.wasm` policy modules
+    #[arg(long, env = "NANO_WASM_POLICIES_DIR")]
+    pub policies_dir: Option<PathBuf>,
+
+    /// Policy used when `/evaluate` is called without `?policy=`
+    #[arg(long, env = "NANO_WASM_DEFAULT_POLICY")]
+    pub default_policy: Option<String>,
+
+    /// Fuel budget per evaluation
+    #[arg(long, env = "NANO_WASM_FUEL_LIMIT", global = true)]
+    pub fuel_limit: Option<u64>,
+
+    /// Fuel budget per `transform_payload` call
+    #[arg(long, env = "NANO_WASM_TRANSFORM_FUEL_LIMIT")]
+    pub transform_fuel_limit: Option<u64>,
+
+    /// Wall-clock budget per evaluation in milliseconds
+    #[arg(long, env = "NANO_WASM_EVAL_TIMEOUT_MS")]
+    pub eval_timeout_ms: Option<u64>,
+
+    /// Largest accepted `/evaluate` body in bytes
+    #[arg(long, env = "NANO_WASM_MAX_BODY_BYTES")]
+    pub max_body_bytes: Option<usize>,
+
+    /// Most requests accepted in one `/evaluate/batch` call
+    #[arg(long, env = "NANO_WASM_MAX_BATCH_ITEMS")]
+    pub max_batch_items: Option<usize>,
+
+    /// Evaluations allowed to run at once
+    #[arg(long, env = "NANO_WASM_MAX_CONCURRENT")]
+    pub max_concurrent: Option<usize>,
+
+    /// Evaluations allowed to wait for a slot
+    #[arg(long, env = "NANO_WASM_MAX_QUEUE")]
+    pub max_queue: Option<usize>,
+
+    /// How long a queued evaluation waits before a 503, in milliseconds
+    #[arg(long, env = "NANO_WASM_QUEUE_TIMEOUT_MS")]
+    pub queue_timeout_ms: Option<u64>,
+
+    /// Decisions kept in the decision cache; 0 disables it
+    #[arg(long, env = "NANO_WASM_CACHE_MAX_ENTRIES")]
+    pub cache_max_entries: Option<usize>,
+
+    /// How long a cached decision stays valid, in milliseconds
+    #[arg(long, env = "NANO_WASM_CACHE_TTL_MS")]
+    pub cache_ttl_ms: Option<u64>,
+
+    /// Address for the Envoy ext_authz gRPC server; unset disables it
+    #[arg(long, env = "NANO_WASM_EXT_AUTHZ_GRPC_LISTEN")]
+    pub ext_authz_grpc_listen: Option<String>,
+
+    /// Address for the policy-enforcing reverse proxy; unset disables it
+    #[arg(long, env = "NANO_WASM_PROXY_LISTEN")]
+    pub proxy_listen: Option<String>,
+
+    /// Hash-chained decision log file; unset disables it
+    #[arg(long, env = "NANO_WASM_AUDIT_LOG")]
+    pub audit_log: Option<PathBuf>,
+
+    /// Collector that decision log batches are shipped to; unset disables it
+    #[arg(long, env = "NANO_WASM_SHIPPING_URL")]
+    pub shipping_url: Option<String>,
+
+    /// Bearer token for the decision log collector
+    #[arg(long, env = "NANO_WASM_SHIPPING_TOKEN", hide_env_values = true)]
+    pub shipping_token: Option<String>,
+
+    /// Log line format: `text` or `json`
+    #[arg(long, env = "NANO_WASM_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Least severe level logged: `error`, `warn`, `info`, `debug` or `trace`
+    #[arg(long, env = "NANO_WASM_LOG_LEVEL")]
+    pub log_level: Option<LogLevel>,
+
+    /// OTLP/gRPC collector for span export; unset disables it
+    #[arg(long, env = "NANO_WASM_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    /// File watcher mode: `native` or `poll`
+    #[arg(long, env = "NANO_WASM_WATCH_MODE")]
+    pub watch_mode: Option<WatchModeSetting>,
+
+    /// File watcher debounce interval in milliseconds
+    #[arg(long, env = "NANO_WASM_WATCH_DEBOUNCE_MS")]
+    pub debounce_ms: Option<u64>,
+
+    /// Polling interval in milliseconds for `--watch-mode poll`
+    #[arg(long, env = "NANO_WASM_WATCH_POLL_MS")]
+    pub poll_interval_ms: Option<u64>,
+
+    /// Bearer token for the admin API
+    #[arg(long, env = "NANO_WASM_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+
+    /// URL of a remote policy registry index
+    #[arg(long, env = "NANO_WASM_REGISTRY_URL")]
+    pub registry_url: Option<String>,
+
+    /// Registry and OCI sync interval in seconds
+    #[arg(long, env = "NANO_WASM_REGISTRY_INTERVAL_SECS")]
+    pub registry_interval_secs: Option<u64>,
+
+    /// Comma-separated `name=reference` OCI policy sources
+    #[arg(long, env = "NANO_WASM_OCI_POLICIES", value_delimiter = ',')]
+    pub oci_policies: Option<Vec<String>>,
+
+    /// Bearer token for the OCI registry
+    #[arg(long, env = "NANO_WASM_OCI_TOKEN", hide_env_values = true)]
+    pub oci_token: Option<String>,
+}
+
+impl CliArgs {
+    /// Parse `argv`, looking the `NANO_WASM_*` variables up through `env`
+    /// instead of the process environment
+    pub fn try_parse_with_env<I, T>(
+        argv: I,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        let argv: Vec<OsString> = argv.into_iter().map(Into::into).collect();
+        // A variable becomes the flag's default: both only apply to absent flags
+        let command = Self::command().mut_args(|arg| {
+            let value = arg.get_env().and_then(|name| env(name.to_str()?));
+            let arg = arg.env(None);
+            match value {
+                Some(value) => arg.default_value(value),
+                None => arg,
+            }
+        });
+        let mut matches = match command.try_get_matches_from(&argv) {
+            Ok(matches) => matches,
+            // Help is rendered from the declared flags so it names the variables
+            Err(e) if e.kind() == ErrorKind::DisplayHelp => {
+                return Err(Self::command().try_get_matches_from(argv).err().unwrap_or(e))
+            }
+            Err(e) => return Err(e),
+        };
+        Self::from_arg_matches_mut(&mut matches).map_err(|e| e.format(&mut Self::command()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchModeSetting {
+    Native,
+    Poll,
+}
+
+/// Effective host configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub policies: PoliciesConfig,
+    pub limits: LimitsConfig,
+    pub cache: CacheConfig,
+    pub watch: WatchConfig,
+    pub admin: AdminConfig,
+    pub registry: RegistryConfig,
+    pub oci: OciConfig,
+    pub failure: FailureConfig,
+    pub mapping: MappingConfig,
+    pub ext_authz: ExtAuthzConfig,
+    pub forward_auth: ForwardAuthConfig,
+    pub proxy: ProxyConfig,
+    pub rollout: RolloutConfig,
+    pub audit: AuditConfig,
+    pub shipping: ShippingConfig,
+    pub logging: LoggingConfig,
+    pub tracing: TracingConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: "0.0.0.0:3000".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoliciesConfig {
+    pub dir: PathBuf,
+    /// Loaded from `<dir>/<default_policy>.wasm`
+    pub default_policy: String,
+    pub fuel_limit: u64,
+    pub transform_fuel_limit: u64,
+    pub eval_timeout_ms: u64,
+}
+
+impl Default for PoliciesConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("./policies"),
+            default_policy: "default".to_string(),
+            fuel_limit: DEFAULT_FUEL_LIMIT,
+            transform_fuel_limit: DEFAULT_TRANSFORM_FUEL_LIMIT,
+            eval_timeout_ms: DEFAULT_EVAL_TIMEOUT.as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_body_bytes: usize,
+    pub max_batch_items: usize,
+    pub max_concurrent: usize,
+    pub max_queue: usize,
+    pub queue_timeout_ms: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            max_batch_items: DEFAULT_MAX_BATCH_ITEMS,
+            max_concurrent: DEFAULT_MAX_CONCURRENT,
+            max_queue: DEFAULT_MAX_QUEUE,
+            queue_timeout_ms: DEFAULT_QUEUE_TIMEOUT.as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExtAuthzConfig {
+    /// gRPC listen address; unset serves only the HTTP mode on `/ext_authz`
+    pub grpc_listen: Option<String>,
+    /// Policy for checks without a `policy` context extension
+    pub policy: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchConfig {
+    pub mode: WatchModeSetting,
+    pub debounce_ms: u64,
+    pub poll_interval_ms: u64,
+    pub settle_ms: u64,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        let defaults = WatchOptions::default();
+        Self {
+            mode: WatchModeSetting::Native,
+            debounce_ms: defaults.debounce.as_millis() as u64,
+            poll_interval_ms: 2000,
+            settle_ms: defaults.settle.as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Bearer token for the admin API; unset disables it
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistryConfig {
+    /// Policy index URL; unset disables registry sync
+    pub url: Option<String>,
+    /// Shared by registry and OCI sync
+    pub interval_secs: u64,
+}
+
+impl Default for RegistryConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            interval_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OciConfig {
+    /// `name=registry/repository:tag` or `...@sha256:<digest>` entries
+    pub policies: Vec<String>,
+    pub token: Option<String>,
+}
+
+impl Config {
+    /// Build the effective configuration from file, environment and flags
+    pub fn load(args: &CliArgs) -> ConnectorResult<Self> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Self::default(),
+        };
+        config.apply_args(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> ConnectorResult<Self> {
+        let raw = std::fs::read_to_string(path).map_err(|e| {
+            ConnectorError::ConfigError(format!("Failed to read {}: {}", path.display(), e))
+        })?;
+        toml::from_str(&raw).map_err(|e| {
+            ConnectorError::ConfigError(format!("Failed to parse {}: {}", path.display(), e))
+        })
+    }
+
+    fn apply_args(&mut self, args: &CliArgs) {
+        fn set<T: Clone>(target: &mut T, value: &Option<T>) {
+            if let Some(value) = value {
+                *target = value.clone();
+            }
+        }
+
+        set(&mut self.server.listen, &args.listen);
+        set(&mut self.policies.dir, &args.policies_dir);
+        set(&mut self.policies.default_policy, &args.default_policy);
+        set(&mut self.policies.fuel_limit, &args.fuel_limit);
+        set(&mut self.policies.transform_fuel_limit, &args.transform_fuel_limit);
+        set(&mut self.policies.eval_timeout_ms, &args.eval_timeout_ms);
+        set(&mut self.limits.max_body_bytes, &args.max_body_bytes);
+        set(&mut self.limits.max_batch_items, &args.max_batch_items);
+        set(&mut self.limits.max_concurrent, &args.max_concurrent);
+        set(&mut self.limits.max_queue, &args.max_queue);
+        set(&mut self.limits.queue_timeout_ms, &args.queue_timeout_ms);
+        set(&mut self.cache.max_entries, &args.cache_max_entries);
+        set(&mut self.cache.ttl_ms, &args.cache_ttl_ms);
+        set(&mut self.logging.format, &args.log_format);
+        set(&mut self.logging.level, &args.log_level);
+        set(&mut self.watch.mode, &args.watch_mode);
+        set(&mut self.watch.debounce_ms, &args.debounce_ms);
+        set(&mut self.watch.poll_interval_ms, &args.poll_interval_ms);
+        set(
+            &mut self.registry.interval_secs,
+            &args.registry_interval_secs,
+        );
+        set(&mut self.oci.policies, &args.oci_policies);
+        if args.admin_token.is_some() {
+            self.admin.token = args.admin_token.clone();
+        }
+        if args.registry_url.is_some() {
+            self.registry.url = args.registry_url.clone();
+        }
+        if args.oci_token.is_some() {
+            self.oci.token = args.oci_token.clone();
+        }
+        if args.proxy_listen.is_some() {
+            self.proxy.listen = args.proxy_listen.clone();
+        }
+        if args.ext_authz_grpc_listen.is_some() {
+            self.ext_authz.grpc_listen = args.ext_authz_grpc_listen.clone();
+        }
+        if args.otlp_endpoint.is_some() {
+            self.tracing.otlp_endpoint = args.otlp_endpoint.clone();
+        }
+        if args.audit_log.is_some() {
+            self.audit.path = args.audit_log.clone();
+        }
+        if args.shipping_url.is_some() {
+            self.shipping.url = args.shipping_url.clone();
+        }
+        if args.shipping_token.is_some() {
+            self.shipping.token = args.shipping_token.clone();
+        }
+
+        // Treat empty strings (e.g. `NANO_WASM_ADMIN_TOKEN=`) as unset
+        for value in [
+            &mut self.admin.token,
+            &mut self.registry.url,
+            &mut self.oci.token,
+            &mut self.ext_authz.grpc_listen,
+            &mut self.ext_authz.policy,
+            &mut self.forward_auth.policy,
+            &mut self.proxy.listen,
+            &mut self.tracing.otlp_endpoint,
+            &mut self.shipping.url,
+            &mut self.shipping.token,
+        ] {
+            if value.as_deref().is_some_and(str::is_empty) {
+                *value = None;
+            }
+        }
+        if self.audit.path.as_ref().is_some_and(|p| p.as_os_str().is_empty()) {
+            self.audit.path = None;
+        }
+        self.oci.policies.retain(|s| !s.trim().is_empty());
+    }
+
+    /// Reject settings that would only fail later at runtime
+    pub fn validate(&self) -> ConnectorResult<()> {
+        let invalid = |msg: String| Err(ConnectorError::ConfigError(msg));
+
+        if self.listen_addr().is_err() {
+            return invalid(format!(
+                "server.listen: invalid address '{}'",
+                self.server.listen
+            ));
+        }
+        if !is_valid_policy_name(&self.policies.default_policy) {
+            return invalid(format!(
+                "policies.default_policy: invalid policy name '{}'",
+                self.policies.default_policy
+            ));
+        }
+        if self.policies.fuel_limit == 0 {
+            return invalid("policies.fuel_limit must be greater than 0".to_string());
+        }
+        if self.policies.transform_fuel_limit == 0 {
+            return invalid("policies.transform_fuel_limit must be greater than 0".to_string());
+        }
+        if self.policies.eval_timeout_ms == 0 {
+            return invalid("policies.eval_timeout_ms must be greater than 0".to_string());
+        }
+        if self.limits.max_body_bytes == 0 {
+            return invalid("limits.max_body_bytes must be greater than 0".to_string());
+        }
+        if self.limits.max_batch_items == 0 {
+            return invalid("limits.max_batch_items must be greater than 0".to_string());
+        }
+        if self.limits.max_concurrent == 0 {
+            return invalid("limits.max_concurrent must be greater than 0".to_string());
+        }
+        if let Some(name) = self
+            .failure
+            .policies
+            .keys()
+            .find(|name| !is_valid_policy_name(name))
+        {
+            return invalid(format!("failure.policies: invalid policy name '{}'", name));
+        }
+        for (name, rule) in &self.rollout.policies {
+            if !is_valid_policy_name(name) {
+                return invalid(format!("rollout.policies: invalid policy name '{}'", name));
+            }
+            if !is_valid_policy_name(&rule.candidate) || rule.candidate == *name {
+                return invalid(format!(
+                    "rollout.policies.{}.candidate: invalid candidate '{}'",
+                    name, rule.candidate
+                ));
+            }
+            if self.rollout.policies.contains_key(&rule.candidate) {
+                return invalid(format!(
+                    "rollout.policies.{}.candidate: '{}' has a candidate itself",
+                    name, rule.candidate
+                ));
+            }
+            if rule.canary_percent > 100 {
+                return invalid(format!(
+                    "rollout.policies.{}.canary_percent must be at most 100",
+                    name
+                ));
+            }
+        }
+        if let Some(name) = self
+            .cache
+            .policies
+            .keys()
+            .find(|name| !is_valid_policy_name(name))
+        {
+            return invalid(format!("cache.policies: invalid policy name '{}'", name));
+        }
+        if self.mapping.role_header.parse::<axum::http::HeaderName>().is_err() {
+            return invalid(format!(
+                "mapping.role_header: invalid header name '{}'",
+                self.mapping.role_header
+            ));
+        }
+        if let Some(name) = self
+            .forward_auth
+            .uri_headers
+            .iter()
+            .chain(&self.forward_auth.method_headers)
+            .find(|name| name.parse::<axum::http::HeaderName>().is_err())
+        {
+            return invalid(format!("forward_auth: invalid header name '{}'", name));
+        }
+        if let Some(listen) = &self.ext_authz.grpc_listen {
+            if listen.parse::<SocketAddr>().is_err() {
+                return invalid(format!("ext_authz.grpc_listen: invalid address '{}'", listen));
+            }
+        }
+        if let Some(listen) = &self.proxy.listen {
+            if listen.parse::<SocketAddr>().is_err() {
+                return invalid(format!("proxy.listen: invalid address '{}'", listen));
+            }
+        }
+        if self.proxy.max_body_bytes == 0 {
+            return invalid("proxy.max_body_bytes must be greater than 0".to_string());
+        }
+        if self.proxy.timeout_ms == 0 {
+            return invalid("proxy.timeout_ms must be greater than 0".to_string());
+        }
+        for route in &self.proxy.routes {
+            route.parse()?;
+        }
+        for (key, policy) in [
+            ("ext_authz.policy", &self.ext_authz.policy),
+            ("forward_auth.policy", &self.forward_auth.policy),
+        ] {
+            if let Some(name) = policy.as_deref().filter(|name| !is_valid_policy_name(name)) {
+                return invalid(format!("{}: invalid policy name '{}'", key, name));
+            }
+        }
+        if self.audit.max_file_bytes < 4096 {
+            return invalid("audit.max_file_bytes must be at least 4096".to_string());
+        }
+        if let Some(name) = &self.audit.client_header {
+            if name.parse::<axum::http::HeaderName>().is_err() {
+                return invalid(format!("audit.client_header: invalid header name '{}'", name));
+            }
+        }
+        if let Some(path) = self
+            .audit
+            .redact_fields
+            .iter()
+            .find(|path| path.split('.').any(str::is_empty))
+        {
+            return invalid(format!("audit.redact_fields: invalid path '{}'", path));
+        }
+        if let Some(url) = &self.shipping.url {
+            let uri = match url.parse::<axum::http::Uri>() {
+                Ok(uri)
+                    if matches!(uri.scheme_str(), Some("http" | "https"))
+                        && uri.host().is_some() =>
+                {
+                    uri
+                }
+                _ => {
+                    return invalid(format!(
+                        "shipping.url: expected an http:// or https:// URL, got '{}'",
+                        url
+                    ))
+                }
+            };
+            if uri.scheme_str() == Some("https") && !cfg!(feature = "tls") {
+                return invalid("shipping.url: https:// needs the `tls` feature".to_string());
+            }
+            // The token would be readable by anyone on the path
+            if self.shipping.token.is_some() {
+                if let Err(e) = http_client::check_transport(&uri) {
+                    return invalid(format!("shipping.token: not sent to '{}': {}", url, e));
+                }
+            }
+            if self.audit.path.is_none() {
+                return invalid("shipping.url requires audit.path".to_string());
+            }
+        }
+        if self.shipping.max_spool_bytes < 64 * 1024 {
+            return invalid("shipping.max_spool_bytes must be at least 65536".to_string());
+        }
+        if self.shipping.batch_max_entries == 0 {
+            return invalid("shipping.batch_max_entries must be greater than 0".to_string());
+        }
+        if self.shipping.batch_max_age_ms == 0 {
+            return invalid("shipping.batch_max_age_ms must be greater than 0".to_string());
+        }
+        if self.shipping.min_backoff_ms == 0 {
+            return invalid("shipping.min_backoff_ms must be greater than 0".to_string());
+        }
+        if self.shipping.max_backoff_ms < self.shipping.min_backoff_ms {
+            return invalid(
+                "shipping.max_backoff_ms must be at least shipping.min_backoff_ms".to_string(),
+            );
+        }
+        if let Some(endpoint) = &self.tracing.otlp_endpoint {
+            if !cfg!(feature = "otlp") {
+                return invalid(
+                    "tracing.otlp_endpoint: built without the `otlp` feature".to_string(),
+                );
+            }
+            match endpoint.parse::<axum::http::Uri>() {
+                Ok(uri) if uri.scheme_str() == Some("http") && uri.host().is_some() => {}
+                _ => {
+                    return invalid(format!(
+                        "tracing.otlp_endpoint: expected an http:// URL, got '{}'",
+                        endpoint
+                    ))
+                }
+            }
+        }
+        if self.watch.debounce_ms == 0 {
+            return invalid("watch.debounce_ms must be greater than 0".to_string());
+        }
+        if self.watch.poll_interval_ms < 100 {
+            return invalid("watch.poll_interval_ms must be at least 100".to_string());
+        }
+        if self.registry.interval_secs == 0 {
+            return invalid("registry.interval_secs must be greater than 0".to_string());
+        }
+        if let Some(url) = &self.registry.url {
+            // Bundles are only as trustworthy as the index listing their digests
+            let checked = url
+                .parse::<axum::http::Uri>()
+                .map_err(|e| e.to_string())
+                .and_then(|uri| http_client::check_transport(&uri));
+            if let Err(e) = checked {
+                return invalid(format!("registry.url: {}, got '{}'", e, url));
+            }
+        }
+        self.oci_sources()?;
+        Ok(())
+    }
+
+    pub fn listen_addr(&self) -> Result<SocketAddr, std::net::AddrParseError> {
+        self.server.listen.parse()
+    }
+
+    pub fn proxy_addr(&self) -> Option<SocketAddr> {
+        self.proxy.listen.as_deref()?.parse().ok()
+    }
+
+    pub fn ext_authz_grpc_addr(&self) -> Option<SocketAddr> {
+        self.ext_authz.grpc_listen.as_deref()?.parse().ok()
+    }
+
+    pub fn oci_sources(&self) -> ConnectorResult<Vec<OciPolicySource>> {
+        self.oci
+            .policies
+            .iter()
+            .map(|spec| OciPolicySource::parse(spec))
+            .collect()
+    }
+
+    pub fn watch_options(&self) -> WatchOptions {
+        WatchOptions {
+            mode: match self.watch.mode {
+                WatchModeSetting::Native => WatchMode::Native,
+                WatchModeSetting::Poll => {
+                    WatchMode::Poll(Duration::from_millis(self.watch.poll_interval_ms))
+                }
+            },
+            debounce: Duration::from_millis(self.watch.debounce_ms),
+            settle: Duration::from_millis(self.watch.settle_ms),
+        }
+    }
+
+    pub fn eval_timeout(&self) -> Duration {
+        Duration::from_millis(self.policies.eval_timeout_ms)
+    }
+
+    pub fn admission(&self) -> Admission {
+        Admission::new(
+            self.limits.max_concurrent,
+            self.limits.max_queue,
+            Duration::from_millis(self.limits.queue_timeout_ms),
+        )
+    }
+
+    pub fn sync_interval(&self) -> Duration {
+        Duration::from_secs(self.registry.interval_secs)
+    }
+
+    /// TOML rendering with secrets redacted
+    pub fn to_redacted_toml(&self) -> String {
+        let mut redacted = self.clone();
+        for secret in [
+            &mut redacted.admin.token,
+            &mut redacted.oci.token,
+            &mut redacted.shipping.token,
+        ] {
+            if secret.is_some() {
+                *secret = Some("<redacted>".to_string());
+            }
+        }
+        toml::to_string_pretty(&redacted).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_dir;
+    use std::collections::HashMap;
+
+    fn parse(raw: &str) -> ConnectorResult<Config> {
+        let config: Config =
+            toml::from_str(raw).map_err(|e| ConnectorError::ConfigError(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn error(raw: &str) -> String {
+        match parse(raw) {
+            Ok(_) => panic!("accepted:\n{}", raw),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn flags_override_the_environment_which_overrides_the_file() {
+        let path = temp_dir("config-layers").join("nano-wasm-edge.toml");
+        std::fs::write(
+            &path,
+            r#"
+            [server]
+            listen = "127.0.0.1:4000"
+            [limits]
+            max_concurrent = 3
+            max_queue = 5
+            [cache]
+            ttl_ms = 100
+            [admin]
+            token = "from-file"
+            "#,
+        )
+        .unwrap();
+
+        let env: HashMap<&str, &str> = [
+            ("NANO_WASM_MAX_QUEUE", "7"),
+            ("NANO_WASM_CACHE_TTL_MS", "200"),
+            ("NANO_WASM_ADMIN_TOKEN", "from-env"),
+        ]
+        .into();
+        let args = CliArgs::try_parse_with_env(
+            [
+                "nano-wasm-edge",
+                "--config",
+                path.to_str().unwrap(),
+                "--cache-ttl-ms",
+                "300",
+                "--admin-token=",
+            ],
+            |name| env.get(name).map(|value| value.to_string()),
+        );
+        let config = Config::load(&args.unwrap()).unwrap();
+
+        assert_eq!(config.server.listen, "127.0.0.1:4000");
+        assert_eq!(config.limits.max_concurrent, 3);
+        assert_eq!(config.limits.max_queue, 7);
+        assert_eq!(config.cache.ttl_ms, 300);
+        assert_eq!(config.policies.fuel_limit, DEFAULT_FUEL_LIMIT);
+        // An empty value unsets the setting instead of keeping the file's
+        assert_eq!(config.admin.token, None);
+    }
+
+    #[test]
+    fn rejects_values_that_would_fail_at_runtime() {
+        let cases = [
+            ("[server]\nlisten = \"nowhere\"", "server.listen"),
+            (
+                "[policies]\ndefault_policy = \"../etc\"",
+                "policies.default_policy",
+            ),
+            ("[policies]\nfuel_limit = 0", "policies.fuel_limit"),
+            ("[limits]\nmax_concurrent = 0", "limits.max_concurrent"),
+            ("[watch]\npoll_interval_ms = 10", "watch.poll_interval_ms"),
+            (
+                "[rollout.policies.default]\nmode = \"shadow\"\ncandidate = \"default\"",
+                "rollout.policies.default.candidate",
+            ),
+            (
+                "[shipping]\nurl = \"ftp://collector\"\n[audit]\npath = \"audit.log\"",
+                "shipping.url",
+            ),
+            (
+                "[shipping]\nurl = \"http://collector:9000\"",
+                "shipping.url requires audit.path",
+            ),
+        ];
+        for (raw, expected) in cases {
+            let error = error(raw);
+            assert!(error.contains(expected), "{}: {}", raw, error);
+        }
+        assert!(error("[limits]\nmax_queues = 1").contains("unknown field"));
+    }
+
+    #[test]
+    fn sends_policies_and_tokens_only_over_tls_or_loopback() {
+        let shipping = |url: &str| {
+            format!(
+                "[audit]\npath = \"audit.log\"\n[shipping]\nurl = \"{}\"\ntoken = \"secret\"",
+                url
+            )
+        };
+        assert!(error(&shipping("http://collector.example/ingest")).contains("shipping.token"));
+        assert!(parse(&shipping("http://127.0.0.1:9000/ingest")).is_ok());
+        assert!(parse(&shipping("http://[::1]:9000/ingest")).is_ok());
+        // Without a token, plain HTTP carries nothing secret
+        assert!(parse(
+            "[audit]\npath = \"audit.log\"\n[shipping]\nurl = \"http://collector.example/ingest\""
+        )
+        .is_ok());
+
+        let registry = |url: &str| format!("[registry]\nurl = \"{}\"", url);
+        assert!(error(&registry("http://registry.example/index.json")).contains("registry.url"));
+        assert!(parse(&registry("http://localhost:8081/index.json")).is_ok());
+        let https = parse(&registry("https://registry.example/index.json"));
+        assert_eq!(https.is_ok(), cfg!(feature = "tls"));
+        assert_eq!(
+            parse(&shipping("https://collector.example/ingest")).is_ok(),
+            cfg!(feature = "tls")
+        );
+    }
+}
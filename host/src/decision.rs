@@ -0,0 +1,588 @@
+//! Policy decisions
+//!
+//! Every enforcement endpoint goes through [`AppState::decide`], which looks
+//! up the policy, waits for an admission slot, evaluates the request off the
+//! async runtime under a deadline, and turns evaluation errors into an allow
+//! or deny according to the configured failure rules. Repeated requests may
+//! be answered from the [`DecisionCache`](crate::cache::DecisionCache).
+//! Decisions are recorded in the [`AuditLog`](crate::audit::AuditLog), and
+//! may be made by, or compared with, a candidate policy under a
+//! [`Rollout`](crate::rollout::Rollout).
+//! Documents released after an allow go through [`AppState::transform`].
+
+use crate::audit::{AuditRequest, Caller};
+use crate::cache::Lookup;
+use crate::error::{ConnectorError, ConnectorResult};
+use crate::failure::{ErrorClass, FailureMode};
+use crate::policy_runtime::{Evaluation, PolicyRuntime};
+use crate::policy_store::{sha256_hex, LoadedPolicy};
+use crate::rollout::ShadowResult;
+use crate::AppState;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{field, Instrument, Span};
+
+/// Default wall-clock budget for one evaluation
+pub const DEFAULT_EVAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Outcome of evaluating a request
+#[derive(Debug)]
+pub struct Decision {
+    pub policy: String,
+    /// Empty when the policy is not loaded
+    pub policy_version: String,
+    /// Module digest; empty when the policy is not loaded
+    pub policy_sha256: String,
+    pub allowed: bool,
+    /// Why the policy did not decide, if it did not
+    pub failure: Option<Failure>,
+    /// What the guest logged; empty for cached and failed decisions
+    pub logs: Vec<String>,
+}
+
+impl Decision {
+    /// Headers describing the decision, for proxies that act on headers
+    /// rather than a response body
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let verdict = if self.allowed { "allow" } else { "deny" };
+        let mut headers = vec![
+            ("x-nano-wasm-decision", verdict.to_string()),
+            ("x-nano-wasm-policy", self.policy.clone()),
+            ("x-nano-wasm-policy-version", self.policy_version.clone()),
+        ];
+        if let Some(failure) = &self.failure {
+            let mode = match failure.mode {
+                FailureMode::Open => "open",
+                FailureMode::Closed => "closed",
+            };
+            headers.push(("x-nano-wasm-failure-mode", mode.to_string()));
+            headers.push(("x-nano-wasm-error", failure.error.code().to_string()));
+        }
+        headers
+    }
+}
+
+/// Outcome of evaluating a batch against one policy snapshot
+#[derive(Debug)]
+pub struct BatchDecision {
+    pub policy: String,
+    /// Version every item was evaluated against; empty when not loaded
+    pub policy_version: String,
+    /// One decision per request, in order; empty when `failure` is set
+    pub decisions: Vec<Decision>,
+    /// Why the batch as a whole could not be evaluated, if it could not
+    pub failure: Option<Failure>,
+}
+
+/// An evaluation error and the failure mode that replaced the decision
+#[derive(Debug)]
+pub struct Failure {
+    pub error: ConnectorError,
+    /// `None` for errors that always fail closed
+    pub class: Option<ErrorClass>,
+    pub mode: FailureMode,
+}
+
+impl AppState {
+    /// Evaluate `request` against `policy`, or the default policy
+    pub async fn decide(&self, policy: Option<&str>, request: &[u8]) -> Decision {
+        let span = tracing::info_span!(
+            "decide",
+            policy = policy.unwrap_or(&self.default_policy),
+            policy_version = field::Empty,
+            canary = field::Empty,
+            cache = field::Empty,
+            fuel_consumed = field::Empty,
+            allowed = field::Empty,
+            error = field::Empty,
+        );
+        let decision = self.evaluate_request(policy, request).instrument(span.clone()).await;
+        let audited = self.audit.is_enabled().then(|| self.audit.describe(request));
+        let decision = self.audited(decision, audited, &Caller::current()).await;
+        self.shadow(&decision, request).await;
+        span.record("policy_version", decision.policy_version.as_str());
+        span.record("allowed", decision.allowed);
+        if let Some(failure) = &decision.failure {
+            span.record("error", failure.error.code());
+        }
+        self.metrics.record_decision(&decision);
+        decision
+    }
+
+    async fn evaluate_request(&self, policy: Option<&str>, request: &[u8]) -> Decision {
+        let name = self.route(policy.unwrap_or(&self.default_policy)).await;
+
+        let Some(loaded) = self.policies.get(&name).await else {
+            let error = ConnectorError::PolicyNotFound(name.clone());
+            return self.fail(name, None, error);
+        };
+        let policy_version = loaded.version.clone();
+
+        if let Err(e) = serde_json::from_slice::<serde_json::Value>(request) {
+            let error = ConnectorError::InvalidRequest(format!("Invalid JSON: {}", e));
+            return self.fail(name, Some(&loaded), error);
+        }
+
+        let cache = self.policies.decision_cache();
+        let pending = match cache.lookup(&loaded, request) {
+            Lookup::Hit(allowed) => {
+                Span::current().record("cache", "hit");
+                return Decision {
+                    policy: name,
+                    policy_version,
+                    policy_sha256: loaded.sha256.clone(),
+                    allowed,
+                    failure: None,
+                    logs: Vec::new(),
+                }
+            }
+            Lookup::Miss(pending) => {
+                Span::current().record("cache", "miss");
+                Some(pending)
+            }
+            Lookup::Bypass => None,
+        };
+
+        let runtime = loaded.runtime.clone();
+        let request_bytes = request.to_vec();
+        let result = self
+            .run_blocking(move || timed_evaluate(&runtime, &request_bytes))
+            .await;
+
+        match result {
+            Ok((evaluation, elapsed)) => {
+                self.guest_logs.write(&name, &evaluation.logs);
+                self.metrics.record_evaluation(&name, &evaluation, elapsed);
+                Span::current().record("fuel_consumed", evaluation.fuel_consumed);
+                if let Some(pending) = pending {
+                    cache.insert(pending, evaluation.allowed);
+                }
+                Decision {
+                    policy: name,
+                    policy_version,
+                    policy_sha256: loaded.sha256.clone(),
+                    allowed: evaluation.allowed,
+                    failure: None,
+                    logs: evaluation.logs,
+                }
+            }
+            Err(error) => self.fail(name, Some(&loaded), error),
+        }
+    }
+
+    /// Evaluate each of `requests` against the same snapshot of `policy`
+    ///
+    /// The batch takes one admission slot and runs on one blocking thread,
+    /// each request in a fresh store. Every item gets the evaluation
+    /// timeout; items still pending when the batch deadline passes time out
+    /// individually.
+    pub async fn decide_batch(&self, policy: Option<&str>, requests: Vec<Vec<u8>>) -> BatchDecision {
+        let name = policy.unwrap_or(&self.default_policy).to_string();
+        let span = tracing::info_span!(
+            "decide_batch",
+            policy = name.as_str(),
+            policy_version = field::Empty,
+            canary = field::Empty,
+            items = requests.len(),
+        );
+        self.evaluate_batch(name, requests).instrument(span).await
+    }
+
+    async fn evaluate_batch(&self, name: String, requests: Vec<Vec<u8>>) -> BatchDecision {
+        let name = self.route(&name).await;
+
+        let Some(loaded) = self.policies.get(&name).await else {
+            let error = ConnectorError::PolicyNotFound(name.clone());
+            return self.fail_batch(name, None, error);
+        };
+        let policy_version = loaded.version.clone();
+        Span::current().record("policy_version", policy_version.as_str());
+
+        let permit = match self.admission.acquire().await {
+            Ok(permit) => permit,
+            Err(error) => return self.fail_batch(name, Some(&loaded), error),
+        };
+
+        let count = requests.len();
+        let mut audited = Vec::new();
+        if self.audit.is_enabled() {
+            audited.extend(requests.iter().map(|request| self.audit.describe(request)));
+        }
+        let mut audited = audited.into_iter();
+        let mut shadowed = Vec::new();
+        if self.rollout.shadow_for(&name).is_some() {
+            shadowed.clone_from(&requests);
+        }
+        let mut shadowed = shadowed.into_iter();
+        let caller = Caller::current();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let runtime = loaded.runtime.clone();
+        let span = Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let _entered = span.enter();
+            for request in requests {
+                // The caller gave up on the rest of the batch
+                if tx.is_closed() {
+                    break;
+                }
+                let _ = tx.send(timed_evaluate(&runtime, &request));
+            }
+        });
+
+        let deadline = Instant::now() + self.eval_timeout * count as u32;
+        let mut decisions = Vec::with_capacity(count);
+        while decisions.len() < count {
+            let result = match tokio::time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some(result)) => result,
+                Ok(None) => Err(ConnectorError::PolicyExecutionError(
+                    "Policy execution ended early".to_string(),
+                )),
+                Err(_) => Err(ConnectorError::EvaluationTimeout {
+                    timeout_ms: self.eval_timeout.as_millis() as u64,
+                }),
+            };
+            let decision = match result {
+                Ok((evaluation, elapsed)) => {
+                    self.guest_logs.write(&name, &evaluation.logs);
+                    self.metrics.record_evaluation(&name, &evaluation, elapsed);
+                    Decision {
+                        policy: name.clone(),
+                        policy_version: policy_version.clone(),
+                        policy_sha256: loaded.sha256.clone(),
+                        allowed: evaluation.allowed,
+                        failure: None,
+                        logs: evaluation.logs,
+                    }
+                }
+                Err(error) => self.fail(name.clone(), Some(&loaded), error),
+            };
+            let decision = self.audited(decision, audited.next(), &caller).await;
+            if let Some(request) = shadowed.next() {
+                self.shadow(&decision, &request).await;
+            }
+            self.metrics.record_decision(&decision);
+            decisions.push(decision);
+        }
+
+        BatchDecision {
+            policy: name,
+            policy_version,
+            decisions,
+            failure: None,
+        }
+    }
+
+    /// Filter `document` through the `transform_payload` of `policy`
+    ///
+    /// `request` is the request the document was released for; the guest
+    /// sees it together with the policy name and version. Returns `None` when
+    /// the policy has no transform. Errors are never failed open: the caller
+    /// must withhold the document.
+    pub async fn transform(
+        &self,
+        policy: Option<&str>,
+        request: serde_json::Value,
+        document: Vec<u8>,
+    ) -> ConnectorResult<Option<Vec<u8>>> {
+        let name = policy.unwrap_or(&self.default_policy);
+        let span = tracing::info_span!(
+            "transform",
+            policy = name,
+            policy_version = field::Empty,
+            fuel_consumed = field::Empty,
+        );
+        self.transform_document(name, request, document)
+            .instrument(span)
+            .await
+    }
+
+    async fn transform_document(
+        &self,
+        name: &str,
+        request: serde_json::Value,
+        document: Vec<u8>,
+    ) -> ConnectorResult<Option<Vec<u8>>> {
+        let loaded = self
+            .policies
+            .get(name)
+            .await
+            .ok_or_else(|| ConnectorError::PolicyNotFound(name.to_string()))?;
+        Span::current().record("policy_version", loaded.version.as_str());
+        if !loaded.runtime.has_transform() {
+            return Ok(None);
+        }
+        if let Err(e) = serde_json::from_slice::<serde_json::Value>(&document) {
+            return Err(ConnectorError::InvalidRequest(format!(
+                "Document is not valid JSON: {}",
+                e
+            )));
+        }
+
+        let context = serde_json::json!({
+            "policy": name,
+            "policy_version": loaded.version,
+            "request": request,
+        });
+        let context = serde_json::to_vec(&context).unwrap_or_default();
+        let runtime = loaded.runtime.clone();
+        let transformation = self
+            .run_blocking(move || runtime.transform(&context, &document))
+            .await?;
+        self.guest_logs.write(name, &transformation.logs);
+        Span::current().record("fuel_consumed", transformation.fuel_consumed);
+        Ok(Some(transformation.document))
+    }
+
+    /// Run guest code off the async runtime, holding an admission slot and
+    /// bounded by the evaluation timeout
+    async fn run_blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> ConnectorResult<T> + Send + 'static,
+    ) -> ConnectorResult<T> {
+        let permit = self.admission.acquire().await?;
+        let span = Span::current();
+        let task = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            span.in_scope(f)
+        });
+
+        // Fuel bounds how long a timed-out evaluation keeps its thread
+        match tokio::time::timeout(self.eval_timeout, task).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) => Err(ConnectorError::PolicyExecutionError(format!(
+                "Policy execution join error: {}",
+                e
+            ))),
+            Err(_) => Err(ConnectorError::EvaluationTimeout {
+                timeout_ms: self.eval_timeout.as_millis() as u64,
+            }),
+        }
+    }
+
+    /// `policy`, or its canary candidate if the request falls into the
+    /// canary share and the candidate is loaded
+    async fn route(&self, policy: &str) -> String {
+        if let Some(candidate) = self.rollout.canary_for(policy) {
+            if self.policies.get(candidate).await.is_some() {
+                self.rollout.record_canary(policy, candidate);
+                Span::current().record("canary", candidate);
+                return candidate.to_string();
+            }
+        }
+        policy.to_string()
+    }
+
+    /// Evaluate `request` in the background against the candidate shadowing
+    /// the policy that made `decision`, and compare the results
+    async fn shadow(&self, decision: &Decision, request: &[u8]) {
+        let Some(candidate) = self.rollout.shadow_for(&decision.policy) else {
+            return;
+        };
+        // A failed decision has nothing to compare against
+        if decision.failure.is_some() {
+            return;
+        }
+        let recorder = self.rollout.shadow_recorder(&decision.policy, candidate);
+        let Some(loaded) = self.policies.get(candidate).await else {
+            recorder.record(ShadowResult::Skipped);
+            return;
+        };
+        let Some(permit) = self.admission.try_acquire() else {
+            recorder.record(ShadowResult::Skipped);
+            return;
+        };
+
+        let span = tracing::info_span!(
+            "shadow",
+            policy = decision.policy.as_str(),
+            candidate = candidate,
+            candidate_version = loaded.version.as_str(),
+        );
+        let allowed = decision.allowed;
+        let request = request.to_vec();
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let _entered = span.enter();
+            let result = match loaded.runtime.evaluate(&request) {
+                Ok(evaluation) if evaluation.allowed == allowed => ShadowResult::Agree,
+                Ok(evaluation) => {
+                    tracing::warn!(
+                        allowed,
+                        candidate_allowed = evaluation.allowed,
+                        request_sha256 = sha256_hex(&request),
+                        "Shadow decision disagrees"
+                    );
+                    ShadowResult::Disagree
+                }
+                Err(e) => {
+                    tracing::warn!(error = %e, "Shadow evaluation failed");
+                    ShadowResult::Error
+                }
+            };
+            recorder.record(result);
+        });
+    }
+
+    fn fail_batch(
+        &self,
+        policy: String,
+        loaded: Option<&LoadedPolicy>,
+        error: ConnectorError,
+    ) -> BatchDecision {
+        let decision = self.fail(policy, loaded, error);
+        self.metrics.record_decision(&decision);
+        BatchDecision {
+            policy: decision.policy,
+            policy_version: decision.policy_version,
+            decisions: Vec::new(),
+            failure: decision.failure,
+        }
+    }
+
+    /// Record `decision` in the audit log; if that fails and the log is
+    /// required, the decision fails closed
+    async fn audited(
+        &self,
+        decision: Decision,
+        request: Option<AuditRequest>,
+        caller: &Caller,
+    ) -> Decision {
+        let Some(request) = request else {
+            return decision;
+        };
+        let Err(e) = self.audit.record(&decision, request, caller).await else {
+            return decision;
+        };
+        tracing::error!(policy = decision.policy.as_str(), error = %e, "Failed to record decision");
+        if !self.audit.is_required() {
+            return decision;
+        }
+        let error = ConnectorError::IoError(std::io::Error::new(
+            e.kind(),
+            format!("Failed to record decision: {}", e),
+        ));
+        let mode = self.failure.mode_for(&decision.policy, &error);
+        Decision {
+            allowed: mode == FailureMode::Open,
+            failure: Some(Failure {
+                class: ErrorClass::of(&error),
+                error,
+                mode,
+            }),
+            ..decision
+        }
+    }
+
+    /// Decision for `policy` when it could not decide; `loaded` is the
+    /// snapshot evaluated, if the policy was loaded
+    fn fail(&self, policy: String, loaded: Option<&LoadedPolicy>, error: ConnectorError) -> Decision {
+        let mode = self.failure.mode_for(&policy, &error);
+        Decision {
+            policy,
+            policy_version: loaded.map(|l| l.version.clone()).unwrap_or_default(),
+            policy_sha256: loaded.map(|l| l.sha256.clone()).unwrap_or_default(),
+            allowed: mode == FailureMode::Open,
+            failure: Some(Failure {
+                class: ErrorClass::of(&error),
+                error,
+                mode,
+            }),
+            logs: Vec::new(),
+        }
+    }
+}
+
+/// Evaluate `request`, also returning how long the guest took
+fn timed_evaluate(runtime: &PolicyRuntime, request: &[u8]) -> ConnectorResult<(Evaluation, Duration)> {
+    let started = Instant::now();
+    let evaluation = runtime.evaluate(request)?;
+    Ok((evaluation, started.elapsed()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{CacheConfig, DecisionCache};
+    use crate::policy_store::PolicyStore;
+    use crate::test_support::activate_default;
+
+    async fn state(max_entries: usize) -> AppState {
+        let cache = DecisionCache::new(CacheConfig {
+            max_entries,
+            ttl_ms: 60_000,
+            ..CacheConfig::default()
+        });
+        let policies = PolicyStore::new(std::env::temp_dir()).with_decision_cache(cache);
+        activate_default(&policies, "default").await;
+        AppState::new(policies, "default")
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn cache_does_not_change_decisions() {
+        // The example guest matches `"blocked":true` byte for byte
+        let requests: [&[u8]; 2] = [
+            br#"{"role":"viewer","blocked": true}"#,
+            br#"{"blocked":true,"role":"viewer"}"#,
+        ];
+        let uncached = state(0).await;
+        let cached = state(16).await;
+        for request in requests {
+            let expected = uncached.decide(None, request).await.allowed;
+            let miss = cached.decide(None, request).await;
+            assert_eq!(
+                miss.allowed,
+                expected,
+                "{}",
+                String::from_utf8_lossy(request)
+            );
+        }
+
+        // Each formatting is decided and cached on its own
+        let stats = cached.policies().decision_cache().stats();
+        assert_eq!((stats.misses, stats.entries), (2, 2));
+        for request in requests {
+            let expected = uncached.decide(None, request).await.allowed;
+            assert_eq!(cached.decide(None, request).await.allowed, expected);
+        }
+        assert_eq!(cached.policies().decision_cache().stats().hits, 2);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn default_policy_filters_documents_by_role() {
+        let state = state(0).await;
+        let document = br#"{"serial":"A-1","gps":[48.1,11.5],"meta":{"lat":1,"serial_number":"B-2"},"temp":21.4}"#;
+        let transform =
+            |request: serde_json::Value| state.transform(None, request, document.to_vec());
+
+        let partner = transform(serde_json::json!({ "role": "partner" }))
+            .await
+            .unwrap();
+        assert_eq!(
+            partner.as_deref(),
+            Some(&br#"{"serial":"***","meta":{"serial_number":"***"},"temp":21.4}"#[..])
+        );
+        let viewer = transform(serde_json::json!({ "role": "viewer" }))
+            .await
+            .unwrap();
+        assert_eq!(
+            viewer.as_deref(),
+            Some(&br#"{"serial":"***","gps":[48.1,11.5],"meta":{"lat":1,"serial_number":"***"},"temp":21.4}"#[..])
+        );
+        let admin = transform(serde_json::json!({ "role": "admin" }))
+            .await
+            .unwrap();
+        assert_eq!(admin.as_deref(), Some(&document[..]));
+
+        // Only the request's own `role` field makes an admin
+        let impostor = serde_json::json!({ "role": "viewer", "note": "\"role\":\"admin\"", "user": { "role": "admin" } });
+        let impostor = transform(impostor).await.unwrap().unwrap();
+        assert!(impostor.starts_with(br#"{"serial":"***""#));
+
+        let malformed = state
+            .transform(None, serde_json::json!({}), b"{\"serial\":".to_vec())
+            .await;
+        assert!(malformed.is_err());
+    }
+}
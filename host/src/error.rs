@@ -19,7 +19,6 @@ pub enum ConnectorError {
     #[error("Function not found: {0}")]
     FunctionNotFound(String),
 
-    #[allow(dead_code)]
     #[error("Function signature mismatch for '{function}': expected {expected}, got {actual}")]
     SignatureMismatch {
         function: String,
@@ -27,6 +26,27 @@ pub enum ConnectorError {
         actual: String,
     },
 
+    #[error("Policy self-test failed: {}", failures.join("; "))]
+    SelfTestFailed { failures: Vec<String> },
+
+    #[error("Registry error: {0}")]
+    RegistryError(String),
+
+    #[error("Invalid configuration: {0}")]
+    ConfigError(String),
+
+    #[error("Policy not found: {0}")]
+    PolicyNotFound(String),
+
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("Policy evaluation timed out after {timeout_ms}ms")]
+    EvaluationTimeout { timeout_ms: u64 },
+
+    #[error("Engine overloaded: {0}")]
+    Overloaded(String),
+
     #[error(transparent)]
     IoError(#[from] std::io::Error),
 
@@ -34,4 +54,27 @@ pub enum ConnectorError {
     WasmtimeError(#[from] wasmtime::Error),
 }
 
+impl ConnectorError {
+    /// Stable machine-readable code, used in problem+json bodies
+    pub fn code(&self) -> &'static str {
+        match self {
+            ConnectorError::WasmLoadError(_) => "wasm_load_failed",
+            ConnectorError::PolicyExecutionError(_) => "policy_execution_failed",
+            ConnectorError::FuelExhausted { .. } => "fuel_exhausted",
+            ConnectorError::MemoryOutOfBounds { .. } => "memory_out_of_bounds",
+            ConnectorError::FunctionNotFound(_) => "function_not_found",
+            ConnectorError::SignatureMismatch { .. } => "signature_mismatch",
+            ConnectorError::SelfTestFailed { .. } => "self_test_failed",
+            ConnectorError::RegistryError(_) => "registry_error",
+            ConnectorError::ConfigError(_) => "config_error",
+            ConnectorError::PolicyNotFound(_) => "policy_not_found",
+            ConnectorError::InvalidRequest(_) => "invalid_request",
+            ConnectorError::EvaluationTimeout { .. } => "evaluation_timeout",
+            ConnectorError::Overloaded(_) => "overloaded",
+            ConnectorError::IoError(_) => "io_error",
+            ConnectorError::WasmtimeError(_) => "wasmtime_error",
+        }
+    }
+}
+
 pub type ConnectorResult<T> = Result<T, ConnectorError>;
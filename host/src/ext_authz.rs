@@ -0,0 +1,517 @@
+//! Envoy external authorization
+//!
+//! Serves `envoy.service.auth.v3.Authorization/Check` over gRPC and the
+//! HTTP ext_authz mode, so the engine can run as a standard sidecar
+//! authorizer. The checked request is turned into a `PolicyRequest` by the
+//! configured [`MappingConfig`](crate::mapping::MappingConfig) and the
+//! decision is returned as `x-nano-wasm-*` headers (see
+//! [`Decision::headers`]).
+//!
+//! The protobuf types are the subset of the Envoy API the check needs; field
+//! tags match the upstream definitions and other fields are skipped on decode.
+
+use crate::audit::Caller;
+use crate::decision::Decision;
+use crate::error::{ConnectorError, ConnectorResult};
+use crate::failure::FailureMode;
+use crate::mapping::normalize_path;
+use crate::problem::Problem;
+use crate::server::{decision_headers, failure_problem};
+use crate::AppState;
+use axum::{
+    extract::State,
+    http::{HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Json,
+};
+use shared::{PolicyRequest, PolicyResponse};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tonic::codegen::{http, Body, BoxFuture, Service, StdError};
+use tonic::server::NamedService;
+use tracing::Instrument;
+
+/// gRPC status codes used in `CheckResponse.status`
+const GRPC_OK: i32 = 0;
+const GRPC_PERMISSION_DENIED: i32 = 7;
+const GRPC_UNAVAILABLE: i32 = 14;
+
+/// `HeaderValueOption.append_action`: replace headers the client sent
+const OVERWRITE_IF_EXISTS_OR_ADD: i32 = 2;
+
+/// Context extension naming the policy to evaluate
+const POLICY_EXTENSION: &str = "policy";
+
+/// `envoy.service.auth.v3.CheckRequest`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CheckRequest {
+    #[prost(message, optional, tag = "1")]
+    pub attributes: Option<AttributeContext>,
+}
+
+/// `envoy.service.auth.v3.AttributeContext`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AttributeContext {
+    #[prost(message, optional, tag = "4")]
+    pub request: Option<attribute_context::Request>,
+    #[prost(map = "string, string", tag = "10")]
+    pub context_extensions: HashMap<String, String>,
+}
+
+pub mod attribute_context {
+    use std::collections::HashMap;
+
+    /// `envoy.service.auth.v3.AttributeContext.Request`
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Request {
+        #[prost(message, optional, tag = "2")]
+        pub http: Option<HttpRequest>,
+    }
+
+    /// `envoy.service.auth.v3.AttributeContext.HttpRequest`
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct HttpRequest {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(string, tag = "2")]
+        pub method: String,
+        /// Keys are lowercase
+        #[prost(map = "string, string", tag = "3")]
+        pub headers: HashMap<String, String>,
+        #[prost(string, tag = "4")]
+        pub path: String,
+        #[prost(string, tag = "5")]
+        pub host: String,
+    }
+}
+
+/// `envoy.service.auth.v3.CheckResponse`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CheckResponse {
+    #[prost(message, optional, tag = "1")]
+    pub status: Option<RpcStatus>,
+    #[prost(oneof = "check_response::HttpResponse", tags = "2, 3")]
+    pub http_response: Option<check_response::HttpResponse>,
+}
+
+pub mod check_response {
+    /// `CheckResponse.http_response`
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum HttpResponse {
+        #[prost(message, tag = "2")]
+        DeniedResponse(super::DeniedHttpResponse),
+        #[prost(message, tag = "3")]
+        OkResponse(super::OkHttpResponse),
+    }
+}
+
+/// `google.rpc.Status`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RpcStatus {
+    #[prost(int32, tag = "1")]
+    pub code: i32,
+    #[prost(string, tag = "2")]
+    pub message: String,
+}
+
+/// `envoy.service.auth.v3.DeniedHttpResponse`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DeniedHttpResponse {
+    #[prost(message, optional, tag = "1")]
+    pub status: Option<HttpStatus>,
+    #[prost(message, repeated, tag = "2")]
+    pub headers: Vec<HeaderValueOption>,
+    #[prost(string, tag = "3")]
+    pub body: String,
+}
+
+/// `envoy.service.auth.v3.OkHttpResponse`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OkHttpResponse {
+    /// Added to the request sent upstream
+    #[prost(message, repeated, tag = "2")]
+    pub headers: Vec<HeaderValueOption>,
+}
+
+/// `envoy.type.v3.HttpStatus`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HttpStatus {
+    #[prost(int32, tag = "1")]
+    pub code: i32,
+}
+
+/// `envoy.config.core.v3.HeaderValueOption`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HeaderValueOption {
+    #[prost(message, optional, tag = "1")]
+    pub header: Option<HeaderValue>,
+    #[prost(int32, tag = "3")]
+    pub append_action: i32,
+}
+
+/// `envoy.config.core.v3.HeaderValue`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HeaderValue {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+/// Policy for a check: the `policy` context extension, then
+/// `ext_authz.policy`, then the default policy
+fn check_policy<'a>(state: &'a AppState, extensions: &'a HashMap<String, String>) -> Option<&'a str> {
+    extensions
+        .get(POLICY_EXTENSION)
+        .map(String::as_str)
+        .or(state.ext_authz_policy.as_deref())
+}
+
+async fn decide(state: &AppState, policy: Option<&str>, request: &PolicyRequest) -> Decision {
+    let body = serde_json::to_vec(request).unwrap_or_default();
+    state.decide(policy, &body).await
+}
+
+/// Answer one gRPC `Check`
+pub async fn check(state: &AppState, request: CheckRequest) -> CheckResponse {
+    let attributes = request.attributes.unwrap_or_default();
+    let http = attributes
+        .request
+        .and_then(|r| r.http)
+        .unwrap_or_default();
+    let path = match normalize_path(&http.path) {
+        Ok(path) => path,
+        Err(e) => return invalid_path(&e),
+    };
+    let policy_request = state
+        .mapping
+        .policy_request(&http.method, &path, |name| http.headers.get(name).cloned());
+
+    let policy = check_policy(state, &attributes.context_extensions);
+    let decision = decide(state, policy, &policy_request).await;
+    let headers: Vec<HeaderValueOption> = decision
+        .headers()
+        .into_iter()
+        .map(|(key, value)| HeaderValueOption {
+            header: Some(HeaderValue {
+                key: key.to_string(),
+                value,
+            }),
+            append_action: OVERWRITE_IF_EXISTS_OR_ADD,
+        })
+        .collect();
+
+    if decision.allowed {
+        return CheckResponse {
+            status: Some(RpcStatus {
+                code: GRPC_OK,
+                message: String::new(),
+            }),
+            http_response: Some(check_response::HttpResponse::OkResponse(OkHttpResponse {
+                headers,
+            })),
+        };
+    }
+
+    let (code, status, body) = match decision.failure {
+        None => {
+            let body = PolicyResponse {
+                allowed: false,
+                policy_version: decision.policy_version,
+                error: None,
+                failure_mode: None,
+                logs: None,
+            };
+            (
+                GRPC_PERMISSION_DENIED,
+                StatusCode::FORBIDDEN,
+                serde_json::to_string(&body).unwrap_or_default(),
+            )
+        }
+        Some(failure) => {
+            let body = PolicyResponse {
+                allowed: false,
+                policy_version: decision.policy_version.clone(),
+                error: Some(failure.error.to_string()),
+                failure_mode: Some(FailureMode::Closed),
+                logs: None,
+            };
+            let problem =
+                failure_problem(state, &decision.policy, decision.policy_version, failure);
+            (
+                GRPC_UNAVAILABLE,
+                problem.status(),
+                serde_json::to_string(&body).unwrap_or_default(),
+            )
+        }
+    };
+
+    CheckResponse {
+        status: Some(RpcStatus {
+            code,
+            message: String::new(),
+        }),
+        http_response: Some(check_response::HttpResponse::DeniedResponse(
+            DeniedHttpResponse {
+                status: Some(HttpStatus {
+                    code: status.as_u16() as i32,
+                }),
+                headers,
+                body,
+            },
+        )),
+    }
+}
+
+/// `envoy.service.auth.v3.Authorization` gRPC service
+#[derive(Clone)]
+pub struct AuthorizationServer {
+    state: Arc<AppState>,
+}
+
+impl AuthorizationServer {
+    pub fn new(state: Arc<AppState>) -> Self {
+        Self { state }
+    }
+}
+
+impl NamedService for AuthorizationServer {
+    const NAME: &'static str = "envoy.service.auth.v3.Authorization";
+}
+
+impl<B> Service<http::Request<B>> for AuthorizationServer
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        if req.uri().path() != "/envoy.service.auth.v3.Authorization/Check" {
+            let status = tonic::Status::unimplemented(req.uri().path().to_string());
+            return Box::pin(async move { Ok(status.into_http()) });
+        }
+
+        let state = self.state.clone();
+        Box::pin(async move {
+            let mut grpc = tonic::server::Grpc::new(tonic::codec::ProstCodec::default());
+            Ok(grpc.unary(CheckService(state), req).await)
+        })
+    }
+}
+
+struct CheckService(Arc<AppState>);
+
+impl tonic::server::UnaryService<CheckRequest> for CheckService {
+    type Response = CheckResponse;
+    type Future = BoxFuture<tonic::Response<CheckResponse>, tonic::Status>;
+
+    fn call(&mut self, request: tonic::Request<CheckRequest>) -> Self::Future {
+        let state = self.0.clone();
+        let http = request
+            .get_ref()
+            .attributes
+            .as_ref()
+            .and_then(|attributes| attributes.request.as_ref())
+            .and_then(|request| request.http.as_ref());
+        let span = tracing::info_span!(
+            "request",
+            // Envoy's `x-request-id`
+            request_id = http.map_or("", |http| http.id.as_str()),
+            rpc.service = <AuthorizationServer as NamedService>::NAME,
+            rpc.method = "Check",
+        );
+        let header = state
+            .audit
+            .client_header()
+            .and_then(|name| http?.headers.get(&name.to_ascii_lowercase()).cloned());
+        let caller = Caller {
+            client: header.or_else(|| request.remote_addr().map(|addr| addr.ip().to_string())),
+            ..Caller::default()
+        };
+        Box::pin(
+            async move {
+                let response = caller.scope(check(&state, request.into_inner())).await;
+                Ok(tonic::Response::new(response))
+            }
+            .instrument(span),
+        )
+    }
+}
+
+/// Denial of a request whose path cannot be normalized
+fn invalid_path(error: &ConnectorError) -> CheckResponse {
+    let problem = Problem::from_error(error);
+    CheckResponse {
+        status: Some(RpcStatus {
+            code: GRPC_PERMISSION_DENIED,
+            message: error.to_string(),
+        }),
+        http_response: Some(check_response::HttpResponse::DeniedResponse(
+            DeniedHttpResponse {
+                status: Some(HttpStatus {
+                    code: problem.status().as_u16() as i32,
+                }),
+                headers: Vec::new(),
+                body: problem.detail().to_string(),
+            },
+        )),
+    }
+}
+
+/// Serve the gRPC authorization service on `addr` until the process exits
+pub async fn serve_grpc(state: Arc<AppState>, addr: SocketAddr) -> ConnectorResult<()> {
+    tonic::transport::Server::builder()
+        .add_service(AuthorizationServer::new(state))
+        .serve(addr)
+        .await
+        .map_err(|e| ConnectorError::ConfigError(format!("ext_authz gRPC server: {}", e)))
+}
+
+/// HTTP ext_authz mode
+///
+/// Envoy sends the original method, path (after `path_prefix`) and allowed
+/// headers. Any 2xx allows the request; anything else is returned to the
+/// client as the denial.
+pub(crate) async fn http_check(
+    State(state): State<Arc<AppState>>,
+    method: Method,
+    uri: Uri,
+    request_headers: HeaderMap,
+) -> Response {
+    let path = uri.path().strip_prefix("/ext_authz").unwrap_or(uri.path());
+    let path = match normalize_path(path) {
+        Ok(path) => path,
+        Err(e) => return Problem::from_error(&e).into_response(),
+    };
+    let policy_request = state.mapping.policy_request(method.as_str(), &path, |name| {
+        request_headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    });
+
+    let policy = state.ext_authz_policy.clone();
+    let decision = decide(&state, policy.as_deref(), &policy_request).await;
+    let headers = decision_headers(&decision);
+
+    if decision.allowed {
+        return (StatusCode::OK, headers).into_response();
+    }
+    match decision.failure {
+        None => {
+            let body = PolicyResponse {
+                allowed: false,
+                policy_version: decision.policy_version,
+                error: None,
+                failure_mode: None,
+                logs: None,
+            };
+            (StatusCode::FORBIDDEN, headers, Json(body)).into_response()
+        }
+        Some(failure) => {
+            let problem =
+                failure_problem(&state, &decision.policy, decision.policy_version, failure);
+            (headers, problem).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy_store::PolicyStore;
+    use crate::test_support::{activate_default, temp_dir};
+    use tonic::transport::server::TcpIncoming;
+    use tonic::transport::{Channel, Server};
+
+    /// A `Check` for `method` on `path` from a client with role `role`
+    fn check_request(method: &str, path: &str, role: &str) -> CheckRequest {
+        let http = attribute_context::HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: HashMap::from([("x-role".to_string(), role.to_string())]),
+            ..Default::default()
+        };
+        CheckRequest {
+            attributes: Some(AttributeContext {
+                request: Some(attribute_context::Request { http: Some(http) }),
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn header<'a>(headers: &'a [HeaderValueOption], key: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .filter_map(|option| option.header.as_ref())
+            .find(|header| header.key == key)
+            .map(|header| header.value.as_str())
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn grpc_check_allows_and_denies() {
+        let policies = PolicyStore::new(temp_dir("ext-authz-grpc"));
+        activate_default(&policies, "default").await;
+        let state = Arc::new(AppState::new(policies, "default"));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(AuthorizationServer::new(state))
+                .serve_with_incoming(incoming),
+        );
+
+        let channel = Channel::from_shared(format!("http://{}", addr))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let client = tonic::client::Grpc::new(channel);
+        let call = |request: CheckRequest| {
+            let path =
+                http::uri::PathAndQuery::from_static("/envoy.service.auth.v3.Authorization/Check");
+            let codec = tonic::codec::ProstCodec::<CheckRequest, CheckResponse>::default();
+            let mut client = client.clone();
+            async move {
+                client.ready().await.unwrap();
+                let response = client
+                    .unary(tonic::Request::new(request), path, codec)
+                    .await;
+                response.unwrap().into_inner()
+            }
+        };
+
+        // The default policy lets viewers read but not write
+        let allowed = call(check_request("GET", "/sensors/1", "viewer")).await;
+        assert_eq!(allowed.status.unwrap().code, GRPC_OK);
+        let Some(check_response::HttpResponse::OkResponse(ok)) = allowed.http_response else {
+            panic!("expected an OkHttpResponse");
+        };
+        assert_eq!(header(&ok.headers, "x-nano-wasm-decision"), Some("allow"));
+        assert_eq!(header(&ok.headers, "x-nano-wasm-policy"), Some("default"));
+
+        let denied = call(check_request("POST", "/sensors/1", "viewer")).await;
+        assert_eq!(denied.status.unwrap().code, GRPC_PERMISSION_DENIED);
+        let Some(check_response::HttpResponse::DeniedResponse(denial)) = denied.http_response
+        else {
+            panic!("expected a DeniedHttpResponse");
+        };
+        assert_eq!(denial.status.unwrap().code, 403);
+        assert_eq!(
+            header(&denial.headers, "x-nano-wasm-decision"),
+            Some("deny")
+        );
+        let body: PolicyResponse = serde_json::from_str(&denial.body).unwrap();
+        assert!(!body.allowed);
+    }
+}
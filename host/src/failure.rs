@@ -0,0 +1,189 @@
+//! Fail-open / fail-closed rules
+//!
+//! When a policy cannot produce a decision the outcome is taken from the
+//! configured [`FailureMode`] for the policy and the class of error, most
+//! specific setting first: policy + class, policy default, class, default.
+//! Errors outside these classes (invalid requests, oversized bodies) always
+//! fail closed.
+
+use crate::error::ConnectorError;
+use serde::{Deserialize, Serialize};
+pub use shared::FailureMode;
+use std::collections::BTreeMap;
+
+/// Evaluation errors whose outcome is configurable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    FuelExhausted,
+    Trap,
+    Timeout,
+    MissingPolicy,
+}
+
+impl ErrorClass {
+    pub fn of(error: &ConnectorError) -> Option<Self> {
+        match error {
+            ConnectorError::FuelExhausted { .. } => Some(ErrorClass::FuelExhausted),
+            ConnectorError::PolicyExecutionError(_)
+            | ConnectorError::FunctionNotFound(_)
+            | ConnectorError::WasmtimeError(_) => Some(ErrorClass::Trap),
+            ConnectorError::EvaluationTimeout { .. } => Some(ErrorClass::Timeout),
+            ConnectorError::PolicyNotFound(_) => Some(ErrorClass::MissingPolicy),
+            _ => None,
+        }
+    }
+}
+
+/// Failure modes for one scope; unset entries fall through
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FailureRules {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<FailureMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fuel_exhausted: Option<FailureMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trap: Option<FailureMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<FailureMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub missing_policy: Option<FailureMode>,
+}
+
+impl FailureRules {
+    fn for_class(&self, class: ErrorClass) -> Option<FailureMode> {
+        match class {
+            ErrorClass::FuelExhausted => self.fuel_exhausted,
+            ErrorClass::Trap => self.trap,
+            ErrorClass::Timeout => self.timeout,
+            ErrorClass::MissingPolicy => self.missing_policy,
+        }
+    }
+}
+
+/// The `[failure]` config section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FailureConfig {
+    pub default: FailureMode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fuel_exhausted: Option<FailureMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trap: Option<FailureMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<FailureMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub missing_policy: Option<FailureMode>,
+    /// Overrides keyed by policy name
+    pub policies: BTreeMap<String, FailureRules>,
+}
+
+impl Default for FailureConfig {
+    fn default() -> Self {
+        Self {
+            default: FailureMode::Closed,
+            fuel_exhausted: None,
+            trap: None,
+            timeout: None,
+            missing_policy: None,
+            policies: BTreeMap::new(),
+        }
+    }
+}
+
+impl FailureConfig {
+    /// Mode for an evaluation of `policy` that failed with `error`
+    pub fn mode_for(&self, policy: &str, error: &ConnectorError) -> FailureMode {
+        let Some(class) = ErrorClass::of(error) else {
+            return FailureMode::Closed;
+        };
+        let rules = self.policies.get(policy);
+
+        rules
+            .and_then(|r| r.for_class(class))
+            .or_else(|| rules.and_then(|r| r.default))
+            .or(match class {
+                ErrorClass::FuelExhausted => self.fuel_exhausted,
+                ErrorClass::Trap => self.trap,
+                ErrorClass::Timeout => self.timeout,
+                ErrorClass::MissingPolicy => self.missing_policy,
+            })
+            .unwrap_or(self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy_store::PolicyStore;
+    use crate::test_support::{activate_default, temp_dir};
+    use crate::AppState;
+    use FailureMode::{Closed, Open};
+
+    fn config() -> FailureConfig {
+        toml::from_str(
+            r#"
+            default = "open"
+            fuel_exhausted = "closed"
+
+            [policies.sensors]
+            default = "closed"
+            trap = "open"
+
+            [policies.cameras]
+            timeout = "closed"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn most_specific_rule_wins() {
+        let config = config();
+        let fuel = ConnectorError::FuelExhausted { consumed: 10 };
+        let trap = ConnectorError::PolicyExecutionError("unreachable".to_string());
+        let timeout = ConnectorError::EvaluationTimeout { timeout_ms: 10 };
+        let missing = ConnectorError::PolicyNotFound("x".to_string());
+
+        // Policy + class, then the policy's default
+        assert_eq!(config.mode_for("sensors", &trap), Open);
+        assert_eq!(config.mode_for("sensors", &timeout), Closed);
+        assert_eq!(config.mode_for("sensors", &fuel), Closed);
+        // Policy + class over the global class; otherwise the global class
+        assert_eq!(config.mode_for("cameras", &timeout), Closed);
+        assert_eq!(config.mode_for("cameras", &fuel), Closed);
+        // Then the global default
+        assert_eq!(config.mode_for("cameras", &trap), Open);
+        assert_eq!(config.mode_for("other", &missing), Open);
+        assert_eq!(config.mode_for("other", &fuel), Closed);
+
+        // Outside the configurable classes: always closed
+        let invalid = ConnectorError::InvalidRequest("not JSON".to_string());
+        assert_eq!(config.mode_for("other", &invalid), Closed);
+        assert_eq!(FailureConfig::default().mode_for("other", &trap), Closed);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn decisions_follow_the_failure_mode() {
+        let dir = temp_dir("failure-modes");
+        let policies = PolicyStore::new(dir.clone());
+        activate_default(&policies, "sensors").await;
+        let state = AppState::new(policies, "default").with_failure_config(config());
+
+        let decision = state.decide(Some("missing"), b"{}").await;
+        assert!(decision.allowed);
+        assert_eq!(
+            decision.failure.map(|f| (f.class, f.mode)),
+            Some((Some(ErrorClass::MissingPolicy), Open))
+        );
+
+        let decision = state.decide(Some("sensors"), b"not json").await;
+        assert!(!decision.allowed);
+        assert_eq!(
+            decision.failure.map(|f| (f.class, f.mode)),
+            Some((None, Closed))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
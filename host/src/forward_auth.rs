@@ -0,0 +1,173 @@
+//! NGINX `auth_request` and Traefik ForwardAuth
+//!
+//! `GET /auth` authorizes the request the proxy is about to forward. The
+//! original method and URI are read from the first configured header that
+//! is present (`X-Original-*` for nginx, `X-Forwarded-*` for Traefik), then
+//! mapped to a `PolicyRequest` like any other proxied request. The answer is
+//! 200 or 403 with the decision headers, which both proxies can copy onto the
+//! upstream request.
+//!
+//! The role is read from `mapping.role_header` as sent by the proxy, which
+//! passes client headers through; the proxy has to set or overwrite that
+//! header, or any client can claim a role.
+
+use crate::mapping::normalize_path;
+use crate::problem::Problem;
+use crate::server::{decision_headers, failure_problem};
+use crate::AppState;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// The `[forward_auth]` config section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardAuthConfig {
+    /// Headers carrying the original request URI, first present wins
+    pub uri_headers: Vec<String>,
+    /// Headers carrying the original request method, first present wins
+    pub method_headers: Vec<String>,
+    /// Policy for `/auth`; `None` uses the default policy
+    pub policy: Option<String>,
+}
+
+impl Default for ForwardAuthConfig {
+    fn default() -> Self {
+        Self {
+            uri_headers: vec!["x-original-uri".to_string(), "x-forwarded-uri".to_string()],
+            method_headers: vec![
+                "x-original-method".to_string(),
+                "x-forwarded-method".to_string(),
+            ],
+            policy: None,
+        }
+    }
+}
+
+fn first_header<'a>(headers: &'a HeaderMap, names: &[String]) -> Option<&'a str> {
+    names
+        .iter()
+        .find_map(|name| headers.get(name.as_str())?.to_str().ok())
+        .filter(|value| !value.is_empty())
+}
+
+/// Forward-auth endpoint
+pub(crate) async fn check(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    let config = &state.forward_auth;
+    // Without the original URI there is nothing to authorize
+    let Some(uri) = first_header(&headers, &config.uri_headers) else {
+        let detail = format!("None of the headers {:?} is set", config.uri_headers);
+        return Problem::new(StatusCode::FORBIDDEN, "invalid_request", detail)
+            .with("allowed", false)
+            .into_response();
+    };
+    let method = first_header(&headers, &config.method_headers).unwrap_or("GET");
+    let path = match normalize_path(uri) {
+        Ok(path) => path,
+        Err(e) => {
+            return Problem::from_error(&e)
+                .with_status(StatusCode::FORBIDDEN)
+                .with("allowed", false)
+                .into_response()
+        }
+    };
+
+    let policy_request = state.mapping.policy_request(method, &path, |name| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    });
+    let body = serde_json::to_vec(&policy_request).unwrap_or_default();
+    let decision = state.decide(config.policy.as_deref(), &body).await;
+    let response_headers = decision_headers(&decision);
+
+    if decision.allowed {
+        return (StatusCode::OK, response_headers).into_response();
+    }
+    match decision.failure {
+        None => (StatusCode::FORBIDDEN, response_headers).into_response(),
+        // nginx turns anything but 401/403 into a 500 for the client
+        Some(failure) => {
+            let problem =
+                failure_problem(&state, &decision.policy, decision.policy_version, failure)
+                    .with_status(StatusCode::FORBIDDEN);
+            (response_headers, problem).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_client;
+    use crate::policy_store::PolicyStore;
+    use crate::test_support::{activate_default, serve, temp_dir};
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn maps_decisions_to_status_codes() {
+        let policies = PolicyStore::new(temp_dir("forward-auth"));
+        activate_default(&policies, "default").await;
+        let state = AppState::new(policies, "default");
+        let (addr, _server) = serve(crate::server::router(Arc::new(state))).await;
+        let client = http_client::new_client();
+        let uri = format!("http://{}/auth", addr).parse().unwrap();
+        let auth = |headers: &'static [(&'static str, &'static str)]| {
+            let client = client.clone();
+            let uri = &uri;
+            async move { http_client::get(&client, uri, headers).await.unwrap() }
+        };
+
+        // nginx: the default policy lets viewers read
+        let allowed = auth(&[
+            ("x-original-uri", "/sensors/1"),
+            ("x-original-method", "GET"),
+            ("x-role", "viewer"),
+        ])
+        .await;
+        assert_eq!(allowed.status, StatusCode::OK);
+        assert_eq!(allowed.header("x-nano-wasm-decision"), Some("allow"));
+        assert_eq!(allowed.header("x-nano-wasm-policy"), Some("default"));
+
+        // Traefik: ...but not write
+        let denied = auth(&[
+            ("x-forwarded-uri", "/sensors/1"),
+            ("x-forwarded-method", "POST"),
+            ("x-role", "viewer"),
+        ])
+        .await;
+        assert_eq!(denied.status, StatusCode::FORBIDDEN);
+        assert_eq!(denied.header("x-nano-wasm-decision"), Some("deny"));
+
+        // Nothing to authorize, or a path that could be read two ways
+        let missing = auth(&[("x-role", "admin")]).await;
+        assert_eq!(missing.status, StatusCode::FORBIDDEN);
+        let ambiguous = auth(&[("x-original-uri", "/a%2Fb"), ("x-role", "admin")]).await;
+        assert_eq!(ambiguous.status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn failures_are_forbidden() {
+        // nginx would turn the 503 of a missing default policy into a 500
+        let policies = PolicyStore::new(temp_dir("forward-auth-failure"));
+        let state = AppState::new(policies, "default");
+        let (addr, _server) = serve(crate::server::router(Arc::new(state))).await;
+        let client = http_client::new_client();
+        let uri = format!("http://{}/auth", addr).parse().unwrap();
+
+        let headers = [("x-original-uri", "/sensors/1"), ("x-role", "admin")];
+        let response = http_client::get(&client, &uri, &headers).await.unwrap();
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+        assert_eq!(response.header("x-nano-wasm-decision"), Some("deny"));
+        assert_eq!(
+            response.header("x-nano-wasm-error"),
+            Some("policy_not_found")
+        );
+        let problem: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(problem["allowed"], false);
+    }
+}
@@ -0,0 +1,154 @@
+//! Minimal HTTP/1.1 client for talking to policy registries and the
+//! decision log collector
+//!
+//! `https://` needs the `tls` feature, which keeps rustls out of the default
+//! edge binary. Policies and credentials only travel over plain HTTP to a
+//! loopback address, such as a TLS-terminating sidecar; see
+//! [`check_transport`].
+
+use crate::error::{ConnectorError, ConnectorResult};
+use axum::body::Bytes;
+use axum::http::{HeaderMap, Method, Request, StatusCode, Uri};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use hyper_util::rt::TokioExecutor;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// Bound on a whole exchange, from connecting to the last body byte
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Upper bound on a downloaded body; policies are a few KB
+const MAX_RESPONSE_BYTES: usize = 8 * 1024 * 1024;
+
+#[cfg(feature = "tls")]
+type Connector = hyper_rustls::HttpsConnector<HttpConnector>;
+#[cfg(not(feature = "tls"))]
+type Connector = HttpConnector;
+
+pub type HttpClient = Client<Connector, Full<Bytes>>;
+
+/// A fully buffered HTTP response
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+}
+
+pub fn new_client() -> HttpClient {
+    #[cfg(feature = "tls")]
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    #[cfg(not(feature = "tls"))]
+    let connector = HttpConnector::new();
+    Client::builder(TokioExecutor::new()).build(connector)
+}
+
+/// Whether `uri` points at this machine, so plain HTTP never leaves it
+pub fn is_loopback(uri: &Uri) -> bool {
+    let Some(host) = uri.host() else {
+        return false;
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    host.eq_ignore_ascii_case("localhost")
+        || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Check that `uri` may carry policies or credentials: `https://`, or
+/// `http://` to a loopback address
+pub fn check_transport(uri: &Uri) -> Result<(), String> {
+    if uri.host().is_none() {
+        return Err("expected an absolute URL".to_string());
+    }
+    match uri.scheme_str() {
+        Some("https") if cfg!(feature = "tls") => Ok(()),
+        Some("https") => Err("https:// needs the `tls` feature".to_string()),
+        Some("http") if is_loopback(uri) => Ok(()),
+        _ => Err("expected an https:// URL, or http:// to a loopback address".to_string()),
+    }
+}
+
+/// Issue a GET request and buffer the response body
+pub async fn get(
+    client: &HttpClient,
+    uri: &Uri,
+    headers: &[(&str, &str)],
+) -> ConnectorResult<HttpResponse> {
+    send(client, Method::GET, uri, headers, Bytes::new()).await
+}
+
+/// Issue a request with the given method and body and buffer the response
+pub async fn send(
+    client: &HttpClient,
+    method: Method,
+    uri: &Uri,
+    headers: &[(&str, &str)],
+    body: Bytes,
+) -> ConnectorResult<HttpResponse> {
+    let mut builder = Request::builder().method(method).uri(uri.clone());
+    for (name, value) in headers {
+        builder = builder.header(*name, *value);
+    }
+    let request = builder
+        .body(Full::new(body))
+        .map_err(|e| ConnectorError::RegistryError(format!("Invalid request: {}", e)))?;
+
+    let exchange = async {
+        let response = client.request(request).await.map_err(|e| {
+            ConnectorError::RegistryError(format!("Request to {} failed: {}", uri, e))
+        })?;
+        let (parts, body) = response.into_parts();
+        let body = Limited::new(body, MAX_RESPONSE_BYTES)
+            .collect()
+            .await
+            .map_err(|e| {
+                ConnectorError::RegistryError(format!("Failed to read response from {}: {}", uri, e))
+            })?
+            .to_bytes();
+        Ok(HttpResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body,
+        })
+    };
+    // A server that stalls mid-body must not hold the caller forever
+    tokio::time::timeout(REQUEST_TIMEOUT, exchange)
+        .await
+        .map_err(|_| ConnectorError::RegistryError(format!("Request to {} timed out", uri)))?
+}
+
+/// Resolve `reference` relative to `base`, like a browser would for links
+pub fn resolve_uri(base: &Uri, reference: &str) -> ConnectorResult<Uri> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        ConnectorError::RegistryError(format!("Invalid URL '{}': {}", reference, e))
+    };
+
+    if reference.starts_with("http://") || reference.starts_with("https://") {
+        return reference.parse().map_err(|e| invalid(&e));
+    }
+
+    let scheme = base.scheme_str().unwrap_or("http");
+    let authority = base
+        .authority()
+        .ok_or_else(|| invalid(&"base URL has no host"))?;
+    let path = if reference.starts_with('/') {
+        reference.to_string()
+    } else {
+        let base_path = base.path();
+        let dir = &base_path[..base_path.rfind('/').map_or(0, |i| i + 1)];
+        format!("{}{}", if dir.is_empty() { "/" } else { dir }, reference)
+    };
+
+    format!("{}://{}{}", scheme, authority, path)
+        .parse()
+        .map_err(|e| invalid(&e))
+}
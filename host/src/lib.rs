@@ -0,0 +1,331 @@
+//! Nano-Wasm Edge Connector
+//!
+//! A lightweight Rust-based Dataspace Connector using WebAssembly
+//! for dynamic policy enforcement on edge devices.
+//!
+//! The engine can be embedded in other services: build a [`PolicyStore`],
+//! evaluate requests through each policy's [`PolicyRuntime`], and optionally
+//! mount the HTTP API from [`router`] into an existing axum app.
+//!
+//! ```no_run
+//! use host::{AppState, PolicyStore, ReloadSource};
+//! use std::sync::Arc;
+//!
+//! # async fn embed() -> host::ConnectorResult<()> {
+//! let policies = PolicyStore::new("./policies");
+//! policies.load_from_disk("default", ReloadSource::Startup).await?;
+//!
+//! let policy = policies.get("default").await.expect("loaded above");
+//! let allowed = policy.runtime.evaluate_policy(br#"{"role": "admin"}"#)?;
+//!
+//! let state = Arc::new(AppState::new(policies, "default"));
+//! let app: axum::Router = axum::Router::new().nest("/policy", host::router(state));
+//! # Ok(())
+//! # }
+//! ```
+
+mod admin;
+pub mod admission;
+pub mod audit;
+pub mod cache;
+pub mod cli;
+pub mod config;
+pub mod decision;
+pub mod error;
+pub mod ext_authz;
+pub mod failure;
+pub mod forward_auth;
+mod http_client;
+pub mod mapping;
+pub mod metrics;
+pub mod oci;
+pub mod policy_runtime;
+pub mod policy_store;
+pub mod problem;
+pub mod proxy;
+pub mod replay;
+pub mod rollout;
+mod server;
+pub mod shipping;
+pub mod status;
+pub mod sync;
+pub mod telemetry;
+#[cfg(test)]
+mod test_support;
+pub mod watcher;
+
+pub use config::Config;
+pub use admission::Admission;
+pub use audit::{AuditConfig, AuditLog};
+pub use cache::{CacheConfig, DecisionCache};
+pub use decision::{BatchDecision, Decision};
+pub use error::{ConnectorError, ConnectorResult};
+pub use failure::{FailureConfig, FailureMode};
+pub use forward_auth::ForwardAuthConfig;
+pub use mapping::MappingConfig;
+pub use policy_runtime::{Evaluation, PolicyRuntime, Transformation};
+pub use policy_store::{LoadedPolicy, PolicyManifest, PolicyStore};
+pub use rollout::RolloutConfig;
+pub use server::router;
+pub use status::ReloadSource;
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Application state shared across handlers
+pub struct AppState {
+    policies: PolicyStore,
+    default_policy: String,
+    /// Bearer token for the admin API; `None` disables it
+    admin_token: Option<String>,
+    /// Fail-open / fail-closed rules for evaluation errors
+    failure: FailureConfig,
+    /// Wall-clock budget per evaluation
+    eval_timeout: Duration,
+    /// Bounds concurrent and queued evaluations
+    admission: Admission,
+    /// Largest accepted `/evaluate` body
+    max_body_bytes: usize,
+    /// Most requests in one `/evaluate/batch` call
+    max_batch_items: usize,
+    /// How proxied requests become policy requests
+    mapping: MappingConfig,
+    /// Policy for ext_authz checks; `None` uses the default policy
+    ext_authz_policy: Option<String>,
+    /// Header derivation for `/auth`
+    forward_auth: ForwardAuthConfig,
+    /// Decision counters and evaluation histograms
+    metrics: metrics::Metrics,
+    /// Hash-chained record of every decision
+    audit: audit::AuditLog,
+    /// Candidate policies shadowing or canarying active ones
+    rollout: rollout::Rollout,
+    /// Rate-limited writer for guest log lines
+    guest_logs: telemetry::GuestLogs,
+    /// Whether `/evaluate?debug=true` returns guest logs
+    allow_debug: bool,
+}
+
+impl AppState {
+    pub fn new(policies: PolicyStore, default_policy: impl Into<String>) -> Self {
+        Self {
+            policies,
+            default_policy: default_policy.into(),
+            admin_token: None,
+            failure: FailureConfig::default(),
+            eval_timeout: decision::DEFAULT_EVAL_TIMEOUT,
+            admission: Admission::default(),
+            max_body_bytes: server::DEFAULT_MAX_BODY_BYTES,
+            max_batch_items: server::DEFAULT_MAX_BATCH_ITEMS,
+            mapping: MappingConfig::default(),
+            ext_authz_policy: None,
+            forward_auth: ForwardAuthConfig::default(),
+            metrics: metrics::Metrics::default(),
+            audit: audit::AuditLog::default(),
+            rollout: rollout::Rollout::default(),
+            guest_logs: telemetry::GuestLogs::default(),
+            allow_debug: false,
+        }
+    }
+
+    /// Enable the admin API with the given bearer token
+    pub fn with_admin_token(mut self, token: Option<String>) -> Self {
+        self.admin_token = token;
+        self
+    }
+
+    pub fn with_failure_config(mut self, failure: FailureConfig) -> Self {
+        self.failure = failure;
+        self
+    }
+
+    pub fn with_eval_timeout(mut self, eval_timeout: Duration) -> Self {
+        self.eval_timeout = eval_timeout;
+        self
+    }
+
+    pub fn with_admission(mut self, admission: Admission) -> Self {
+        self.admission = admission;
+        self
+    }
+
+    pub fn with_max_body_bytes(mut self, max_body_bytes: usize) -> Self {
+        self.max_body_bytes = max_body_bytes;
+        self
+    }
+
+    pub fn with_max_batch_items(mut self, max_batch_items: usize) -> Self {
+        self.max_batch_items = max_batch_items;
+        self
+    }
+
+    pub fn with_mapping(mut self, mapping: MappingConfig) -> Self {
+        self.mapping = mapping;
+        self
+    }
+
+    pub fn with_ext_authz_policy(mut self, policy: Option<String>) -> Self {
+        self.ext_authz_policy = policy;
+        self
+    }
+
+    pub fn with_forward_auth(mut self, forward_auth: ForwardAuthConfig) -> Self {
+        self.forward_auth = forward_auth;
+        self
+    }
+
+    pub fn with_audit_log(mut self, audit: audit::AuditLog) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Shadow or canary the policies in `config` with their candidates
+    pub fn with_rollout(mut self, config: RolloutConfig) -> Self {
+        self.rollout = rollout::Rollout::new(config);
+        self
+    }
+
+    /// Write at most `lines_per_sec` guest log lines per second
+    pub fn with_guest_log_rate(mut self, lines_per_sec: u32) -> Self {
+        self.guest_logs = telemetry::GuestLogs::new(lines_per_sec);
+        self
+    }
+
+    /// Let `/evaluate` callers ask for the guest logs of their decision
+    pub fn with_debug_responses(mut self, allow_debug: bool) -> Self {
+        self.allow_debug = allow_debug;
+        self
+    }
+
+    pub fn policies(&self) -> &PolicyStore {
+        &self.policies
+    }
+
+    pub fn audit_log(&self) -> &audit::AuditLog {
+        &self.audit
+    }
+
+    pub fn default_policy(&self) -> &str {
+        &self.default_policy
+    }
+
+    /// Build the state described by `config`: the default policy must load,
+    /// other modules in the policies directory are skipped if broken
+    pub async fn from_config(config: &Config) -> ConnectorResult<Self> {
+        let policies_dir = &config.policies.dir;
+        if !policies_dir.exists() {
+            std::fs::create_dir_all(policies_dir)?;
+            tracing::info!(dir = %policies_dir.display(), "Created policies directory");
+        }
+
+        let default_policy = config.policies.default_policy.clone();
+        let policies = PolicyStore::new(policies_dir)
+            .with_fuel_limit(config.policies.fuel_limit)
+            .with_transform_fuel_limit(config.policies.transform_fuel_limit)
+            .with_decision_cache(DecisionCache::new(config.cache.clone()));
+
+        let policy = policies
+            .load_from_disk(&default_policy, ReloadSource::Startup)
+            .await?;
+        tracing::info!(
+            path = %policies.module_path(&default_policy).display(),
+            size_bytes = policy.size_bytes,
+            "Loaded policy"
+        );
+
+        // Load any additional named policies next to the default one
+        for name in policies.scan_dir()? {
+            if name == default_policy {
+                continue;
+            }
+            match policies.load_from_disk(&name, ReloadSource::Startup).await {
+                Ok(policy) => tracing::info!(
+                    path = %policies.module_path(&name).display(),
+                    size_bytes = policy.size_bytes,
+                    "Loaded policy"
+                ),
+                Err(e) => tracing::warn!(policy = name.as_str(), error = %e, "Skipping policy"),
+            }
+        }
+
+        Ok(Self::new(policies, default_policy)
+            .with_admin_token(config.admin.token.clone())
+            .with_failure_config(config.failure.clone())
+            .with_eval_timeout(config.eval_timeout())
+            .with_admission(config.admission())
+            .with_max_body_bytes(config.limits.max_body_bytes)
+            .with_max_batch_items(config.limits.max_batch_items)
+            .with_mapping(config.mapping.clone())
+            .with_ext_authz_policy(config.ext_authz.policy.clone())
+            .with_forward_auth(config.forward_auth.clone())
+            .with_audit_log(open_audit_log(config)?)
+            .with_rollout(config.rollout.clone())
+            .with_guest_log_rate(config.logging.guest_lines_per_sec)
+            .with_debug_responses(config.logging.allow_debug))
+    }
+}
+
+/// The decision log, queueing entries for shipping if `shipping.url` is set
+fn open_audit_log(config: &Config) -> ConnectorResult<audit::AuditLog> {
+    let spool = match config.shipping.url {
+        Some(_) => Some(Arc::new(shipping::Spool::open(&config.shipping)?)),
+        None => None,
+    };
+    audit::AuditLog::open(config.audit.clone(), spool)
+}
+
+/// Start the file watcher plus any registry and OCI sync and decision log
+/// shipping configured
+pub fn spawn_background_tasks(state: &Arc<AppState>, config: &Config) -> ConnectorResult<()> {
+    // Setup hot-reload watcher
+    let state_clone = state.clone();
+    let policies_dir = config.policies.dir.clone();
+    let watch_options = config.watch_options();
+    tokio::spawn(async move {
+        watcher::watch_policies(state_clone, &policies_dir, watch_options).await;
+    });
+
+    // Setup remote registry sync
+    if let Some(url) = &config.registry.url {
+        let index_url: axum::http::Uri = url
+            .parse()
+            .map_err(|e| ConnectorError::ConfigError(format!("registry.url: {}", e)))?;
+        let state_clone = state.clone();
+        let interval = config.sync_interval();
+        tokio::spawn(async move {
+            sync::sync_policies(state_clone, index_url, interval).await;
+        });
+    }
+
+    let oci_sources = config.oci_sources()?;
+    if !oci_sources.is_empty() {
+        let cache = oci::BlobCache::new(oci::default_cache_dir(&config.policies.dir));
+        let state_clone = state.clone();
+        let token = config.oci.token.clone();
+        let interval = config.sync_interval();
+        tokio::spawn(async move {
+            oci::sync_oci_policies(state_clone, oci_sources, cache, token, interval).await;
+        });
+    }
+
+    if let (Some(url), Some(spool)) = (&config.shipping.url, state.audit.spool()) {
+        let url: axum::http::Uri = url
+            .parse()
+            .map_err(|e| ConnectorError::ConfigError(format!("shipping.url: {}", e)))?;
+        let spool = spool.clone();
+        let shipping = config.shipping.clone();
+        tokio::spawn(async move {
+            shipping::ship_batches(spool, url, shipping).await;
+        });
+    }
+
+    Ok(())
+}
+
+pub fn make_policy_version(bytes_len: usize) -> String {
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    format!("{}-{}b-{}", env!("CARGO_PKG_VERSION"), bytes_len, ts)
+}
@@ -5,233 +5,127 @@
 //!
 //! Target: <10MB RAM operation with single binary deployment.
 
-mod error;
-mod policy_runtime;
-mod watcher;
-
-use axum::{
-    body::Bytes,
-    extract::State,
-    routing::{get, post},
-    Json, Router,
-};
-use policy_runtime::PolicyRuntime;
-use serde_json::{json, Value};
-use shared::PolicyResponse;
-use std::path::PathBuf;
+use host::config::{CliArgs, Config};
+use host::telemetry::{LogFormat, Telemetry};
+use host::{AppState, ConnectorError};
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::sync::RwLock;
-
-/// Application state shared across handlers
-pub struct AppState {
-    runtime: RwLock<Arc<PolicyRuntime>>,
-    policy_version: RwLock<String>,
-}
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
-    println!("╔══════════════════════════════════════════════════╗");
-    println!("║     Nano-Wasm Edge Connector v{}          ║", env!("CARGO_PKG_VERSION"));
-    println!("║     Lightweight Policy Enforcement Engine        ║");
-    println!("╚══════════════════════════════════════════════════╝");
+    let mut args =
+        CliArgs::try_parse_with_env(std::env::args_os(), |name| std::env::var(name).ok())
+            .unwrap_or_else(|e| e.exit());
+    let config = Config::load(&args)?;
+    if let Some(command) = args.command.take() {
+        return host::cli::run(command, &config);
+    }
+    if args.print_config {
+        print!("{}", config.to_redacted_toml());
+        return Ok(());
+    }
 
-    // Ensure policies directory exists
-    let policies_dir = PathBuf::from("./policies");
-    if !policies_dir.exists() {
-        std::fs::create_dir_all(&policies_dir)?;
-        println!("Created policies directory: {}", policies_dir.display());
+    // JSON logs go to collectors, which only want the event lines
+    let text = config.logging.format == LogFormat::Text;
+    if text {
+        println!("╔══════════════════════════════════════════════════╗");
+        println!("║     Nano-Wasm Edge Connector v{}          ║", env!("CARGO_PKG_VERSION"));
+        println!("║     Lightweight Policy Enforcement Engine        ║");
+        println!("╚══════════════════════════════════════════════════╝");
     }
 
-    let policy_file = "default.wasm";
-    let policy_path = policies_dir.join(policy_file);
+    let telemetry = Telemetry::init(&config.logging, &config.tracing)?;
+    if let Some(endpoint) = &config.tracing.otlp_endpoint {
+        tracing::info!(endpoint = endpoint.as_str(), "Exporting spans");
+    }
 
-    // Load initial policy module
-    let wasm_bytes = match std::fs::read(&policy_path) {
-        Ok(bytes) => {
-            println!("✓ Loaded policy: {} ({} bytes)", policy_path.display(), bytes.len());
-            bytes
-        }
+    // Load the default policy and any other modules next to it
+    let state = match AppState::from_config(&config).await {
+        Ok(state) => state,
+        Err(e @ ConnectorError::ConfigError(_)) => return Err(e.into()),
         Err(e) => {
-            eprintln!("✗ Failed to load policy from {}: {}", policy_path.display(), e);
-            eprintln!("  Please build the guest module and copy to policies/default.wasm");
-            eprintln!("  Run: cargo build -p guest --target wasm32-unknown-unknown --release");
-            eprintln!("       cp target/wasm32-unknown-unknown/release/guest.wasm policies/default.wasm");
+            let policy_path = config
+                .policies
+                .dir
+                .join(format!("{}.wasm", config.policies.default_policy));
+            tracing::error!(path = %policy_path.display(), error = %e, "Failed to load policy");
+            if text {
+                eprintln!("  Please build the guest module and copy to {}", policy_path.display());
+                eprintln!("  Run: cargo build -p guest --target wasm32-unknown-unknown --release");
+                eprintln!("       cp target/wasm32-unknown-unknown/release/guest.wasm {}", policy_path.display());
+            }
             return Err(e.into());
         }
     };
+    tracing::info!("Policy runtime initialized");
+
+    if config.admin.token.is_none() {
+        tracing::info!("Admin API disabled (set admin.token or NANO_WASM_ADMIN_TOKEN to enable)");
+    }
 
-    let runtime = PolicyRuntime::new(&wasm_bytes)?;
-    println!("✓ Policy runtime initialized");
+    let state = Arc::new(state);
+    host::spawn_background_tasks(&state, &config)?;
 
-    let policy_version = make_policy_version(wasm_bytes.len());
-    let state = Arc::new(AppState {
-        runtime: RwLock::new(Arc::new(runtime)),
-        policy_version: RwLock::new(policy_version),
-    });
+    if let Some(addr) = config.ext_authz_grpc_addr() {
+        let state_clone = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = host::ext_authz::serve_grpc(state_clone, addr).await {
+                tracing::error!(error = %e, "ext_authz gRPC server failed");
+            }
+        });
+        tracing::info!(%addr, "Envoy ext_authz gRPC listening");
+    }
 
-    // Setup hot-reload watcher
-    let state_clone = state.clone();
-    let policies_dir_clone = policies_dir.clone();
-    tokio::spawn(async move {
-        watcher::watch_policies(state_clone, &policies_dir_clone, policy_file).await;
-    });
+    if let Some(addr) = config.proxy_addr() {
+        let proxy = host::proxy::router(state.clone(), &config.proxy)?;
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        tokio::spawn(async move {
+            let proxy = proxy.into_make_service_with_connect_info::<SocketAddr>();
+            if let Err(e) = axum::serve(listener, proxy).await {
+                tracing::error!(error = %e, "Proxy server failed");
+            }
+        });
+        tracing::info!(%addr, routes = config.proxy.routes.len(), "Enforcing proxy listening");
+    }
 
     // Build router
-    let app = Router::new()
-        .route("/health", get(health_check))
-        .route("/evaluate", post(evaluate_policy))
-        .route("/reload", post(reload_policy))
-        .route("/metrics", get(get_metrics))
-        .with_state(state);
+    let app = host::router(state.clone());
 
     // Bind listener
-    let addr = "0.0.0.0:3000";
+    let addr = config.listen_addr()?;
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    println!("✓ Listening on http://{}", addr);
-    println!("");
-    println!("Endpoints:");
-    println!("  GET  /health   - Health check");
-    println!("  POST /evaluate - Evaluate policy");
-    println!("  POST /reload   - Force policy reload");
-    println!("  GET  /metrics  - Runtime metrics");
-    println!("");
+    tracing::info!(%addr, "Listening");
+    if text {
+        println!();
+        println!("Endpoints:");
+        println!("  GET    /health          - Health check");
+        println!("  POST   /evaluate        - Evaluate policy");
+        println!("  POST   /evaluate/batch  - Evaluate a batch of requests");
+        println!("  POST   /transform       - Evaluate and filter a document");
+        println!("  GET    /auth            - nginx/Traefik forward-auth check");
+        println!("  ANY    /ext_authz/*     - Envoy HTTP ext_authz check");
+        println!("  POST   /reload          - Force policy reload");
+        println!("  GET    /metrics         - Prometheus metrics");
+        println!("  GET    /policies/status - Policy reload status");
+        println!("  GET    /events          - Reload event stream (SSE)");
+        println!("  GET    /policies        - List loaded policies (admin)");
+        println!("  PUT    /policies/:name  - Upload and activate a policy (admin)");
+        println!("  DELETE /policies/:name  - Remove a policy (admin)");
+        println!();
+    }
 
     // Start server with graceful shutdown
-    axum::serve(listener, app)
+    // Peer addresses identify clients in the audit log
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(shutdown_signal())
         .await?;
 
-    println!("Server shutdown complete");
+    state.audit_log().flush().await;
+    telemetry.shutdown().await;
+    tracing::info!("Server shutdown complete");
     Ok(())
 }
 
-/// Health check endpoint
-async fn health_check() -> &'static str {
-    "OK"
-}
-
-/// Policy evaluation endpoint
-async fn evaluate_policy(
-    State(state): State<Arc<AppState>>,
-    body: Bytes,
-) -> Json<PolicyResponse> {
-    let runtime = { state.runtime.read().await.clone() };
-    let policy_version = { state.policy_version.read().await.clone() };
-    if let Err(e) = serde_json::from_slice::<Value>(&body) {
-        return Json(PolicyResponse {
-            allowed: false,
-            policy_version,
-            error: Some(format!("Invalid JSON: {}", e)),
-        });
-    }
-
-    let request_bytes = body.to_vec();
-
-    let eval_result =
-        tokio::task::spawn_blocking(move || runtime.evaluate_policy(&request_bytes)).await;
-
-    match eval_result {
-        Ok(Ok(allowed)) => Json(PolicyResponse {
-            allowed,
-            policy_version,
-            error: None,
-        }),
-        Ok(Err(e)) => Json(PolicyResponse {
-            allowed: false,
-            policy_version,
-            error: Some(e.to_string()),
-        }),
-        Err(e) => Json(PolicyResponse {
-            allowed: false,
-            policy_version,
-            error: Some(format!("Policy execution join error: {}", e)),
-        }),
-    }
-}
-
-/// Force policy reload endpoint
-async fn reload_policy(State(state): State<Arc<AppState>>) -> Json<Value> {
-    let policy_path = PathBuf::from("./policies/default.wasm");
-
-    match std::fs::read(&policy_path) {
-        Ok(bytes) => match PolicyRuntime::new(&bytes) {
-            Ok(new_runtime) => {
-                let new_version = make_policy_version(bytes.len());
-                let mut runtime = state.runtime.write().await;
-                *runtime = Arc::new(new_runtime);
-                let mut version = state.policy_version.write().await;
-                *version = new_version.clone();
-                println!("✓ Policy manually reloaded");
-                Json(json!({
-                    "success": true,
-                    "message": "Policy reloaded successfully",
-                    "size_bytes": bytes.len(),
-                    "policy_version": new_version
-                }))
-            }
-            Err(e) => Json(json!({
-                "success": false,
-                "error": format!("Failed to compile policy: {}", e)
-            })),
-        },
-        Err(e) => Json(json!({
-            "success": false,
-            "error": format!("Failed to read policy file: {}", e)
-        })),
-    }
-}
-
-/// Runtime metrics endpoint
-async fn get_metrics() -> Json<Value> {
-    // Get process memory info (platform-specific)
-    let memory_kb = get_memory_usage_kb();
-
-    Json(json!({
-        "memory_kb": memory_kb,
-        "memory_mb": memory_kb as f64 / 1024.0,
-        "target_mb": 10,
-        "within_target": memory_kb < 10 * 1024
-    }))
-}
-
-/// Get current process memory usage in KB
-fn get_memory_usage_kb() -> u64 {
-    #[cfg(target_os = "macos")]
-    {
-        use std::process::Command;
-        let pid = std::process::id();
-        if let Ok(output) = Command::new("ps")
-            .args(["-o", "rss=", "-p", &pid.to_string()])
-            .output()
-        {
-            if let Ok(rss) = String::from_utf8_lossy(&output.stdout).trim().parse::<u64>() {
-                return rss;
-            }
-        }
-        0
-    }
-
-    #[cfg(target_os = "linux")]
-    {
-        if let Ok(status) = std::fs::read_to_string("/proc/self/status") {
-            for line in status.lines() {
-                if line.starts_with("VmRSS:") {
-                    if let Some(kb) = line.split_whitespace().nth(1) {
-                        return kb.parse().unwrap_or(0);
-                    }
-                }
-            }
-        }
-        0
-    }
-
-    #[cfg(not(any(target_os = "macos", target_os = "linux")))]
-    {
-        0
-    }
-}
-
 /// Wait for shutdown signal (SIGINT/SIGTERM)
 async fn shutdown_signal() {
     let ctrl_c = async {
@@ -252,15 +146,7 @@ async fn shutdown_signal() {
     let terminate = std::future::pending::<()>();
 
     tokio::select! {
-        _ = ctrl_c => println!("\nReceived CTRL+C, shutting down..."),
-        _ = terminate => println!("\nReceived SIGTERM, shutting down..."),
+        _ = ctrl_c => tracing::info!("Received CTRL+C, shutting down"),
+        _ = terminate => tracing::info!("Received SIGTERM, shutting down"),
     }
 }
-
-pub fn make_policy_version(bytes_len: usize) -> String {
-    let ts = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs();
-    format!("{}-{}b-{}", env!("CARGO_PKG_VERSION"), bytes_len, ts)
-}
@@ -0,0 +1,239 @@
+//! Request attribute mapping
+//!
+//! Proxy integrations authorize an HTTP request rather than a
+//! `PolicyRequest`. The role comes from a configured header, the resource
+//! from the first matching path prefix rule (or the path itself) and the
+//! action is derived from the method.
+//!
+//! Paths are normalized once with [`normalize_path`] before anything is
+//! matched against them, so `/public/../secret` and `/%73ecret` are decided
+//! as the `/secret` they will be served as.
+
+use crate::error::{ConnectorError, ConnectorResult};
+use serde::{Deserialize, Serialize};
+use shared::PolicyRequest;
+use std::collections::BTreeMap;
+
+/// The `[mapping]` config section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MappingConfig {
+    /// Header carrying the caller's role
+    pub role_header: String,
+    /// Policy action per HTTP method; other methods map to their lowercase name
+    pub actions: BTreeMap<String, String>,
+    /// Path prefix rules matched on segment boundaries, first match wins;
+    /// unmatched paths are the resource
+    pub resources: Vec<ResourceRule>,
+}
+
+/// Maps every path under `prefix` to one resource name
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResourceRule {
+    pub prefix: String,
+    pub resource: String,
+}
+
+impl Default for MappingConfig {
+    fn default() -> Self {
+        let actions = [
+            ("GET", "read"),
+            ("HEAD", "read"),
+            ("OPTIONS", "read"),
+            ("POST", "write"),
+            ("PUT", "write"),
+            ("PATCH", "write"),
+            ("DELETE", "delete"),
+        ];
+        Self {
+            role_header: "x-role".to_string(),
+            actions: actions
+                .into_iter()
+                .map(|(method, action)| (method.to_string(), action.to_string()))
+                .collect(),
+            resources: Vec::new(),
+        }
+    }
+}
+
+impl ResourceRule {
+    /// Whether `path` is the prefix or below it
+    fn matches(&self, path: &str) -> bool {
+        path.strip_prefix(self.prefix.trim_end_matches('/'))
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+}
+
+impl MappingConfig {
+    /// Build the policy request for `method` on `path`
+    ///
+    /// `path` must come from [`normalize_path`]. `header` looks up a request
+    /// header by lowercase name.
+    pub fn policy_request(
+        &self,
+        method: &str,
+        path: &str,
+        header: impl Fn(&str) -> Option<String>,
+    ) -> PolicyRequest {
+        let method = method.to_ascii_uppercase();
+        let action = self
+            .actions
+            .get(&method)
+            .cloned()
+            .unwrap_or_else(|| method.to_ascii_lowercase());
+        let resource = self
+            .resources
+            .iter()
+            .find(|rule| rule.matches(path))
+            .map_or(path, |rule| rule.resource.as_str());
+
+        PolicyRequest {
+            role: header(&self.role_header.to_ascii_lowercase()).filter(|r| !r.is_empty()),
+            resource: Some(resource.to_string()).filter(|r| !r.is_empty()),
+            action: Some(action),
+            blocked: false,
+        }
+    }
+}
+
+/// Percent-decode `path` and resolve its dot-segments
+///
+/// The query and fragment are dropped and empty segments collapse, so the
+/// result is the path an upstream serves. Paths that different servers
+/// could read differently are rejected: an encoded `/`, escapes that are
+/// not valid UTF-8, and `..` above the root.
+pub fn normalize_path(path: &str) -> ConnectorResult<String> {
+    let invalid = |msg: &str| ConnectorError::InvalidRequest(format!("path '{}': {}", path, msg));
+
+    let raw = path.split(['?', '#']).next().unwrap_or_default();
+    if raw.is_empty() {
+        return Ok("/".to_string());
+    }
+    let Some(raw) = raw.strip_prefix('/') else {
+        return Err(invalid("must start with '/'"));
+    };
+
+    let mut segments: Vec<String> = Vec::new();
+    let mut trailing_slash = false;
+    for segment in raw.split('/') {
+        let segment = percent_decode(segment).ok_or_else(|| invalid("invalid percent-encoding"))?;
+        if segment.contains('/') {
+            return Err(invalid("encoded '/'"));
+        }
+        trailing_slash = matches!(segment.as_str(), "" | "." | "..");
+        match segment.as_str() {
+            "" | "." => {}
+            ".." => {
+                segments.pop().ok_or_else(|| invalid("'..' above the root"))?;
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    let mut normalized = String::new();
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if trailing_slash || normalized.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// Percent-encode a normalized path for use in a URI
+pub fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => encoded.push(byte as char),
+            b'-' | b'.' | b'_' | b'~' | b'/' | b':' | b'@' => encoded.push(byte as char),
+            b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'=' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn percent_decode(segment: &str) -> Option<String> {
+    if !segment.contains('%') {
+        return Some(segment.to_string());
+    }
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_dot_segments_and_escapes() {
+        let cases = [
+            ("", "/"),
+            ("/", "/"),
+            ("/public/../secret/x", "/secret/x"),
+            ("/%73ecret/x", "/secret/x"),
+            ("/public/%2e%2e/secret", "/secret"),
+            ("//secret//x", "/secret/x"),
+            ("/a/./b/", "/a/b/"),
+            ("/a/b/..", "/a/"),
+            ("/a/..", "/"),
+            ("/x?path=/../y#top", "/x"),
+            ("/caf%C3%A9", "/café"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(normalize_path(path).unwrap(), expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_ambiguous_paths() {
+        let paths = ["/secret%2Fx", "/secret%2fx", "/..", "/a/../../b", "/%2e%2e/x"];
+        for path in paths.into_iter().chain(["/%zz", "/%C3", "/%", "relative"]) {
+            assert!(normalize_path(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn matches_resources_on_segment_boundaries() {
+        let mapping = MappingConfig {
+            resources: vec![ResourceRule {
+                prefix: "/secret/".to_string(),
+                resource: "secret".to_string(),
+            }],
+            ..MappingConfig::default()
+        };
+        let resource = |path: &str| {
+            let path = normalize_path(path).unwrap();
+            mapping.policy_request("GET", &path, |_| None).resource.unwrap()
+        };
+        assert_eq!(resource("/secret"), "secret");
+        assert_eq!(resource("/secret/x"), "secret");
+        assert_eq!(resource("/public/../secret/x"), "secret");
+        assert_eq!(resource("/%73ecret/x"), "secret");
+        assert_eq!(resource("/secretive"), "/secretive");
+    }
+
+    #[test]
+    fn encodes_for_the_upstream() {
+        assert_eq!(encode_path("/a b/%/café"), "/a%20b/%25/caf%C3%A9");
+        assert_eq!(encode_path("/items/a:b@c;d=e"), "/items/a:b@c;d=e");
+        let path = normalize_path("/a%3Fb").unwrap();
+        assert_eq!(encode_path(&path), "/a%3Fb");
+    }
+}
@@ -0,0 +1,533 @@
+//! Prometheus metrics
+//!
+//! Decisions, evaluation latency and fuel, and errors are recorded as they
+//! happen; reload counts, policy versions, load and memory are read from the
+//! rest of the state when `/metrics` is scraped. The text exposition format is
+//! written by hand to keep the binary small.
+//!
+//! Policy names come from requests, so per-policy series are kept only for
+//! loaded policies: decisions for a policy that is not loaded are counted
+//! under `policy="unknown"`, and the series of removed policies are dropped
+//! at the next scrape.
+
+use crate::decision::Decision;
+use crate::policy_runtime::Evaluation;
+use crate::AppState;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// `Content-Type` of the text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Policy label of decisions for policies that are not loaded
+pub const UNKNOWN_POLICY: &str = "unknown";
+
+/// Upper bounds of the evaluation latency buckets, in seconds
+const DURATION_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+/// Upper bounds of the fuel buckets, in units
+const FUEL_BUCKETS: &[f64] = &[1e3, 5e3, 1e4, 5e4, 1e5, 2.5e5, 5e5, 1e6, 2.5e6, 5e6, 1e7];
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// Per bucket, not cumulative
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name, labels, self.count
+        );
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+#[derive(Default)]
+struct Recorded {
+    /// Keyed by (policy, "allow" | "deny")
+    decisions: BTreeMap<(String, &'static str), u64>,
+    /// Keyed by (policy, error code)
+    errors: BTreeMap<(String, &'static str), u64>,
+    durations: BTreeMap<String, Histogram>,
+    fuel: BTreeMap<String, Histogram>,
+    /// Linear memory of each policy's most recent instance
+    instance_memory: BTreeMap<String, usize>,
+}
+
+impl Recorded {
+    /// Drop the series of policies `loaded` rejects
+    fn retain_loaded(&mut self, loaded: impl Fn(&str) -> bool) {
+        let keep = |policy: &String| policy == UNKNOWN_POLICY || loaded(policy);
+        self.decisions.retain(|(policy, _), _| keep(policy));
+        self.errors.retain(|(policy, _), _| keep(policy));
+        self.durations.retain(|policy, _| keep(policy));
+        self.fuel.retain(|policy, _| keep(policy));
+        self.instance_memory.retain(|policy, _| keep(policy));
+    }
+}
+
+/// Counters and histograms recorded on the decision path
+#[derive(Default)]
+pub struct Metrics {
+    recorded: Mutex<Recorded>,
+}
+
+impl Metrics {
+    /// Count a decision, and its error if the policy did not decide
+    pub fn record_decision(&self, decision: &Decision) {
+        let verdict = if decision.allowed { "allow" } else { "deny" };
+        let policy = if decision.policy_version.is_empty() {
+            UNKNOWN_POLICY
+        } else {
+            decision.policy.as_str()
+        };
+        let mut recorded = self.recorded.lock();
+        *recorded
+            .decisions
+            .entry((policy.to_string(), verdict))
+            .or_default() += 1;
+        if let Some(failure) = &decision.failure {
+            *recorded
+                .errors
+                .entry((policy.to_string(), failure.error.code()))
+                .or_default() += 1;
+        }
+    }
+
+    /// Record a guest evaluation that ran to completion; `policy` is the
+    /// loaded policy that ran it
+    pub fn record_evaluation(&self, policy: &str, evaluation: &Evaluation, elapsed: Duration) {
+        let mut recorded = self.recorded.lock();
+        recorded
+            .durations
+            .entry(policy.to_string())
+            .or_insert_with(|| Histogram::new(DURATION_BUCKETS))
+            .observe(elapsed.as_secs_f64());
+        recorded
+            .fuel
+            .entry(policy.to_string())
+            .or_insert_with(|| Histogram::new(FUEL_BUCKETS))
+            .observe(evaluation.fuel_consumed as f64);
+        recorded
+            .instance_memory
+            .insert(policy.to_string(), evaluation.memory_bytes);
+    }
+}
+
+/// Render every metric in the text exposition format
+pub async fn render(state: &AppState) -> String {
+    let mut out = String::new();
+    let policies = state.policies.list().await;
+
+    {
+        let mut recorded = state.metrics.recorded.lock();
+        recorded.retain_loaded(|name| policies.iter().any(|policy| policy.name == name));
+
+        header(
+            &mut out,
+            "nano_wasm_evaluations_total",
+            "counter",
+            "Decisions by policy and outcome",
+        );
+        for ((policy, decision), count) in &recorded.decisions {
+            let _ = writeln!(
+                out,
+                "nano_wasm_evaluations_total{{policy=\"{}\",decision=\"{}\"}} {}",
+                escape(policy),
+                decision,
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "nano_wasm_evaluation_errors_total",
+            "counter",
+            "Evaluations the policy did not decide, by error code",
+        );
+        for ((policy, error), count) in &recorded.errors {
+            let _ = writeln!(
+                out,
+                "nano_wasm_evaluation_errors_total{{policy=\"{}\",error=\"{}\"}} {}",
+                escape(policy),
+                error,
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "nano_wasm_evaluation_duration_seconds",
+            "histogram",
+            "Wall time of guest evaluations",
+        );
+        for (policy, histogram) in &recorded.durations {
+            let labels = format!("policy=\"{}\"", escape(policy));
+            histogram.write(&mut out, "nano_wasm_evaluation_duration_seconds", &labels);
+        }
+
+        header(
+            &mut out,
+            "nano_wasm_evaluation_fuel",
+            "histogram",
+            "Fuel consumed by guest evaluations",
+        );
+        for (policy, histogram) in &recorded.fuel {
+            let labels = format!("policy=\"{}\"", escape(policy));
+            histogram.write(&mut out, "nano_wasm_evaluation_fuel", &labels);
+        }
+
+        header(
+            &mut out,
+            "nano_wasm_instance_memory_bytes",
+            "gauge",
+            "Linear memory of the most recent instance of each policy",
+        );
+        for (policy, bytes) in &recorded.instance_memory {
+            let _ = writeln!(
+                out,
+                "nano_wasm_instance_memory_bytes{{policy=\"{}\"}} {}",
+                escape(policy),
+                bytes
+            );
+        }
+    }
+
+    let status = state.policies.status().snapshot();
+    header(
+        &mut out,
+        "nano_wasm_policy_reloads_total",
+        "counter",
+        "Policy load attempts by result",
+    );
+    for (policy, status) in &status {
+        for (result, count) in [
+            ("success", status.reloads),
+            ("failure", status.reload_failures),
+        ] {
+            let _ = writeln!(
+                out,
+                "nano_wasm_policy_reloads_total{{policy=\"{}\",result=\"{}\"}} {}",
+                escape(policy),
+                result,
+                count
+            );
+        }
+    }
+
+    header(
+        &mut out,
+        "nano_wasm_policy_info",
+        "gauge",
+        "Active version of each loaded policy",
+    );
+    for policy in &policies {
+        let _ = writeln!(
+            out,
+            "nano_wasm_policy_info{{policy=\"{}\",version=\"{}\",sha256=\"{}\"}} 1",
+            escape(&policy.name),
+            escape(&policy.version),
+            policy.sha256
+        );
+    }
+
+    let load = state.admission.stats();
+    gauge(
+        &mut out,
+        "nano_wasm_evaluations_in_flight",
+        "Evaluations running",
+        load.in_flight,
+    );
+    gauge(
+        &mut out,
+        "nano_wasm_evaluations_queued",
+        "Evaluations waiting for a slot",
+        load.queued,
+    );
+
+    let cache = state.policies.decision_cache().stats();
+    for (name, help, value) in [
+        (
+            "nano_wasm_decision_cache_hits_total",
+            "Decisions served from the cache",
+            cache.hits,
+        ),
+        (
+            "nano_wasm_decision_cache_misses_total",
+            "Cache lookups that evaluated the policy",
+            cache.misses,
+        ),
+        (
+            "nano_wasm_decision_cache_bypassed_total",
+            "Evaluations of uncacheable policies",
+            cache.bypassed,
+        ),
+    ] {
+        header(&mut out, name, "counter", help);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+    gauge(
+        &mut out,
+        "nano_wasm_decision_cache_entries",
+        "Decisions in the cache",
+        cache.entries,
+    );
+
+    for (name, help, value) in [
+        (
+            "nano_wasm_audit_entries_total",
+            "Decisions written to the decision log",
+            state.audit.written(),
+        ),
+        (
+            "nano_wasm_audit_write_failures_total",
+            "Decisions that could not be written to the decision log",
+            state.audit.failures(),
+        ),
+    ] {
+        header(&mut out, name, "counter", help);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+
+    header(
+        &mut out,
+        "nano_wasm_shadow_evaluations_total",
+        "counter",
+        "Candidate evaluations compared with the policy's decision",
+    );
+    for (policy, candidate, result, count) in state.rollout.shadow_counts() {
+        let _ = writeln!(
+            out,
+            "nano_wasm_shadow_evaluations_total{{policy=\"{}\",candidate=\"{}\",result=\"{}\"}} {}",
+            escape(&policy),
+            escape(&candidate),
+            result.as_str(),
+            count
+        );
+    }
+    header(
+        &mut out,
+        "nano_wasm_canary_decisions_total",
+        "counter",
+        "Decisions made by a candidate instead of the policy",
+    );
+    for (policy, candidate, count) in state.rollout.canary_counts() {
+        let _ = writeln!(
+            out,
+            "nano_wasm_canary_decisions_total{{policy=\"{}\",candidate=\"{}\"}} {}",
+            escape(&policy),
+            escape(&candidate),
+            count
+        );
+    }
+
+    let spool = state.audit.spool().map(|spool| spool.stats()).unwrap_or_default();
+    for (name, help, value) in [
+        (
+            "nano_wasm_shipping_entries_total",
+            "Decision log entries acknowledged by the collector",
+            spool.shipped,
+        ),
+        (
+            "nano_wasm_shipping_dropped_entries_total",
+            "Decision log entries dropped from the full spool",
+            spool.dropped,
+        ),
+        (
+            "nano_wasm_shipping_failures_total",
+            "Failed decision log batch uploads",
+            spool.failures,
+        ),
+    ] {
+        header(&mut out, name, "counter", help);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+    gauge(
+        &mut out,
+        "nano_wasm_shipping_pending_entries",
+        "Decision log entries waiting to be shipped",
+        spool.pending_entries,
+    );
+    gauge(
+        &mut out,
+        "nano_wasm_shipping_pending_bytes",
+        "Size of the decision log spool",
+        spool.pending_bytes,
+    );
+
+    header(
+        &mut out,
+        "nano_wasm_guest_log_lines_dropped_total",
+        "counter",
+        "Guest log lines not written because of the rate limit",
+    );
+    let _ = writeln!(
+        out,
+        "nano_wasm_guest_log_lines_dropped_total {}",
+        state.guest_logs.dropped()
+    );
+
+    gauge(
+        &mut out,
+        "nano_wasm_resident_memory_bytes",
+        "Resident set size of the process",
+        resident_memory_kb() * 1024,
+    );
+
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Get current process memory usage in KB
+pub(crate) fn resident_memory_kb() -> u64 {
+    #[cfg(target_os = "macos")]
+    {
+        use std::process::Command;
+        let pid = std::process::id();
+        if let Ok(output) = Command::new("ps")
+            .args(["-o", "rss=", "-p", &pid.to_string()])
+            .output()
+        {
+            if let Ok(rss) = String::from_utf8_lossy(&output.stdout)
+                .trim()
+                .parse::<u64>()
+            {
+                return rss;
+            }
+        }
+        0
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        if let Ok(status) = std::fs::read_to_string("/proc/self/status") {
+            for line in status.lines() {
+                if line.starts_with("VmRSS:") {
+                    if let Some(kb) = line.split_whitespace().nth(1) {
+                        return kb.parse().unwrap_or(0);
+                    }
+                }
+            }
+        }
+        0
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "linux")))]
+    {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decision::Failure;
+    use crate::error::ConnectorError;
+    use crate::failure::FailureMode;
+
+    fn decision(policy: &str, policy_version: &str) -> Decision {
+        let failure = policy_version.is_empty().then(|| Failure {
+            error: ConnectorError::PolicyNotFound(policy.to_string()),
+            class: None,
+            mode: FailureMode::Closed,
+        });
+        Decision {
+            policy: policy.to_string(),
+            policy_version: policy_version.to_string(),
+            policy_sha256: String::new(),
+            allowed: failure.is_none(),
+            failure,
+            logs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn counts_unloaded_policies_as_unknown() {
+        let metrics = Metrics::default();
+        for i in 0..100 {
+            metrics.record_decision(&decision(&format!("probe-{}", i), ""));
+        }
+        metrics.record_decision(&decision("default", "v1-772"));
+
+        let recorded = metrics.recorded.lock();
+        let policies: Vec<&str> = recorded
+            .decisions
+            .keys()
+            .map(|(policy, _)| policy.as_str())
+            .collect();
+        assert_eq!(policies, ["default", UNKNOWN_POLICY]);
+        assert_eq!(recorded.decisions[&(UNKNOWN_POLICY.to_string(), "deny")], 100);
+        assert_eq!(recorded.errors.len(), 1);
+    }
+
+    #[test]
+    fn drops_series_of_removed_policies() {
+        let metrics = Metrics::default();
+        metrics.record_decision(&decision("default", "v1-772"));
+        metrics.record_decision(&decision("removed", "v1-900"));
+        metrics.record_decision(&decision("missing", ""));
+
+        let mut recorded = metrics.recorded.lock();
+        recorded.retain_loaded(|name| name == "default");
+        let policies: Vec<&str> = recorded
+            .decisions
+            .keys()
+            .map(|(policy, _)| policy.as_str())
+            .collect();
+        assert_eq!(policies, ["default", UNKNOWN_POLICY]);
+    }
+}
@@ -0,0 +1,668 @@
+//! Policy artifacts pulled from an OCI distribution registry
+//!
+//! References look like `registry.local/policies/access:1.2.0` or
+//! `registry.local/policies/access@sha256:<hex>`. The manifest is resolved
+//! through the distribution API, its policy layer is downloaded, verified
+//! and cached by digest, then installed like any other policy.
+//!
+//! Registries are reached over `https://`, which needs the `tls` feature;
+//! only a registry on a loopback address is spoken to over plain HTTP.
+
+use crate::error::{ConnectorError, ConnectorResult};
+use crate::http_client::{self, HttpClient};
+use crate::policy_store::{is_valid_policy_name, parse_bundle, sha256_hex};
+use crate::status::ReloadSource;
+use crate::AppState;
+use axum::http::{StatusCode, Uri};
+use serde::Deserialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+const MANIFEST_MEDIA_TYPES: &str =
+    "application/vnd.oci.image.manifest.v1+json, application/vnd.docker.distribution.manifest.v2+json";
+
+/// Layer media types recognised as policies, in order of preference
+const POLICY_LAYER_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.nano-wasm.policy.bundle.v1+json",
+    "application/vnd.wasm.content.layer.v1+wasm",
+    "application/wasm",
+];
+
+/// A parsed `registry/repository[:tag][@digest]` reference
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OciReference {
+    pub registry: String,
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl OciReference {
+    pub fn parse(reference: &str) -> ConnectorResult<Self> {
+        let invalid = |why: &str| {
+            ConnectorError::RegistryError(format!("Invalid OCI reference '{}': {}", reference, why))
+        };
+
+        let (rest, digest) = match reference.split_once('@') {
+            Some((rest, digest)) => {
+                if !digest.starts_with("sha256:") {
+                    return Err(invalid("only sha256 digests are supported"));
+                }
+                let digest = digest.to_ascii_lowercase();
+                if !is_valid_digest(&digest) {
+                    return Err(invalid("malformed digest"));
+                }
+                (rest, Some(digest))
+            }
+            None => (reference, None),
+        };
+
+        let (registry, path) = rest
+            .split_once('/')
+            .ok_or_else(|| invalid("missing registry host"))?;
+
+        // A ':' after the last '/' separates the tag; one before it is a port
+        let (repository, tag) = match path.rsplit_once(':') {
+            Some((repo, tag)) if !tag.contains('/') => (repo, Some(tag.to_string())),
+            _ => (path, None),
+        };
+
+        if registry.is_empty() || repository.is_empty() {
+            return Err(invalid("empty registry or repository"));
+        }
+        if tag.is_none() && digest.is_none() {
+            return Err(invalid("a tag or digest is required"));
+        }
+
+        Ok(Self {
+            registry: registry.to_string(),
+            repository: repository.to_string(),
+            tag,
+            digest,
+        })
+    }
+
+    /// Digest when pinned, otherwise the tag
+    fn manifest_reference(&self) -> &str {
+        self.digest
+            .as_deref()
+            .or(self.tag.as_deref())
+            .unwrap_or("latest")
+    }
+
+    /// `http` for a registry on a loopback address, `https` otherwise
+    fn scheme(&self) -> &'static str {
+        let local = format!("http://{}/", self.registry)
+            .parse::<Uri>()
+            .is_ok_and(|uri| http_client::is_loopback(&uri));
+        if local {
+            "http"
+        } else {
+            "https"
+        }
+    }
+
+    fn api_uri(&self, kind: &str, reference: &str) -> ConnectorResult<Uri> {
+        format!(
+            "{}://{}/v2/{}/{}/{}",
+            self.scheme(),
+            self.registry,
+            self.repository,
+            kind,
+            reference
+        )
+        .parse()
+        .map_err(|e| ConnectorError::RegistryError(format!("Invalid registry URL: {}", e)))
+    }
+}
+
+impl fmt::Display for OciReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.registry, self.repository)?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OciManifest {
+    layers: Vec<OciDescriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OciDescriptor {
+    #[serde(rename = "mediaType")]
+    media_type: String,
+    digest: String,
+}
+
+/// A policy to keep in sync with an OCI reference
+#[derive(Debug, Clone)]
+pub struct OciPolicySource {
+    pub name: String,
+    pub reference: OciReference,
+}
+
+impl OciPolicySource {
+    /// Parse `name=reference`, or a bare reference named after its repository
+    pub fn parse(spec: &str) -> ConnectorResult<Self> {
+        let (name, reference) = match spec.split_once('=') {
+            Some((name, reference)) => (Some(name.trim()), reference.trim()),
+            None => (None, spec.trim()),
+        };
+        let reference = OciReference::parse(reference)?;
+        if reference.scheme() == "https" && !cfg!(feature = "tls") {
+            return Err(ConnectorError::RegistryError(format!(
+                "Registry {} is not a loopback address; https:// needs the `tls` feature",
+                reference.registry
+            )));
+        }
+        let name = name.map(str::to_string).unwrap_or_else(|| {
+            reference
+                .repository
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        });
+        if !is_valid_policy_name(&name) {
+            return Err(ConnectorError::RegistryError(format!(
+                "Invalid policy name '{}'",
+                name
+            )));
+        }
+        Ok(Self { name, reference })
+    }
+}
+
+/// Blob cache keyed by content digest
+pub struct BlobCache {
+    dir: PathBuf,
+}
+
+impl BlobCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// `sha256/<hex>` under the cache; `None` unless `digest` is well-formed
+    fn path(&self, digest: &str) -> Option<PathBuf> {
+        is_valid_digest(digest).then(|| self.dir.join(digest.replace(':', "/")))
+    }
+
+    /// Cached blob, re-verified so a corrupted file is never used
+    fn get(&self, digest: &str) -> Option<Vec<u8>> {
+        let bytes = std::fs::read(self.path(digest)?).ok()?;
+        verify_digest(digest, &bytes).ok()?;
+        Some(bytes)
+    }
+
+    fn put(&self, digest: &str, bytes: &[u8]) -> std::io::Result<()> {
+        let path = self.path(digest).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "malformed digest")
+        })?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, bytes)?;
+        std::fs::rename(&tmp_path, &path)
+    }
+}
+
+/// Periodically resolve OCI references and install changed policies
+pub async fn sync_oci_policies(
+    state: Arc<AppState>,
+    sources: Vec<OciPolicySource>,
+    cache: BlobCache,
+    token: Option<String>,
+    interval: Duration,
+) {
+    let client = http_client::new_client();
+
+    for source in &sources {
+        tracing::info!(
+            policy = source.name.as_str(),
+            reference = %source.reference,
+            "Tracking OCI policy"
+        );
+    }
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        for source in &sources {
+            if let Err(e) = sync_source(&state, &client, &cache, token.as_deref(), source).await {
+                tracing::warn!(
+                    policy = source.name.as_str(),
+                    error = %e,
+                    "Failed to pull OCI policy, keeping current policy"
+                );
+            }
+        }
+    }
+}
+
+async fn sync_source(
+    state: &AppState,
+    client: &HttpClient,
+    cache: &BlobCache,
+    token: Option<&str>,
+    source: &OciPolicySource,
+) -> ConnectorResult<()> {
+    let bytes = pull_policy(client, cache, token, &source.reference).await?;
+    let (wasm_bytes, manifest) = parse_bundle(&bytes).map_err(ConnectorError::RegistryError)?;
+
+    if let Some(current) = state.policies.get(&source.name).await {
+        if current.is_unchanged(&wasm_bytes, &manifest) {
+            return Ok(());
+        }
+    }
+
+    let policy = state
+        .policies
+        .install(&source.name, &wasm_bytes, manifest, true, ReloadSource::Oci)
+        .await?;
+    tracing::info!(
+        policy = policy.name.as_str(),
+        reference = %source.reference,
+        policy_version = policy.version.as_str(),
+        "Policy pulled"
+    );
+    Ok(())
+}
+
+/// Resolve `reference` and return the verified policy layer
+pub(crate) async fn pull_policy(
+    client: &HttpClient,
+    cache: &BlobCache,
+    token: Option<&str>,
+    reference: &OciReference,
+) -> ConnectorResult<Vec<u8>> {
+    let auth = token.map(|t| format!("Bearer {}", t));
+    let mut headers = vec![("accept", MANIFEST_MEDIA_TYPES)];
+    if let Some(auth) = auth.as_deref() {
+        headers.push(("authorization", auth));
+    }
+
+    // Pinned manifests are immutable, so a cached copy avoids the round-trip
+    let cached_manifest = reference.digest.as_deref().and_then(|d| cache.get(d));
+    let manifest_bytes = match cached_manifest {
+        Some(bytes) => bytes,
+        None => {
+            let manifest_uri = reference.api_uri("manifests", reference.manifest_reference())?;
+            let response = http_client::get(client, &manifest_uri, &headers).await?;
+            if response.status != StatusCode::OK {
+                return Err(ConnectorError::RegistryError(format!(
+                    "Manifest request for {} returned {}",
+                    reference, response.status
+                )));
+            }
+
+            // Digest pinning: the manifest must hash to exactly the pinned digest
+            if let Some(pinned) = &reference.digest {
+                verify_digest(pinned, &response.body)?;
+                cache_blob(cache, pinned, &response.body);
+            }
+            response.body.to_vec()
+        }
+    };
+
+    let manifest: OciManifest = serde_json::from_slice(&manifest_bytes)
+        .map_err(|e| ConnectorError::RegistryError(format!("Invalid OCI manifest: {}", e)))?;
+    let layer = select_policy_layer(&manifest.layers).ok_or_else(|| {
+        ConnectorError::RegistryError(format!("No policy layer in {}", reference))
+    })?;
+    // The digest becomes a cache path and part of the blob URL
+    if !is_valid_digest(&layer.digest) {
+        return Err(ConnectorError::RegistryError(format!(
+            "Invalid layer digest in {}",
+            reference
+        )));
+    }
+
+    if let Some(bytes) = cache.get(&layer.digest) {
+        return Ok(bytes);
+    }
+
+    let blob_uri = reference.api_uri("blobs", &layer.digest)?;
+    let response = http_client::get(client, &blob_uri, &headers[1..]).await?;
+    if response.status != StatusCode::OK {
+        return Err(ConnectorError::RegistryError(format!(
+            "Blob request for {} returned {}",
+            layer.digest, response.status
+        )));
+    }
+    verify_digest(&layer.digest, &response.body)?;
+    cache_blob(cache, &layer.digest, &response.body);
+
+    Ok(response.body.to_vec())
+}
+
+/// Caching is best-effort; a full flash must not block activation
+fn cache_blob(cache: &BlobCache, digest: &str, bytes: &[u8]) {
+    if let Err(e) = cache.put(digest, bytes) {
+        tracing::warn!(digest, error = %e, "Failed to cache blob");
+    }
+}
+
+fn select_policy_layer(layers: &[OciDescriptor]) -> Option<&OciDescriptor> {
+    POLICY_LAYER_MEDIA_TYPES
+        .iter()
+        .find_map(|media_type| layers.iter().find(|l| l.media_type == *media_type))
+        .or_else(|| layers.first())
+}
+
+/// `sha256:` followed by 64 lowercase hex digits
+fn is_valid_digest(digest: &str) -> bool {
+    digest.strip_prefix("sha256:").is_some_and(|hex| {
+        hex.len() == 64 && hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    })
+}
+
+fn verify_digest(digest: &str, bytes: &[u8]) -> ConnectorResult<()> {
+    let expected = digest.strip_prefix("sha256:").ok_or_else(|| {
+        ConnectorError::RegistryError(format!("Unsupported digest algorithm: {}", digest))
+    })?;
+    let actual = sha256_hex(bytes);
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(ConnectorError::RegistryError(format!(
+            "Digest mismatch: expected sha256:{}, got sha256:{}",
+            expected, actual
+        )));
+    }
+    Ok(())
+}
+
+/// Default cache location inside the policies directory
+pub fn default_cache_dir(policies_dir: &Path) -> PathBuf {
+    policies_dir.join(".oci-cache")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy_store::{LoadedPolicy, PolicyStore};
+    use crate::test_support::{policy_bundle, serve, temp_dir};
+    use axum::extract::{Path as UrlPath, State};
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::get;
+    use axum::Router;
+    use parking_lot::Mutex;
+    use std::collections::HashMap;
+
+    /// What the stub registry serves and how often it was asked
+    #[derive(Default)]
+    struct Registry {
+        /// Keyed by tag and by digest
+        manifests: HashMap<String, Vec<u8>>,
+        blobs: HashMap<String, Vec<u8>>,
+        manifest_requests: usize,
+        blob_requests: usize,
+    }
+
+    type Shared = Arc<Mutex<Registry>>;
+
+    impl Registry {
+        /// Push `blob` as the policy layer of a manifest tagged `tag`,
+        /// returning the manifest digest
+        fn push(&mut self, tag: &str, blob: Vec<u8>) -> String {
+            let layer_digest = format!("sha256:{}", sha256_hex(&blob));
+            let manifest = manifest_with_layer(&layer_digest);
+            let digest = format!("sha256:{}", sha256_hex(&manifest));
+            self.manifests.insert(tag.to_string(), manifest.clone());
+            self.manifests.insert(digest.clone(), manifest);
+            self.blobs.insert(layer_digest, blob);
+            digest
+        }
+    }
+
+    fn manifest_with_layer(digest: &str) -> Vec<u8> {
+        serde_json::json!({
+            "schemaVersion": 2,
+            "layers": [{ "mediaType": POLICY_LAYER_MEDIA_TYPES[0], "digest": digest }],
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    async fn manifest(
+        State(registry): State<Shared>,
+        UrlPath(reference): UrlPath<String>,
+    ) -> Response {
+        let mut registry = registry.lock();
+        registry.manifest_requests += 1;
+        match registry.manifests.get(&reference) {
+            Some(manifest) => manifest.clone().into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        }
+    }
+
+    async fn blob(State(registry): State<Shared>, UrlPath(digest): UrlPath<String>) -> Response {
+        let mut registry = registry.lock();
+        registry.blob_requests += 1;
+        match registry.blobs.get(&digest) {
+            Some(blob) => blob.clone().into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        }
+    }
+
+    /// Serve `registry`, returning the repository as `host:port/policies/access`
+    async fn start(registry: &Shared) -> String {
+        let router = Router::new()
+            .route("/v2/policies/access/manifests/:reference", get(manifest))
+            .route("/v2/policies/access/blobs/:digest", get(blob))
+            .with_state(registry.clone());
+        let (addr, _server) = serve(router).await;
+        format!("{}/policies/access", addr)
+    }
+
+    fn source(reference: &str) -> OciPolicySource {
+        OciPolicySource::parse(&format!("access={}", reference)).unwrap()
+    }
+
+    fn installed_version(policy: &LoadedPolicy) -> Option<&str> {
+        policy.manifest.as_ref().and_then(|m| m.version.as_deref())
+    }
+
+    #[test]
+    fn parses_tags_digests_and_ports() {
+        let reference = OciReference::parse("localhost:5000/policies/access:1.2.0").unwrap();
+        assert_eq!(reference.registry, "localhost:5000");
+        assert_eq!(reference.repository, "policies/access");
+        assert_eq!(reference.tag.as_deref(), Some("1.2.0"));
+        assert_eq!(reference.digest, None);
+        assert_eq!(reference.manifest_reference(), "1.2.0");
+
+        let hex = "AB".repeat(32);
+        let pinned = format!("registry.local/policies/access:1.2.0@sha256:{}", hex);
+        let reference = OciReference::parse(&pinned).unwrap();
+        assert_eq!(reference.tag.as_deref(), Some("1.2.0"));
+        let digest = format!("sha256:{}", hex.to_ascii_lowercase());
+        assert_eq!(reference.digest.as_deref(), Some(digest.as_str()));
+        // Pinned references resolve by digest, never by the tag
+        assert_eq!(reference.manifest_reference(), digest);
+        assert_eq!(
+            reference.to_string(),
+            format!("registry.local/policies/access:1.2.0@{}", digest)
+        );
+
+        let pinned = format!("localhost:5000/access@sha256:{}", "0".repeat(64));
+        let reference = OciReference::parse(&pinned).unwrap();
+        assert_eq!(reference.registry, "localhost:5000");
+        assert_eq!(
+            (reference.repository.as_str(), reference.tag),
+            ("access", None)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_references() {
+        for reference in [
+            "access:1.0",
+            "localhost:5000/policies/access",
+            "/policies/access:1.0",
+            "localhost:5000/:1.0",
+            "localhost:5000/access@md5:0123",
+            "localhost:5000/access@sha256:0123",
+            &format!("localhost:5000/access@sha256:{}", "g".repeat(64)),
+        ] {
+            assert!(OciReference::parse(reference).is_err(), "{}", reference);
+        }
+    }
+
+    #[test]
+    fn speaks_plain_http_to_loopback_registries_only() {
+        for (registry, scheme) in [
+            ("localhost:5000", "http"),
+            ("127.0.0.1:5000", "http"),
+            ("[::1]:5000", "http"),
+            ("registry.local", "https"),
+            ("10.0.0.7:5000", "https"),
+        ] {
+            let reference = OciReference::parse(&format!("{}/access:1.0", registry)).unwrap();
+            assert_eq!(reference.scheme(), scheme, "{}", registry);
+            let uri = reference.api_uri("manifests", "1.0").unwrap();
+            assert_eq!(uri.scheme_str(), Some(scheme));
+        }
+        let remote = OciPolicySource::parse("registry.local/access:1.0");
+        assert_eq!(remote.is_ok(), cfg!(feature = "tls"));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn resolves_tags_and_installs_the_policy_layer() {
+        let registry = Shared::default();
+        registry.lock().push("1.0", policy_bundle("1"));
+        let repository = start(&registry).await;
+        let state = AppState::new(PolicyStore::new(temp_dir("oci-tag")), "default");
+        let cache = BlobCache::new(temp_dir("oci-tag-cache"));
+        let client = http_client::new_client();
+        let source = source(&format!("{}:1.0", repository));
+
+        sync_source(&state, &client, &cache, None, &source)
+            .await
+            .unwrap();
+        let installed = state.policies.get("access").await.unwrap();
+        assert_eq!(installed_version(&installed), Some("1"));
+
+        // Re-resolving an unchanged tag installs nothing
+        sync_source(&state, &client, &cache, None, &source)
+            .await
+            .unwrap();
+        let current = state.policies.get("access").await.unwrap();
+        assert!(Arc::ptr_eq(&installed, &current));
+
+        // The same module under a new manifest is a new policy
+        registry.lock().push("1.0", policy_bundle("2"));
+        sync_source(&state, &client, &cache, None, &source)
+            .await
+            .unwrap();
+        let current = state.policies.get("access").await.unwrap();
+        assert_eq!(installed_version(&current), Some("2"));
+        assert_eq!(registry.lock().manifest_requests, 3);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn serves_cached_blobs_without_downloading_them_again() {
+        let registry = Shared::default();
+        let bundle = policy_bundle("1");
+        registry.lock().push("1.0", bundle.clone());
+        let repository = start(&registry).await;
+        let cache = BlobCache::new(temp_dir("oci-blob-cache"));
+        let client = http_client::new_client();
+        let reference = OciReference::parse(&format!("{}:1.0", repository)).unwrap();
+
+        for _ in 0..2 {
+            let pulled = pull_policy(&client, &cache, None, &reference)
+                .await
+                .unwrap();
+            assert_eq!(pulled, bundle);
+        }
+        let registry = registry.lock();
+        assert_eq!((registry.manifest_requests, registry.blob_requests), (2, 1));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn pins_manifests_by_digest() {
+        let registry = Shared::default();
+        let bundle = policy_bundle("1");
+        let digest = registry.lock().push("1.0", bundle.clone());
+        let repository = start(&registry).await;
+        let cache = BlobCache::new(temp_dir("oci-pinned-cache"));
+        let client = http_client::new_client();
+        let reference = OciReference::parse(&format!("{}:1.0@{}", repository, digest)).unwrap();
+
+        // Moving the tag does not move a pinned reference
+        registry.lock().push("1.0", policy_bundle("2"));
+        for _ in 0..2 {
+            let pulled = pull_policy(&client, &cache, None, &reference)
+                .await
+                .unwrap();
+            assert_eq!(pulled, bundle);
+        }
+        // A pinned manifest is immutable, so the second pull needs no request
+        let registry = registry.lock();
+        assert_eq!((registry.manifest_requests, registry.blob_requests), (1, 1));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn rejects_content_that_does_not_match_its_digest() {
+        let registry = Shared::default();
+        let digest = registry.lock().push("1.0", policy_bundle("1"));
+        let repository = start(&registry).await;
+        let cache_dir = temp_dir("oci-mismatch-cache");
+        let cache = BlobCache::new(&cache_dir);
+        let client = http_client::new_client();
+
+        // A manifest served for a digest it does not hash to
+        let forged = format!("sha256:{}", "ab".repeat(32));
+        let manifest = registry.lock().manifests[&digest].clone();
+        registry.lock().manifests.insert(forged.clone(), manifest);
+        let reference = OciReference::parse(&format!("{}@{}", repository, forged)).unwrap();
+        let result = pull_policy(&client, &cache, None, &reference).await;
+        assert!(
+            matches!(result, Err(ConnectorError::RegistryError(e)) if e.contains("Digest mismatch"))
+        );
+
+        // A layer that does not hash to its digest
+        for blob in registry.lock().blobs.values_mut() {
+            blob.push(b' ');
+        }
+        let reference = OciReference::parse(&format!("{}:1.0", repository)).unwrap();
+        let result = pull_policy(&client, &cache, None, &reference).await;
+        assert!(
+            matches!(result, Err(ConnectorError::RegistryError(e)) if e.contains("Digest mismatch"))
+        );
+        assert!(std::fs::read_dir(&cache_dir).unwrap().next().is_none());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn rejects_malformed_layer_digests() {
+        let registry = Shared::default();
+        registry.lock().manifests.insert(
+            "1.0".to_string(),
+            manifest_with_layer("sha256:../../../escape"),
+        );
+        let repository = start(&registry).await;
+        let cache_dir = temp_dir("oci-layer-digest-cache");
+        let cache = BlobCache::new(&cache_dir);
+        let client = http_client::new_client();
+        let reference = OciReference::parse(&format!("{}:1.0", repository)).unwrap();
+
+        let result = pull_policy(&client, &cache, None, &reference).await;
+        assert!(
+            matches!(result, Err(ConnectorError::RegistryError(e)) if e.contains("Invalid layer digest"))
+        );
+        assert_eq!(registry.lock().blob_requests, 0);
+        assert!(cache.path("sha256:../../../escape").is_none());
+        assert!(std::fs::read_dir(&cache_dir).unwrap().next().is_none());
+    }
+}
@@ -2,19 +2,87 @@
 
 use crate::error::{ConnectorError, ConnectorResult};
 use std::sync::Arc;
-use wasmtime::{Caller, Config, Engine, Extern, Linker, Module, OptLevel, Store, Trap};
+use wasmtime::{
+    Caller, Config, Engine, Extern, ExternType, FuncType, Instance, InstancePre, Linker, Memory,
+    Module, OptLevel, Store, Trap,
+};
 
 // Input buffer offset in Wasm memory
 const INPUT_BUFFER_OFFSET: usize = 1024;
-const FUEL_LIMIT: u64 = 1_000_000;
+pub const DEFAULT_FUEL_LIMIT: u64 = 1_000_000;
+/// Documents are larger than requests, so transforms get more fuel
+pub const DEFAULT_TRANSFORM_FUEL_LIMIT: u64 = 10_000_000;
+
+/// Most `host.log` lines kept per evaluation
+pub const MAX_GUEST_LOG_LINES: usize = 32;
+/// Longer `host.log` lines are truncated
+pub const MAX_GUEST_LOG_LINE_BYTES: usize = 512;
 
 /// Host state
-pub struct HostState;
+#[derive(Default)]
+pub struct HostState {
+    /// Messages written by the guest through `host.log`
+    logs: Vec<String>,
+    /// Messages past `MAX_GUEST_LOG_LINES`
+    dropped_logs: usize,
+}
+
+impl HostState {
+    fn push_log(&mut self, msg: &str) {
+        if self.logs.len() >= MAX_GUEST_LOG_LINES {
+            self.dropped_logs += 1;
+            return;
+        }
+        let mut end = msg.len().min(MAX_GUEST_LOG_LINE_BYTES);
+        while !msg.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut line = msg[..end].to_string();
+        if end < msg.len() {
+            line.push_str("...");
+        }
+        self.logs.push(line);
+    }
+
+    /// The captured lines, ending with a note if some were dropped
+    fn take_logs(&mut self) -> Vec<String> {
+        let mut logs = std::mem::take(&mut self.logs);
+        if self.dropped_logs > 0 {
+            logs.push(format!("({} more log lines dropped)", self.dropped_logs));
+            self.dropped_logs = 0;
+        }
+        logs
+    }
+}
+
+/// Outcome of a single evaluation
+#[derive(Debug, Clone)]
+pub struct Evaluation {
+    pub allowed: bool,
+    pub fuel_consumed: u64,
+    pub logs: Vec<String>,
+    /// Linear memory of the instance when the evaluation finished
+    pub memory_bytes: usize,
+}
+
+/// Outcome of a `transform_payload` call
+#[derive(Debug, Clone)]
+pub struct Transformation {
+    /// The filtered JSON document
+    pub document: Vec<u8>,
+    pub fuel_consumed: u64,
+    pub logs: Vec<String>,
+}
 
 /// Policy runtime managing Wasm module execution
+#[derive(Clone)]
 pub struct PolicyRuntime {
     engine: Arc<Engine>,
-    module: Arc<Module>,
+    /// Imports resolved once; each evaluation still gets a fresh store so
+    /// no guest state leaks between requests
+    instance_pre: InstancePre<HostState>,
+    fuel_limit: u64,
+    transform_fuel_limit: u64,
 }
 
 impl PolicyRuntime {
@@ -24,42 +92,158 @@ impl PolicyRuntime {
         let module = Module::new(&engine, wasm_bytes).map_err(|e| {
             ConnectorError::WasmLoadError(format!("Failed to compile module: {}", e))
         })?;
+        validate_abi(&module)?;
+        let instance_pre = host_linker(&engine)?.instantiate_pre(&module).map_err(|e| {
+            ConnectorError::WasmLoadError(format!("Failed to link module: {}", e))
+        })?;
 
         Ok(Self {
             engine: Arc::new(engine),
-            module: Arc::new(module),
+            instance_pre,
+            fuel_limit: DEFAULT_FUEL_LIMIT,
+            transform_fuel_limit: DEFAULT_TRANSFORM_FUEL_LIMIT,
         })
     }
 
+    /// Override the per-evaluation fuel budget
+    pub fn with_fuel_limit(mut self, fuel_limit: u64) -> Self {
+        self.fuel_limit = fuel_limit;
+        self
+    }
+
+    pub fn fuel_limit(&self) -> u64 {
+        self.fuel_limit
+    }
+
     /// Evaluate a policy with the given request data
     pub fn evaluate_policy(&self, request_data: &[u8]) -> ConnectorResult<bool> {
-        let mut store = Store::new(&self.engine, HostState);
-        
+        let evaluation = self.evaluate(request_data)?;
+        for msg in &evaluation.logs {
+            tracing::info!(target: "guest", "{}", msg);
+        }
+        Ok(evaluation.allowed)
+    }
+
+    /// Evaluate a policy, also returning guest logs and fuel consumed
+    pub fn evaluate(&self, request_data: &[u8]) -> ConnectorResult<Evaluation> {
+        let mut guest = self.instantiate(self.fuel_limit)?;
+        let input_ptr = guest.write_input(request_data)?;
+
+        // Call policy evaluation with pointer and length
+        let evaluate = guest
+            .instance
+            .get_typed_func::<(i32, i32), i32>(&mut guest.store, "evaluate_access")
+            .map_err(|e| ConnectorError::FunctionNotFound(format!("evaluate_access: {}", e)))?;
+
+        let len_i32 = i32::try_from(request_data.len()).map_err(|_| {
+            ConnectorError::PolicyExecutionError("Request too large".to_string())
+        })?;
+
+        let span = tracing::info_span!(
+            "guest.evaluate_access",
+            fuel_limit = self.fuel_limit,
+            fuel_consumed = tracing::field::Empty,
+        );
+        let result = span.in_scope(|| evaluate.call(&mut guest.store, (input_ptr as i32, len_i32)));
+        let fuel_consumed = self.fuel_limit - guest.store.get_fuel().unwrap_or(0);
+        span.record("fuel_consumed", fuel_consumed);
+
+        match result {
+            Ok(result) => Ok(Evaluation {
+                allowed: result != 0,
+                fuel_consumed,
+                logs: guest.store.data_mut().take_logs(),
+                memory_bytes: guest.memory.data_size(&guest.store),
+            }),
+            Err(e) => Err(execution_error(e, self.fuel_limit)),
+        }
+    }
+
+    /// Whether the module exports `transform_payload`
+    pub fn has_transform(&self) -> bool {
+        self.instance_pre.module().get_export("transform_payload").is_some()
+    }
+
+    /// Override the fuel budget for `transform_payload`
+    pub fn with_transform_fuel_limit(mut self, fuel_limit: u64) -> Self {
+        self.transform_fuel_limit = fuel_limit;
+        self
+    }
+
+    pub fn transform_fuel_limit(&self) -> u64 {
+        self.transform_fuel_limit
+    }
+
+    /// Filter `document` through the guest's `transform_payload`
+    ///
+    /// The guest gets the decision context and the document side by side in
+    /// its input buffer and returns `(ptr << 32) | len` of the filtered
+    /// document, or a negative value to withhold it. The result must be JSON.
+    pub fn transform(&self, context: &[u8], document: &[u8]) -> ConnectorResult<Transformation> {
+        let mut guest = self.instantiate(self.transform_fuel_limit)?;
+        let input = [context, document].concat();
+        let context_ptr = guest.write_input(&input)?;
+        let document_ptr = context_ptr + context.len();
+
+        let transform = guest
+            .instance
+            .get_typed_func::<(i32, i32, i32, i32), i64>(&mut guest.store, "transform_payload")
+            .map_err(|e| ConnectorError::FunctionNotFound(format!("transform_payload: {}", e)))?;
+
+        let args = (
+            context_ptr as i32,
+            context.len() as i32,
+            document_ptr as i32,
+            document.len() as i32,
+        );
+        let span = tracing::info_span!(
+            "guest.transform_payload",
+            fuel_limit = self.transform_fuel_limit,
+            fuel_consumed = tracing::field::Empty,
+        );
+        let result = span.in_scope(|| transform.call(&mut guest.store, args));
+        let fuel_consumed = self.transform_fuel_limit - guest.store.get_fuel().unwrap_or(0);
+        span.record("fuel_consumed", fuel_consumed);
+        let packed = result.map_err(|e| execution_error(e, self.transform_fuel_limit))?;
+        if packed < 0 {
+            return Err(ConnectorError::PolicyExecutionError(format!(
+                "transform_payload withheld the document ({})",
+                packed
+            )));
+        }
+
+        let ptr = (packed >> 32) as usize;
+        let len = (packed & 0xffff_ffff) as usize;
+        let data = guest.memory.data(&guest.store);
+        let output = data
+            .get(ptr..ptr.saturating_add(len))
+            .ok_or(ConnectorError::MemoryOutOfBounds { offset: ptr })?
+            .to_vec();
+        if serde_json::from_slice::<serde_json::Value>(&output).is_err() {
+            return Err(ConnectorError::PolicyExecutionError(
+                "transform_payload returned invalid JSON".to_string(),
+            ));
+        }
+
+        Ok(Transformation {
+            document: output,
+            fuel_consumed,
+            logs: guest.store.data_mut().take_logs(),
+        })
+    }
+
+    /// Fresh instance with its own store and `fuel` budget
+    fn instantiate(&self, fuel: u64) -> ConnectorResult<Guest> {
+        let _span = tracing::info_span!("instantiate").entered();
+        let mut store = Store::new(&self.engine, HostState::default());
+
         // Set fuel limit for DoS protection
-        store.set_fuel(FUEL_LIMIT).map_err(|e| {
+        store.set_fuel(fuel).map_err(|e| {
             ConnectorError::PolicyExecutionError(format!("Failed to set fuel: {}", e))
         })?;
 
-        let mut linker: Linker<HostState> = Linker::new(&self.engine);
-        
-        // Register host log function - access memory via caller
-        linker
-            .func_wrap("host", "log", |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
-                if let Some(Extern::Memory(mem)) = caller.get_export("memory") {
-                    let data = mem.data(&caller);
-                    let start = ptr as usize;
-                    let end = start.saturating_add(len as usize).min(data.len());
-                    if start < end {
-                        if let Ok(msg) = std::str::from_utf8(&data[start..end]) {
-                            println!("[WASM] {}", msg);
-                        }
-                    }
-                }
-            })
-            .map_err(|e| ConnectorError::PolicyExecutionError(format!("Failed to register log: {}", e)))?;
-
         // Instantiate module
-        let instance = linker.instantiate(&mut store, &self.module).map_err(|e| {
+        let instance = self.instance_pre.instantiate(&mut store).map_err(|e| {
             ConnectorError::PolicyExecutionError(format!("Failed to instantiate: {}", e))
         })?;
 
@@ -67,9 +251,30 @@ impl PolicyRuntime {
         let memory = instance.get_memory(&mut store, "memory")
             .ok_or_else(|| ConnectorError::FunctionNotFound("memory".to_string()))?;
 
-        let input_ptr = match instance.get_typed_func::<(), i32>(&mut store, "get_input_buffer") {
+        Ok(Guest {
+            store,
+            instance,
+            memory,
+        })
+    }
+}
+
+/// A running instance of a policy module
+struct Guest {
+    store: Store<HostState>,
+    instance: Instance,
+    memory: Memory,
+}
+
+impl Guest {
+    /// Copy `data` into the guest's input buffer and return its offset
+    fn write_input(&mut self, data: &[u8]) -> ConnectorResult<usize> {
+        let input_ptr = match self
+            .instance
+            .get_typed_func::<(), i32>(&mut self.store, "get_input_buffer")
+        {
             Ok(func) => func
-                .call(&mut store, ())
+                .call(&mut self.store, ())
                 .map_err(|e| {
                     ConnectorError::PolicyExecutionError(format!(
                         "Failed to get input buffer: {}",
@@ -79,46 +284,201 @@ impl PolicyRuntime {
             Err(_) => INPUT_BUFFER_OFFSET,
         };
 
-        let required_len = input_ptr.saturating_add(request_data.len());
-        if required_len > memory.data_size(&store) {
+        let required_len = input_ptr.saturating_add(data.len());
+        if required_len > self.memory.data_size(&self.store) {
             return Err(ConnectorError::MemoryOutOfBounds { offset: input_ptr });
         }
 
         // Write request data to memory at the input buffer
-        memory
-            .write(&mut store, input_ptr, request_data)
+        self.memory
+            .write(&mut self.store, input_ptr, data)
             .map_err(|_| ConnectorError::MemoryOutOfBounds { offset: input_ptr })?;
+        Ok(input_ptr)
+    }
+}
 
-        // Call policy evaluation with pointer and length
-        let evaluate = instance
-            .get_typed_func::<(i32, i32), i32>(&mut store, "evaluate_access")
-            .map_err(|e| ConnectorError::FunctionNotFound(format!("evaluate_access: {}", e)))?;
+/// Map a trap from guest code, telling fuel exhaustion apart
+fn execution_error(e: wasmtime::Error, fuel_limit: u64) -> ConnectorError {
+    if let Some(trap) = e.downcast_ref::<Trap>() {
+        if matches!(trap, Trap::OutOfFuel) {
+            return ConnectorError::FuelExhausted {
+                consumed: fuel_limit,
+            };
+        }
+    }
 
-        let len_i32 = i32::try_from(request_data.len()).map_err(|_| {
-            ConnectorError::PolicyExecutionError("Request too large".to_string())
+    let err_str = format!("{}", e);
+    if err_str.contains("fuel") || err_str.contains("Fuel") {
+        return ConnectorError::FuelExhausted {
+            consumed: fuel_limit,
+        };
+    }
+    ConnectorError::PolicyExecutionError(format!("Policy execution failed: {}", e))
+}
+
+/// Imports, exports and custom sections of a module
+#[derive(Debug, Clone)]
+pub struct ModuleInfo {
+    /// `(module.name, type)`
+    pub imports: Vec<(String, String)>,
+    /// `(name, type)`
+    pub exports: Vec<(String, String)>,
+    /// `(name, size in bytes)`
+    pub custom_sections: Vec<(String, usize)>,
+}
+
+impl PolicyRuntime {
+    /// Compile a module without ABI validation and describe its interface
+    pub fn describe(wasm_bytes: &[u8]) -> ConnectorResult<ModuleInfo> {
+        let engine = create_edge_engine()?;
+        let module = Module::new(&engine, wasm_bytes).map_err(|e| {
+            ConnectorError::WasmLoadError(format!("Failed to compile module: {}", e))
         })?;
 
-        match evaluate.call(&mut store, (input_ptr as i32, len_i32)) {
-            Ok(result) => Ok(result != 0),
-            Err(e) => {
-                if let Some(trap) = e.downcast_ref::<Trap>() {
-                    if matches!(trap, Trap::OutOfFuel) {
-                        return Err(ConnectorError::FuelExhausted {
-                            consumed: FUEL_LIMIT,
-                        });
-                    }
+        let imports = module
+            .imports()
+            .map(|i| (format!("{}.{}", i.module(), i.name()), extern_type_string(&i.ty())))
+            .collect();
+        let exports = module
+            .exports()
+            .map(|e| (e.name().to_string(), extern_type_string(&e.ty())))
+            .collect();
+        let custom_sections = wasmparser::Parser::new(0)
+            .parse_all(wasm_bytes)
+            .filter_map(|payload| match payload {
+                Ok(wasmparser::Payload::CustomSection(reader)) => {
+                    Some((reader.name().to_string(), reader.data().len()))
                 }
+                _ => None,
+            })
+            .collect();
 
-                let err_str = format!("{}", e);
-                if err_str.contains("fuel") || err_str.contains("Fuel") {
-                    return Err(ConnectorError::FuelExhausted {
-                        consumed: FUEL_LIMIT,
-                    });
+        Ok(ModuleInfo {
+            imports,
+            exports,
+            custom_sections,
+        })
+    }
+}
+
+/// Linker providing the host functions guests may import
+fn host_linker(engine: &Engine) -> ConnectorResult<Linker<HostState>> {
+    let mut linker: Linker<HostState> = Linker::new(engine);
+    
+    // Register host log function - access memory via caller
+    linker
+        .func_wrap("host", "log", |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+            let _span = tracing::info_span!("host.log", len).entered();
+            if let Some(Extern::Memory(mem)) = caller.get_export("memory") {
+                let data = mem.data(&caller);
+                let start = ptr as usize;
+                let end = start.saturating_add(len as usize).min(data.len());
+                if start < end {
+                    if let Ok(msg) = std::str::from_utf8(&data[start..end]) {
+                        let msg = msg.to_string();
+                        caller.data_mut().push_log(&msg);
+                    }
                 }
-                Err(ConnectorError::PolicyExecutionError(format!("Policy execution failed: {}", e)))
+            }
+        })
+        .map_err(|e| ConnectorError::PolicyExecutionError(format!("Failed to register log: {}", e)))?;
+
+    Ok(linker)
+}
+
+/// Check that a compiled module matches the host/guest ABI
+///
+/// Guests must export `memory` and `evaluate_access(i32, i32) -> i32`, may
+/// export `get_input_buffer() -> i32` and
+/// `transform_payload(i32, i32, i32, i32) -> i64`, and may only import
+/// functions the host actually provides.
+fn validate_abi(module: &Module) -> ConnectorResult<()> {
+    if !matches!(module.get_export("memory"), Some(ExternType::Memory(_))) {
+        return Err(ConnectorError::FunctionNotFound("memory".to_string()));
+    }
+
+    match module.get_export("evaluate_access") {
+        Some(ExternType::Func(ty)) => check_signature("evaluate_access", &ty, "(i32, i32) -> i32")?,
+        _ => return Err(ConnectorError::FunctionNotFound("evaluate_access".to_string())),
+    }
+
+    if let Some(export) = module.get_export("get_input_buffer") {
+        match export {
+            ExternType::Func(ty) => check_signature("get_input_buffer", &ty, "() -> i32")?,
+            _ => return Err(ConnectorError::FunctionNotFound("get_input_buffer".to_string())),
+        }
+    }
+
+    if let Some(export) = module.get_export("transform_payload") {
+        match export {
+            ExternType::Func(ty) => {
+                check_signature("transform_payload", &ty, "(i32, i32, i32, i32) -> i64")?
+            }
+            _ => return Err(ConnectorError::FunctionNotFound("transform_payload".to_string())),
+        }
+    }
+
+    // Nothing but the request can influence a decision, which the decision
+    // cache relies on; imports that read time or state would break that
+    for import in module.imports() {
+        let name = format!("{}.{}", import.module(), import.name());
+        let expected = match (import.module(), import.name()) {
+            ("host", "log") => "(i32, i32) -> ()",
+            _ => {
+                return Err(ConnectorError::WasmLoadError(format!(
+                    "Unsupported import: {}",
+                    name
+                )))
+            }
+        };
+        match import.ty() {
+            ExternType::Func(ty) => check_signature(&name, &ty, expected)?,
+            _ => {
+                return Err(ConnectorError::WasmLoadError(format!(
+                    "Import {} must be a function",
+                    name
+                )))
             }
         }
     }
+
+    Ok(())
+}
+
+fn check_signature(function: &str, ty: &FuncType, expected: &str) -> ConnectorResult<()> {
+    let actual = signature_string(ty);
+    if actual != expected {
+        return Err(ConnectorError::SignatureMismatch {
+            function: function.to_string(),
+            expected: expected.to_string(),
+            actual,
+        });
+    }
+    Ok(())
+}
+
+/// Render a function type as `(i32, i32) -> i32`
+fn signature_string(ty: &FuncType) -> String {
+    let params: Vec<String> = ty.params().map(|p| p.to_string()).collect();
+    let results: Vec<String> = ty.results().map(|r| r.to_string()).collect();
+    let results = match results.len() {
+        0 => "()".to_string(),
+        1 => results[0].clone(),
+        _ => format!("({})", results.join(", ")),
+    };
+    format!("({}) -> {}", params.join(", "), results)
+}
+
+fn extern_type_string(ty: &ExternType) -> String {
+    match ty {
+        ExternType::Func(ty) => format!("func {}", signature_string(ty)),
+        ExternType::Memory(ty) => match ty.maximum() {
+            Some(max) => format!("memory {}..{} pages", ty.minimum(), max),
+            None => format!("memory {}.. pages", ty.minimum()),
+        },
+        ExternType::Global(ty) => format!("global {}", ty.content()),
+        ExternType::Table(ty) => format!("table {}", ty.element()),
+    }
 }
 
 /// Create an engine optimized for edge devices
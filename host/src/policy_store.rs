@@ -0,0 +1,461 @@
+//! Policy store - named policy modules loaded from the policies directory
+//!
+//! Each policy is a `<name>.wasm` module with an optional `<name>.json`
+//! manifest sidecar. Updates are persisted with a write-then-rename so the
+//! watcher never observes a half-written module.
+
+use crate::cache::DecisionCache;
+use crate::error::{ConnectorError, ConnectorResult};
+use crate::status::{ReloadSource, ReloadStatus};
+use crate::make_policy_version;
+use crate::policy_runtime::{PolicyRuntime, DEFAULT_FUEL_LIMIT, DEFAULT_TRANSFORM_FUEL_LIMIT};
+use base64::Engine as _;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use shared::PolicyResult;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+
+const MAX_POLICY_NAME_LEN: usize = 64;
+const WASM_MAGIC: &[u8] = b"\0asm";
+
+/// Policy distribution envelope carrying a module plus its manifest
+#[derive(Deserialize)]
+struct PolicyBundle {
+    /// Base64-encoded Wasm module
+    module: String,
+    #[serde(default)]
+    manifest: Option<PolicyManifest>,
+}
+
+/// Optional metadata shipped alongside a policy module
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PolicyManifest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    /// Requests evaluated against the module before it is activated
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tests: Vec<PolicyTestCase>,
+}
+
+/// A single self-test case from a policy manifest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyTestCase {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub request: serde_json::Value,
+    pub expect: PolicyResult,
+}
+
+impl PolicyManifest {
+    /// Run every self-test against `runtime`, returning the failures
+    pub fn run_self_tests(&self, runtime: &PolicyRuntime) -> Vec<String> {
+        let mut failures = Vec::new();
+        for (i, test) in self.tests.iter().enumerate() {
+            let label = test.name.clone().unwrap_or_else(|| format!("test #{}", i));
+            let request = test.request.to_string();
+            match runtime.evaluate_policy(request.as_bytes()) {
+                Ok(allowed) if allowed == test.expect.is_allowed() => {}
+                Ok(allowed) => failures.push(format!(
+                    "{}: expected {:?}, got {}",
+                    label,
+                    test.expect,
+                    if allowed { "Allow" } else { "Deny" }
+                )),
+                Err(e) => failures.push(format!("{}: {}", label, e)),
+            }
+        }
+        failures
+    }
+}
+
+/// A compiled policy together with its metadata
+pub struct LoadedPolicy {
+    pub name: String,
+    pub runtime: Arc<PolicyRuntime>,
+    pub version: String,
+    pub sha256: String,
+    pub size_bytes: usize,
+    pub loaded_at: u64,
+    pub manifest: Option<PolicyManifest>,
+}
+
+impl LoadedPolicy {
+    /// Compile `wasm_bytes` into a policy ready for activation
+    pub fn compile(
+        name: &str,
+        wasm_bytes: &[u8],
+        manifest: Option<PolicyManifest>,
+        fuel_limit: u64,
+        transform_fuel_limit: u64,
+    ) -> ConnectorResult<Self> {
+        let version = make_policy_version(wasm_bytes.len());
+        let span = tracing::info_span!(
+            "compile",
+            policy = name,
+            policy_version = version.as_str(),
+            size_bytes = wasm_bytes.len(),
+        );
+        let runtime = span.in_scope(|| PolicyRuntime::new(wasm_bytes))?
+            .with_fuel_limit(fuel_limit)
+            .with_transform_fuel_limit(transform_fuel_limit);
+        Ok(Self {
+            name: name.to_string(),
+            runtime: Arc::new(runtime),
+            version,
+            sha256: sha256_hex(wasm_bytes),
+            size_bytes: wasm_bytes.len(),
+            loaded_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            manifest,
+        })
+    }
+
+    /// Whether installing `wasm_bytes` with `manifest` would change nothing
+    pub fn is_unchanged(&self, wasm_bytes: &[u8], manifest: &Option<PolicyManifest>) -> bool {
+        let same_manifest =
+            serde_json::to_value(&self.manifest).ok() == serde_json::to_value(manifest).ok();
+        self.sha256 == sha256_hex(wasm_bytes) && same_manifest
+    }
+
+    /// Metadata as returned by the admin API
+    pub fn metadata(&self) -> serde_json::Value {
+        serde_json::json!({
+            "name": self.name,
+            "policy_version": self.version,
+            "sha256": self.sha256,
+            "size_bytes": self.size_bytes,
+            "loaded_at": self.loaded_at,
+            "manifest": self.manifest,
+        })
+    }
+}
+
+/// Registry of named policies backed by the policies directory
+pub struct PolicyStore {
+    dir: PathBuf,
+    policies: RwLock<HashMap<String, Arc<LoadedPolicy>>>,
+    status: ReloadStatus,
+    fuel_limit: u64,
+    transform_fuel_limit: u64,
+    /// Recent decisions, dropped per policy on activate and remove
+    decision_cache: DecisionCache,
+}
+
+impl PolicyStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            policies: RwLock::new(HashMap::new()),
+            status: ReloadStatus::new(),
+            fuel_limit: DEFAULT_FUEL_LIMIT,
+            transform_fuel_limit: DEFAULT_TRANSFORM_FUEL_LIMIT,
+            decision_cache: DecisionCache::default(),
+        }
+    }
+
+    /// Fuel budget applied to every policy compiled by this store
+    pub fn with_fuel_limit(mut self, fuel_limit: u64) -> Self {
+        self.fuel_limit = fuel_limit;
+        self
+    }
+
+    /// Fuel budget for `transform_payload` in every policy compiled by this store
+    pub fn with_transform_fuel_limit(mut self, transform_fuel_limit: u64) -> Self {
+        self.transform_fuel_limit = transform_fuel_limit;
+        self
+    }
+
+    pub fn with_decision_cache(mut self, decision_cache: DecisionCache) -> Self {
+        self.decision_cache = decision_cache;
+        self
+    }
+
+    pub fn decision_cache(&self) -> &DecisionCache {
+        &self.decision_cache
+    }
+
+    pub fn module_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.wasm", name))
+    }
+
+    pub fn manifest_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+
+    pub async fn get(&self, name: &str) -> Option<Arc<LoadedPolicy>> {
+        self.policies.read().await.get(name).cloned()
+    }
+
+    /// All loaded policies, sorted by name
+    pub async fn list(&self) -> Vec<Arc<LoadedPolicy>> {
+        let mut list: Vec<_> = self.policies.read().await.values().cloned().collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    /// Atomically swap in a compiled policy
+    pub async fn activate(&self, policy: LoadedPolicy) -> Arc<LoadedPolicy> {
+        let policy = Arc::new(policy);
+        let mut policies = self.policies.write().await;
+        policies.insert(policy.name.clone(), policy.clone());
+        self.decision_cache.invalidate(&policy.name);
+        policy
+    }
+
+    pub async fn remove(&self, name: &str) -> Option<Arc<LoadedPolicy>> {
+        self.status.remove(name);
+        let removed = self.policies.write().await.remove(name);
+        self.decision_cache.invalidate(name);
+        removed
+    }
+
+    pub fn status(&self) -> &ReloadStatus {
+        &self.status
+    }
+
+    /// Compile, self-test, persist and activate a policy
+    ///
+    /// Nothing is written to disk or swapped in unless every step before it
+    /// succeeded, so a bad upload leaves the last-known-good policy in place.
+    pub async fn install(
+        &self,
+        name: &str,
+        wasm_bytes: &[u8],
+        manifest: Option<PolicyManifest>,
+        self_test: bool,
+        source: ReloadSource,
+    ) -> ConnectorResult<Arc<LoadedPolicy>> {
+        let result = self
+            .try_install(name, wasm_bytes, manifest, self_test)
+            .await;
+        self.record(name, source, result.as_ref().map(Some));
+        result
+    }
+
+    async fn try_install(
+        &self,
+        name: &str,
+        wasm_bytes: &[u8],
+        manifest: Option<PolicyManifest>,
+        self_test: bool,
+    ) -> ConnectorResult<Arc<LoadedPolicy>> {
+        let policy = self
+            .compile(name, wasm_bytes.to_vec(), manifest, self_test)
+            .await?;
+        self.persist(name, wasm_bytes, policy.manifest.as_ref())?;
+        Ok(self.activate(policy).await)
+    }
+
+    /// Compile `wasm_bytes` and, with `self_test`, run the manifest's
+    /// self-tests, off the async runtime: a compile takes long enough to
+    /// stall every request on it
+    async fn compile(
+        &self,
+        name: &str,
+        wasm_bytes: Vec<u8>,
+        manifest: Option<PolicyManifest>,
+        self_test: bool,
+    ) -> ConnectorResult<LoadedPolicy> {
+        let name = name.to_string();
+        let (fuel_limit, transform_fuel_limit) = (self.fuel_limit, self.transform_fuel_limit);
+        let span = tracing::Span::current();
+        let task = tokio::task::spawn_blocking(move || {
+            span.in_scope(|| {
+                let policy = LoadedPolicy::compile(
+                    &name,
+                    &wasm_bytes,
+                    manifest,
+                    fuel_limit,
+                    transform_fuel_limit,
+                )?;
+                if let (Some(manifest), true) = (&policy.manifest, self_test) {
+                    let failures = manifest.run_self_tests(&policy.runtime);
+                    if !failures.is_empty() {
+                        return Err(ConnectorError::SelfTestFailed { failures });
+                    }
+                }
+                Ok(policy)
+            })
+        });
+        task.await.map_err(|e| {
+            ConnectorError::WasmLoadError(format!("Policy compilation join error: {}", e))
+        })?
+    }
+
+    /// Read a policy (and its manifest sidecar) from disk and activate it
+    pub async fn load_from_disk(
+        &self,
+        name: &str,
+        source: ReloadSource,
+    ) -> ConnectorResult<Arc<LoadedPolicy>> {
+        let result = async {
+            let (bytes, manifest) = self.read_from_disk(name).await?;
+            let policy = self.compile(name, bytes, manifest, false).await?;
+            Ok(self.activate(policy).await)
+        }
+        .await;
+        self.record(name, source, result.as_ref().map(Some));
+        result
+    }
+
+    /// Like [`load_from_disk`](Self::load_from_disk), but returns `Ok(None)`
+    /// when the module on disk is identical to the one already loaded, so a
+    /// persisted upload is not reloaded twice.
+    pub async fn reload_if_changed(
+        &self,
+        name: &str,
+        source: ReloadSource,
+    ) -> ConnectorResult<Option<Arc<LoadedPolicy>>> {
+        let result = async {
+            let (bytes, manifest) = self.read_from_disk(name).await?;
+            if let Some(current) = self.get(name).await {
+                if current.sha256 == sha256_hex(&bytes) {
+                    return Ok(None);
+                }
+            }
+
+            let policy = self.compile(name, bytes, manifest, false).await?;
+            Ok(Some(self.activate(policy).await))
+        }
+        .await;
+        self.record(name, source, result.as_ref().map(Option::as_ref));
+        result
+    }
+
+    /// Record a reload attempt; `Ok(None)` means nothing changed
+    fn record(
+        &self,
+        name: &str,
+        source: ReloadSource,
+        result: Result<Option<&Arc<LoadedPolicy>>, &ConnectorError>,
+    ) {
+        match result {
+            Ok(Some(policy)) => self.status.record_success(name, &policy.version, source),
+            Ok(None) => {}
+            Err(e) => self.status.record_failure(name, e, source),
+        }
+    }
+
+    async fn read_from_disk(
+        &self,
+        name: &str,
+    ) -> ConnectorResult<(Vec<u8>, Option<PolicyManifest>)> {
+        let bytes = tokio::fs::read(self.module_path(name)).await?;
+        let manifest = match tokio::fs::read(self.manifest_path(name)).await {
+            Ok(raw) => Some(serde_json::from_slice(&raw).map_err(|e| {
+                ConnectorError::WasmLoadError(format!("Invalid manifest for '{}': {}", name, e))
+            })?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok((bytes, manifest))
+    }
+
+    /// Names of all `*.wasm` modules in the policies directory
+    pub fn scan_dir(&self) -> std::io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if let Some(name) = policy_name_from_path(&path) {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Persist a module and its manifest with write-then-rename
+    pub fn persist(
+        &self,
+        name: &str,
+        wasm_bytes: &[u8],
+        manifest: Option<&PolicyManifest>,
+    ) -> std::io::Result<()> {
+        // Sidecar first so the watcher sees the final manifest with the module
+        match manifest {
+            Some(manifest) => {
+                let raw = serde_json::to_vec_pretty(manifest)?;
+                write_atomic(&self.manifest_path(name), &raw)?;
+            }
+            None => remove_if_exists(&self.manifest_path(name))?,
+        }
+        write_atomic(&self.module_path(name), wasm_bytes)
+    }
+
+    /// Remove a policy's module and manifest from disk
+    pub fn delete_files(&self, name: &str) -> std::io::Result<()> {
+        remove_if_exists(&self.module_path(name))?;
+        remove_if_exists(&self.manifest_path(name))
+    }
+}
+
+/// Split raw Wasm bytes or a JSON bundle into module bytes and manifest
+pub fn parse_bundle(body: &[u8]) -> Result<(Vec<u8>, Option<PolicyManifest>), String> {
+    if body.starts_with(WASM_MAGIC) {
+        return Ok((body.to_vec(), None));
+    }
+
+    let bundle: PolicyBundle =
+        serde_json::from_slice(body).map_err(|e| format!("Invalid policy bundle: {}", e))?;
+    let wasm_bytes = base64::engine::general_purpose::STANDARD
+        .decode(bundle.module.trim())
+        .map_err(|e| format!("Invalid base64 module: {}", e))?;
+    if !wasm_bytes.starts_with(WASM_MAGIC) {
+        return Err("Bundle module is not a Wasm binary".to_string());
+    }
+
+    Ok((wasm_bytes, bundle.manifest))
+}
+
+/// Policy names are used as file names, so keep them to a safe alphabet
+pub fn is_valid_policy_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_POLICY_NAME_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Map `policies/<name>.wasm` to `<name>`
+pub fn policy_name_from_path(path: &Path) -> Option<String> {
+    if path.extension()? != "wasm" {
+        return None;
+    }
+    let name = path.file_stem()?.to_str()?;
+    is_valid_policy_name(name).then(|| name.to_string())
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+fn write_atomic(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut file = std::fs::File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    drop(file);
+
+    std::fs::rename(&tmp_path, path)
+}
+
+fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
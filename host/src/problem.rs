@@ -0,0 +1,220 @@
+//! RFC 7807 problem details for HTTP error responses
+//!
+//! Errors are returned as `application/problem+json` with a stable `code`
+//! (see [`ConnectorError::code`]) so clients can tell a policy denial from a
+//! broken engine without parsing messages.
+
+use crate::error::ConnectorError;
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde_json::{Map, Value};
+
+const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// A problem+json error response
+#[derive(Debug)]
+pub struct Problem {
+    status: StatusCode,
+    code: &'static str,
+    detail: String,
+    extensions: Map<String, Value>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, code: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            detail: detail.into(),
+            extensions: Map::new(),
+        }
+    }
+
+    /// Map an error to its HTTP status and stable code
+    pub fn from_error(error: &ConnectorError) -> Self {
+        Self::new(status_for(error), error.code(), error.to_string())
+    }
+
+    pub fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Add an extension member to the body
+    pub fn with(mut self, key: &str, value: impl Into<Value>) -> Self {
+        self.extensions.insert(key.to_string(), value.into());
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn detail(&self) -> &str {
+        &self.detail
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let mut body = Map::new();
+        body.insert(
+            "type".to_string(),
+            format!("urn:nano-wasm-edge:error:{}", self.code).into(),
+        );
+        body.insert(
+            "title".to_string(),
+            self.status.canonical_reason().unwrap_or("Error").into(),
+        );
+        body.insert("status".to_string(), self.status.as_u16().into());
+        body.insert("detail".to_string(), self.detail.into());
+        body.insert("code".to_string(), self.code.into());
+        body.extend(self.extensions);
+
+        (
+            self.status,
+            [(header::CONTENT_TYPE, PROBLEM_CONTENT_TYPE)],
+            Value::Object(body).to_string(),
+        )
+            .into_response()
+    }
+}
+
+/// HTTP status for each error class
+fn status_for(error: &ConnectorError) -> StatusCode {
+    match error {
+        ConnectorError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+        ConnectorError::PolicyNotFound(_) => StatusCode::NOT_FOUND,
+        // The request does not fit into guest memory
+        ConnectorError::MemoryOutOfBounds { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        ConnectorError::WasmLoadError(_)
+        | ConnectorError::FunctionNotFound(_)
+        | ConnectorError::SignatureMismatch { .. }
+        | ConnectorError::SelfTestFailed { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+        // The policy did not decide within its budget
+        ConnectorError::FuelExhausted { .. } | ConnectorError::EvaluationTimeout { .. } => {
+            StatusCode::GATEWAY_TIMEOUT
+        }
+        ConnectorError::RegistryError(_) => StatusCode::BAD_GATEWAY,
+        ConnectorError::Overloaded(_) => StatusCode::SERVICE_UNAVAILABLE,
+        ConnectorError::PolicyExecutionError(_)
+        | ConnectorError::ConfigError(_)
+        | ConnectorError::IoError(_)
+        | ConnectorError::WasmtimeError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::json_response;
+
+    #[test]
+    fn maps_every_error_to_a_status_and_code() {
+        let text = || "x".to_string();
+        let cases = [
+            (
+                ConnectorError::InvalidRequest(text()),
+                400,
+                "invalid_request",
+            ),
+            (
+                ConnectorError::PolicyNotFound(text()),
+                404,
+                "policy_not_found",
+            ),
+            (
+                ConnectorError::MemoryOutOfBounds { offset: 1024 },
+                413,
+                "memory_out_of_bounds",
+            ),
+            (
+                ConnectorError::WasmLoadError(text()),
+                422,
+                "wasm_load_failed",
+            ),
+            (
+                ConnectorError::FunctionNotFound(text()),
+                422,
+                "function_not_found",
+            ),
+            (
+                ConnectorError::SignatureMismatch {
+                    function: text(),
+                    expected: text(),
+                    actual: text(),
+                },
+                422,
+                "signature_mismatch",
+            ),
+            (
+                ConnectorError::SelfTestFailed {
+                    failures: vec![text()],
+                },
+                422,
+                "self_test_failed",
+            ),
+            (
+                ConnectorError::FuelExhausted { consumed: 10 },
+                504,
+                "fuel_exhausted",
+            ),
+            (
+                ConnectorError::EvaluationTimeout { timeout_ms: 10 },
+                504,
+                "evaluation_timeout",
+            ),
+            (ConnectorError::RegistryError(text()), 502, "registry_error"),
+            (ConnectorError::Overloaded(text()), 503, "overloaded"),
+            (
+                ConnectorError::PolicyExecutionError(text()),
+                500,
+                "policy_execution_failed",
+            ),
+            (ConnectorError::ConfigError(text()), 500, "config_error"),
+            (
+                ConnectorError::IoError(std::io::Error::other("disk")),
+                500,
+                "io_error",
+            ),
+            (
+                ConnectorError::WasmtimeError(wasmtime::Error::msg("trap")),
+                500,
+                "wasmtime_error",
+            ),
+        ];
+        for (error, status, code) in cases {
+            let problem = Problem::from_error(&error);
+            assert_eq!(problem.status().as_u16(), status, "{}", code);
+            assert_eq!(problem.code, code);
+            assert_eq!(problem.detail(), error.to_string());
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn renders_problem_json() {
+        let problem = Problem::from_error(&ConnectorError::PolicyNotFound("sensors".to_string()))
+            .with("policy", "sensors");
+        let response = problem.into_response();
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            PROBLEM_CONTENT_TYPE
+        );
+
+        let (status, body) = json_response(response).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "type": "urn:nano-wasm-edge:error:policy_not_found",
+                "title": "Not Found",
+                "status": 404,
+                "detail": "Policy not found: sensors",
+                "code": "policy_not_found",
+                "policy": "sensors",
+            })
+        );
+    }
+}
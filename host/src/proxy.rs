@@ -0,0 +1,653 @@
+//! Policy Enforcement Point reverse proxy
+//!
+//! Requests to a configured route prefix are forwarded to the route's
+//! upstream only when the policy allows them, so protected data never leaves
+//! the device without a decision. The route's obligations are applied on the
+//! way through: headers added to the upstream request and to the response,
+//! and JSON response fields masked before they reach the client. Policies
+//! that export `transform_payload` filter the response before the masks.
+//!
+//! The request path is normalized once, before the route is matched (see
+//! [`normalize_path`]); the route, the decision and the upstream all see the
+//! same path, and ambiguous paths are rejected with a 400.
+//!
+//! Bodies are buffered (up to `proxy.max_body_bytes`) so masking sees the
+//! whole document, and `Accept-Encoding` is dropped from requests whose
+//! response is masked or transformed. A response that has to be masked or
+//! transformed but is not labelled JSON, is too large, or whose transform
+//! fails is withheld with a 502; only policies without a transform let other
+//! content types through. The transform is the one of the policy that
+//! decided, i.e. the candidate for canary requests.
+//!
+//! Hop-by-hop headers, the standard ones and any the `Connection` header
+//! names, are dropped in both directions.
+//!
+//! The proxy faces clients directly, so the role header
+//! (`mapping.role_header`) is removed from incoming requests before the
+//! decision unless `proxy.trust_role_header` says a trusted hop in front of
+//! the proxy sets it.
+
+use crate::audit::{identify_caller, Caller};
+use crate::error::{ConnectorError, ConnectorResult};
+use crate::http_client::{self, HttpClient};
+use crate::mapping::{encode_path, normalize_path};
+use crate::problem::Problem;
+use crate::server::{decision_headers, failure_problem, trace_request};
+use crate::AppState;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode, Uri},
+    middleware,
+    response::{IntoResponse, Response},
+    Router,
+};
+use http_body_util::{BodyExt, Full, Limited};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Replacement for masked response fields
+const MASK: &str = "***";
+
+/// Connection-level headers that are not forwarded
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// The `[proxy]` config section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// Listen address for the proxy; unset disables it
+    pub listen: Option<String>,
+    /// Largest request or response body passed through
+    pub max_body_bytes: usize,
+    /// Upstream request timeout in milliseconds
+    pub timeout_ms: u64,
+    /// Take the role header from the client instead of removing it; only
+    /// for a proxy behind a hop that sets or overwrites that header
+    pub trust_role_header: bool,
+    /// First matching prefix wins
+    pub routes: Vec<ProxyRoute>,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            max_body_bytes: 1024 * 1024,
+            timeout_ms: 10_000,
+            trust_role_header: false,
+            routes: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyRoute {
+    /// Path prefix, matched on segment boundaries
+    pub prefix: String,
+    /// `http://host:port[/base]`
+    pub upstream: String,
+    /// Policy deciding this route; `None` uses the default policy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+    /// Remove `prefix` from the path sent upstream
+    #[serde(default)]
+    pub strip_prefix: bool,
+    #[serde(default)]
+    pub obligations: Obligations,
+}
+
+/// What an allowed request must go through
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Obligations {
+    /// Added to the upstream request, replacing client values
+    pub request_headers: BTreeMap<String, String>,
+    /// Added to the response
+    pub response_headers: BTreeMap<String, String>,
+    /// Dot-separated JSON paths replaced with `"***"`; arrays apply the rest
+    /// of the path to every element
+    pub mask_fields: Vec<String>,
+}
+
+impl Obligations {
+    /// One `kind:target` string per obligation, e.g. `mask:user.email`
+    pub fn describe(&self) -> Vec<String> {
+        let request = self.request_headers.keys().map(|name| format!("request_header:{}", name));
+        let response = self.response_headers.keys().map(|name| format!("response_header:{}", name));
+        let masks = self.mask_fields.iter().map(|path| format!("mask:{}", path));
+        request.chain(response).chain(masks).collect()
+    }
+}
+
+/// A route with its upstream and headers parsed
+#[derive(Debug, Clone)]
+pub struct Route {
+    prefix: String,
+    upstream: Uri,
+    policy: Option<String>,
+    strip_prefix: bool,
+    request_headers: HeaderMap,
+    response_headers: HeaderMap,
+    mask_fields: Vec<Vec<String>>,
+    /// The obligations as recorded in the audit log
+    obligations: Vec<String>,
+}
+
+impl ProxyRoute {
+    pub fn parse(&self) -> ConnectorResult<Route> {
+        let invalid = |msg: String| {
+            ConnectorError::ConfigError(format!("proxy route '{}': {}", self.prefix, msg))
+        };
+
+        if !self.prefix.starts_with('/') {
+            return Err(invalid("prefix must start with '/'".to_string()));
+        }
+        let upstream: Uri = self
+            .upstream
+            .parse()
+            .map_err(|e| invalid(format!("upstream: {}", e)))?;
+        if upstream.scheme_str() != Some("http") || upstream.host().is_none() {
+            return Err(invalid(format!(
+                "upstream: expected an http:// URL, got '{}'",
+                self.upstream
+            )));
+        }
+        if let Some(name) = &self.policy {
+            if !crate::policy_store::is_valid_policy_name(name) {
+                return Err(invalid(format!("invalid policy name '{}'", name)));
+            }
+        }
+        let headers = |map: &BTreeMap<String, String>| -> ConnectorResult<HeaderMap> {
+            map.iter()
+                .map(|(name, value)| {
+                    let name = HeaderName::try_from(name.as_str())
+                        .map_err(|_| invalid(format!("invalid header name '{}'", name)))?;
+                    let value = HeaderValue::try_from(value.as_str())
+                        .map_err(|_| invalid(format!("invalid value for header '{}'", name)))?;
+                    Ok((name, value))
+                })
+                .collect()
+        };
+
+        Ok(Route {
+            prefix: self.prefix.trim_end_matches('/').to_string(),
+            upstream,
+            policy: self.policy.clone(),
+            strip_prefix: self.strip_prefix,
+            request_headers: headers(&self.obligations.request_headers)?,
+            response_headers: headers(&self.obligations.response_headers)?,
+            mask_fields: self
+                .obligations
+                .mask_fields
+                .iter()
+                .map(|path| path.split('.').map(str::to_string).collect())
+                .collect(),
+            obligations: self.obligations.describe(),
+        })
+    }
+}
+
+impl Route {
+    /// Path below the prefix, if `path` is under it
+    fn matches<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let rest = path.strip_prefix(&self.prefix)?;
+        (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+    }
+
+    /// Upstream URI for the normalized `path`, re-encoded
+    fn upstream_uri(&self, path: &str, rest: &str, query: Option<&str>) -> ConnectorResult<Uri> {
+        let path = if self.strip_prefix { rest } else { path };
+        let base = self.upstream.path().trim_end_matches('/');
+        let mut target = format!("{}{}", base, encode_path(path));
+        if target.is_empty() {
+            target.push('/');
+        }
+        if let Some(query) = query {
+            target = format!("{}?{}", target, query);
+        }
+        http_client::resolve_uri(&self.upstream, &target)
+    }
+}
+
+struct Proxy {
+    state: Arc<AppState>,
+    routes: Vec<Route>,
+    client: HttpClient,
+    max_body_bytes: usize,
+    timeout: Duration,
+    /// Role header removed from incoming requests; `None` when trusted
+    untrusted_role_header: Option<HeaderName>,
+}
+
+/// Build the proxy router for `config`
+pub fn router(state: Arc<AppState>, config: &ProxyConfig) -> ConnectorResult<Router> {
+    let routes = config
+        .routes
+        .iter()
+        .map(ProxyRoute::parse)
+        .collect::<ConnectorResult<_>>()?;
+    let proxy = Proxy {
+        state: state.clone(),
+        routes,
+        client: http_client::new_client(),
+        max_body_bytes: config.max_body_bytes,
+        timeout: Duration::from_millis(config.timeout_ms),
+        untrusted_role_header: if config.trust_role_header {
+            None
+        } else {
+            HeaderName::try_from(state.mapping.role_header.as_str()).ok()
+        },
+    };
+    Ok(Router::new()
+        .fallback(forward)
+        .layer(middleware::from_fn_with_state(state, identify_caller))
+        .layer(middleware::from_fn(trace_request))
+        .with_state(Arc::new(proxy)))
+}
+
+async fn forward(State(proxy): State<Arc<Proxy>>, request: Request) -> Response {
+    let (mut parts, body) = request.into_parts();
+    // A role the client claims for itself is not evidence of anything
+    if let Some(name) = &proxy.untrusted_role_header {
+        parts.headers.remove(name);
+    }
+    // One path for the route, the decision and the upstream
+    let path = match normalize_path(parts.uri.path()) {
+        Ok(path) => path,
+        Err(e) => return Problem::from_error(&e).into_response(),
+    };
+    let path = path.as_str();
+    let Some((route, rest)) = proxy
+        .routes
+        .iter()
+        .find_map(|route| Some((route, route.matches(path)?)))
+    else {
+        return Problem::new(StatusCode::NOT_FOUND, "no_route", format!("No route for {}", path))
+            .into_response();
+    };
+
+    let state = &proxy.state;
+    let policy_request = state
+        .mapping
+        .policy_request(parts.method.as_str(), path, |name| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        });
+    let decision_body = serde_json::to_vec(&policy_request).unwrap_or_default();
+    let caller = Caller {
+        obligations: route.obligations.clone(),
+        ..Caller::current()
+    };
+    let decision = caller
+        .scope(state.decide(route.policy.as_deref(), &decision_body))
+        .await;
+    let decided_headers = decision_headers(&decision);
+
+    if !decision.allowed {
+        let problem = match decision.failure {
+            None => Problem::new(
+                StatusCode::FORBIDDEN,
+                "access_denied",
+                format!("{} {} denied by policy", parts.method, path),
+            )
+            .with("allowed", false)
+            .with("policy_version", decision.policy_version),
+            Some(failure) => {
+                failure_problem(state, &decision.policy, decision.policy_version, failure)
+            }
+        };
+        return (decided_headers, problem).into_response();
+    }
+
+    let body = match Limited::new(body, proxy.max_body_bytes).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => {
+            return Problem::new(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", e.to_string())
+                .into_response()
+        }
+    };
+    let uri = match route.upstream_uri(path, rest, parts.uri.query()) {
+        Ok(uri) => uri,
+        Err(e) => return upstream_problem(e.to_string()),
+    };
+
+    let mut request_headers = parts.headers.clone();
+    strip_hop_by_hop(&mut request_headers);
+    request_headers.remove(header::HOST);
+    // A body the obligations have to read must arrive uncompressed
+    let transformed = match state.policies().get(&decision.policy).await {
+        Some(loaded) => loaded.runtime.has_transform(),
+        None => false,
+    };
+    if transformed || !route.mask_fields.is_empty() {
+        request_headers.remove(header::ACCEPT_ENCODING);
+    }
+    request_headers.extend(route.request_headers.clone());
+    request_headers.extend(decided_headers.clone());
+
+    let mut upstream = axum::http::Request::new(Full::new(body));
+    *upstream.method_mut() = parts.method.clone();
+    *upstream.uri_mut() = uri.clone();
+    *upstream.headers_mut() = request_headers;
+
+    let response = match tokio::time::timeout(proxy.timeout, proxy.client.request(upstream)).await
+    {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => return upstream_problem(format!("Request to {} failed: {}", uri, e)),
+        Err(_) => return upstream_problem(format!("Request to {} timed out", uri)),
+    };
+
+    let (mut parts, body) = response.into_parts();
+    let body = match Limited::new(body, proxy.max_body_bytes).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => return upstream_problem(format!("Failed to read response: {}", e)),
+    };
+    // The filtering of the policy that decided first, then the route's masks
+    let body = if body.is_empty() || (!transformed && !is_json(&parts.headers)) {
+        body
+    } else if !is_json(&parts.headers) {
+        // Unlabelled JSON would otherwise leave without the policy's filtering
+        return upstream_problem("Response must be filtered but is not JSON".to_string());
+    } else {
+        let request = serde_json::to_value(&policy_request).unwrap_or_default();
+        match state.transform(Some(&decision.policy), request, body.to_vec()).await {
+            Ok(Some(document)) => Bytes::from(document),
+            Ok(None) => body,
+            Err(e) => {
+                return Problem::new(StatusCode::BAD_GATEWAY, "obligation_failed", e.to_string())
+                    .into_response()
+            }
+        }
+    };
+    let body = match apply_masks(&route.mask_fields, &parts.headers, body) {
+        Ok(body) => body,
+        Err(detail) => {
+            return Problem::new(StatusCode::BAD_GATEWAY, "obligation_failed", detail)
+                .into_response()
+        }
+    };
+
+    // The client connection has its own protocol version
+    parts.version = Default::default();
+    strip_hop_by_hop(&mut parts.headers);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.extend(route.response_headers.clone());
+    parts.headers.extend(decided_headers);
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Mask the configured fields of a JSON body
+fn apply_masks(masks: &[Vec<String>], headers: &HeaderMap, body: Bytes) -> Result<Bytes, String> {
+    if masks.is_empty() || body.is_empty() {
+        return Ok(body);
+    }
+    if !is_json(headers) {
+        return Err("Response must be masked but is not JSON".to_string());
+    }
+
+    let mut document: Value = serde_json::from_slice(&body)
+        .map_err(|e| format!("Response must be masked but is not valid JSON: {}", e))?;
+    for path in masks {
+        mask(&mut document, path);
+    }
+    serde_json::to_vec(&document)
+        .map(Bytes::from)
+        .map_err(|e| e.to_string())
+}
+
+/// Whether the `Content-Type` is JSON
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json") || v.contains("+json"))
+}
+
+/// Replace the field at `path` with `***`, in every element of arrays on the way
+pub(crate) fn mask(value: &mut Value, path: &[String]) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(|item| mask(item, path)),
+        Value::Object(fields) => {
+            let Some((first, rest)) = path.split_first() else {
+                return;
+            };
+            if let Some(field) = fields.get_mut(first) {
+                if rest.is_empty() {
+                    *field = Value::String(MASK.to_string());
+                } else {
+                    mask(field, rest);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Remove the standard hop-by-hop headers and those `Connection` names
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let named: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::try_from(name.trim()).ok())
+        .collect();
+    for name in named {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+}
+
+fn upstream_problem(detail: String) -> Response {
+    Problem::new(StatusCode::BAD_GATEWAY, "upstream_error", detail).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy_store::PolicyStore;
+    use crate::test_support::{activate_default, serve, temp_dir};
+    use axum::routing::get;
+
+    fn route(prefix: &str, strip_prefix: bool) -> Route {
+        ProxyRoute {
+            prefix: prefix.to_string(),
+            upstream: "http://upstream:8080/base".to_string(),
+            policy: None,
+            strip_prefix,
+            obligations: Obligations::default(),
+        }
+        .parse()
+        .unwrap()
+    }
+
+    #[test]
+    fn routes_the_normalized_path() {
+        let public = route("/public", false);
+        let secret = route("/secret", true);
+        for raw in ["/public/../secret/x", "/%73ecret/x", "//secret/./x"] {
+            let path = normalize_path(raw).unwrap();
+            assert_eq!(public.matches(&path), None, "{}", raw);
+            let rest = secret.matches(&path).unwrap();
+            let uri = secret.upstream_uri(&path, rest, Some("q=1")).unwrap();
+            assert_eq!(
+                uri.to_string(),
+                "http://upstream:8080/base/x?q=1",
+                "{}",
+                raw
+            );
+        }
+        assert_eq!(secret.matches("/secretive"), None);
+    }
+
+    #[test]
+    fn re_encodes_the_upstream_path() {
+        let public = route("/public", false);
+        let path = normalize_path("/public/a%20b/%25").unwrap();
+        let uri = public.upstream_uri(&path, "", None).unwrap();
+        assert_eq!(uri.path(), "/base/public/a%20b/%25");
+    }
+
+    /// Upstream serving JSON, the same JSON labelled as plain text or not
+    /// at all, and echoing whether `x-hop` and `x-role` arrived
+    fn upstream() -> Router {
+        Router::new()
+            .route(
+                "/api/reading",
+                get(|| async {
+                    (
+                        [(header::CONTENT_TYPE, "application/json")],
+                        r#"{"serial":"A-1","temp":21.4}"#,
+                    )
+                }),
+            )
+            .route(
+                "/api/plain",
+                get(|| async {
+                    (
+                        [(header::CONTENT_TYPE, "text/plain")],
+                        r#"{"serial":"A-1","temp":21.4}"#,
+                    )
+                }),
+            )
+            .route(
+                "/api/unlabelled",
+                get(|| async { Response::new(Body::from(r#"{"serial":"A-1","temp":21.4}"#)) }),
+            )
+            .route(
+                "/api/echo",
+                get(|headers: HeaderMap| async move {
+                    let hop = headers.contains_key("x-hop");
+                    let role = headers.contains_key("x-role");
+                    (
+                        [
+                            ("connection", "x-internal"),
+                            ("x-internal", "secret"),
+                            ("content-type", "application/json"),
+                        ],
+                        format!(r#"{{"hop":{},"role":{}}}"#, hop, role),
+                    )
+                }),
+            )
+    }
+
+    async fn start_proxy(
+        name: &str,
+        trust_role_header: bool,
+    ) -> (
+        String,
+        tokio::task::JoinHandle<()>,
+        tokio::task::JoinHandle<()>,
+    ) {
+        let (upstream_addr, upstream) = serve(upstream()).await;
+        let policies = PolicyStore::new(temp_dir(name));
+        activate_default(&policies, "default").await;
+        let state = Arc::new(AppState::new(policies, "default"));
+        let config = ProxyConfig {
+            routes: vec![ProxyRoute {
+                prefix: "/api".to_string(),
+                upstream: format!("http://{}", upstream_addr),
+                policy: None,
+                strip_prefix: false,
+                obligations: Obligations::default(),
+            }],
+            trust_role_header,
+            ..ProxyConfig::default()
+        };
+        let (addr, proxy) = serve(router(state, &config).unwrap()).await;
+        (format!("http://{}", addr), upstream, proxy)
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn transforms_json_and_withholds_unlabelled_content() {
+        let (base, _upstream, _proxy) = start_proxy("proxy-transform", false).await;
+        let client = http_client::new_client();
+
+        // The default policy masks serials for everyone but admins
+        let uri = format!("{}/api/reading", base).parse().unwrap();
+        let response = http_client::get(&client, &uri, &[]).await.unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(&response.body[..], br#"{"serial":"***","temp":21.4}"#);
+
+        // The same document not labelled as JSON is not released unfiltered
+        for path in ["/api/plain", "/api/unlabelled"] {
+            let uri = format!("{}{}", base, path).parse().unwrap();
+            let response = http_client::get(&client, &uri, &[]).await.unwrap();
+            assert_eq!(response.status, StatusCode::BAD_GATEWAY, "{}", path);
+            let problem: Value = serde_json::from_slice(&response.body).unwrap();
+            assert_eq!(problem["code"], "upstream_error");
+            assert!(!String::from_utf8_lossy(&response.body).contains("A-1"));
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn drops_headers_named_in_connection() {
+        let (base, _upstream, _proxy) = start_proxy("proxy-hop-by-hop", false).await;
+        let client = http_client::new_client();
+
+        let uri = format!("{}/api/echo", base).parse().unwrap();
+        let headers = [("connection", "X-Hop"), ("x-hop", "1")];
+        let response = http_client::get(&client, &uri, &headers).await.unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(&response.body[..], br#"{"hop":false,"role":false}"#);
+        assert_eq!(response.header("x-internal"), None);
+        assert_eq!(response.header("connection"), None);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn client_roles_are_only_used_when_trusted() {
+        let client = http_client::new_client();
+        let admin = [("x-role", "admin")];
+
+        let (base, _upstream, _proxy) = start_proxy("proxy-untrusted-role", false).await;
+        let uri = format!("{}/api/reading", base).parse().unwrap();
+        let response = http_client::get(&client, &uri, &admin).await.unwrap();
+        assert_eq!(&response.body[..], br#"{"serial":"***","temp":21.4}"#);
+        let uri = format!("{}/api/echo", base).parse().unwrap();
+        let response = http_client::get(&client, &uri, &admin).await.unwrap();
+        assert_eq!(&response.body[..], br#"{"hop":false,"role":false}"#);
+
+        let (base, _upstream, _proxy) = start_proxy("proxy-trusted-role", true).await;
+        let uri = format!("{}/api/reading", base).parse().unwrap();
+        let response = http_client::get(&client, &uri, &admin).await.unwrap();
+        assert_eq!(&response.body[..], br#"{"serial":"A-1","temp":21.4}"#);
+        let uri = format!("{}/api/echo", base).parse().unwrap();
+        let response = http_client::get(&client, &uri, &admin).await.unwrap();
+        assert_eq!(&response.body[..], br#"{"hop":false,"role":true}"#);
+    }
+
+    #[test]
+    fn strips_the_headers_connection_names() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONNECTION,
+            HeaderValue::from_static("close, X-Trace"),
+        );
+        headers.append(header::CONNECTION, HeaderValue::from_static("x-debug"));
+        for name in ["x-trace", "x-debug", "keep-alive", "x-kept"] {
+            headers.insert(name, HeaderValue::from_static("1"));
+        }
+        strip_hop_by_hop(&mut headers);
+        let left: Vec<&str> = headers.keys().map(HeaderName::as_str).collect();
+        assert_eq!(left, ["x-kept"]);
+    }
+}
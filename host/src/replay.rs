@@ -0,0 +1,457 @@
+//! Replay of recorded requests against a candidate policy
+//!
+//! Requests are taken from a decision log (entries kept with
+//! `audit.request = "redacted"`; digest-only entries cannot be replayed) or
+//! from a corpus of captured requests, either JSON lines or one JSON array.
+//! Each request is evaluated by a baseline module, usually the one deployed,
+//! and by the candidate, and the outcomes are compared: decisions that flip
+//! either way, errors the candidate introduces or fixes, and the change in
+//! fuel. Decision log entries also carry the decision actually made, so
+//! requests the baseline decides differently are counted too; redacted
+//! fields replay as their mask, which can explain such mismatches.
+//!
+//! Requests are evaluated byte for byte as they appear in the file: policies
+//! may match on formatting or key order, which re-serializing would change.
+
+use crate::audit::{AuditEntry, INVALID_REQUEST};
+use crate::error::{ConnectorError, ConnectorResult};
+use crate::policy_runtime::PolicyRuntime;
+use crate::policy_store::sha256_hex;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::path::Path;
+
+/// One request to replay
+#[derive(Debug, Clone)]
+pub struct ReplayCase {
+    /// Where the request came from, e.g. `decisions.log:12`
+    pub source: String,
+    pub request: Vec<u8>,
+    /// Digest of the request as first received
+    pub request_sha256: String,
+    /// Decision recorded for the request, if the policy made one
+    pub recorded: Option<bool>,
+}
+
+/// Requests read from a decision log or corpus
+#[derive(Debug, Clone, Default)]
+pub struct Corpus {
+    pub cases: Vec<ReplayCase>,
+    /// Decision log entries without a replayable request, or for another
+    /// policy
+    pub skipped: u64,
+}
+
+impl Corpus {
+    /// Append the requests of `other`
+    pub fn extend(&mut self, other: Corpus) {
+        self.cases.extend(other.cases);
+        self.skipped += other.skipped;
+    }
+}
+
+/// Read the requests in `path`; decision log entries are kept only for
+/// `policy`, if given
+pub fn load_corpus(path: &Path, policy: Option<&str>) -> ConnectorResult<Corpus> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut corpus = Corpus::default();
+
+    if contents.trim_start().starts_with('[') {
+        let requests: Vec<&RawValue> = serde_json::from_str(&contents).map_err(|e| {
+            ConnectorError::InvalidRequest(format!("{}: invalid JSON array: {}", path.display(), e))
+        })?;
+        for (index, request) in requests.iter().enumerate() {
+            let request = request.get().as_bytes().to_vec();
+            corpus.cases.push(ReplayCase {
+                source: format!("{}[{}]", path.display(), index),
+                request_sha256: sha256_hex(&request),
+                request,
+                recorded: None,
+            });
+        }
+        return Ok(corpus);
+    }
+
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let source = format!("{}:{}", path.display(), index + 1);
+        serde_json::from_str::<&RawValue>(line).map_err(|e| {
+            ConnectorError::InvalidRequest(format!("{}: invalid JSON: {}", source, e))
+        })?;
+        let Ok(entry) = serde_json::from_str::<AuditEntry>(line) else {
+            corpus.cases.push(ReplayCase {
+                source,
+                request: line.as_bytes().to_vec(),
+                request_sha256: sha256_hex(line.as_bytes()),
+                recorded: None,
+            });
+            continue;
+        };
+
+        let replayable = entry
+            .request
+            .as_ref()
+            .is_some_and(|request| request.as_str() != Some(INVALID_REQUEST));
+        let Some(request) = serde_json::from_str::<LoggedRequest>(line)
+            .ok()
+            .and_then(|logged| logged.request)
+            .filter(|_| replayable && policy.is_none_or(|policy| entry.policy == policy))
+        else {
+            corpus.skipped += 1;
+            continue;
+        };
+        // Failed evaluations were decided by the failure mode, not the policy
+        let decided = entry.reasons == ["policy"];
+        corpus.cases.push(ReplayCase {
+            source: format!("{} (seq {})", source, entry.seq),
+            request: request.get().as_bytes().to_vec(),
+            request_sha256: entry.request_sha256,
+            recorded: decided.then_some(entry.allowed),
+        });
+    }
+    Ok(corpus)
+}
+
+/// The `request` of a decision log entry, as written
+#[derive(Deserialize)]
+struct LoggedRequest<'a> {
+    #[serde(borrow)]
+    request: Option<&'a RawValue>,
+}
+
+/// What a module made of a request
+#[derive(Debug, Clone, Serialize)]
+pub struct Outcome {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fuel_consumed: Option<u64>,
+    /// Error `code` and message if the evaluation failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Outcome {
+    fn of(runtime: &PolicyRuntime, request: &[u8]) -> Self {
+        match runtime.evaluate(request) {
+            Ok(evaluation) => Self {
+                allowed: Some(evaluation.allowed),
+                fuel_consumed: Some(evaluation.fuel_consumed),
+                error: None,
+            },
+            Err(e) => Self {
+                allowed: None,
+                fuel_consumed: None,
+                error: Some(format!("{}: {}", e.code(), e)),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    AllowToDeny,
+    DenyToAllow,
+    /// The candidate fails where the baseline decided
+    NewError,
+    /// The candidate decides where the baseline failed
+    FixedError,
+}
+
+/// A request the candidate treats differently
+#[derive(Debug, Clone, Serialize)]
+pub struct Difference {
+    pub source: String,
+    pub request_sha256: String,
+    pub change: Change,
+    pub baseline: Outcome,
+    pub candidate: Outcome,
+}
+
+/// Fuel used by requests both modules evaluated
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FuelDelta {
+    pub requests: u64,
+    pub baseline_total: u64,
+    pub candidate_total: u64,
+    /// Change of the total, in percent of the baseline
+    pub change_percent: f64,
+    /// Largest per-request increase, 0 if none grew
+    pub max_increase: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_increase_source: Option<String>,
+}
+
+/// Outcome of a replay
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReplayReport {
+    pub requests: u64,
+    pub skipped: u64,
+    pub unchanged: u64,
+    pub allow_to_deny: u64,
+    pub deny_to_allow: u64,
+    pub new_errors: u64,
+    pub fixed_errors: u64,
+    /// Requests the baseline decides differently than recorded
+    pub recorded_mismatches: u64,
+    pub fuel: FuelDelta,
+    pub differences: Vec<Difference>,
+}
+
+impl ReplayReport {
+    /// Whether any request flips or newly fails
+    pub fn has_regressions(&self) -> bool {
+        self.allow_to_deny + self.deny_to_allow + self.new_errors > 0
+    }
+}
+
+/// Evaluate every request of `corpus` with `baseline` and `candidate` and
+/// compare the outcomes
+pub fn replay(baseline: &PolicyRuntime, candidate: &PolicyRuntime, corpus: &Corpus) -> ReplayReport {
+    let mut report = ReplayReport {
+        skipped: corpus.skipped,
+        ..ReplayReport::default()
+    };
+
+    for case in &corpus.cases {
+        report.requests += 1;
+        let before = Outcome::of(baseline, &case.request);
+        let after = Outcome::of(candidate, &case.request);
+
+        if let (Some(recorded), Some(allowed)) = (case.recorded, before.allowed) {
+            if recorded != allowed {
+                report.recorded_mismatches += 1;
+            }
+        }
+        if let (Some(before), Some(after)) = (before.fuel_consumed, after.fuel_consumed) {
+            let fuel = &mut report.fuel;
+            fuel.requests += 1;
+            fuel.baseline_total += before;
+            fuel.candidate_total += after;
+            if after.saturating_sub(before) > fuel.max_increase {
+                fuel.max_increase = after - before;
+                fuel.max_increase_source = Some(case.source.clone());
+            }
+        }
+
+        let change = match (before.allowed, after.allowed) {
+            (Some(true), Some(false)) => Change::AllowToDeny,
+            (Some(false), Some(true)) => Change::DenyToAllow,
+            (Some(_), None) => Change::NewError,
+            (None, Some(_)) => Change::FixedError,
+            _ => {
+                report.unchanged += 1;
+                continue;
+            }
+        };
+        match change {
+            Change::AllowToDeny => report.allow_to_deny += 1,
+            Change::DenyToAllow => report.deny_to_allow += 1,
+            Change::NewError => report.new_errors += 1,
+            Change::FixedError => report.fixed_errors += 1,
+        }
+        report.differences.push(Difference {
+            source: case.source.clone(),
+            request_sha256: case.request_sha256.clone(),
+            change,
+            baseline: before,
+            candidate: after,
+        });
+    }
+
+    let fuel = &mut report.fuel;
+    if fuel.baseline_total > 0 {
+        fuel.change_percent = (fuel.candidate_total as f64 - fuel.baseline_total as f64) * 100.0
+            / fuel.baseline_total as f64;
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{temp_dir, DEFAULT_MODULE};
+    use std::fs;
+
+    /// A decision log line for `policy` whose `request` field is written
+    /// as `request`
+    fn entry(seq: u64, policy: &str, request: Option<&str>, reasons: &str) -> String {
+        let request = request.map_or(String::new(), |r| format!(r#""request": {}, "#, r));
+        format!(
+            r#"{{"seq":{seq},"timestamp_ms":0,"policy":"{policy}","policy_version":"v1","policy_sha256":"00","request_sha256":"sha-{seq}",{request}"allowed":false,"reasons":[{reasons}],"prev_hash":"","hash":""}}"#
+        )
+    }
+
+    #[test]
+    fn loads_requests_as_written() {
+        let dir = temp_dir("replay-corpus");
+
+        // Key order and whitespace survive; the example policy matches
+        // `"blocked":true` byte for byte
+        let array = dir.join("captured.json");
+        fs::write(
+            &array,
+            "[\n  {\"role\": \"viewer\", \"blocked\": true},\n  {\"b\":1,\"a\":2}\n]",
+        )
+        .unwrap();
+        let corpus = load_corpus(&array, None).unwrap();
+        let requests: Vec<&[u8]> = corpus.cases.iter().map(|c| &c.request[..]).collect();
+        assert_eq!(
+            requests,
+            [
+                &br#"{"role": "viewer", "blocked": true}"#[..],
+                br#"{"b":1,"a":2}"#
+            ]
+        );
+        assert_eq!(corpus.cases[1].source, format!("{}[1]", array.display()));
+        assert_eq!(
+            corpus.cases[1].request_sha256,
+            sha256_hex(br#"{"b":1,"a":2}"#)
+        );
+
+        let lines = dir.join("captured.jsonl");
+        fs::write(&lines, "{\"role\":\"admin\"}\n\n{\"blocked\": true}\n").unwrap();
+        let corpus = load_corpus(&lines, None).unwrap();
+        let sources: Vec<&str> = corpus.cases.iter().map(|c| c.source.as_str()).collect();
+        assert_eq!(
+            sources,
+            [
+                format!("{}:1", lines.display()),
+                format!("{}:3", lines.display())
+            ]
+        );
+        assert_eq!(corpus.cases[1].request, br#"{"blocked": true}"#);
+
+        let log = dir.join("decisions.log");
+        let entries = [
+            entry(
+                0,
+                "default",
+                Some(r#"{"role": "viewer", "blocked": true}"#),
+                r#""policy""#,
+            ),
+            entry(1, "other", Some(r#"{"role":"admin"}"#), r#""policy""#),
+            entry(2, "default", None, r#""policy""#),
+            entry(
+                3,
+                "default",
+                Some(&format!("{:?}", INVALID_REQUEST)),
+                r#""policy""#,
+            ),
+            entry(
+                4,
+                "default",
+                Some(r#"{"role":"admin"}"#),
+                r#""fuel_exhausted","fail_closed""#,
+            ),
+        ];
+        fs::write(&log, entries.join("\n")).unwrap();
+        let corpus = load_corpus(&log, Some("default")).unwrap();
+        assert_eq!(corpus.skipped, 3);
+        assert_eq!(corpus.cases.len(), 2);
+        assert_eq!(
+            corpus.cases[0].request,
+            br#"{"role": "viewer", "blocked": true}"#
+        );
+        assert_eq!(corpus.cases[0].request_sha256, "sha-0");
+        assert_eq!(
+            corpus.cases[0].source,
+            format!("{}:1 (seq 0)", log.display())
+        );
+        assert_eq!(corpus.cases[0].recorded, Some(false));
+        // Decided by the failure mode, so there is no decision to compare
+        assert_eq!(corpus.cases[1].recorded, None);
+        assert_eq!(load_corpus(&log, None).unwrap().cases.len(), 3);
+
+        fs::write(&lines, "{\"role\":\"admin\"}\n{not json\n").unwrap();
+        let error = load_corpus(&lines, None).unwrap_err().to_string();
+        assert!(
+            error.contains(&format!("{}:2: invalid JSON", lines.display())),
+            "{}",
+            error
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reports_every_kind_of_change() {
+        // The candidate blocks on `"private":true` instead of `"blocked":true`
+        // and runs out of fuel on long requests
+        let pattern = br#""blocked":true"#;
+        let at = DEFAULT_MODULE
+            .windows(pattern.len())
+            .position(|w| w == pattern)
+            .unwrap();
+        let mut candidate = DEFAULT_MODULE.to_vec();
+        candidate[at..at + pattern.len()].copy_from_slice(br#""private":true"#);
+        let baseline = PolicyRuntime::new(DEFAULT_MODULE).unwrap();
+        let candidate = PolicyRuntime::new(&candidate)
+            .unwrap()
+            .with_fuel_limit(20_000);
+
+        let long = format!(r#"{{"role":"viewer","padding":"{}"}}"#, "x".repeat(4096));
+        let requests = [
+            (r#"{"private":true}"#, None),
+            (r#"{"blocked":true}"#, None),
+            // Recorded when the baseline still denied admins
+            (r#"{"role":"admin"}"#, Some(false)),
+            (r#"{"blocked": true}"#, None),
+            (long.as_str(), None),
+        ];
+        let corpus = Corpus {
+            cases: requests
+                .iter()
+                .enumerate()
+                .map(|(i, (request, recorded))| ReplayCase {
+                    source: format!("case {}", i),
+                    request: request.as_bytes().to_vec(),
+                    request_sha256: sha256_hex(request.as_bytes()),
+                    recorded: *recorded,
+                })
+                .collect(),
+            skipped: 2,
+        };
+
+        let report = replay(&baseline, &candidate, &corpus);
+        assert_eq!(
+            (report.requests, report.skipped, report.unchanged),
+            (5, 2, 2)
+        );
+        assert_eq!((report.allow_to_deny, report.deny_to_allow), (1, 1));
+        assert_eq!((report.new_errors, report.fixed_errors), (1, 0));
+        assert_eq!(report.recorded_mismatches, 1);
+        assert!(report.has_regressions());
+        let changes: Vec<(&str, Change)> = report
+            .differences
+            .iter()
+            .map(|d| (d.source.as_str(), d.change))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                ("case 0", Change::AllowToDeny),
+                ("case 1", Change::DenyToAllow),
+                ("case 4", Change::NewError)
+            ]
+        );
+        let error = report.differences[2].candidate.error.as_deref().unwrap();
+        assert!(error.starts_with("fuel_exhausted: "), "{}", error);
+        assert_eq!(report.fuel.requests, 4);
+        assert!(report.fuel.baseline_total > 0);
+
+        // Swapped, the flips reverse and the error is fixed
+        let report = replay(&candidate, &baseline, &corpus);
+        assert_eq!((report.allow_to_deny, report.deny_to_allow), (1, 1));
+        assert_eq!((report.new_errors, report.fixed_errors), (0, 1));
+        assert_eq!(report.differences[2].change, Change::FixedError);
+
+        // Unchanged decisions are no regression
+        let report = replay(&baseline, &baseline, &corpus);
+        assert_eq!(report.unchanged, 5);
+        assert_eq!(report.fuel.change_percent, 0.0);
+        assert!(!report.has_regressions());
+    }
+}
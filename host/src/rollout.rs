@@ -0,0 +1,263 @@
+//! Shadow and canary evaluation of candidate policies
+//!
+//! A rollout pairs a policy with a candidate: another policy, loaded like any
+//! other (e.g. `default-next.wasm` next to `default.wasm`). In `shadow` mode
+//! every request decided by the policy is also evaluated by the candidate;
+//! the candidate's result is discarded, and disagreements are logged and
+//! counted. In `canary` mode `canary_percent` of the requests are decided by
+//! the candidate instead. The split counts requests rather than looking at
+//! them, so every `canary_percent` out of 100 go to the candidate no matter
+//! how often a client repeats or reformats a request; batches are routed as
+//! a whole and count once.
+//!
+//! Shadow evaluations run after the decision, and only when an evaluation
+//! slot is free, so they never delay or displace real traffic. Canary
+//! requests fall back to the policy while the candidate is not loaded.
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The `[rollout]` config section
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RolloutConfig {
+    /// Candidates keyed by the policy they may replace
+    pub policies: BTreeMap<String, RolloutRule>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RolloutRule {
+    /// Policy being tried out
+    pub candidate: String,
+    pub mode: RolloutMode,
+    /// Share of requests decided by the candidate in `canary` mode
+    #[serde(default)]
+    pub canary_percent: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RolloutMode {
+    /// Evaluate the candidate too and compare, deciding with the policy
+    Shadow,
+    /// Decide a share of the requests with the candidate
+    Canary,
+}
+
+/// How a shadow evaluation went
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ShadowResult {
+    Agree,
+    Disagree,
+    /// The candidate failed to evaluate
+    Error,
+    /// Not evaluated: the candidate was not loaded or no slot was free
+    Skipped,
+}
+
+impl ShadowResult {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ShadowResult::Agree => "agree",
+            ShadowResult::Disagree => "disagree",
+            ShadowResult::Error => "error",
+            ShadowResult::Skipped => "skipped",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    /// Keyed by (policy, candidate, result)
+    shadow: BTreeMap<(String, String, ShadowResult), u64>,
+    /// Keyed by (policy, candidate)
+    canary: BTreeMap<(String, String), u64>,
+}
+
+/// Rollout rules and their outcome counts
+#[derive(Default)]
+pub struct Rollout {
+    config: RolloutConfig,
+    /// Requests routed so far, keyed by policy in canary mode
+    routed: BTreeMap<String, AtomicU64>,
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl Rollout {
+    pub fn new(config: RolloutConfig) -> Self {
+        let routed = config
+            .policies
+            .iter()
+            .filter(|(_, rule)| rule.mode == RolloutMode::Canary)
+            .map(|(policy, _)| (policy.clone(), AtomicU64::new(0)))
+            .collect();
+        Self {
+            config,
+            routed,
+            counts: Arc::default(),
+        }
+    }
+
+    /// Candidate that `policy` is shadowed by, if any
+    pub fn shadow_for(&self, policy: &str) -> Option<&str> {
+        self.config
+            .policies
+            .get(policy)
+            .filter(|rule| rule.mode == RolloutMode::Shadow)
+            .map(|rule| rule.candidate.as_str())
+    }
+
+    /// Candidate that decides the next request (or batch) instead of
+    /// `policy`, if it falls into the canary share
+    ///
+    /// Request `n` goes to the candidate when `n * canary_percent / 100`
+    /// steps up, which spreads the share evenly over every 100 requests.
+    pub fn canary_for(&self, policy: &str) -> Option<&str> {
+        let rule = self
+            .config
+            .policies
+            .get(policy)
+            .filter(|rule| rule.mode == RolloutMode::Canary && rule.canary_percent > 0)?;
+        let n = self.routed.get(policy)?.fetch_add(1, Ordering::Relaxed);
+        let percent = u64::from(rule.canary_percent);
+        let steps_up = (n + 1) * percent / 100 > n * percent / 100;
+        steps_up.then_some(rule.candidate.as_str())
+    }
+
+    /// Where shadow results of `policy` against `candidate` are counted
+    pub fn shadow_recorder(&self, policy: &str, candidate: &str) -> ShadowRecorder {
+        ShadowRecorder {
+            policy: policy.to_string(),
+            candidate: candidate.to_string(),
+            counts: self.counts.clone(),
+        }
+    }
+
+    pub fn record_canary(&self, policy: &str, candidate: &str) {
+        *self
+            .counts
+            .lock()
+            .canary
+            .entry((policy.to_string(), candidate.to_string()))
+            .or_default() += 1;
+    }
+
+    /// Shadow evaluations by (policy, candidate, result)
+    pub fn shadow_counts(&self) -> Vec<(String, String, ShadowResult, u64)> {
+        self.counts
+            .lock()
+            .shadow
+            .iter()
+            .map(|((policy, candidate, result), count)| {
+                (policy.clone(), candidate.clone(), *result, *count)
+            })
+            .collect()
+    }
+
+    /// Canary decisions by (policy, candidate)
+    pub fn canary_counts(&self) -> Vec<(String, String, u64)> {
+        self.counts
+            .lock()
+            .canary
+            .iter()
+            .map(|((policy, candidate), count)| (policy.clone(), candidate.clone(), *count))
+            .collect()
+    }
+}
+
+/// Counts the outcome of shadow evaluations off the request path
+pub struct ShadowRecorder {
+    policy: String,
+    candidate: String,
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl ShadowRecorder {
+    pub fn record(&self, result: ShadowResult) {
+        *self
+            .counts
+            .lock()
+            .shadow
+            .entry((self.policy.clone(), self.candidate.clone(), result))
+            .or_default() += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy_store::PolicyStore;
+    use crate::test_support::activate_default;
+    use crate::AppState;
+
+    fn config(mode: RolloutMode, canary_percent: u8) -> RolloutConfig {
+        let rule = RolloutRule {
+            candidate: "default-next".to_string(),
+            mode,
+            canary_percent,
+        };
+        RolloutConfig {
+            policies: [("default".to_string(), rule)].into(),
+        }
+    }
+
+    #[test]
+    fn canary_share_matches_the_configured_percent() {
+        for percent in [0, 1, 10, 33, 50, 99, 100] {
+            let rollout = Rollout::new(config(RolloutMode::Canary, percent));
+            let routed = (0..1000)
+                .filter(|_| rollout.canary_for("default").is_some())
+                .count();
+            assert_eq!(routed, usize::from(percent) * 10, "{}%", percent);
+        }
+    }
+
+    #[test]
+    fn canary_share_is_spread_evenly() {
+        let rollout = Rollout::new(config(RolloutMode::Canary, 10));
+        let routed: Vec<bool> = (0..100)
+            .map(|_| rollout.canary_for("default").is_some())
+            .collect();
+        for window in routed.chunks(10) {
+            assert_eq!(window.iter().filter(|r| **r).count(), 1);
+        }
+    }
+
+    #[test]
+    fn only_canary_rules_route_requests() {
+        let rollout = Rollout::new(config(RolloutMode::Shadow, 100));
+        assert_eq!(rollout.canary_for("default"), None);
+        assert_eq!(rollout.shadow_for("default"), Some("default-next"));
+        let rollout = Rollout::new(config(RolloutMode::Canary, 100));
+        assert_eq!(rollout.canary_for("other"), None);
+        assert_eq!(rollout.shadow_for("default"), None);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn repeated_requests_are_split_by_the_percentage() {
+        let policies = PolicyStore::new(std::env::temp_dir());
+        activate_default(&policies, "default").await;
+        activate_default(&policies, "default-next").await;
+        let state =
+            AppState::new(policies, "default").with_rollout(config(RolloutMode::Canary, 25));
+
+        // A polling client sends the very same bytes every time
+        let mut by_candidate = 0;
+        for _ in 0..200 {
+            let decision = state.decide(None, br#"{"role":"viewer"}"#).await;
+            assert!(decision.failure.is_none());
+            if decision.policy == "default-next" {
+                by_candidate += 1;
+            }
+        }
+        assert_eq!(by_candidate, 50);
+        assert_eq!(
+            state.rollout.canary_counts(),
+            [("default".to_string(), "default-next".to_string(), 50)]
+        );
+    }
+}
@@ -0,0 +1,611 @@
+//! HTTP API
+//!
+//! Policy evaluation, reload, status and admin endpoints as an axum router
+//! that can run standalone or be nested into another app.
+
+use crate::admin;
+use crate::audit::identify_caller;
+use crate::ext_authz;
+use crate::forward_auth;
+use crate::metrics;
+use crate::decision::{BatchDecision, Decision, Failure};
+use crate::error::ConnectorError;
+use crate::failure::FailureMode;
+use crate::problem::Problem;
+use crate::status::ReloadSource;
+use crate::AppState;
+use axum::{
+    body::Bytes,
+    extract::{rejection::BytesRejection, DefaultBodyLimit, MatchedPath, Query, Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{any, get, post},
+    Json, Router,
+};
+use futures_util::Stream;
+use serde::Deserialize;
+use serde_json::{json, value::RawValue, Value};
+use shared::{BatchResponse, BatchResult, PolicyResponse};
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::Instrument;
+
+/// Default limit for `/evaluate` bodies; policies see far smaller requests
+pub const DEFAULT_MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// Default limit on requests per `/evaluate/batch` call
+pub const DEFAULT_MAX_BATCH_ITEMS: usize = 100;
+
+/// Build the HTTP API router
+pub fn router(state: Arc<AppState>) -> Router {
+    // Enforced while reading, so oversized bodies are never fully buffered
+    let body_limit = DefaultBodyLimit::max(state.max_body_bytes);
+
+    Router::new()
+        .route("/health", get(health_check))
+        .route("/evaluate", post(evaluate_policy).layer(body_limit))
+        .route("/evaluate/batch", post(evaluate_batch).layer(body_limit))
+        .route("/transform", post(transform_document).layer(body_limit))
+        .route("/auth", get(forward_auth::check))
+        .route("/ext_authz", any(ext_authz::http_check))
+        .route("/ext_authz/*path", any(ext_authz::http_check))
+        .route("/reload", post(reload_policy))
+        .route("/metrics", get(get_metrics))
+        .route("/policies", get(admin::list_policies))
+        .route("/policies/status", get(policy_status))
+        .route("/events", get(reload_events))
+        .route(
+            "/policies/:name",
+            axum::routing::put(admin::put_policy).delete(admin::delete_policy),
+        )
+        .route_layer(middleware::from_fn_with_state(state.clone(), identify_caller))
+        .route_layer(middleware::from_fn(trace_request))
+        .with_state(state)
+}
+
+/// Run each request in a span named after its route
+pub(crate) async fn trace_request(mut request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(request.uri().path(), MatchedPath::as_str)
+        .to_string();
+    // Forwarded upstream by the proxy, so its lines correlate too
+    let request_id = match request.headers().get(&REQUEST_ID) {
+        Some(value) if is_valid_request_id(value) => value.clone(),
+        _ => {
+            let value = HeaderValue::from_str(&new_request_id()).expect("hex is a valid header");
+            request.headers_mut().insert(REQUEST_ID, value.clone());
+            value
+        }
+    };
+    let span = tracing::info_span!(
+        "request",
+        request_id = request_id.to_str().unwrap_or_default(),
+        http.method = %request.method(),
+        http.route = %route,
+        http.status_code = tracing::field::Empty,
+    );
+    let mut response = next.run(request).instrument(span.clone()).await;
+    span.record("http.status_code", response.status().as_u16());
+    response.headers_mut().insert(REQUEST_ID, request_id);
+    response
+}
+
+/// Correlates the log lines of one request
+const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Caller-supplied IDs are kept if they are short printable ASCII
+fn is_valid_request_id(value: &HeaderValue) -> bool {
+    value
+        .to_str()
+        .is_ok_and(|id| !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic()))
+}
+
+/// 16 random hex digits
+fn new_request_id() -> String {
+    use std::hash::{BuildHasher, Hasher};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    // `RandomState` is randomly keyed; hashing a counter keeps IDs distinct
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u64(NEXT.fetch_add(1, Ordering::Relaxed));
+    format!("{:016x}", hasher.finish())
+}
+
+/// Health check endpoint
+async fn health_check() -> &'static str {
+    "OK"
+}
+
+#[derive(Deserialize)]
+struct EvaluateParams {
+    /// Named policy to evaluate; defaults to the default policy
+    policy: Option<String>,
+    /// Return the guest logs, if `logging.allow_debug` is set
+    #[serde(default)]
+    debug: bool,
+}
+
+/// Policy evaluation endpoint
+async fn evaluate_policy(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<EvaluateParams>,
+    body: Result<Bytes, BytesRejection>,
+) -> Result<Json<PolicyResponse>, Problem> {
+    let body = body.map_err(body_problem)?;
+    let decision = state.decide(params.policy.as_deref(), &body).await;
+    decision_response(&state, decision, params.debug && state.allow_debug)
+}
+
+#[derive(Deserialize)]
+struct TransformBody {
+    /// Policy request the document is released for, decided as sent
+    request: Box<RawValue>,
+    document: Value,
+}
+
+/// Data-plane endpoint: decide on `request`, then return `document` filtered
+/// by the policy's `transform_payload` (unchanged if it has none)
+async fn transform_document(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<EvaluateParams>,
+    body: Result<Bytes, BytesRejection>,
+) -> Result<Response, Problem> {
+    let body = body.map_err(body_problem)?;
+    let TransformBody { request, document } = serde_json::from_slice(&body).map_err(|e| {
+        let error = ConnectorError::InvalidRequest(format!(
+            "Expected {{\"request\": ..., \"document\": ...}}: {}",
+            e
+        ));
+        closed_problem(Problem::from_error(&error))
+    })?;
+
+    let decision = state
+        .decide(params.policy.as_deref(), request.get().as_bytes())
+        .await;
+    let headers = decision_headers(&decision);
+    if !decision.allowed {
+        let problem = match decision.failure {
+            None => Problem::new(
+                StatusCode::FORBIDDEN,
+                "access_denied",
+                "Document release denied by policy",
+            )
+            .with("allowed", false)
+            .with("policy_version", decision.policy_version),
+            Some(failure) => {
+                failure_problem(&state, &decision.policy, decision.policy_version, failure)
+            }
+        };
+        return Err(problem);
+    }
+
+    let request = serde_json::from_str(request.get()).unwrap_or_default();
+    let document = serde_json::to_vec(&document).unwrap_or_default();
+    // Filtered by the policy that decided, which may be a canary candidate
+    let policy = Some(decision.policy.as_str());
+    let document = match state.transform(policy, request, document.clone()).await {
+        Ok(transformed) => transformed.unwrap_or(document),
+        Err(e) => return Err(closed_problem(Problem::from_error(&e))),
+    };
+    Ok((headers, [(header::CONTENT_TYPE, "application/json")], document).into_response())
+}
+
+/// Batch evaluation endpoint: a JSON array of requests in, one result per
+/// request out, all decided by the same policy version
+async fn evaluate_batch(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<EvaluateParams>,
+    body: Result<Bytes, BytesRejection>,
+) -> Result<Json<BatchResponse>, Problem> {
+    let body = body.map_err(body_problem)?;
+    // Items are decided on the bytes sent, like `/evaluate` requests
+    let requests: Vec<&RawValue> = serde_json::from_slice(&body).map_err(|e| {
+        let error = ConnectorError::InvalidRequest(format!("Expected a JSON array of requests: {}", e));
+        closed_problem(Problem::from_error(&error))
+    })?;
+    if requests.len() > state.max_batch_items {
+        let error = ConnectorError::InvalidRequest(format!(
+            "Batch of {} requests exceeds the limit of {}",
+            requests.len(),
+            state.max_batch_items
+        ));
+        return Err(closed_problem(Problem::from_error(&error)));
+    }
+
+    let count = requests.len();
+    let requests = requests.iter().map(|r| r.get().as_bytes().to_vec()).collect();
+    let batch = state.decide_batch(params.policy.as_deref(), requests).await;
+    batch_response(&state, batch, count)
+}
+
+/// Render a batch for `/evaluate/batch`
+///
+/// Per-item errors are reported in the item. A failure of the whole batch
+/// (missing policy, overload) is rendered like a single `/evaluate`
+/// failure, except that fail-open repeats the allow for every item.
+fn batch_response(
+    state: &AppState,
+    batch: BatchDecision,
+    count: usize,
+) -> Result<Json<BatchResponse>, Problem> {
+    let Some(failure) = batch.failure else {
+        let results = batch
+            .decisions
+            .into_iter()
+            .map(|decision| match decision.failure {
+                None => BatchResult {
+                    allowed: decision.allowed,
+                    error: None,
+                    code: None,
+                    failure_mode: None,
+                },
+                Some(failure) => BatchResult {
+                    allowed: decision.allowed,
+                    error: Some(failure.error.to_string()),
+                    code: Some(failure.error.code().to_string()),
+                    failure_mode: Some(failure.mode),
+                },
+            })
+            .collect();
+        return Ok(Json(BatchResponse {
+            policy: batch.policy,
+            policy_version: batch.policy_version,
+            results,
+        }));
+    };
+
+    if failure.mode == FailureMode::Open {
+        let result = BatchResult {
+            allowed: true,
+            error: Some(failure.error.to_string()),
+            code: Some(failure.error.code().to_string()),
+            failure_mode: Some(FailureMode::Open),
+        };
+        return Ok(Json(BatchResponse {
+            policy: batch.policy,
+            policy_version: batch.policy_version,
+            results: vec![result; count],
+        }));
+    }
+
+    Err(failure_problem(state, &batch.policy, batch.policy_version, failure))
+}
+
+/// Mark a request-level problem as a denial
+fn closed_problem(problem: Problem) -> Problem {
+    let detail = problem.detail().to_string();
+    problem
+        .with("allowed", false)
+        .with("error", detail)
+        .with("failure_mode", serde_json::to_value(FailureMode::Closed).unwrap_or_default())
+}
+
+/// Problem for a body that could not be read, typically over the size limit
+fn body_problem(rejection: BytesRejection) -> Problem {
+    let problem = if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        Problem::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            rejection.body_text(),
+        )
+    } else {
+        Problem::new(rejection.status(), "invalid_request", rejection.body_text())
+    };
+    closed_problem(problem)
+}
+
+/// Render a decision for `/evaluate`
+///
+/// Fail-open errors are a 200 with `allowed: true`. Fail-closed errors are
+/// problem+json with a non-2xx status; the body still carries
+/// `allowed: false` so clients that only read the decision keep denying.
+/// With `debug`, successful responses carry the guest logs.
+fn decision_response(
+    state: &AppState,
+    decision: Decision,
+    debug: bool,
+) -> Result<Json<PolicyResponse>, Problem> {
+    let logs = debug.then_some(decision.logs);
+    let Some(failure) = decision.failure else {
+        return Ok(Json(PolicyResponse {
+            allowed: decision.allowed,
+            policy_version: decision.policy_version,
+            error: None,
+            failure_mode: None,
+            logs,
+        }));
+    };
+
+    if failure.mode == FailureMode::Open {
+        return Ok(Json(PolicyResponse {
+            allowed: true,
+            policy_version: decision.policy_version,
+            error: Some(failure.error.to_string()),
+            failure_mode: Some(FailureMode::Open),
+            logs,
+        }));
+    }
+
+    Err(failure_problem(state, &decision.policy, decision.policy_version, failure))
+}
+
+/// Problem for an evaluation that failed closed
+pub(crate) fn failure_problem(
+    state: &AppState,
+    policy: &str,
+    policy_version: String,
+    failure: Failure,
+) -> Problem {
+    let mut problem = Problem::from_error(&failure.error);
+    // Without its default policy the engine cannot serve at all
+    if matches!(failure.error, ConnectorError::PolicyNotFound(_)) && policy == state.default_policy
+    {
+        problem = problem.with_status(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    if let Some(class) = failure.class {
+        problem = problem.with("error_class", serde_json::to_value(class).unwrap_or_default());
+    }
+    let detail = problem.detail().to_string();
+    problem
+        .with("allowed", false)
+        .with("policy_version", policy_version)
+        .with("error", detail)
+        .with("failure_mode", serde_json::to_value(failure.mode).unwrap_or_default())
+}
+
+/// Decision headers for proxy integrations
+pub(crate) fn decision_headers(decision: &Decision) -> HeaderMap {
+    decision
+        .headers()
+        .into_iter()
+        .filter_map(|(name, value)| {
+            Some((HeaderName::from_static(name), HeaderValue::from_str(&value).ok()?))
+        })
+        .collect()
+}
+
+/// Force policy reload endpoint
+async fn reload_policy(State(state): State<Arc<AppState>>) -> Result<Json<Value>, Problem> {
+    match state
+        .policies
+        .load_from_disk(&state.default_policy, ReloadSource::Manual)
+        .await {
+        Ok(policy) => {
+            tracing::info!(policy = state.default_policy.as_str(), "Policy manually reloaded");
+            Ok(Json(json!({
+                "success": true,
+                "message": "Policy reloaded successfully",
+                "size_bytes": policy.size_bytes,
+                "policy_version": policy.version
+            })))
+        }
+        Err(e) => Err(Problem::from_error(&e)
+            .with("success", false)
+            .with("policy", state.default_policy.as_str())),
+    }
+}
+
+/// Reload status of every policy
+async fn policy_status(State(state): State<Arc<AppState>>) -> Json<Value> {
+    let policies = state.policies.status().snapshot();
+    let healthy = !policies.values().any(|p| p.is_failing());
+
+    Json(json!({
+        "healthy": healthy,
+        "default_policy": state.default_policy,
+        "policies": policies
+    }))
+}
+
+/// Server-Sent Events stream of reload, rollback and failure events
+async fn reload_events(
+    State(state): State<Arc<AppState>>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = state.policies.status().subscribe();
+
+    let stream = futures_util::stream::unfold(events, |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    let sse = Event::default()
+                        .event(event.kind())
+                        .json_data(&event)
+                        .unwrap_or_default();
+                    return Some((Ok(sse), events));
+                }
+                // A slow client missed some events; carry on with newer ones
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Runtime metrics endpoint
+///
+/// Prometheus text format, or the JSON summary for `Accept: application/json`.
+async fn get_metrics(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    let wants_json = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"));
+    if !wants_json {
+        let body = metrics::render(&state).await;
+        return ([(header::CONTENT_TYPE, metrics::CONTENT_TYPE)], body).into_response();
+    }
+
+    // Get process memory info (platform-specific)
+    let memory_kb = metrics::resident_memory_kb();
+
+    Json(json!({
+        "memory_kb": memory_kb,
+        "memory_mb": memory_kb as f64 / 1024.0,
+        "target_mb": 10,
+        "within_target": memory_kb < 10 * 1024,
+        "evaluations": state.admission.stats(),
+        "cache": state.policies.decision_cache().stats()
+    }))
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_client;
+    use crate::policy_runtime::{DEFAULT_FUEL_LIMIT, DEFAULT_TRANSFORM_FUEL_LIMIT};
+    use crate::policy_store::{LoadedPolicy, PolicyStore};
+    use crate::rollout::RolloutConfig;
+    use crate::test_support::{activate_default, serve, temp_dir, DEFAULT_MODULE};
+    use axum::http::Method;
+
+    /// POST `body` to `path` on the server at `addr`
+    async fn post(addr: std::net::SocketAddr, path: &str, body: &str) -> (StatusCode, Value) {
+        let client = http_client::new_client();
+        let uri = format!("http://{}{}", addr, path).parse().unwrap();
+        let body = Bytes::copy_from_slice(body.as_bytes());
+        let response = http_client::send(&client, Method::POST, &uri, &[], body)
+            .await
+            .unwrap();
+        (
+            response.status,
+            serde_json::from_slice(&response.body).unwrap(),
+        )
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn batch_reports_errors_per_item() {
+        // Enough fuel to scan a short request, not a long one
+        let policies = PolicyStore::new(temp_dir("batch-items"));
+        let policy = LoadedPolicy::compile(
+            "default",
+            DEFAULT_MODULE,
+            None,
+            20_000,
+            DEFAULT_TRANSFORM_FUEL_LIMIT,
+        )
+        .unwrap();
+        policies.activate(policy).await;
+        let long = json!({ "role": "viewer", "padding": "x".repeat(4096) });
+        let body = json!([{ "role": "admin" }, long, { "blocked": true }]).to_string();
+
+        let (addr, _server) = serve(router(Arc::new(AppState::new(policies, "default")))).await;
+        let (status, batch) = post(addr, "/evaluate/batch", &body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(batch["policy"], "default");
+        let results = batch["results"].as_array().unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0], json!({ "allowed": true }));
+        assert_eq!(results[1]["allowed"], false);
+        assert_eq!(results[1]["code"], "fuel_exhausted");
+        assert_eq!(results[1]["failure_mode"], "closed");
+        assert_eq!(results[2], json!({ "allowed": false }));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn batch_size_is_limited() {
+        let policies = PolicyStore::new(temp_dir("batch-limit"));
+        activate_default(&policies, "default").await;
+        let state = AppState::new(policies, "default").with_max_batch_items(2);
+        let (addr, _server) = serve(router(Arc::new(state))).await;
+
+        let (status, batch) = post(addr, "/evaluate/batch", r#"[{"role":"admin"},{}]"#).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(batch["results"].as_array().unwrap().len(), 2);
+
+        let (status, problem) = post(addr, "/evaluate/batch", r#"[{"role":"admin"},{},{}]"#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(problem["code"], "invalid_request");
+        assert!(problem["detail"].as_str().unwrap().contains("limit of 2"));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn batch_items_are_decided_as_sent() {
+        let policies = PolicyStore::new(temp_dir("batch-raw"));
+        activate_default(&policies, "default").await;
+        let (addr, _server) = serve(router(Arc::new(AppState::new(policies, "default")))).await;
+
+        // The example policy matches `"blocked":true` byte for byte, so
+        // re-serializing either request would change its decision
+        for request in [
+            r#"{"role": "viewer", "blocked": true}"#,
+            r#"{"z":1,"blocked":true}"#,
+        ] {
+            let (status, single) = post(addr, "/evaluate", request).await;
+            assert_eq!(status, StatusCode::OK);
+            let (status, batch) = post(addr, "/evaluate/batch", &format!("[{}]", request)).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(
+                batch["results"][0]["allowed"], single["allowed"],
+                "{}",
+                request
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn transform_uses_the_canary_that_decided() {
+        // The candidate masks serials with `###` instead of `***`
+        let (mask, canary_mask) = (b"\"***\"", b"\"###\"");
+        let at = DEFAULT_MODULE
+            .windows(mask.len())
+            .position(|w| w == mask)
+            .unwrap();
+        let mut candidate = DEFAULT_MODULE.to_vec();
+        candidate[at..at + mask.len()].copy_from_slice(canary_mask);
+
+        let policies = PolicyStore::new(temp_dir("transform-canary"));
+        activate_default(&policies, "default").await;
+        let policy = LoadedPolicy::compile(
+            "default-next",
+            &candidate,
+            None,
+            DEFAULT_FUEL_LIMIT,
+            DEFAULT_TRANSFORM_FUEL_LIMIT,
+        )
+        .unwrap();
+        policies.activate(policy).await;
+        let rollout: RolloutConfig = toml::from_str(
+            r#"
+            [policies.default]
+            candidate = "default-next"
+            mode = "canary"
+            canary_percent = 100
+            "#,
+        )
+        .unwrap();
+        let state = AppState::new(policies, "default").with_rollout(rollout);
+        let (addr, _server) = serve(router(Arc::new(state))).await;
+
+        let body = r#"{"request":{"role":"viewer"},"document":{"serial":"A-1"}}"#;
+        let (status, document) = post(addr, "/transform", body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(document, json!({ "serial": "###" }));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn transform_decides_the_request_as_sent() {
+        let policies = PolicyStore::new(temp_dir("transform-raw"));
+        activate_default(&policies, "default").await;
+        let (addr, _server) = serve(router(Arc::new(AppState::new(policies, "default")))).await;
+
+        // Allowed on `/evaluate`; `"blocked":true` without the space is not
+        let request = r#"{"role": "viewer", "blocked": true}"#;
+        let (_, single) = post(addr, "/evaluate", request).await;
+        assert_eq!(single["allowed"], true);
+        let body = format!(
+            r#"{{"request": {}, "document": {{"temp": 21.4}}}}"#,
+            request
+        );
+        let (status, document) = post(addr, "/transform", &body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(document, json!({ "temp": 21.4 }));
+    }
+}
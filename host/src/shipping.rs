@@ -0,0 +1,671 @@
+//! Store-and-forward shipping of the decision log
+//!
+//! With `shipping.url` set, every decision log entry is also appended to a
+//! batch in `shipping.spool_dir`. A batch is closed once it holds
+//! `shipping.batch_max_entries` entries or is `shipping.batch_max_age_ms`
+//! old, and a background task POSTs closed batches, oldest first, to the
+//! collector as JSON lines. A batch is deleted only after the collector
+//! answered 2xx; failed uploads are retried with exponential backoff, so an
+//! offline device keeps its decisions until it reconnects and survives
+//! restarts meanwhile.
+//!
+//! The spool is bounded by `shipping.max_spool_bytes`: past it the oldest
+//! batches are dropped, and only the local decision log still has them. A
+//! batch is delivered again if the collector's answer is lost, so collectors
+//! should deduplicate entries on their `hash`.
+
+use crate::error::{ConnectorError, ConnectorResult};
+use crate::http_client::{self, HttpClient};
+use axum::body::Bytes;
+use axum::http::{Method, Uri};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+pub const DEFAULT_MAX_SPOOL_BYTES: u64 = 16 * 1024 * 1024;
+pub const DEFAULT_BATCH_MAX_ENTRIES: u64 = 500;
+pub const DEFAULT_BATCH_MAX_AGE: Duration = Duration::from_secs(10);
+
+/// File of the batch being filled; closed batches are `<id>.ndjson`
+const OPEN_BATCH: &str = "open.ndjson";
+const BATCH_EXTENSION: &str = "ndjson";
+
+/// The `[shipping]` config section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShippingConfig {
+    /// Collector that batches are POSTed to; `None` disables shipping
+    pub url: Option<String>,
+    /// Bearer token sent to the collector; refused over plain HTTP to
+    /// anything but a loopback address
+    pub token: Option<String>,
+    /// Batches waiting to be shipped
+    pub spool_dir: PathBuf,
+    /// Drop the oldest batches once the spool grows past this
+    pub max_spool_bytes: u64,
+    /// Close a batch once it holds this many entries
+    pub batch_max_entries: u64,
+    /// Close a batch once its first entry is this old
+    pub batch_max_age_ms: u64,
+    /// Delay before retrying a failed upload, doubled after each failure
+    pub min_backoff_ms: u64,
+    /// Longest delay between retries
+    pub max_backoff_ms: u64,
+}
+
+impl Default for ShippingConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            token: None,
+            spool_dir: PathBuf::from("./decision-spool"),
+            max_spool_bytes: DEFAULT_MAX_SPOOL_BYTES,
+            batch_max_entries: DEFAULT_BATCH_MAX_ENTRIES,
+            batch_max_age_ms: DEFAULT_BATCH_MAX_AGE.as_millis() as u64,
+            min_backoff_ms: 1000,
+            max_backoff_ms: 5 * 60 * 1000,
+        }
+    }
+}
+
+/// A closed batch waiting for upload
+#[derive(Debug, Clone)]
+struct Batch {
+    id: u64,
+    path: PathBuf,
+    bytes: u64,
+    entries: u64,
+}
+
+struct OpenBatch {
+    file: File,
+    entries: u64,
+    bytes: u64,
+    opened: Instant,
+}
+
+struct Queue {
+    open: Option<OpenBatch>,
+    /// Oldest first
+    closed: VecDeque<Batch>,
+    next_id: u64,
+    /// Size of the open and closed batches
+    bytes: u64,
+}
+
+/// Point-in-time spool counters
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpoolStats {
+    /// Entries waiting to be shipped
+    pub pending_entries: u64,
+    pub pending_bytes: u64,
+    /// Entries acknowledged by the collector since startup
+    pub shipped: u64,
+    /// Entries dropped from the full spool since startup
+    pub dropped: u64,
+    /// Failed uploads since startup
+    pub failures: u64,
+}
+
+/// Durable queue of decision log batches
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    batch_max_entries: u64,
+    batch_max_age: Duration,
+    queue: Mutex<Queue>,
+    /// Signalled when a batch is closed
+    ready: Notify,
+    shipped: AtomicU64,
+    dropped: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl Spool {
+    /// Open the spool in `shipping.spool_dir`, picking up batches left by a
+    /// previous run
+    pub fn open(config: &ShippingConfig) -> ConnectorResult<Self> {
+        Self::open_dir(config).map_err(|e| {
+            ConnectorError::ConfigError(format!(
+                "shipping.spool_dir {}: {}",
+                config.spool_dir.display(),
+                e
+            ))
+        })
+    }
+
+    fn open_dir(config: &ShippingConfig) -> io::Result<Self> {
+        let dir = config.spool_dir.clone();
+        fs::create_dir_all(&dir)?;
+
+        let mut closed = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let id = path
+                .extension()
+                .filter(|ext| *ext == BATCH_EXTENSION)
+                .and(path.file_stem())
+                .and_then(|stem| stem.to_str()?.parse::<u64>().ok());
+            if let Some(id) = id {
+                let contents = fs::read(&path)?;
+                closed.push(Batch {
+                    id,
+                    bytes: contents.len() as u64,
+                    entries: count_lines(&contents),
+                    path,
+                });
+            }
+        }
+        closed.sort_by_key(|batch| batch.id);
+
+        let open = recover_open_batch(&dir.join(OPEN_BATCH))?;
+        let bytes = closed.iter().map(|batch| batch.bytes).sum::<u64>()
+            + open.as_ref().map_or(0, |open| open.bytes);
+        let queue = Queue {
+            open,
+            next_id: closed.last().map_or(0, |batch| batch.id + 1),
+            closed: closed.into(),
+            bytes,
+        };
+        if !queue.closed.is_empty() || queue.open.is_some() {
+            tracing::info!(
+                dir = %dir.display(),
+                batches = queue.closed.len(),
+                bytes = queue.bytes,
+                "Resuming decision log shipping"
+            );
+        }
+
+        Ok(Self {
+            dir,
+            max_bytes: config.max_spool_bytes,
+            batch_max_entries: config.batch_max_entries,
+            batch_max_age: Duration::from_millis(config.batch_max_age_ms),
+            queue: Mutex::new(queue),
+            ready: Notify::new(),
+            shipped: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+        })
+    }
+
+    pub fn stats(&self) -> SpoolStats {
+        let queue = self.queue.lock();
+        SpoolStats {
+            pending_entries: queue.closed.iter().map(|batch| batch.entries).sum::<u64>()
+                + queue.open.as_ref().map_or(0, |open| open.entries),
+            pending_bytes: queue.bytes,
+            shipped: self.shipped.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Append one decision log line to the open batch
+    pub fn push(&self, line: &[u8]) -> io::Result<()> {
+        let mut guard = self.queue.lock();
+        let queue = &mut *guard;
+        let open = match &mut queue.open {
+            Some(open) => open,
+            None => queue.open.insert(OpenBatch {
+                file: OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.dir.join(OPEN_BATCH))?,
+                entries: 0,
+                bytes: 0,
+                opened: Instant::now(),
+            }),
+        };
+        if let Err(e) = open.file.write_all(line) {
+            // Don't leave half a line for the next one to be appended to
+            let _ = open.file.set_len(open.bytes);
+            return Err(e);
+        }
+        open.entries += 1;
+        open.bytes += line.len() as u64;
+        queue.bytes += line.len() as u64;
+
+        if open.entries >= self.batch_max_entries {
+            self.close(queue)?;
+        }
+        self.enforce_limit(queue);
+        Ok(())
+    }
+
+    /// Close the open batch if it is due; otherwise how long until it is
+    fn close_expired(&self) -> Option<Duration> {
+        let mut queue = self.queue.lock();
+        let age = queue.open.as_ref()?.opened.elapsed();
+        if age < self.batch_max_age {
+            return Some(self.batch_max_age - age);
+        }
+        if let Err(e) = self.close(&mut queue) {
+            tracing::warn!(error = %e, "Failed to close decision log batch");
+        }
+        None
+    }
+
+    fn close(&self, queue: &mut Queue) -> io::Result<()> {
+        let Some(open) = &queue.open else {
+            return Ok(());
+        };
+        open.file.sync_all()?;
+        let batch = Batch {
+            id: queue.next_id,
+            path: self
+                .dir
+                .join(format!("{:020}.{}", queue.next_id, BATCH_EXTENSION)),
+            bytes: open.bytes,
+            entries: open.entries,
+        };
+        fs::rename(self.dir.join(OPEN_BATCH), &batch.path)?;
+
+        queue.open = None;
+        queue.next_id += 1;
+        queue.closed.push_back(batch);
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    /// Drop the oldest closed batches until the spool fits
+    fn enforce_limit(&self, queue: &mut Queue) {
+        while queue.bytes > self.max_bytes {
+            let Some(batch) = queue.closed.pop_front() else {
+                break;
+            };
+            if let Err(e) = remove_if_exists(&batch.path) {
+                tracing::warn!(path = %batch.path.display(), error = %e, "Failed to delete decision log batch");
+            }
+            queue.bytes -= batch.bytes;
+            self.dropped.fetch_add(batch.entries, Ordering::Relaxed);
+            tracing::warn!(
+                batch = batch.id,
+                entries = batch.entries,
+                "Decision log spool full, dropped oldest batch"
+            );
+        }
+    }
+
+    fn oldest(&self) -> Option<Batch> {
+        self.queue.lock().closed.front().cloned()
+    }
+
+    /// Delete a batch the collector accepted
+    ///
+    /// The batch leaves the queue even if its file cannot be deleted, so it
+    /// is not uploaded again until a restart finds the file.
+    fn ack(&self, batch: &Batch) -> io::Result<()> {
+        if !self.take(batch) {
+            return Ok(());
+        }
+        self.shipped.fetch_add(batch.entries, Ordering::Relaxed);
+        remove_if_exists(&batch.path)
+    }
+
+    /// Take `batch` out of the queue; `false` if it was already dropped
+    fn take(&self, batch: &Batch) -> bool {
+        let mut queue = self.queue.lock();
+        let Some(index) = queue.closed.iter().position(|b| b.id == batch.id) else {
+            return false;
+        };
+        queue.closed.remove(index);
+        queue.bytes -= batch.bytes;
+        true
+    }
+}
+
+/// Reopen the batch a previous run was filling, without a torn final line
+fn recover_open_batch(path: &Path) -> io::Result<Option<OpenBatch>> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let complete = contents
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(0, |i| i + 1);
+    if complete < contents.len() {
+        tracing::warn!(
+            path = %path.display(),
+            bytes = contents.len() - complete,
+            "Dropping torn decision log batch entry"
+        );
+    }
+    if complete == 0 {
+        fs::remove_file(path)?;
+        return Ok(None);
+    }
+    let file = OpenOptions::new().append(true).open(path)?;
+    file.set_len(complete as u64)?;
+    Ok(Some(OpenBatch {
+        file,
+        entries: count_lines(&contents[..complete]),
+        bytes: complete as u64,
+        opened: Instant::now(),
+    }))
+}
+
+fn count_lines(contents: &[u8]) -> u64 {
+    contents.iter().filter(|b| **b == b'\n').count() as u64
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Upload closed batches to the collector, oldest first, until the process
+/// exits
+pub async fn ship_batches(spool: Arc<Spool>, url: Uri, config: ShippingConfig) {
+    let client = http_client::new_client();
+    let authorization = config
+        .token
+        .as_ref()
+        .map(|token| format!("Bearer {}", token));
+    let min_backoff = Duration::from_millis(config.min_backoff_ms);
+    let max_backoff = Duration::from_millis(config.max_backoff_ms);
+    let mut backoff = min_backoff;
+
+    tracing::info!(%url, dir = %spool.dir.display(), "Shipping decision log");
+
+    loop {
+        let wait = spool.close_expired();
+        let Some(batch) = spool.oldest() else {
+            tokio::select! {
+                _ = spool.ready.notified() => {}
+                _ = tokio::time::sleep(wait.unwrap_or(spool.batch_max_age)) => {}
+            }
+            continue;
+        };
+
+        let result = match fs::read(&batch.path) {
+            Ok(body) => upload(&client, &url, authorization.as_deref(), body).await,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                // Dropped to make room, or deleted by hand
+                if spool.take(&batch) {
+                    spool.dropped.fetch_add(batch.entries, Ordering::Relaxed);
+                    tracing::warn!(batch = batch.id, "Decision log batch disappeared");
+                }
+                continue;
+            }
+            Err(e) => Err(e.to_string()),
+        };
+
+        match result {
+            Ok(()) => {
+                tracing::debug!(
+                    batch = batch.id,
+                    entries = batch.entries,
+                    "Shipped decision log batch"
+                );
+                if let Err(e) = spool.ack(&batch) {
+                    tracing::warn!(batch = batch.id, error = %e, "Failed to delete shipped batch");
+                }
+                backoff = min_backoff;
+            }
+            Err(e) => {
+                spool.failures.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(
+                    batch = batch.id,
+                    error = %e,
+                    retry_in_ms = backoff.as_millis() as u64,
+                    "Failed to ship decision log batch"
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(max_backoff);
+            }
+        }
+    }
+}
+
+async fn upload(
+    client: &HttpClient,
+    url: &Uri,
+    authorization: Option<&str>,
+    body: Vec<u8>,
+) -> Result<(), String> {
+    let mut headers = vec![("content-type", "application/x-ndjson")];
+    if let Some(authorization) = authorization {
+        headers.push(("authorization", authorization));
+    }
+
+    let response = http_client::send(client, Method::POST, url, &headers, Bytes::from(body))
+        .await
+        .map_err(|e| match e {
+            ConnectorError::RegistryError(msg) => msg,
+            e => e.to_string(),
+        })?;
+    if !response.status.is_success() {
+        return Err(format!("Collector returned {}", response.status));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{serve, temp_dir};
+    use axum::extract::State;
+    use axum::http::{header, HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::Router;
+
+    /// What the mock collector answers and what it received
+    #[derive(Default)]
+    struct Collector {
+        /// Answered, in order, before the collector starts accepting
+        failures: VecDeque<StatusCode>,
+        /// Authorization header and body of every upload
+        uploads: Vec<(Option<String>, Vec<u8>)>,
+    }
+
+    type Shared = Arc<Mutex<Collector>>;
+
+    async fn collect(
+        State(collector): State<Shared>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        let mut collector = collector.lock();
+        let authorization = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        collector.uploads.push((authorization, body.to_vec()));
+        collector.failures.pop_front().unwrap_or(StatusCode::OK)
+    }
+
+    async fn start(collector: &Shared) -> Uri {
+        let router = Router::new()
+            .route("/decisions", post(collect))
+            .with_state(collector.clone());
+        let (addr, _server) = serve(router).await;
+        format!("http://{}/decisions", addr).parse().unwrap()
+    }
+
+    fn config(name: &str) -> ShippingConfig {
+        ShippingConfig {
+            spool_dir: temp_dir(name),
+            batch_max_entries: 2,
+            batch_max_age_ms: 60_000,
+            min_backoff_ms: 50,
+            max_backoff_ms: 1000,
+            ..ShippingConfig::default()
+        }
+    }
+
+    fn line(n: u64) -> Vec<u8> {
+        format!("{{\"n\":{}}}\n", n).into_bytes()
+    }
+
+    fn lines(range: std::ops::Range<u64>) -> Vec<u8> {
+        range.flat_map(line).collect()
+    }
+
+    /// Wait until `done` holds for the stats of `spool`, failing after a
+    /// few seconds
+    async fn wait_for(spool: &Spool, done: impl Fn(SpoolStats) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(spool.stats()) {
+            assert!(Instant::now() < deadline, "{:?}", spool.stats());
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    fn ship(spool: &Arc<Spool>, url: Uri, config: &ShippingConfig) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(ship_batches(spool.clone(), url, config.clone()))
+    }
+
+    fn batch_files(dir: &Path) -> Vec<String> {
+        let mut files: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn deletes_batches_once_the_collector_acknowledges_them() {
+        let collector = Shared::default();
+        let url = start(&collector).await;
+        let config = ShippingConfig {
+            token: Some("secret".to_string()),
+            ..config("shipping-ack")
+        };
+        let spool = Arc::new(Spool::open(&config).unwrap());
+        for n in 0..4 {
+            spool.push(&line(n)).unwrap();
+        }
+        assert_eq!(batch_files(&config.spool_dir).len(), 2);
+
+        let shipper = ship(&spool, url, &config);
+        wait_for(&spool, |stats| stats.shipped == 4).await;
+        shipper.abort();
+        let auth = Some("Bearer secret".to_string());
+        assert_eq!(
+            collector.lock().uploads,
+            [(auth.clone(), lines(0..2)), (auth, lines(2..4))]
+        );
+        assert!(batch_files(&config.spool_dir).is_empty());
+        let stats = spool.stats();
+        assert_eq!((stats.pending_entries, stats.pending_bytes), (0, 0));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn retries_with_backoff_after_server_errors() {
+        let collector = Shared::default();
+        collector.lock().failures = [
+            StatusCode::SERVICE_UNAVAILABLE,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ]
+        .into();
+        let url = start(&collector).await;
+        let config = config("shipping-retry");
+        let spool = Arc::new(Spool::open(&config).unwrap());
+        spool.push(&line(0)).unwrap();
+        spool.push(&line(1)).unwrap();
+
+        let started = Instant::now();
+        let shipper = ship(&spool, url, &config);
+        wait_for(&spool, |stats| stats.failures == 1).await;
+        // A rejected batch is kept
+        assert_eq!(batch_files(&config.spool_dir).len(), 1);
+        assert_eq!(spool.stats().pending_entries, 2);
+
+        wait_for(&spool, |stats| stats.shipped == 2).await;
+        shipper.abort();
+        // Waited 50ms after the first failure, 100ms after the second
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert_eq!(spool.stats().failures, 2);
+        let uploads = &collector.lock().uploads;
+        assert_eq!(uploads.len(), 3);
+        assert!(uploads.iter().all(|(_, body)| *body == lines(0..2)));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn resumes_open_and_closed_batches_after_a_restart() {
+        let config = config("shipping-restart");
+        let spool = Spool::open(&config).unwrap();
+        for n in 0..3 {
+            spool.push(&line(n)).unwrap();
+        }
+        drop(spool);
+        // A crash mid-append leaves a torn line in the open batch
+        let mut open = OpenOptions::new()
+            .append(true)
+            .open(config.spool_dir.join(OPEN_BATCH))
+            .unwrap();
+        open.write_all(b"{\"n\":").unwrap();
+
+        let spool = Arc::new(Spool::open(&config).unwrap());
+        let stats = spool.stats();
+        assert_eq!(stats.pending_entries, 3);
+        assert_eq!(stats.pending_bytes, lines(0..3).len() as u64);
+        // The recovered open batch keeps filling and closes after the old one
+        spool.push(&line(3)).unwrap();
+
+        let collector = Shared::default();
+        let url = start(&collector).await;
+        let shipper = ship(&spool, url, &config);
+        wait_for(&spool, |stats| stats.shipped == 4).await;
+        shipper.abort();
+        let bodies: Vec<Vec<u8>> = collector
+            .lock()
+            .uploads
+            .iter()
+            .map(|(_, b)| b.clone())
+            .collect();
+        assert_eq!(bodies, [lines(0..2), lines(2..4)]);
+    }
+
+    #[test]
+    fn drops_the_oldest_batch_when_the_spool_is_full() {
+        let line_bytes = line(0).len() as u64;
+        let config = ShippingConfig {
+            batch_max_entries: 1,
+            max_spool_bytes: line_bytes * 5 / 2,
+            ..config("shipping-full")
+        };
+        let spool = Spool::open(&config).unwrap();
+        for n in 0..3 {
+            spool.push(&line(n)).unwrap();
+        }
+
+        let stats = spool.stats();
+        assert_eq!((stats.pending_entries, stats.dropped), (2, 1));
+        assert_eq!(stats.pending_bytes, 2 * line_bytes);
+        assert_eq!(spool.oldest().unwrap().id, 1);
+        assert_eq!(
+            batch_files(&config.spool_dir),
+            [format!("{:020}.ndjson", 1), format!("{:020}.ndjson", 2)]
+        );
+    }
+
+    #[test]
+    fn acknowledged_batches_leave_the_queue_even_if_they_cannot_be_deleted() {
+        let config = config("shipping-undeletable");
+        let spool = Spool::open(&config).unwrap();
+        spool.push(&line(0)).unwrap();
+        spool.push(&line(1)).unwrap();
+        let batch = spool.oldest().unwrap();
+        // Something the batch file cannot be removed as
+        fs::remove_file(&batch.path).unwrap();
+        fs::create_dir_all(batch.path.join("stuck")).unwrap();
+
+        assert!(spool.ack(&batch).is_err());
+        assert!(spool.oldest().is_none());
+        assert_eq!(spool.stats().shipped, 2);
+    }
+}
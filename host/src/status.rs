@@ -0,0 +1,335 @@
+//! Reload lifecycle tracking
+//!
+//! Every attempt to (re)load a policy is recorded per policy name and
+//! published on a broadcast channel, which backs `GET /policies/status` and
+//! the `GET /events` Server-Sent Events stream.
+
+use crate::error::ConnectorError;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Events buffered for slow SSE subscribers before they start lagging
+const EVENT_CHANNEL_CAPACITY: usize = 64;
+
+/// What triggered a reload attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReloadSource {
+    Startup,
+    Watcher,
+    Manual,
+    Admin,
+    Registry,
+    Oci,
+}
+
+/// A reload lifecycle event
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ReloadEvent {
+    /// A new policy version was activated
+    Reload {
+        policy: String,
+        version: String,
+        source: ReloadSource,
+        timestamp: u64,
+    },
+    /// A reload attempt failed
+    Failure {
+        policy: String,
+        source: ReloadSource,
+        error: String,
+        timestamp: u64,
+    },
+    /// After a failure, the previous version remains active
+    Rollback {
+        policy: String,
+        version: String,
+        source: ReloadSource,
+        timestamp: u64,
+    },
+}
+
+impl ReloadEvent {
+    /// SSE event name
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ReloadEvent::Reload { .. } => "reload",
+            ReloadEvent::Failure { .. } => "failure",
+            ReloadEvent::Rollback { .. } => "rollback",
+        }
+    }
+}
+
+/// Reload history of a single policy
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PolicyStatus {
+    pub current_version: Option<String>,
+    pub last_attempt: Option<u64>,
+    pub last_success: Option<u64>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<u64>,
+    pub last_source: Option<ReloadSource>,
+    pub consecutive_failures: u32,
+    /// Successful (re)loads since the policy was first seen
+    pub reloads: u64,
+    /// Failed reload attempts since the policy was first seen
+    pub reload_failures: u64,
+}
+
+impl PolicyStatus {
+    /// The most recent attempt failed
+    pub fn is_failing(&self) -> bool {
+        self.consecutive_failures > 0
+    }
+}
+
+/// Reload status for all policies plus the event broadcaster
+pub struct ReloadStatus {
+    policies: Mutex<BTreeMap<String, PolicyStatus>>,
+    events: broadcast::Sender<ReloadEvent>,
+}
+
+impl Default for ReloadStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReloadStatus {
+    pub fn new() -> Self {
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Self {
+            policies: Mutex::new(BTreeMap::new()),
+            events,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ReloadEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, PolicyStatus> {
+        self.policies.lock().clone()
+    }
+
+    pub fn record_success(&self, policy: &str, version: &str, source: ReloadSource) {
+        let now = unix_now();
+        {
+            let mut policies = self.policies.lock();
+            let status = policies.entry(policy.to_string()).or_default();
+            status.current_version = Some(version.to_string());
+            status.last_attempt = Some(now);
+            status.last_success = Some(now);
+            status.last_source = Some(source);
+            status.consecutive_failures = 0;
+            status.reloads += 1;
+        }
+
+        self.publish(ReloadEvent::Reload {
+            policy: policy.to_string(),
+            version: version.to_string(),
+            source,
+            timestamp: now,
+        });
+    }
+
+    /// Record a failed reload of `policy`
+    ///
+    /// A name that never loaded only gets the event: an entry for it would
+    /// report unhealthy with nothing serving, and nothing could clear it.
+    pub fn record_failure(&self, policy: &str, error: &ConnectorError, source: ReloadSource) {
+        let now = unix_now();
+        let current_version = {
+            let mut policies = self.policies.lock();
+            policies.get_mut(policy).and_then(|status| {
+                status.last_attempt = Some(now);
+                status.last_error = Some(error.to_string());
+                status.last_error_at = Some(now);
+                status.last_source = Some(source);
+                status.consecutive_failures += 1;
+                status.reload_failures += 1;
+                status.current_version.clone()
+            })
+        };
+
+        self.publish(ReloadEvent::Failure {
+            policy: policy.to_string(),
+            source,
+            error: error.to_string(),
+            timestamp: now,
+        });
+        if let Some(version) = current_version {
+            self.publish(ReloadEvent::Rollback {
+                policy: policy.to_string(),
+                version,
+                source,
+                timestamp: now,
+            });
+        }
+    }
+
+    /// Forget a policy that was removed
+    pub fn remove(&self, policy: &str) {
+        self.policies.lock().remove(policy);
+    }
+
+    fn publish(&self, event: ReloadEvent) {
+        // No subscribers is not an error
+        let _ = self.events.send(event);
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_client;
+    use crate::policy_store::PolicyStore;
+    use crate::test_support::{activate_default, serve, temp_dir};
+    use crate::AppState;
+    use axum::body::Bytes;
+    use axum::http::{Method, StatusCode};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn failure() -> ConnectorError {
+        ConnectorError::WasmLoadError("bad magic".to_string())
+    }
+
+    fn kinds(events: &mut broadcast::Receiver<ReloadEvent>) -> Vec<&'static str> {
+        std::iter::from_fn(|| events.try_recv().ok())
+            .map(|event| event.kind())
+            .collect()
+    }
+
+    #[test]
+    fn records_reloads_failures_and_rollbacks() {
+        let status = ReloadStatus::new();
+        let mut events = status.subscribe();
+
+        // Nothing to roll back to yet, nor a policy to report on
+        status.record_failure("default", &failure(), ReloadSource::Startup);
+        assert_eq!(kinds(&mut events), ["failure"]);
+        assert!(status.snapshot().is_empty());
+
+        status.record_success("default", "1.0.0", ReloadSource::Startup);
+        assert_eq!(kinds(&mut events), ["reload"]);
+        assert!(!status.snapshot()["default"].is_failing());
+
+        status.record_failure("default", &failure(), ReloadSource::Watcher);
+        let failed = std::iter::from_fn(|| events.try_recv().ok()).collect::<Vec<_>>();
+        assert!(matches!(&failed[..], [
+            ReloadEvent::Failure { source: ReloadSource::Watcher, error, .. },
+            ReloadEvent::Rollback { version, .. },
+        ] if error.contains("bad magic") && version == "1.0.0"));
+
+        let policy = &status.snapshot()["default"];
+        assert!(policy.is_failing());
+        assert_eq!(policy.current_version.as_deref(), Some("1.0.0"));
+        assert_eq!(
+            (
+                policy.reloads,
+                policy.reload_failures,
+                policy.consecutive_failures
+            ),
+            (1, 1, 1)
+        );
+        assert_eq!(policy.last_source, Some(ReloadSource::Watcher));
+
+        status.record_success("default", "1.0.1", ReloadSource::Admin);
+        assert_eq!(status.snapshot()["default"].consecutive_failures, 0);
+        status.remove("default");
+        assert!(status.snapshot().is_empty());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn streams_events_to_sse_clients() {
+        let state = Arc::new(AppState::new(
+            PolicyStore::new(temp_dir("status-sse")),
+            "default",
+        ));
+        let (addr, _server) = serve(crate::server::router(state.clone())).await;
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut received = String::new();
+        let mut buffer = [0u8; 4096];
+        // The handler has subscribed once the response headers are out
+        while !received.contains("\r\n\r\n") {
+            let n = stream.read(&mut buffer).await.unwrap();
+            received.push_str(&String::from_utf8_lossy(&buffer[..n]));
+        }
+        assert!(received.starts_with("HTTP/1.1 200"));
+        assert!(received.contains("content-type: text/event-stream"));
+
+        let status = state.policies.status();
+        status.record_success("default", "1.0.0", ReloadSource::Manual);
+        status.record_failure("default", &failure(), ReloadSource::Manual);
+
+        let read_all = async {
+            while !received.contains("event: rollback") {
+                let n = stream.read(&mut buffer).await.unwrap();
+                assert!(n > 0, "stream closed");
+                received.push_str(&String::from_utf8_lossy(&buffer[..n]));
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), read_all)
+            .await
+            .unwrap();
+
+        let data: Vec<serde_json::Value> = received
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        let types: Vec<&str> = data
+            .iter()
+            .map(|event| event["type"].as_str().unwrap())
+            .collect();
+        assert_eq!(types, ["reload", "failure", "rollback"]);
+        assert_eq!(data[0]["version"], "1.0.0");
+        assert_eq!(data[0]["source"], "manual");
+        assert_eq!(data[2]["version"], "1.0.0");
+        let order = ["event: reload", "event: failure", "event: rollback"]
+            .map(|e| received.find(e).unwrap());
+        assert!(order.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn rejected_uploads_of_new_names_leave_status_healthy() {
+        let policies = PolicyStore::new(temp_dir("status-new-name"));
+        activate_default(&policies, "default").await;
+        let state = AppState::new(policies, "default").with_admin_token(Some("secret".to_string()));
+        let (addr, _server) = serve(crate::server::router(Arc::new(state))).await;
+        let client = http_client::new_client();
+
+        let uri = format!("http://{}/policies/fresh", addr).parse().unwrap();
+        let token = [("authorization", "Bearer secret")];
+        // A valid module, but without the policy exports
+        let body = Bytes::from_static(b"\0asm\x01\0\0\0");
+        let response = http_client::send(&client, Method::PUT, &uri, &token, body)
+            .await
+            .unwrap();
+        assert_eq!(response.status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let uri = format!("http://{}/policies/status", addr).parse().unwrap();
+        let response = http_client::get(&client, &uri, &[]).await.unwrap();
+        let status: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(status["healthy"], true);
+        assert!(status["policies"].get("fresh").is_none());
+    }
+}
@@ -0,0 +1,359 @@
+//! Background sync of policies from a remote HTTP registry
+//!
+//! The registry serves a JSON index listing policy bundles and their SHA-256
+//! digests:
+//!
+//! ```json
+//! { "policies": [ { "name": "default", "url": "bundles/default.json", "sha256": "..." } ] }
+//! ```
+//!
+//! The index is polled with `If-None-Match`, changed bundles are downloaded,
+//! verified against their digest, self-tested and staged into the policies
+//! directory. Any failure leaves the last-known-good policy active.
+//!
+//! The digests are only as trustworthy as the index, so the index and every
+//! bundle are fetched over `https://`, or plain HTTP to a loopback address
+//! such as a TLS-terminating sidecar.
+
+use crate::error::{ConnectorError, ConnectorResult};
+use crate::http_client::{self, HttpClient};
+use crate::policy_store::{is_valid_policy_name, parse_bundle, sha256_hex};
+use crate::status::ReloadSource;
+use crate::AppState;
+use axum::http::{StatusCode, Uri};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Registry index document
+#[derive(Debug, Deserialize)]
+struct PolicyIndex {
+    policies: Vec<IndexEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IndexEntry {
+    name: String,
+    /// Bundle location, absolute or relative to the index URL
+    url: String,
+    /// Hex-encoded SHA-256 of the bundle as served
+    sha256: String,
+}
+
+/// Periodically pull the registry index and install changed policies
+pub async fn sync_policies(state: Arc<AppState>, index_url: Uri, interval: Duration) {
+    let client = http_client::new_client();
+    let mut etag: Option<String> = None;
+
+    tracing::info!(
+        %index_url,
+        interval_secs = interval.as_secs(),
+        "Syncing policies from registry"
+    );
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        if let Err(e) = sync_once(&state, &client, &index_url, &mut etag).await {
+            tracing::warn!(error = %e, "Registry sync failed, keeping current policies");
+        }
+    }
+}
+
+/// Run one sync pass
+///
+/// `etag` is only advanced once every entry was installed, so a bundle that
+/// failed to download or verify is retried on the next pass.
+async fn sync_once(
+    state: &AppState,
+    client: &HttpClient,
+    index_url: &Uri,
+    etag: &mut Option<String>,
+) -> ConnectorResult<()> {
+    let mut headers = vec![("accept", "application/json")];
+    if let Some(etag) = etag.as_deref() {
+        headers.push(("if-none-match", etag));
+    }
+
+    let response = http_client::get(client, index_url, &headers).await?;
+    if response.status == StatusCode::NOT_MODIFIED {
+        return Ok(());
+    }
+    if !response.status.is_success() {
+        return Err(ConnectorError::RegistryError(format!(
+            "Index request returned {}",
+            response.status
+        )));
+    }
+
+    let index: PolicyIndex = serde_json::from_slice(&response.body)
+        .map_err(|e| ConnectorError::RegistryError(format!("Invalid policy index: {}", e)))?;
+
+    let mut all_ok = true;
+    for entry in &index.policies {
+        if let Err(e) = sync_entry(state, client, index_url, entry).await {
+            tracing::warn!(policy = entry.name.as_str(), error = %e, "Failed to sync policy");
+            all_ok = false;
+        }
+    }
+
+    *etag = if all_ok {
+        response.header("etag").map(str::to_string)
+    } else {
+        None
+    };
+    Ok(())
+}
+
+async fn sync_entry(
+    state: &AppState,
+    client: &HttpClient,
+    index_url: &Uri,
+    entry: &IndexEntry,
+) -> ConnectorResult<()> {
+    if !is_valid_policy_name(&entry.name) {
+        return Err(ConnectorError::RegistryError(
+            "Invalid policy name".to_string(),
+        ));
+    }
+
+    let bundle_url = http_client::resolve_uri(index_url, &entry.url)?;
+    http_client::check_transport(&bundle_url).map_err(|e| {
+        ConnectorError::RegistryError(format!("Bundle URL {}: {}", bundle_url, e))
+    })?;
+    let response = http_client::get(client, &bundle_url, &[]).await?;
+    if !response.status.is_success() {
+        return Err(ConnectorError::RegistryError(format!(
+            "Bundle request returned {}",
+            response.status
+        )));
+    }
+
+    let digest = sha256_hex(&response.body);
+    if !digest.eq_ignore_ascii_case(&entry.sha256) {
+        return Err(ConnectorError::RegistryError(format!(
+            "Digest mismatch: expected {}, got {}",
+            entry.sha256, digest
+        )));
+    }
+
+    let (wasm_bytes, manifest) =
+        parse_bundle(&response.body).map_err(ConnectorError::RegistryError)?;
+
+    if let Some(current) = state.policies.get(&entry.name).await {
+        if current.is_unchanged(&wasm_bytes, &manifest) {
+            return Ok(());
+        }
+    }
+
+    let policy = state
+        .policies
+        .install(
+            &entry.name,
+            &wasm_bytes,
+            manifest,
+            true,
+            ReloadSource::Registry,
+        )
+        .await?;
+    tracing::info!(
+        policy = policy.name.as_str(),
+        policy_version = policy.version.as_str(),
+        "Policy synced from registry"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy_store::PolicyStore;
+    use crate::test_support::{policy_bundle, serve, temp_dir};
+    use axum::body::Bytes;
+    use axum::extract::State;
+    use axum::http::{header, HeaderMap};
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::get;
+    use axum::Router;
+    use parking_lot::Mutex;
+
+    /// What the stub registry serves and what it was asked
+    #[derive(Default)]
+    struct Registry {
+        etag: String,
+        index: String,
+        bundle: Vec<u8>,
+        /// `If-None-Match` of each index request
+        index_requests: Vec<Option<String>>,
+        bundle_requests: usize,
+    }
+
+    type Shared = Arc<Mutex<Registry>>;
+
+    impl Registry {
+        /// Serve `bundle`, listed with `sha256`, under a new ETag
+        fn publish(&mut self, bundle: Vec<u8>, sha256: &str) {
+            self.index = serde_json::json!({
+                "policies": [{ "name": "default", "url": "bundles/default.json", "sha256": sha256 }]
+            })
+            .to_string();
+            self.etag = format!("\"{}\"", &sha256[..16]);
+            self.bundle = bundle;
+        }
+    }
+
+    async fn index(State(registry): State<Shared>, headers: HeaderMap) -> Response {
+        let mut registry = registry.lock();
+        let if_none_match = headers
+            .get(header::IF_NONE_MATCH)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        registry.index_requests.push(if_none_match.clone());
+        if if_none_match.as_deref() == Some(registry.etag.as_str()) {
+            return StatusCode::NOT_MODIFIED.into_response();
+        }
+        (
+            [(header::ETAG, registry.etag.clone())],
+            registry.index.clone(),
+        )
+            .into_response()
+    }
+
+    async fn bundle(State(registry): State<Shared>) -> Bytes {
+        let mut registry = registry.lock();
+        registry.bundle_requests += 1;
+        Bytes::from(registry.bundle.clone())
+    }
+
+    async fn start(registry: &Shared) -> (Uri, tokio::task::JoinHandle<()>) {
+        let router = Router::new()
+            .route("/index.json", get(index))
+            .route("/bundles/default.json", get(bundle))
+            .with_state(registry.clone());
+        let (addr, server) = serve(router).await;
+        (
+            format!("http://{}/index.json", addr).parse().unwrap(),
+            server,
+        )
+    }
+
+    fn state(name: &str) -> AppState {
+        AppState::new(PolicyStore::new(temp_dir(name)), "default")
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn revalidates_the_index_with_its_etag() {
+        let registry = Shared::default();
+        let bundle = policy_bundle("1");
+        registry
+            .lock()
+            .publish(bundle.clone(), &sha256_hex(&bundle));
+        let (url, _server) = start(&registry).await;
+        let state = state("sync-etag");
+        let client = http_client::new_client();
+        let mut etag = None;
+
+        sync_once(&state, &client, &url, &mut etag).await.unwrap();
+        let installed = state.policies.get("default").await.unwrap();
+        assert_eq!(etag.as_deref(), Some(registry.lock().etag.as_str()));
+
+        // Unchanged: a 304, no download, nothing reinstalled
+        sync_once(&state, &client, &url, &mut etag).await.unwrap();
+        {
+            let registry = registry.lock();
+            assert_eq!(registry.index_requests, [None, Some(registry.etag.clone())]);
+            assert_eq!(registry.bundle_requests, 1);
+        }
+        let current = state.policies.get("default").await.unwrap();
+        assert!(Arc::ptr_eq(&installed, &current));
+
+        // A new manifest alone is installed
+        let bundle = policy_bundle("2");
+        registry
+            .lock()
+            .publish(bundle.clone(), &sha256_hex(&bundle));
+        sync_once(&state, &client, &url, &mut etag).await.unwrap();
+        let current = state.policies.get("default").await.unwrap();
+        let version = current.manifest.as_ref().and_then(|m| m.version.as_deref());
+        assert_eq!(version, Some("2"));
+        assert_eq!(registry.lock().bundle_requests, 2);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn rejects_bundles_that_do_not_match_their_digest() {
+        let registry = Shared::default();
+        let bundle = policy_bundle("1");
+        registry.lock().publish(bundle, &"ab".repeat(32));
+        let (url, _server) = start(&registry).await;
+        let state = state("sync-digest");
+        let client = http_client::new_client();
+        let mut etag = None;
+
+        sync_once(&state, &client, &url, &mut etag).await.unwrap();
+        assert!(state.policies.get("default").await.is_none());
+        assert!(!state.policies.module_path("default").exists());
+        // Not advanced, so the next pass fetches the index again
+        assert_eq!(etag, None);
+
+        let bundle = policy_bundle("1");
+        registry
+            .lock()
+            .publish(bundle.clone(), &sha256_hex(&bundle));
+        sync_once(&state, &client, &url, &mut etag).await.unwrap();
+        assert!(state.policies.get("default").await.is_some());
+        assert_eq!(registry.lock().index_requests, [None, None]);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn refuses_bundles_served_over_plain_http_from_other_hosts() {
+        let registry = Shared::default();
+        let bundle = policy_bundle("1");
+        registry
+            .lock()
+            .publish(bundle.clone(), &sha256_hex(&bundle));
+        let url = "http://registry.example/bundles/default.json";
+        registry.lock().index = serde_json::json!({
+            "policies": [{ "name": "default", "url": url, "sha256": sha256_hex(&bundle) }]
+        })
+        .to_string();
+        let (index_url, _server) = start(&registry).await;
+        let state = state("sync-transport");
+        let client = http_client::new_client();
+        let mut etag = None;
+
+        sync_once(&state, &client, &index_url, &mut etag)
+            .await
+            .unwrap();
+        assert!(state.policies.get("default").await.is_none());
+        assert_eq!(etag, None);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn keeps_the_last_known_good_policy_while_the_registry_is_down() {
+        let registry = Shared::default();
+        let bundle = policy_bundle("1");
+        registry
+            .lock()
+            .publish(bundle.clone(), &sha256_hex(&bundle));
+        let (url, server) = start(&registry).await;
+        let state = state("sync-offline");
+        let client = http_client::new_client();
+        let mut etag = None;
+
+        sync_once(&state, &client, &url, &mut etag).await.unwrap();
+        let installed = state.policies.get("default").await.unwrap();
+
+        server.abort();
+        let _ = server.await;
+        // A fresh client, so no pooled keep-alive connection outlives the listener
+        let client = http_client::new_client();
+        let result = sync_once(&state, &client, &url, &mut etag).await;
+        assert!(matches!(result, Err(ConnectorError::RegistryError(_))));
+
+        let current = state.policies.get("default").await.unwrap();
+        assert!(Arc::ptr_eq(&installed, &current));
+        let decision = state.decide(None, br#"{"role":"admin"}"#).await;
+        assert!(decision.allowed && decision.failure.is_none());
+    }
+}
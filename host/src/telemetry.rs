@@ -0,0 +1,391 @@
+//! Logging and tracing
+//!
+//! Host events are written through `tracing` as text or JSON lines with a
+//! level. Events during a request carry its `request_id` (taken from
+//! `x-request-id` or generated), so host and guest lines of one request can be
+//! correlated. Guest `host.log` output is captured per evaluation, capped in
+//! size by the runtime and rate-limited here before it reaches the log.
+//!
+//! Request handling, policy compilation, instantiation, guest execution and
+//! host-import calls run in spans. Decision spans carry the policy name and
+//! version, guest spans the fuel consumed, so a slow decision shows whether
+//! compile, instantiate or guest logic took the time. Built with the `otlp`
+//! feature and `tracing.otlp_endpoint` set, spans are batched and exported
+//! over OTLP/gRPC.
+
+use crate::error::{ConnectorError, ConnectorResult};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+pub const DEFAULT_GUEST_LINES_PER_SEC: u32 = 50;
+
+/// The `[logging]` config section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    /// Least severe level written
+    pub level: LogLevel,
+    /// Guest log lines written per second across all evaluations; 0 mutes
+    /// guest logs
+    pub guest_lines_per_sec: u32,
+    /// Honor `?debug=true` on `/evaluate`, returning the guest logs
+    pub allow_debug: bool,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Text,
+            level: LogLevel::Info,
+            guest_lines_per_sec: DEFAULT_GUEST_LINES_PER_SEC,
+            allow_debug: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+/// The `[tracing]` config section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingConfig {
+    /// OTLP/gRPC collector, e.g. `http://localhost:4317`; `None` disables export
+    pub otlp_endpoint: Option<String>,
+    /// `service.name` of exported spans
+    pub service_name: String,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: "nano-wasm-edge".to_string(),
+        }
+    }
+}
+
+/// Installed log writer and span export; flush it with
+/// [`shutdown`](Self::shutdown)
+#[derive(Default)]
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::TracerProvider>,
+}
+
+impl Telemetry {
+    /// Install the log writer and, if configured, the span exporter
+    ///
+    /// Must be called from within the tokio runtime.
+    pub fn init(logging: &LoggingConfig, tracing: &TracingConfig) -> ConnectorResult<Self> {
+        let level = LevelFilter::from(logging.level);
+        let log_layer = match logging.format {
+            LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+            LogFormat::Json => tracing_subscriber::fmt::layer()
+                .json()
+                .flatten_event(true)
+                .with_current_span(false)
+                .with_span_list(true)
+                .boxed(),
+        }
+        .with_filter(level);
+
+        #[cfg(feature = "otlp")]
+        let (span_layer, provider) = match otlp_provider(tracing)? {
+            Some(provider) => {
+                use opentelemetry::trace::TracerProvider as _;
+                let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+                let layer = tracing_opentelemetry::layer().with_tracer(tracer);
+                (Some(layer), Some(provider))
+            }
+            None => (None, None),
+        };
+        #[cfg(not(feature = "otlp"))]
+        let span_layer: Option<tracing_subscriber::layer::Identity> = {
+            let _ = tracing;
+            None
+        };
+
+        tracing_subscriber::registry()
+            .with(log_layer)
+            .with(span_layer)
+            .try_init()
+            .map_err(|e| ConnectorError::ConfigError(format!("tracing subscriber: {}", e)))?;
+
+        Ok(Self {
+            #[cfg(feature = "otlp")]
+            provider,
+        })
+    }
+
+    /// Export buffered spans and stop the exporter
+    pub async fn shutdown(self) {
+        // The gRPC channel is driven by this runtime, so the blocking flush
+        // has to run elsewhere
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider {
+            match tokio::task::spawn_blocking(move || provider.shutdown()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => tracing::warn!(error = %e, "Failed to flush spans"),
+                Err(e) => tracing::warn!(error = %e, "Failed to flush spans"),
+            }
+        }
+    }
+}
+
+/// Batched OTLP/gRPC span export, if `tracing.otlp_endpoint` is set
+#[cfg(feature = "otlp")]
+fn otlp_provider(
+    config: &TracingConfig,
+) -> ConnectorResult<Option<opentelemetry_sdk::trace::TracerProvider>> {
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{runtime, Resource};
+
+    let Some(endpoint) = &config.otlp_endpoint else {
+        return Ok(None);
+    };
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint.clone())
+        .build()
+        .map_err(|e| ConnectorError::ConfigError(format!("OTLP exporter: {}", e)))?;
+    let resource = Resource::new([KeyValue::new("service.name", config.service_name.clone())]);
+    Ok(Some(
+        opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::TokioCurrentThread)
+            .with_resource(resource)
+            .build(),
+    ))
+}
+
+/// Writes guest log lines as `guest` events, at most `lines_per_sec`
+pub struct GuestLogs {
+    lines_per_sec: u32,
+    window: Mutex<LogWindow>,
+    dropped_total: AtomicU64,
+}
+
+struct LogWindow {
+    started: Instant,
+    written: u32,
+    dropped: u64,
+}
+
+impl Default for GuestLogs {
+    fn default() -> Self {
+        Self::new(DEFAULT_GUEST_LINES_PER_SEC)
+    }
+}
+
+impl GuestLogs {
+    pub fn new(lines_per_sec: u32) -> Self {
+        Self {
+            lines_per_sec,
+            window: Mutex::new(LogWindow {
+                started: Instant::now(),
+                written: 0,
+                dropped: 0,
+            }),
+            dropped_total: AtomicU64::new(0),
+        }
+    }
+
+    /// Write the log lines of one evaluation of `policy`
+    pub fn write(&self, policy: &str, lines: &[String]) {
+        for line in lines {
+            if self.admit() {
+                tracing::info!(target: "guest", policy, "{}", line);
+            }
+        }
+    }
+
+    /// Lines not written because of the rate limit
+    pub fn dropped(&self) -> u64 {
+        self.dropped_total.load(Ordering::Relaxed)
+    }
+
+    fn admit(&self) -> bool {
+        let (admitted, dropped_last_window) = {
+            let mut window = self.window.lock();
+            let mut dropped_last_window = 0;
+            if window.started.elapsed() >= Duration::from_secs(1) {
+                dropped_last_window = window.dropped;
+                *window = LogWindow {
+                    started: Instant::now(),
+                    written: 0,
+                    dropped: 0,
+                };
+            }
+            let admitted = window.written < self.lines_per_sec;
+            if admitted {
+                window.written += 1;
+            } else {
+                window.dropped += 1;
+            }
+            (admitted, dropped_last_window)
+        };
+
+        if !admitted {
+            self.dropped_total.fetch_add(1, Ordering::Relaxed);
+        }
+        if dropped_last_window > 0 && self.lines_per_sec > 0 {
+            tracing::warn!(
+                dropped = dropped_last_window,
+                "Guest log rate limit hit, lines dropped"
+            );
+        }
+        admitted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy_store::PolicyStore;
+    use crate::test_support::activate_default;
+    use crate::AppState;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::Subscriber;
+    use tracing_subscriber::layer::Context;
+    use tracing_subscriber::registry::LookupSpan;
+
+    #[test]
+    fn admits_lines_up_to_the_rate_and_counts_the_rest() {
+        let logs = GuestLogs::new(3);
+        let admitted: Vec<bool> = (0..5).map(|_| logs.admit()).collect();
+        assert_eq!(admitted, [true, true, true, false, false]);
+        assert_eq!(logs.dropped(), 2);
+
+        // A new window admits lines again; the total keeps counting
+        logs.window.lock().started = Instant::now() - Duration::from_secs(1);
+        assert!(logs.admit());
+        assert!(logs.admit() && logs.admit() && !logs.admit());
+        assert_eq!(logs.dropped(), 3);
+
+        let muted = GuestLogs::new(0);
+        muted.write("default", &["a".to_string(), "b".to_string()]);
+        assert_eq!(muted.dropped(), 2);
+    }
+
+    type SpanFields = HashMap<String, String>;
+
+    /// Name and fields of every span, as recorded so far
+    #[derive(Clone, Default)]
+    struct Spans(Arc<Mutex<HashMap<u64, (String, SpanFields)>>>);
+
+    struct Fields<'a>(&'a mut SpanFields);
+
+    impl Visit for Fields<'_> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> tracing_subscriber::Layer<S> for Spans {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, _ctx: Context<'_, S>) {
+            let mut fields = HashMap::new();
+            attrs.record(&mut Fields(&mut fields));
+            let name = attrs.metadata().name().to_string();
+            self.0.lock().insert(id.into_u64(), (name, fields));
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, _ctx: Context<'_, S>) {
+            if let Some((_, fields)) = self.0.lock().get_mut(&id.into_u64()) {
+                values.record(&mut Fields(fields));
+            }
+        }
+    }
+
+    impl Spans {
+        /// Fields of the spans called `name`
+        fn named(&self, name: &str) -> Vec<SpanFields> {
+            let spans = self.0.lock();
+            spans
+                .values()
+                .filter(|(span, _)| span == name)
+                .map(|(_, fields)| fields.clone())
+                .collect()
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn spans_carry_policy_version_and_fuel() {
+        let spans = Spans::default();
+        let _guard = tracing_subscriber::registry()
+            .with(spans.clone())
+            .set_default();
+        let policies = PolicyStore::new(std::env::temp_dir());
+        activate_default(&policies, "sensors").await;
+        let state = AppState::new(policies, "default");
+
+        // Guest spans report the budget and what the guest used of it. With
+        // one subscriber, callsite interest is cached from whichever thread
+        // hits it first; other tests and blocking threads have none.
+        tracing::callsite::rebuild_interest_cache();
+        let policy = state.policies.get("sensors").await.unwrap();
+        let evaluation = policy.runtime.evaluate(br#"{"role":"viewer"}"#).unwrap();
+        let guest = spans.named("guest.evaluate_access");
+        assert_eq!(guest.len(), 1);
+        assert_eq!(
+            guest[0]["fuel_consumed"],
+            evaluation.fuel_consumed.to_string()
+        );
+        assert_eq!(
+            guest[0]["fuel_limit"],
+            crate::policy_runtime::DEFAULT_FUEL_LIMIT.to_string()
+        );
+
+        let decision = state.decide(Some("sensors"), br#"{"role":"viewer"}"#).await;
+        assert!(decision.allowed);
+        let decide = spans.named("decide");
+        assert_eq!(decide.len(), 1);
+        assert_eq!(decide[0]["policy"], "sensors");
+        assert_eq!(decide[0]["policy_version"], decision.policy_version);
+        assert_eq!(
+            decide[0]["fuel_consumed"],
+            evaluation.fuel_consumed.to_string()
+        );
+    }
+}
@@ -0,0 +1,63 @@
+//! Helpers shared by the unit tests
+
+use crate::policy_runtime::{DEFAULT_FUEL_LIMIT, DEFAULT_TRANSFORM_FUEL_LIMIT};
+use crate::policy_store::{LoadedPolicy, PolicyStore};
+use axum::response::Response;
+use axum::Router;
+use base64::Engine;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::task::JoinHandle;
+
+/// An empty directory for the test `name`
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("nano-wasm-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Serve `router` on a free local port; aborting the task closes the port
+pub async fn serve(router: Router) -> (SocketAddr, JoinHandle<()>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        axum::serve(listener, router).await.unwrap();
+    });
+    (addr, server)
+}
+
+/// The example policy shipped in `policies/`
+pub const DEFAULT_MODULE: &[u8] = include_bytes!("../../policies/default.wasm");
+
+/// A JSON policy bundle of [`DEFAULT_MODULE`] with manifest `version`
+pub fn policy_bundle(version: &str) -> Vec<u8> {
+    serde_json::json!({
+        "module": base64::engine::general_purpose::STANDARD.encode(DEFAULT_MODULE),
+        "manifest": { "version": version },
+    })
+    .to_string()
+    .into_bytes()
+}
+
+/// Activate [`DEFAULT_MODULE`] in `policies` under `name`
+pub async fn activate_default(policies: &PolicyStore, name: &str) {
+    let policy = LoadedPolicy::compile(
+        name,
+        DEFAULT_MODULE,
+        None,
+        DEFAULT_FUEL_LIMIT,
+        DEFAULT_TRANSFORM_FUEL_LIMIT,
+    )
+    .unwrap();
+    policies.activate(policy).await;
+}
+
+/// Status and JSON body of `response`
+pub async fn json_response(response: Response) -> (axum::http::StatusCode, serde_json::Value) {
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (status, serde_json::from_slice(&body).unwrap())
+}
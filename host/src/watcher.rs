@@ -2,84 +2,374 @@
 //!
 //! Watches the policies directory and triggers atomic module swap
 //! when .wasm files are modified.
+//!
+//! Events are treated as a hint that *something* in the directory changed
+//! rather than trusted per path: atomic `x.wasm.tmp -> x.wasm` renames and
+//! Kubernetes ConfigMap `..data` symlink swaps never report the policy's own
+//! path. Every event therefore triggers a rescan, and only modules whose
+//! resolved path, size or mtime changed are read and, if their content hash
+//! differs, reloaded. Before reading, each file must keep the same
+//! fingerprint across a settle window so a partially written module is never
+//! picked up.
 
-use crate::{make_policy_version, policy_runtime::PolicyRuntime, AppState};
-use notify_debouncer_mini::{new_debouncer, notify::*, DebounceEventResult};
-use std::path::Path;
+use crate::{error::ConnectorError, status::ReloadSource, AppState};
+use notify_debouncer_mini::{
+    new_debouncer_opt, notify::*, Config as DebouncerConfig, DebounceEventResult, Debouncer,
+};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tokio::sync::mpsc;
 
+/// Give up waiting for a file to settle after this many windows
+const MAX_SETTLE_ROUNDS: u32 = 50;
+
+/// How the watcher learns about changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchMode {
+    /// Native OS notifications (inotify, FSEvents, ...), polling if unavailable
+    Native,
+    /// Periodic directory scans for filesystems without notifications
+    Poll(Duration),
+}
+
+/// Watcher tuning knobs
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    pub mode: WatchMode,
+    /// Quiet period before a burst of events is delivered
+    pub debounce: Duration,
+    /// A file must be unchanged for this long before it is read
+    pub settle: Duration,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self {
+            mode: WatchMode::Native,
+            debounce: Duration::from_millis(500),
+            settle: Duration::from_millis(200),
+        }
+    }
+}
+
 /// Watch the policies directory and hot-reload on changes
-pub async fn watch_policies(
-    state: Arc<AppState>,
-    policies_dir: &Path,
-    policy_file: &str,
-) {
-    let (tx, mut rx) = mpsc::channel::<()>(10);
+pub async fn watch_policies(state: Arc<AppState>, policies_dir: &Path, options: WatchOptions) {
+    // Capacity 1: events arriving while a rescan is pending are coalesced
+    let (tx, mut rx) = mpsc::channel::<()>(1);
     let policies_path = policies_dir.to_path_buf();
+    let watch_options = options.clone();
 
     // Spawn blocking watcher thread
     std::thread::spawn(move || {
-        let debouncer_tx = tx.clone();
-        let mut debouncer = match new_debouncer(
-            Duration::from_millis(500),
-            move |res: DebounceEventResult| {
-                if let Ok(events) = res {
-                    for event in events {
-                        if event.path.extension().map_or(false, |e| e == "wasm") {
-                            let _ = debouncer_tx.blocking_send(());
-                        }
-                    }
-                }
-            },
-        ) {
-            Ok(d) => d,
+        let handler = move |res: DebounceEventResult| {
+            if res.is_ok_and(|events| !events.is_empty()) {
+                let _ = tx.try_send(());
+            }
+        };
+
+        let _watcher = match start_watcher(&policies_path, &watch_options, handler) {
+            Ok(watcher) => watcher,
             Err(e) => {
-                eprintln!("Failed to create file watcher: {}", e);
+                tracing::error!(error = %e, "Failed to watch policies directory");
                 return;
             }
         };
 
-        if let Err(e) = debouncer
-            .watcher()
-            .watch(&policies_path, RecursiveMode::NonRecursive)
-        {
-            eprintln!("Failed to watch policies directory: {}", e);
-            return;
-        }
-
-        println!("Watching {} for policy changes", policies_path.display());
-
         // Keep thread alive
         loop {
             std::thread::park();
         }
     });
 
-    let policy_path = policies_dir.join(policy_file);
+    // Fingerprints of the files as last processed. Unchanged files are not
+    // read again, which also keeps our own reads (inotify open/access events)
+    // from triggering endless rescans and retries of a broken module.
+    let mut seen: HashMap<String, Fingerprint> = state
+        .policies
+        .scan_dir()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|name| {
+            let fingerprint = file_fingerprint(&state.policies.module_path(&name))?;
+            Some((name, fingerprint))
+        })
+        .collect();
 
     // Process reload events
     while rx.recv().await.is_some() {
-        println!("Detected policy change, hot-reloading...");
-
-        match tokio::fs::read(&policy_path).await {
-            Ok(bytes) => match PolicyRuntime::new(&bytes) {
-                Ok(new_runtime) => {
-                    let new_version = make_policy_version(bytes.len());
-                    let mut guard = state.runtime.write().await;
-                    *guard = Arc::new(new_runtime);
-                    let mut version = state.policy_version.write().await;
-                    *version = new_version;
-                    println!("✓ Policy hot-reload successful");
+        rescan(&state, policies_dir, options.settle, &mut seen).await;
+    }
+}
+
+/// Keeps whichever debouncer is running alive
+enum ActiveWatcher {
+    Native(#[allow(dead_code)] Debouncer<RecommendedWatcher>),
+    Poll(#[allow(dead_code)] Debouncer<PollWatcher>),
+}
+
+fn start_watcher(
+    dir: &Path,
+    options: &WatchOptions,
+    handler: impl Fn(DebounceEventResult) + Send + Clone + 'static,
+) -> Result<ActiveWatcher> {
+    let base = DebouncerConfig::default().with_timeout(options.debounce);
+
+    if options.mode == WatchMode::Native {
+        let native = new_debouncer_opt::<_, RecommendedWatcher>(base.clone(), handler.clone())
+            .and_then(|mut d| {
+                d.watcher().watch(dir, RecursiveMode::NonRecursive)?;
+                Ok(d)
+            });
+        match native {
+            Ok(debouncer) => {
+                tracing::info!(dir = %dir.display(), "Watching for policy changes");
+                return Ok(ActiveWatcher::Native(debouncer));
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "Native file watching unavailable, falling back to polling");
+            }
+        }
+    }
+
+    let interval = match options.mode {
+        WatchMode::Poll(interval) => interval,
+        WatchMode::Native => Duration::from_secs(2),
+    };
+    // Compare contents so a symlink swap to a file with an identical mtime
+    // (ConfigMap `..data` updates) is still noticed; policies are small
+    let notify_config = notify_debouncer_mini::notify::Config::default()
+        .with_poll_interval(interval)
+        .with_compare_contents(true);
+    let mut debouncer =
+        new_debouncer_opt::<_, PollWatcher>(base.with_notify_config(notify_config), handler)?;
+    debouncer
+        .watcher()
+        .watch(dir, RecursiveMode::NonRecursive)?;
+    tracing::info!(
+        dir = %dir.display(),
+        interval_ms = interval.as_millis() as u64,
+        "Polling for policy changes"
+    );
+    Ok(ActiveWatcher::Poll(debouncer))
+}
+
+/// Reload every policy in the directory whose content changed
+async fn rescan(
+    state: &AppState,
+    policies_dir: &Path,
+    settle: Duration,
+    seen: &mut HashMap<String, Fingerprint>,
+) {
+    let names = match state.policies.scan_dir() {
+        Ok(names) => names,
+        Err(e) => {
+            tracing::warn!(dir = %policies_dir.display(), error = %e, "Failed to scan policies directory");
+            return;
+        }
+    };
+    seen.retain(|name, _| names.contains(name));
+
+    let changed: Vec<String> = names
+        .into_iter()
+        .filter(|name| {
+            file_fingerprint(&state.policies.module_path(name)).as_ref() != seen.get(name)
+        })
+        .collect();
+
+    for (name, fingerprint) in wait_until_stable(state, changed, settle).await {
+        seen.insert(name.clone(), fingerprint);
+
+        match state
+            .policies
+            .reload_if_changed(&name, ReloadSource::Watcher)
+            .await
+        {
+            Ok(Some(policy)) => tracing::info!(
+                policy = name.as_str(),
+                policy_version = policy.version.as_str(),
+                "Policy hot-reloaded"
+            ),
+            Ok(None) => {}
+            Err(ConnectorError::IoError(e)) => {
+                tracing::warn!(policy = name.as_str(), error = %e, "Failed to read policy file");
+            }
+            Err(e) => {
+                tracing::warn!(policy = name.as_str(), error = %e, "Failed to compile new policy");
+            }
+        }
+    }
+}
+
+/// Wait until each module keeps its fingerprint for a full settle window,
+/// returning the names that settled. Files that vanish meanwhile are dropped;
+/// deleted modules are unloaded through the admin API, not here.
+async fn wait_until_stable(
+    state: &AppState,
+    names: Vec<String>,
+    settle: Duration,
+) -> Vec<(String, Fingerprint)> {
+    let mut pending: Vec<(String, Option<Fingerprint>)> = names
+        .into_iter()
+        .map(|name| {
+            let fingerprint = file_fingerprint(&state.policies.module_path(&name));
+            (name, fingerprint)
+        })
+        .collect();
+    let mut settled = Vec::new();
+
+    for _ in 0..MAX_SETTLE_ROUNDS {
+        if pending.is_empty() {
+            break;
+        }
+        tokio::time::sleep(settle).await;
+
+        pending.retain_mut(|(name, previous)| {
+            let current = file_fingerprint(&state.policies.module_path(name));
+            match current {
+                None => false,
+                Some(fingerprint) if Some(&fingerprint) == previous.as_ref() => {
+                    settled.push((std::mem::take(name), fingerprint));
+                    false
                 }
-                Err(e) => {
-                    eprintln!("✗ Failed to compile new policy: {}", e);
+                current => {
+                    *previous = current;
+                    true
                 }
-            },
-            Err(e) => {
-                eprintln!("✗ Failed to read policy file: {}", e);
             }
+        });
+    }
+
+    for (name, _) in pending {
+        tracing::warn!(policy = name.as_str(), "Policy file did not settle, skipping");
+    }
+    settled
+}
+
+/// Resolved path, size and mtime of a module. Resolving symlinks makes a
+/// ConfigMap `..data` swap visible even if size and mtime happen to match.
+type Fingerprint = (PathBuf, u64, SystemTime);
+
+fn file_fingerprint(path: &Path) -> Option<Fingerprint> {
+    let resolved = std::fs::canonicalize(path).ok()?;
+    let metadata = std::fs::metadata(&resolved).ok()?;
+    Some((resolved, metadata.len(), metadata.modified().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy_store::PolicyStore;
+    use crate::status::ReloadEvent;
+    use crate::test_support::{temp_dir, DEFAULT_MODULE};
+    use tokio::sync::broadcast;
+
+    /// [`DEFAULT_MODULE`] with a custom section, so each version hashes differently
+    fn module(version: u8) -> Vec<u8> {
+        [DEFAULT_MODULE, &[0, 3, 1, b'v', version]].concat()
+    }
+
+    /// Load `default` from `dir`, start watching it and return the event stream
+    async fn watch(dir: &Path) -> (Arc<AppState>, broadcast::Receiver<ReloadEvent>) {
+        let state = Arc::new(AppState::new(PolicyStore::new(dir), "default"));
+        state
+            .policies
+            .load_from_disk("default", ReloadSource::Startup)
+            .await
+            .unwrap();
+        let events = state.policies.status().subscribe();
+        let options = WatchOptions {
+            mode: WatchMode::Native,
+            debounce: Duration::from_millis(50),
+            settle: Duration::from_millis(300),
+        };
+        let (watched, dir) = (state.clone(), dir.to_path_buf());
+        tokio::spawn(async move { watch_policies(watched, &dir, options).await });
+        // Let the watcher take its baseline before anything changes
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        (state, events)
+    }
+
+    /// Every event until none arrived for a while; the first may take a poll round
+    async fn collect(events: &mut broadcast::Receiver<ReloadEvent>) -> Vec<ReloadEvent> {
+        let mut collected = Vec::new();
+        let mut quiet = Duration::from_secs(10);
+        while let Ok(Ok(event)) = tokio::time::timeout(quiet, events.recv()).await {
+            collected.push(event);
+            quiet = Duration::from_millis(1500);
         }
+        collected
+    }
+
+    fn assert_one_watcher_reload(events: &[ReloadEvent]) {
+        assert_eq!(events.len(), 1, "{:?}", events);
+        assert!(
+            matches!(&events[0], ReloadEvent::Reload { policy, source: ReloadSource::Watcher, .. } if policy == "default"),
+            "{:?}",
+            events
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn reloads_once_after_an_atomic_rename() {
+        let dir = temp_dir("watch-rename");
+        std::fs::write(dir.join("default.wasm"), module(1)).unwrap();
+        let (state, mut events) = watch(&dir).await;
+
+        std::fs::write(dir.join("default.wasm.tmp"), module(2)).unwrap();
+        std::fs::rename(dir.join("default.wasm.tmp"), dir.join("default.wasm")).unwrap();
+
+        assert_one_watcher_reload(&collect(&mut events).await);
+        let current = state.policies.get("default").await.unwrap();
+        assert_eq!(current.sha256, crate::policy_store::sha256_hex(&module(2)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "current_thread")]
+    async fn reloads_once_after_a_configmap_symlink_swap() {
+        use std::os::unix::fs::symlink;
+
+        // The layout Kubernetes mounts a ConfigMap with
+        let dir = temp_dir("watch-symlink");
+        std::fs::create_dir(dir.join("..v1")).unwrap();
+        std::fs::write(dir.join("..v1/default.wasm"), module(1)).unwrap();
+        symlink("..v1", dir.join("..data")).unwrap();
+        symlink("..data/default.wasm", dir.join("default.wasm")).unwrap();
+        let (state, mut events) = watch(&dir).await;
+
+        std::fs::create_dir(dir.join("..v2")).unwrap();
+        std::fs::write(dir.join("..v2/default.wasm"), module(2)).unwrap();
+        symlink("..v2", dir.join("..data_tmp")).unwrap();
+        std::fs::rename(dir.join("..data_tmp"), dir.join("..data")).unwrap();
+
+        assert_one_watcher_reload(&collect(&mut events).await);
+        let current = state.policies.get("default").await.unwrap();
+        assert_eq!(current.sha256, crate::policy_store::sha256_hex(&module(2)));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn waits_for_a_partial_write_to_settle() {
+        let dir = temp_dir("watch-settle");
+        std::fs::write(dir.join("default.wasm"), module(1)).unwrap();
+        let (state, mut events) = watch(&dir).await;
+
+        // Written in place in two steps, the first half alone does not compile
+        let next = module(2);
+        let (head, tail) = next.split_at(next.len() / 2);
+        std::fs::write(dir.join("default.wasm"), head).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join("default.wasm"))
+            .unwrap();
+        std::io::Write::write_all(&mut file, tail).unwrap();
+        drop(file);
+
+        assert_one_watcher_reload(&collect(&mut events).await);
+        assert_eq!(
+            state.policies.status().snapshot()["default"].reload_failures,
+            0
+        );
     }
 }
@@ -45,4 +45,43 @@ pub struct PolicyResponse {
     pub policy_version: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
+    /// Failure mode that decided `allowed` when evaluation failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure_mode: Option<FailureMode>,
+    /// Guest log lines, when the request asked for them with `debug=true`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logs: Option<Vec<String>>,
+}
+
+/// Response from the batch evaluation endpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchResponse {
+    pub policy: String,
+    /// Every result was decided by this version of the policy
+    pub policy_version: String,
+    /// One result per request, in request order
+    pub results: Vec<BatchResult>,
+}
+
+/// Decision for one request of a batch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchResult {
+    pub allowed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Stable error code, as in problem+json responses
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure_mode: Option<FailureMode>,
+}
+
+/// What to decide when a policy cannot be evaluated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailureMode {
+    /// Allow the request
+    Open,
+    /// Deny the request
+    Closed,
 }